@@ -0,0 +1,1122 @@
+//! The HTTP [`Agent`] of the web songs: which TLS certificates are trusted and which credentials are sent.
+//!
+//! The settings come from the command-line options and from a JSON configuration file
+//! (`$XDG_CONFIG_HOME/audio-player/web.json` by default):
+//! ```json
+//! {
+//!     "tls": {
+//!         "system_roots": false,
+//!         "bundled_ca": false,
+//!         "ca": ["/etc/audio-player/ca.pem", "/etc/audio-player/certs/"],
+//!         "pins": ["9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"],
+//!         "client_cert": "/etc/audio-player/client.pem",
+//!         "client_key": "/etc/audio-player/client.key"
+//!     },
+//!     "credentials": [
+//!         {"host": "music.example.com", "basic": {"username": "user", "password": "secret"}},
+//!         {"host": "cdn.example.com", "bearer": {"token": "secret"}}
+//!     ]
+//! }
+//! ```
+//!
+//! The certificate authority bundled with a program (if it has one) is trusted with the other ones,
+//! unless `bundled_ca` is `false` (or `--no-bundled-ca` is given).
+//!
+//! The credentials are looked up in this order:
+//! 1. the `$AUDIO_PLAYER_WEB_TOKEN` (Bearer) or `$AUDIO_PLAYER_WEB_USERNAME` and `$AUDIO_PLAYER_WEB_PASSWORD`
+//!    (Basic) environment variables, for the host of the library only
+//! 2. the configuration file
+//! 3. the netrc file (`$NETRC` or `~/.netrc` by default)
+//!
+//! The credentials are sent at once over HTTPS. Over plain HTTP, they are only sent
+//! once the server has asked for them (with a `401` response), so a server that doesn't need them
+//! (or that uses Digest) never receives the password or the token in clear text.
+//! The Basic credentials also answer the Digest challenges of the servers (RFC 7616):
+//! once a server has sent a challenge, the next requests to its host use Digest.
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Debug, Formatter, Write},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{ring::default_provider, CryptoProvider},
+    version::{TLS12, TLS13},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tinyrand::{Rand, Seeded, StdRand};
+use ureq::{Agent, Middleware, MiddlewareNext, Request, Response};
+use url::Url;
+
+use crate::{download::NetworkOptions, song::EBox};
+
+/// The TLS settings of the [`Agent`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Are the root certificates of the system trusted?
+    pub system_roots: bool,
+    /// Is the certificate authority bundled with the program (if any) trusted?
+    pub bundled_ca: bool,
+    /// The PEM certificates of the authority bundled with the program.
+    #[serde(skip)]
+    pub bundled: Option<&'static [u8]>,
+    /// The PEM files (or the folders of PEM files) of the other trusted certificate authorities.
+    pub ca: Vec<PathBuf>,
+    /// The SHA-256 fingerprints (in hexadecimal, with or without `:`) of the certificates that
+    /// must be in the chain of the servers, in addition to the usual verification (if it's not empty).
+    pub pins: Vec<String>,
+    /// The PEM file of the client certificate chain (for mutual TLS).
+    pub client_cert: Option<PathBuf>,
+    /// The PEM file of the private key of the client certificate.
+    pub client_key: Option<PathBuf>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            system_roots: true,
+            bundled_ca: true,
+            bundled: None,
+            ca: vec![],
+            pins: vec![],
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Adds the settings of `other` to these ones.
+    ///
+    /// The system roots and the bundled authority are only trusted if both trust them,
+    /// and the client certificate of `other` replaces this one.
+    fn merge(&mut self, other: &Self) {
+        self.system_roots &= other.system_roots;
+        self.bundled_ca &= other.bundled_ca;
+        self.bundled = self.bundled.or(other.bundled);
+        self.ca.extend(other.ca.iter().cloned());
+        self.pins.extend(other.pins.iter().cloned());
+        if other.client_cert.is_some() {
+            self.client_cert.clone_from(&other.client_cert);
+        }
+        if other.client_key.is_some() {
+            self.client_key.clone_from(&other.client_key);
+        }
+    }
+}
+
+/// How to authenticate to a server.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    /// The HTTP Basic authentication.
+    Basic {
+        /// The name of the user.
+        username: String,
+        /// The password of the user.
+        password: String,
+    },
+    /// A Bearer token.
+    Bearer {
+        /// The token.
+        token: String,
+    },
+}
+
+impl Debug for Auth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Don't leak the secrets in the logs
+        match self {
+            Self::Basic { username, .. } => write!(f, "Basic({username}, ***)"),
+            Self::Bearer { .. } => write!(f, "Bearer(***)"),
+        }
+    }
+}
+
+impl Auth {
+    /// Returns the value of the `Authorization` header.
+    fn header(&self) -> String {
+        match self {
+            Self::Basic { username, password } => {
+                format!(
+                    "Basic {}",
+                    STANDARD.encode(format!("{username}:{password}"))
+                )
+            }
+            Self::Bearer { token } => format!("Bearer {token}"),
+        }
+    }
+}
+
+/// The credentials of a server.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Credentials {
+    /// The host of the server (or [`None`] for all the servers).
+    #[serde(default)]
+    pub host: Option<String>,
+    /// How to authenticate to the server.
+    #[serde(flatten)]
+    pub auth: Auth,
+}
+
+/// The configuration file of the [`Agent`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    /// The TLS settings.
+    pub tls: TlsConfig,
+    /// The credentials of the servers, the first matching ones are used.
+    pub credentials: Vec<Credentials>,
+}
+
+/// Returns the path of the configuration file of the [`Agent`].
+///
+/// It is in `$XDG_CONFIG_HOME` or in `~/.config`. There is none without them:
+/// the shared folders (like the temporary one) could have a file of another user.
+#[must_use]
+pub fn default_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|config| config.join("audio-player").join("web.json"))
+}
+
+/// Returns the path of the netrc file: `$NETRC` or `~/.netrc`.
+#[must_use]
+pub fn default_netrc_path() -> Option<PathBuf> {
+    env::var_os("NETRC")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc")))
+}
+
+/// Parses the credentials of a netrc file.
+///
+/// The `default` entry applies to all the hosts and the macros are ignored.
+/// If the file isn't `strict`, the entries without a login or a password
+/// (that are used by other programs) are skipped.
+///
+/// # Errors
+/// Fails if the file is `strict` and an entry has no login or no password.
+pub fn parse_netrc(content: &str, strict: bool) -> Result<Vec<Credentials>, EBox> {
+    let mut credentials = vec![];
+    let mut lines = content.lines();
+    let mut tokens = vec![];
+    while let Some(line) = lines.next() {
+        for word in line.split_whitespace() {
+            if word == "macdef" {
+                // The macro ends with an empty line
+                lines
+                    .by_ref()
+                    .take_while(|line| !line.trim().is_empty())
+                    .for_each(drop);
+                break;
+            }
+            if word.starts_with('#') {
+                break;
+            }
+            tokens.push(word);
+        }
+    }
+
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let host = match token {
+            "machine" => match tokens.next() {
+                Some(name) => Some(name),
+                None if strict => return Err("Missing netrc machine name".into()),
+                None => break,
+            },
+            "default" => None,
+            _ => continue,
+        };
+        let (mut username, mut password) = (None, None);
+        while let Some(&key) = tokens.peek() {
+            if matches!(key, "machine" | "default") {
+                break;
+            }
+            tokens.next();
+            let value = tokens.next();
+            match key {
+                "login" => username = value,
+                "password" => password = value,
+                _ => {}
+            }
+        }
+        let name = host.unwrap_or("default");
+        let (username, password) = match (username, password) {
+            (Some(username), Some(password)) => (username, password),
+            _ if !strict => continue,
+            (None, _) => return Err(format!("Missing netrc login for {name}").into()),
+            (Some(_), None) => return Err(format!("Missing netrc password for {name}").into()),
+        };
+        credentials.push(Credentials {
+            host: host.map(str::to_owned),
+            auth: Auth::Basic {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            },
+        });
+    }
+    Ok(credentials)
+}
+
+/// Returns the credentials of the environment variables for the `host` of the library.
+fn env_credentials(host: Option<&str>) -> Option<Credentials> {
+    let auth = if let Ok(token) = env::var("AUDIO_PLAYER_WEB_TOKEN") {
+        Auth::Bearer { token }
+    } else {
+        Auth::Basic {
+            username: env::var("AUDIO_PLAYER_WEB_USERNAME").ok()?,
+            password: env::var("AUDIO_PLAYER_WEB_PASSWORD").unwrap_or_default(),
+        }
+    };
+    Some(Credentials {
+        host: Some(host?.to_owned()),
+        auth,
+    })
+}
+
+/// Reads the file at `path`, or the `default` one if it exists.
+///
+/// # Errors
+/// Fails if the file at `path` can't be read.
+fn read_optional(path: Option<&Path>, default: Option<PathBuf>) -> Result<Option<String>, EBox> {
+    match (path, default) {
+        (Some(path), _) => fs::read_to_string(path)
+            .map(Some)
+            .map_err(|err| format!("Can't read {}: {err}", path.display()).into()),
+        (None, Some(default)) => Ok(fs::read_to_string(default).ok()),
+        (None, None) => Ok(None),
+    }
+}
+
+impl AgentConfig {
+    /// Loads the configuration of the [`Agent`] of the library at `url`.
+    ///
+    /// The configuration file and the netrc file are optional if their paths are not given.
+    /// The `tls` settings (from the command line) are added to the ones of the file.
+    ///
+    /// # Errors
+    /// Fails if a file can't be read or is invalid.
+    pub fn load(
+        url: &Url,
+        tls: &TlsConfig,
+        config_path: Option<&Path>,
+        netrc_path: Option<&Path>,
+    ) -> Result<Self, EBox> {
+        let mut config: Self = match read_optional(config_path, default_config_path())? {
+            Some(content) => serde_json::from_str(&content)
+                .map_err(|err| format!("Invalid web configuration: {err}"))?,
+            None => Self::default(),
+        };
+        config.tls.merge(tls);
+        if let Some(credentials) = env_credentials(url.host_str()) {
+            config.credentials.insert(0, credentials);
+        }
+        if let Some(content) = read_optional(netrc_path, default_netrc_path())? {
+            // The default file may have entries for other programs
+            config
+                .credentials
+                .extend(parse_netrc(&content, netrc_path.is_some())?);
+        }
+        Ok(config)
+    }
+
+    /// Returns the credentials of a host.
+    #[must_use]
+    pub fn credentials(&self, host: &str) -> Option<&Auth> {
+        self.credentials
+            .iter()
+            .find(|credentials| {
+                credentials
+                    .host
+                    .as_deref()
+                    .is_none_or(|credentials_host| credentials_host.eq_ignore_ascii_case(host))
+            })
+            .map(|credentials| &credentials.auth)
+    }
+}
+
+/// A Digest challenge of a server (RFC 7616).
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct DigestChallenge {
+    /// The protection space of the credentials.
+    realm: String,
+    /// The nonce of the server.
+    nonce: String,
+    /// The opaque value that must be sent back to the server.
+    opaque: Option<String>,
+    /// The hash algorithm (`MD5` or `SHA-256`, optionally with `-sess`).
+    algorithm: String,
+    /// Does the server support the `auth` quality of protection?
+    qop: bool,
+    /// The number of requests that answered this challenge.
+    count: u32,
+}
+
+/// Is the `WWW-Authenticate` header a challenge of the given scheme (`Basic` or `Digest`)?
+#[must_use]
+pub fn is_scheme(header: &str, scheme: &str) -> bool {
+    header
+        .trim_start()
+        .split(|char: char| char.is_whitespace() || char == ',')
+        .next()
+        .is_some_and(|name| name.eq_ignore_ascii_case(scheme))
+}
+
+impl DigestChallenge {
+    /// Parses a `WWW-Authenticate` header, if it's a Digest challenge with a supported algorithm.
+    fn parse(header: &str) -> Option<Self> {
+        if !is_scheme(header, "Digest") {
+            return None;
+        }
+        let (_, mut rest) = header.trim().split_once(char::is_whitespace)?;
+        let mut parameters = HashMap::new();
+        loop {
+            rest = rest.trim_start_matches(|char: char| char == ',' || char.is_whitespace());
+            let Some((name, value)) = rest.split_once('=') else {
+                break;
+            };
+            let value = value.trim_start();
+            let (value, next) = if let Some(quoted) = value.strip_prefix('"') {
+                // A quoted string, where `\` escapes a character
+                let mut unquoted = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((index, char)) = chars.next() {
+                    match char {
+                        '\\' => unquoted.extend(chars.next().map(|(_, char)| char)),
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        _ => unquoted.push(char),
+                    }
+                }
+                (unquoted, &quoted[end..])
+            } else {
+                let (value, next) = value.split_once(',').unwrap_or((value, ""));
+                (value.trim().to_owned(), next)
+            };
+            parameters.insert(name.trim().to_lowercase(), value);
+            rest = next;
+        }
+
+        let algorithm = parameters
+            .remove("algorithm")
+            .unwrap_or_else(|| "MD5".to_owned())
+            .to_uppercase();
+        if !["MD5", "MD5-SESS", "SHA-256", "SHA-256-SESS"].contains(&algorithm.as_str()) {
+            return None;
+        }
+        Some(Self {
+            realm: parameters.remove("realm")?,
+            nonce: parameters.remove("nonce")?,
+            opaque: parameters.remove("opaque"),
+            algorithm,
+            qop: parameters
+                .get("qop")
+                .is_some_and(|qop| qop.split(',').any(|qop| qop.trim() == "auth")),
+            count: 0,
+        })
+    }
+
+    /// Returns the hash of `data` in hexadecimal, with the algorithm of the challenge.
+    fn hash(&self, data: &str) -> String {
+        if self.algorithm.starts_with("SHA-256") {
+            format!("{:x}", Sha256::digest(data))
+        } else {
+            format!("{:x}", Md5::digest(data))
+        }
+    }
+
+    /// Returns the `Authorization` header of a request that answers the challenge
+    /// with the client nonce `cnonce`.
+    fn authorization(
+        &mut self,
+        method: &str,
+        uri: &str,
+        username: &str,
+        password: &str,
+        cnonce: &str,
+    ) -> String {
+        self.count += 1;
+        let mut secret = self.hash(&format!("{username}:{}:{password}", self.realm));
+        if self.algorithm.ends_with("-SESS") {
+            secret = self.hash(&format!("{secret}:{}:{cnonce}", self.nonce));
+        }
+        let request = self.hash(&format!("{method}:{uri}"));
+        let count = format!("{:08x}", self.count);
+        let response = if self.qop {
+            self.hash(&format!(
+                "{secret}:{}:{count}:{cnonce}:auth:{request}",
+                self.nonce
+            ))
+        } else {
+            self.hash(&format!("{secret}:{}:{request}", self.nonce))
+        };
+
+        let quote = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{response}\"",
+            quote(username),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(uri),
+            self.algorithm,
+        );
+        if self.qop {
+            let _ = write!(header, ", qop=auth, nc={count}, cnonce=\"{cnonce}\"");
+        }
+        if let Some(opaque) = &self.opaque {
+            let _ = write!(header, ", opaque=\"{}\"", quote(opaque));
+        }
+        header
+    }
+}
+
+/// Returns a new client nonce of the Digest authentication.
+fn cnonce() -> String {
+    let seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() ^ u64::from(time.subsec_nanos()));
+    format!("{:016x}", StdRand::seed(seed).next_u64())
+}
+
+/// The last authentication challenge of a host.
+enum Challenge {
+    /// The host asked for the Basic credentials.
+    Basic,
+    /// The host asked for a Bearer token.
+    Bearer,
+    /// The host sent a Digest challenge.
+    Digest(DigestChallenge),
+}
+
+/// A [`Middleware`] that adds the `Authorization` header of the matching [`Credentials`].
+///
+/// The header is removed by the [`Agent`] on the redirections.
+/// The credentials answer the last challenge of the host. Without a challenge,
+/// they are only sent over HTTPS.
+struct Authenticator {
+    /// The configuration with the credentials.
+    config: AgentConfig,
+    /// The last challenges, by host.
+    challenges: Mutex<HashMap<String, Challenge>>,
+}
+
+impl Middleware for Authenticator {
+    fn handle(&self, request: Request, next: MiddlewareNext) -> Result<Response, ureq::Error> {
+        let Ok(url) = request.request_url() else {
+            return next.handle(request);
+        };
+        let auth = match self.config.credentials(url.host()) {
+            Some(auth) if request.header("Authorization").is_none() => auth,
+            _ => return next.handle(request),
+        };
+        let host = url.host().to_owned();
+        let header = match (auth, self.challenges.lock()) {
+            (Auth::Basic { username, password }, Ok(mut challenges)) => {
+                match challenges.get_mut(&host) {
+                    Some(Challenge::Digest(challenge)) => {
+                        let url = url.as_url();
+                        let uri = match url.query() {
+                            Some(query) => format!("{}?{query}", url.path()),
+                            None => url.path().to_owned(),
+                        };
+                        Some(challenge.authorization(
+                            request.method(),
+                            &uri,
+                            username,
+                            password,
+                            &cnonce(),
+                        ))
+                    }
+                    Some(Challenge::Basic) => Some(auth.header()),
+                    // Wait for a challenge before sending the password in clear text
+                    _ if url.scheme() == "https" => Some(auth.header()),
+                    _ => None,
+                }
+            }
+            (Auth::Bearer { .. }, Ok(challenges))
+                if matches!(challenges.get(&host), Some(Challenge::Bearer)) =>
+            {
+                Some(auth.header())
+            }
+            _ if url.scheme() == "https" => Some(auth.header()),
+            _ => None,
+        };
+
+        let response = match header {
+            Some(header) => next.handle(request.set("Authorization", &header))?,
+            None => next.handle(request)?,
+        };
+        if response.status() == 401 {
+            let headers = response.all("WWW-Authenticate");
+            let has_scheme = |scheme| headers.iter().any(|header| is_scheme(header, scheme));
+            let challenge = match auth {
+                Auth::Basic { .. } => headers
+                    .iter()
+                    .find_map(|header| DigestChallenge::parse(header))
+                    .map(Challenge::Digest)
+                    .or_else(|| has_scheme("Basic").then_some(Challenge::Basic)),
+                Auth::Bearer { .. } => has_scheme("Bearer").then_some(Challenge::Bearer),
+            };
+            if let (Some(challenge), Ok(mut challenges)) = (challenge, self.challenges.lock()) {
+                challenges.insert(host, challenge);
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// A [`ServerCertVerifier`] that also checks that a pinned certificate is in the chain of the server.
+#[derive(Debug)]
+struct PinnedVerifier {
+    /// The verifier of the chain.
+    verifier: Arc<WebPkiServerVerifier>,
+    /// The SHA-256 fingerprints of the pinned certificates.
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if [end_entity]
+            .into_iter()
+            .chain(intermediates)
+            .any(|cert| self.pins.contains(&Sha256::digest(cert).to_vec()))
+        {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(
+                "The certificate of the server isn't pinned".to_owned(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// Parses a SHA-256 fingerprint in hexadecimal (with or without `:`).
+///
+/// # Errors
+/// Fails if the fingerprint is invalid.
+fn parse_pin(pin: &str) -> Result<Vec<u8>, EBox> {
+    let hex: String = pin.chars().filter(|&char| char != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("Invalid SHA-256 fingerprint: {pin}").into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&hex[index..index + 2], 16)
+                .map_err(|_| format!("Invalid SHA-256 fingerprint: {pin}").into())
+        })
+        .collect()
+}
+
+/// Adds the certificates of a PEM file (or of the `.pem` and `.crt` files of a folder) to the `roots`.
+///
+/// # Errors
+/// Fails if a file can't be read or contains an invalid certificate.
+fn add_certificates(roots: &mut RootCertStore, path: &Path) -> Result<(), EBox> {
+    if path.is_dir() {
+        let mut files = fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>, EBox>>()?;
+        files.retain(|file| {
+            file.extension()
+                .is_some_and(|extension| extension == "pem" || extension == "crt")
+        });
+        files.sort();
+        return files
+            .iter()
+            .try_for_each(|file| add_certificates(roots, file));
+    }
+
+    for cert in CertificateDer::pem_file_iter(path)
+        .map_err(|err| format!("Can't read {}: {err}", path.display()))?
+    {
+        roots
+            .add(cert.map_err(|err| format!("Invalid certificate in {}: {err}", path.display()))?)
+            .map_err(|err| format!("Invalid certificate in {}: {err}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns the rustls configuration of the [`TlsConfig`].
+///
+/// # Errors
+/// Fails if a file can't be read, if a certificate, a key or a pin is invalid,
+/// or if no certificate authority is trusted.
+fn tls_config(config: &TlsConfig) -> Result<ClientConfig, EBox> {
+    let provider: Arc<CryptoProvider> = default_provider().into();
+
+    let mut roots = RootCertStore::empty();
+    if config.system_roots {
+        // The invalid system certificates are ignored, like the browsers do
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    }
+    if let Some(bundled) = config.bundled.filter(|_| config.bundled_ca) {
+        for cert in CertificateDer::pem_slice_iter(bundled) {
+            roots
+                .add(cert.map_err(|err| format!("Invalid bundled certificate: {err}"))?)
+                .map_err(|err| format!("Invalid bundled certificate: {err}"))?;
+        }
+    }
+    for path in &config.ca {
+        add_certificates(&mut roots, path)?;
+    }
+    if roots.is_empty() {
+        return Err("No trusted certificate authority: add one or trust the system ones".into());
+    }
+
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+    let builder =
+        ClientConfig::builder_with_provider(provider).with_protocol_versions(&[&TLS12, &TLS13])?;
+    let builder = if config.pins.is_empty() {
+        builder.with_webpki_verifier(verifier)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                verifier,
+                pins: config
+                    .pins
+                    .iter()
+                    .map(|pin| parse_pin(pin))
+                    .collect::<Result<_, _>>()?,
+            }))
+    };
+
+    Ok(match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .map_err(|err| format!("Can't read {}: {err}", cert.display()))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| format!("Invalid certificate in {}: {err}", cert.display()))?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|err| format!("Invalid private key in {}: {err}", key.display()))?;
+            builder.with_client_auth_cert(chain, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err("The client certificate and its private key must be given together".into())
+        }
+    })
+}
+
+/// Builds the [`Agent`] of a [`AgentConfig`], with the timeouts of the [`NetworkOptions`].
+///
+/// The same agent should be used for all the requests to the library.
+///
+/// # Errors
+/// Fails if the TLS configuration is invalid (see [`TlsConfig`]).
+pub fn build_agent(config: &AgentConfig, network: &NetworkOptions) -> Result<Agent, EBox> {
+    Ok(ureq::builder()
+        .timeout_connect(network.connect_timeout)
+        .timeout_read(network.read_timeout)
+        .tls_config(Arc::new(tls_config(&config.tls)?))
+        .middleware(Authenticator {
+            config: config.clone(),
+            challenges: Mutex::new(HashMap::new()),
+        })
+        .build())
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        path::Path,
+        sync::{Arc, Mutex},
+        thread::spawn,
+    };
+
+    use tiny_http::{Header, Response, Server};
+    use ureq::Response as UreqResponse;
+    use url::Url;
+
+    use super::{
+        build_agent, is_scheme, parse_netrc, parse_pin, AgentConfig, Auth, Credentials,
+        DigestChallenge, TlsConfig,
+    };
+    use crate::{
+        download::{fetch, NetworkOptions},
+        song::EBox,
+    };
+
+    #[test]
+    fn config_file() {
+        let config: AgentConfig = serde_json::from_str(
+            r#"{
+                "tls": {"system_roots": false, "bundled_ca": false, "pins": ["AB:cd"]},
+                "credentials": [
+                    {"host": "a.example.com", "basic": {"username": "user", "password": "pass"}},
+                    {"bearer": {"token": "token"}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(!config.tls.system_roots);
+        assert!(!config.tls.bundled_ca);
+        assert_eq!(
+            config.credentials("b.example.com"),
+            Some(&Auth::Bearer {
+                token: "token".to_owned()
+            })
+        );
+        assert_eq!(
+            config.credentials("A.example.com").unwrap().header(),
+            "Basic dXNlcjpwYXNz"
+        );
+        assert!(format!("{config:?}").contains("Basic(user, ***)"));
+        assert!(!format!("{config:?}").contains("pass\""));
+        assert!(serde_json::from_str::<AgentConfig>(r#"{"proxy": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn netrc() {
+        let netrc = "
+            # Comment
+            machine a.example.com login user password pass
+            macdef init
+            cd /pub
+            machine ignored.example.com
+
+            default
+                login anonymous
+                password guest
+        ";
+        assert_eq!(
+            parse_netrc(netrc, true).unwrap(),
+            [
+                Credentials {
+                    host: Some("a.example.com".to_owned()),
+                    auth: Auth::Basic {
+                        username: "user".to_owned(),
+                        password: "pass".to_owned(),
+                    },
+                },
+                Credentials {
+                    host: None,
+                    auth: Auth::Basic {
+                        username: "anonymous".to_owned(),
+                        password: "guest".to_owned(),
+                    },
+                },
+            ]
+        );
+        assert!(parse_netrc("machine a.example.com login user", true)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Missing netrc password"));
+        // The incomplete entries of the default file are skipped
+        assert_eq!(
+            parse_netrc(
+                "machine a.example.com login user\nmachine b.example.com login user password pass",
+                false
+            )
+            .unwrap(),
+            [Credentials {
+                host: Some("b.example.com".to_owned()),
+                auth: Auth::Basic {
+                    username: "user".to_owned(),
+                    password: "pass".to_owned(),
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn pins() {
+        let pin = "9F:86:D0:81:88:4C:7D:65:9A:2F:EA:A0:C5:5A:D0:15:A3:BF:4F:1B:2B:0B:82:2C:D1:5D:6C:15:B0:F0:0A:08";
+        assert_eq!(
+            parse_pin(pin).unwrap(),
+            parse_pin(&pin.replace(':', "")).unwrap()
+        );
+        assert_eq!(parse_pin(pin).unwrap()[..2], [0x9f, 0x86]);
+        assert!(parse_pin("9f86").is_err());
+        assert!(parse_pin(&"z".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn authorization() {
+        let (url, _received) = challenge_server(r#"Bearer realm="files""#);
+        let mut tls = TlsConfig {
+            system_roots: false,
+            ..TlsConfig::default()
+        };
+        assert!(build_agent(
+            &AgentConfig {
+                tls: tls.clone(),
+                credentials: vec![],
+            },
+            &NetworkOptions::default()
+        )
+        .unwrap_err()
+        .to_string()
+        .starts_with("No trusted certificate authority"));
+
+        // The bundled authority is only trusted if it's not turned off
+        tls.bundled = Some(include_bytes!("../fixtures/tls/ca.pem"));
+        tls.bundled_ca = false;
+        assert!(build_agent(
+            &AgentConfig {
+                tls: tls.clone(),
+                credentials: vec![],
+            },
+            &NetworkOptions::default()
+        )
+        .is_err());
+        tls.bundled_ca = true;
+        assert!(build_agent(
+            &AgentConfig {
+                tls: tls.clone(),
+                credentials: vec![],
+            },
+            &NetworkOptions::default()
+        )
+        .is_ok());
+
+        tls.bundled = None;
+        tls.ca
+            .push(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/tls"));
+        let agent = build_agent(
+            &AgentConfig {
+                tls,
+                credentials: vec![
+                    Credentials {
+                        host: Some("example.com".to_owned()),
+                        auth: Auth::Bearer {
+                            token: "other".to_owned(),
+                        },
+                    },
+                    Credentials {
+                        host: Some("127.0.0.1".to_owned()),
+                        auth: Auth::Bearer {
+                            token: "token".to_owned(),
+                        },
+                    },
+                ],
+            },
+            &NetworkOptions::default(),
+        )
+        .unwrap();
+        let authorization = |request: ureq::Request| request.call().unwrap().into_string().unwrap();
+        // The token is sent over HTTP after the challenge of the server
+        assert!(matches!(
+            agent.request_url("GET", &url).call(),
+            Err(ureq::Error::Status(401, _))
+        ));
+        assert_eq!(
+            authorization(agent.request_url("GET", &url)),
+            "Bearer token"
+        );
+        assert_eq!(
+            authorization(
+                agent
+                    .request_url("GET", &url)
+                    .set("Authorization", "Basic x")
+            ),
+            "Basic x"
+        );
+    }
+
+    /// Starts a server that sends `challenge` to the requests without credentials
+    /// and answers the other ones with their `Authorization` header.
+    ///
+    /// Returns the URL of the server and the `Authorization` headers that it receives.
+    fn challenge_server(challenge: &'static str) -> (Url, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+        let server_received = Arc::clone(&received);
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let authorization = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Authorization"))
+                    .map(|header| header.value.to_string());
+                server_received.lock().unwrap().push(authorization.clone());
+                let response = match authorization {
+                    Some(authorization) => Response::from_string(authorization),
+                    None => Response::from_string("")
+                        .with_status_code(401)
+                        .with_header(Header::from_bytes("WWW-Authenticate", challenge).unwrap()),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        (Url::parse(&format!("http://{address}/")).unwrap(), received)
+    }
+
+    /// Fetches `url` twice with the given credentials for all the hosts.
+    ///
+    /// # Errors
+    /// Fails if a request fails.
+    fn get_twice(url: &Url, auth: Auth) -> Result<[String; 2], EBox> {
+        let network = NetworkOptions {
+            retries: 0,
+            ..NetworkOptions::default()
+        };
+        let agent = build_agent(
+            &AgentConfig {
+                credentials: vec![Credentials { host: None, auth }],
+                ..AgentConfig::default()
+            },
+            &network,
+        )?;
+        let get = || {
+            fetch(
+                &network,
+                || agent.request_url("GET", url),
+                UreqResponse::into_string,
+            )
+        };
+        Ok([get()?, get()?])
+    }
+
+    #[test]
+    fn basic_over_http() {
+        let (url, received) = challenge_server(r#"Basic realm="files""#);
+        let auth = Auth::Basic {
+            username: "alice".to_owned(),
+            password: "secret".to_owned(),
+        };
+        assert_eq!(
+            get_twice(&url, auth).unwrap(),
+            ["Basic YWxpY2U6c2VjcmV0"; 2]
+        );
+        // The password is only sent after the challenge of the server
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                None,
+                Some("Basic YWxpY2U6c2VjcmV0".to_owned()),
+                Some("Basic YWxpY2U6c2VjcmV0".to_owned())
+            ]
+        );
+
+        assert!(is_scheme("basic realm=\"files\"", "Basic"));
+        assert!(is_scheme(" Digest realm=\"files\"", "Digest"));
+        assert!(!is_scheme("Digestive", "Digest"));
+    }
+
+    #[test]
+    fn bearer_over_http() {
+        let (url, received) = challenge_server(r#"Bearer realm="files""#);
+        let auth = Auth::Bearer {
+            token: "abc".to_owned(),
+        };
+        assert_eq!(get_twice(&url, auth).unwrap(), ["Bearer abc"; 2]);
+        // The token is only sent after the challenge of the server
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                None,
+                Some("Bearer abc".to_owned()),
+                Some("Bearer abc".to_owned())
+            ]
+        );
+
+        // A Basic challenge doesn't get the token
+        let (url, received) = challenge_server(r#"Basic realm="files""#);
+        let auth = Auth::Bearer {
+            token: "abc".to_owned(),
+        };
+        assert!(get_twice(&url, auth).is_err());
+        assert!(received.lock().unwrap().iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn digest() {
+        // The examples of RFC 7616
+        let challenge = r#"Digest
+            realm="http-auth@example.org",
+            qop="auth, auth-int",
+            algorithm=SHA-256,
+            nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+            opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#;
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        let mut sha256 = DigestChallenge::parse(challenge).unwrap();
+        assert!(sha256.qop);
+        assert_eq!(
+            sha256.authorization("GET", "/dir/index.html", "Mufasa", "Circle of Life", cnonce),
+            "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", \
+             nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", uri=\"/dir/index.html\", \
+             algorithm=SHA-256, \
+             response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\", \
+             qop=auth, nc=00000001, cnonce=\"f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ\", \
+             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\""
+        );
+        let mut md5 =
+            DigestChallenge::parse(&challenge.replace("algorithm=SHA-256", "algorithm=MD5"))
+                .unwrap();
+        assert!(md5
+            .authorization("GET", "/dir/index.html", "Mufasa", "Circle of Life", cnonce)
+            .contains("response=\"8ca523f5e9506fed4657c9700eebdbec\""));
+        // The count of requests is increased
+        assert!(md5
+            .authorization("GET", "/dir/index.html", "Mufasa", "Circle of Life", cnonce)
+            .contains("nc=00000002"));
+
+        assert_eq!(DigestChallenge::parse("Basic realm=\"files\""), None);
+        assert_eq!(
+            DigestChallenge::parse("Digest realm=\"a\", nonce=\"b\", algorithm=SHA-512-256"),
+            None
+        );
+    }
+}
@@ -2,4 +2,7 @@
 
 use audio_player::web;
 use compile_dotenv::compile_env;
-web!(compile_env!("WEB_CHRISTMAS_URL"), true);
+web!(
+    compile_env!("WEB_CHRISTMAS_URL"),
+    include_bytes!("cert.pem")
+);
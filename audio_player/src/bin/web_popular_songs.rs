@@ -2,4 +2,7 @@
 
 use audio_player::web;
 use compile_dotenv::compile_env;
-web!(compile_env!("WEB_POPULAR_SONGS_URL"), true);
+web!(
+    compile_env!("WEB_POPULAR_SONGS_URL"),
+    include_bytes!("cert.pem")
+);
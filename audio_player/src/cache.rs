@@ -0,0 +1,537 @@
+//! The local copy of a web library, to play it when the server can't be reached.
+//!
+//! The last successful listing of the library and the downloaded songs are kept in a folder.
+//! If the listing can't be fetched, the player starts in offline mode with the cached songs,
+//! and the [`LibraryWatcher`] checks the server until it's back, to add its songs to the queue.
+//!
+//! The songs of all the libraries share a maximum size: the least recently played ones
+//! are removed when a new song doesn't fit.
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use ureq::Agent;
+use url::Url;
+
+use crate::{
+    download::NetworkOptions,
+    options::{Options, DEFAULT_CACHE_SIZE},
+    player::{
+        plugin::{Plugin, PluginContext},
+        Command, StatusMessage,
+    },
+    song::EBox,
+    web_utils::{get_files, CrawlOptions},
+};
+
+/// The interval between two checks of the server in offline mode.
+pub static RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The name of the file of the listing.
+const LISTING_FILE_NAME: &str = "listing.json";
+
+/// The name of the folder of the songs of a library.
+const SONGS_FOLDER_NAME: &str = "songs";
+
+/// Returns the SHA-256 hash of a URL, in hexadecimal.
+fn url_hash(url: &Url) -> String {
+    format!("{:x}", Sha256::digest(url.as_str()))
+}
+
+/// The cache of a web library.
+pub struct LibraryCache {
+    /// The folder of the cache of all the libraries.
+    cache_folder: PathBuf,
+    /// The folder of the cache of this library.
+    folder: PathBuf,
+    /// The maximum size of the songs of all the libraries, in bytes.
+    max_size: u64,
+    /// Is the server unreachable?
+    offline: AtomicBool,
+}
+
+impl LibraryCache {
+    /// Creates the cache of the library at `url`, in a subfolder of `cache_folder`.
+    #[must_use]
+    pub fn new(cache_folder: &Path, url: &Url) -> Self {
+        Self {
+            cache_folder: cache_folder.to_owned(),
+            folder: cache_folder.join(&url_hash(url)[..16]),
+            max_size: DEFAULT_CACHE_SIZE,
+            offline: AtomicBool::new(false),
+        }
+    }
+
+    /// Sets the maximum size of the songs of all the libraries, in bytes.
+    #[must_use]
+    pub const fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Is the server unreachable?
+    #[must_use]
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// Sets whether the server is unreachable.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+
+    /// Writes a file of the cache atomically.
+    ///
+    /// # Errors
+    /// Fails if the file can't be written.
+    fn write(path: &Path, data: &[u8]) -> Result<(), EBox> {
+        if let Some(folder) = path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        let temp_path = path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Saves the listing of the library.
+    ///
+    /// # Errors
+    /// Fails if the listing can't be written.
+    pub fn save_listing(&self, files: &[Url]) -> Result<(), EBox> {
+        Self::write(
+            &self.folder.join(LISTING_FILE_NAME),
+            &serde_json::to_vec(files)?,
+        )
+    }
+
+    /// Returns the last saved listing of the library.
+    ///
+    /// # Errors
+    /// Fails if there is no listing or if it's invalid.
+    pub fn load_listing(&self) -> Result<Vec<Url>, EBox> {
+        Ok(serde_json::from_slice(&fs::read(
+            self.folder.join(LISTING_FILE_NAME),
+        )?)?)
+    }
+
+    /// Returns the path of a cached song.
+    fn song_path(&self, url: &Url) -> PathBuf {
+        self.folder.join(SONGS_FOLDER_NAME).join(url_hash(url))
+    }
+
+    /// Is the song at `url` cached?
+    #[must_use]
+    pub fn contains(&self, url: &Url) -> bool {
+        self.song_path(url).is_file()
+    }
+
+    /// Returns the data of a cached song, if it's cached.
+    ///
+    /// The modification time of the song is updated, so it's removed after the songs that weren't played since.
+    #[must_use]
+    pub fn load_song(&self, url: &Url) -> Option<Vec<u8>> {
+        let path = self.song_path(url);
+        let data = fs::read(&path).ok()?;
+        // The song can still be played if its time can't be changed
+        let _ = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        Some(data)
+    }
+
+    /// Saves the data of a song, and removes the least recently played songs
+    /// if the cache is bigger than its maximum size.
+    ///
+    /// # Errors
+    /// Fails if the song is bigger than the cache, if it can't be written
+    /// or if the other songs can't be removed.
+    pub fn save_song(&self, url: &Url, data: &[u8]) -> Result<(), EBox> {
+        if data.len() as u64 > self.max_size {
+            return Err(format!("The song {url} is bigger than the cache").into());
+        }
+        let path = self.song_path(url);
+        Self::write(&path, data)?;
+        self.evict(&path)
+    }
+
+    /// Removes the least recently played songs of all the libraries (except `kept`)
+    /// until the cache isn't bigger than its maximum size.
+    ///
+    /// # Errors
+    /// Fails if the cache can't be listed or if a song can't be removed.
+    fn evict(&self, kept: &Path) -> Result<(), EBox> {
+        let mut songs = vec![];
+        for library in fs::read_dir(&self.cache_folder)? {
+            let Ok(entries) = fs::read_dir(library?.path().join(SONGS_FOLDER_NAME)) else {
+                continue;
+            };
+            for entry in entries {
+                let path = entry?.path();
+                let metadata = fs::metadata(&path)?;
+                // The temporary files are being written by another player
+                if metadata.is_file() && path.extension().is_none() {
+                    songs.push((metadata.modified()?, metadata.len(), path));
+                }
+            }
+        }
+
+        let mut size: u64 = songs.iter().map(|(_, len, _)| len).sum();
+        songs.sort();
+        for (_, len, path) in songs {
+            if size <= self.max_size {
+                break;
+            }
+            if path != kept {
+                fs::remove_file(&path)?;
+                size -= len;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the files of the library at `url` and saves them in the `cache`.
+///
+/// If the listing can't be fetched and some songs of the last saved listing are cached,
+/// the cache goes offline and the last saved listing is returned.
+///
+/// # Errors
+/// Fails if the listing can't be fetched and there is no cached song.
+pub fn get_library(
+    agent: &Agent,
+    url: &Url,
+    options: &Options,
+    cache: Option<&LibraryCache>,
+) -> Result<Vec<Url>, EBox> {
+    let result = get_files(agent, url, &options.crawl, &options.network);
+    let Some(cache) = cache else {
+        return result;
+    };
+    match result {
+        Ok(files) => {
+            // The cache is optional: the player can start without it
+            let _ = cache.save_listing(&files);
+            Ok(files)
+        }
+        Err(err) => match cache.load_listing() {
+            Ok(files) if files.iter().any(|file| cache.contains(file)) => {
+                cache.set_offline(true);
+                Ok(files)
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+/// A [`Plugin`] that shows the offline mode and checks the server until it's back.
+///
+/// When the server is back, the listing is saved, all the songs can be played again
+/// and the songs of the listing are added to the queue (see [`Command::AppendSongs`]).
+pub struct LibraryWatcher {
+    /// The [`Agent`] of the library.
+    agent: Agent,
+    /// The URL of the library.
+    url: Url,
+    /// The crawl options of the library.
+    crawl: CrawlOptions,
+    /// The network options of the checks (without retries).
+    network: NetworkOptions,
+    /// The cache of the library.
+    cache: Arc<LibraryCache>,
+    /// When the next check should start.
+    next_check: Instant,
+    /// The running check.
+    check: Option<JoinHandle<Result<Vec<Url>, EBox>>>,
+}
+
+impl LibraryWatcher {
+    /// Creates a watcher for the library at `url`.
+    #[must_use]
+    pub fn new(agent: Agent, url: Url, options: &Options, cache: Arc<LibraryCache>) -> Self {
+        Self {
+            agent,
+            url,
+            crawl: options.crawl.clone(),
+            network: NetworkOptions {
+                retries: 0,
+                ..options.network
+            },
+            cache,
+            next_check: Instant::now() + RECONNECT_INTERVAL,
+            check: None,
+        }
+    }
+
+    /// Handles the result of a check of the server.
+    ///
+    /// # Errors
+    /// Fails if the listing can't be saved or if a message can't be sent.
+    fn checked(
+        &mut self,
+        context: &PluginContext,
+        result: Result<Vec<Url>, EBox>,
+    ) -> Result<(), EBox> {
+        let Ok(files) = result else {
+            self.next_check = Instant::now() + RECONNECT_INTERVAL;
+            return Ok(());
+        };
+        let known_files = self.cache.load_listing().unwrap_or_default();
+        let new_files = files
+            .iter()
+            .filter(|file| !known_files.contains(file))
+            .count();
+        self.cache.set_offline(false);
+        context.send(Command::SetOffline(false))?;
+        // The player skips the songs that are already in the queue
+        context.send(Command::AppendSongs(
+            files.iter().map(Url::to_string).collect(),
+        ))?;
+        let message = if new_files == 0 {
+            "The library is back online".to_owned()
+        } else {
+            format!("The library is back online ({new_files} new songs are added to the queue)")
+        };
+        context.send(Command::DisplayMessage(StatusMessage::five_seconds(
+            message,
+        )))?;
+        self.cache.save_listing(&files)
+    }
+}
+
+impl Plugin for LibraryWatcher {
+    fn on_start(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        if !self.cache.is_offline() {
+            return Ok(());
+        }
+        context.send(Command::SetOffline(true))?;
+        context.send(Command::DisplayMessage(StatusMessage::warning(
+            "The library can't be reached: only the downloaded songs are played".to_owned(),
+        )))
+    }
+
+    fn on_tick(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        if !self.cache.is_offline() {
+            return Ok(());
+        }
+        match self.check.take() {
+            Some(check) if check.is_finished() => {
+                let result = check
+                    .join()
+                    .unwrap_or_else(|_| Err("The check of the library panicked".into()));
+                self.checked(context, result)
+            }
+            Some(check) => {
+                self.check = Some(check);
+                Ok(())
+            }
+            None if Instant::now() >= self.next_check => {
+                let (agent, url) = (self.agent.clone(), self.url.clone());
+                let (crawl, network) = (self.crawl.clone(), self.network);
+                self.check = Some(thread::spawn(move || {
+                    get_files(&agent, &url, &crawl, &network)
+                }));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs,
+        net::TcpListener,
+        process,
+        sync::{mpsc::channel, Arc},
+        thread::{sleep, spawn},
+        time::{Duration, Instant},
+    };
+
+    use tiny_http::{Response, Server};
+    use url::Url;
+
+    use super::{get_library, LibraryCache, LibraryWatcher};
+    use crate::{
+        download::NetworkOptions,
+        options::Options,
+        player::{
+            plugin::{Plugin, PluginContext},
+            Command,
+        },
+        song::Song,
+        song::Web,
+    };
+
+    #[test]
+    fn offline_library() {
+        let folder = temp_dir().join(format!("audio-player-test-cache-{}", process::id()));
+        let _ = fs::remove_dir_all(&folder);
+
+        // A server that is down
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = Url::parse(&format!("http://{address}/music/")).unwrap();
+        let options = Options {
+            network: NetworkOptions {
+                retries: 0,
+                ..NetworkOptions::default()
+            },
+            ..Options::default()
+        };
+        let agent = ureq::agent();
+        let cache = LibraryCache::new(&folder, &url);
+
+        // Nothing is cached
+        assert!(get_library(&agent, &url, &options, Some(&cache)).is_err());
+
+        let files = [url.join("a.mp3").unwrap(), url.join("b.mp3").unwrap()];
+        cache.save_listing(&files).unwrap();
+        // The listing is saved, but no song is cached
+        assert!(get_library(&agent, &url, &options, Some(&cache)).is_err());
+
+        cache.save_song(&files[0], b"song a").unwrap();
+        assert_eq!(
+            get_library(&agent, &url, &options, Some(&cache)).unwrap(),
+            files
+        );
+        assert!(cache.is_offline());
+
+        let mut song_a = Web::new(&files[0], &agent).with_cache(Some(&cache));
+        let mut song_b = Web::new(&files[1], &agent).with_cache(Some(&cache));
+        assert!(song_a.is_available());
+        assert!(!song_b.is_available());
+        song_a.preload().unwrap();
+        assert!(song_b.preload().is_err());
+
+        cache.set_offline(false);
+        assert!(song_b.is_available());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn library_back_online() {
+        let folder = temp_dir().join(format!("audio-player-test-cache-back-{}", process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        // The server is down until it listens on this address
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = Url::parse(&format!("http://{address}/music/")).unwrap();
+        let options = Options {
+            network: NetworkOptions {
+                retries: 0,
+                ..NetworkOptions::default()
+            },
+            ..Options::default()
+        };
+        let agent = ureq::agent();
+        let cache = Arc::new(LibraryCache::new(&folder, &url));
+        let files = [url.join("a.mp3").unwrap(), url.join("b.mp3").unwrap()];
+        cache.save_listing(&files).unwrap();
+        cache.save_song(&files[0], b"song a").unwrap();
+        assert_eq!(
+            get_library(&agent, &url, &options, Some(&cache)).unwrap(),
+            files
+        );
+
+        let (tx, rx) = channel();
+        let context = PluginContext::new(tx);
+        let mut watcher = LibraryWatcher::new(agent, url.clone(), &options, Arc::clone(&cache));
+        // Checks the server until the check is done
+        let check = |watcher: &mut LibraryWatcher| {
+            watcher.next_check = Instant::now();
+            watcher.on_tick(&context).unwrap();
+            while watcher.check.is_some() {
+                sleep(Duration::from_millis(10));
+                watcher.on_tick(&context).unwrap();
+            }
+        };
+        watcher.on_start(&context).unwrap();
+        assert!(matches!(rx.recv().unwrap(), Command::SetOffline(true)));
+        assert!(matches!(rx.recv().unwrap(), Command::DisplayMessage(_)));
+
+        // The server is still down
+        check(&mut watcher);
+        assert!(cache.is_offline());
+        assert!(rx.try_recv().is_err());
+
+        // The server is back, with a new song
+        let server = Server::http(address).unwrap();
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match request.url() {
+                    "/music/" => Response::from_string(
+                        r#"<a href="a.mp3">A</a> <a href="b.mp3">B</a> <a href="c.mp3">C</a>"#,
+                    ),
+                    _ => Response::from_string("").with_status_code(404),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        check(&mut watcher);
+        assert!(!cache.is_offline());
+        assert!(matches!(rx.recv().unwrap(), Command::SetOffline(false)));
+        assert!(matches!(
+            rx.recv().unwrap(),
+            Command::AppendSongs(paths) if paths == [
+                format!("{url}a.mp3"),
+                format!("{url}b.mp3"),
+                format!("{url}c.mp3"),
+            ]
+        ));
+        assert!(matches!(rx.recv().unwrap(), Command::DisplayMessage(_)));
+        assert_eq!(cache.load_listing().unwrap().len(), 3);
+        // The server isn't checked anymore
+        check(&mut watcher);
+        assert!(rx.try_recv().is_err());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn max_size() {
+        let folder = temp_dir().join(format!("audio-player-test-cache-size-{}", process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let url = |library: &str, song: &str| {
+            Url::parse(&format!("https://example.com/{library}/{song}.mp3")).unwrap()
+        };
+        let cache_1 = LibraryCache::new(&folder, &url("1", "")).with_max_size(10);
+        let cache_2 = LibraryCache::new(&folder, &url("2", "")).with_max_size(10);
+
+        cache_1.save_song(&url("1", "a"), b"aaaa").unwrap();
+        sleep(Duration::from_millis(50));
+        cache_2.save_song(&url("2", "b"), b"bbbb").unwrap();
+        sleep(Duration::from_millis(50));
+        // The song a is played again, the song b is now the least recently played
+        assert!(cache_1.load_song(&url("1", "a")).is_some());
+        sleep(Duration::from_millis(50));
+        cache_1.save_song(&url("1", "c"), b"cccc").unwrap();
+        assert!(cache_1.contains(&url("1", "a")));
+        assert!(!cache_2.contains(&url("2", "b")));
+        assert!(cache_1.contains(&url("1", "c")));
+
+        // A song that can't fit is not cached
+        assert!(cache_2.save_song(&url("2", "d"), &[0; 11]).is_err());
+        assert!(!cache_2.contains(&url("2", "d")));
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
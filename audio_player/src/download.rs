@@ -0,0 +1,377 @@
+//! Requests that survive the network errors: timeouts, retries and resumable downloads.
+//!
+//! The failed requests are retried after an exponential backoff with jitter,
+//! and the interrupted downloads resume where they stopped with a `Range` request.
+use std::{
+    io::{self, Read},
+    thread::sleep,
+    time::{Duration, SystemTime},
+};
+
+use tinyrand::{Rand, Seeded, StdRand};
+use ureq::{Agent, Request, Response};
+use url::Url;
+
+use crate::{agent::is_scheme, song::EBox};
+
+/// The maximum delay between two attempts.
+pub static MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The timeouts and the retries of the requests to the web songs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkOptions {
+    /// The timeout of the connections.
+    pub connect_timeout: Duration,
+    /// The maximum time without receiving data.
+    pub read_timeout: Duration,
+    /// The number of retries after a failed attempt.
+    pub retries: u32,
+    /// The delay before the first retry, doubled at each retry (up to [`MAX_BACKOFF`]).
+    pub backoff: Duration,
+}
+
+impl NetworkOptions {
+    /// The default [`NetworkOptions`].
+    pub const DEFAULT: Self = Self {
+        connect_timeout: Duration::from_secs(10),
+        read_timeout: Duration::from_secs(30),
+        retries: 4,
+        backoff: Duration::from_millis(500),
+    };
+
+    /// Returns the delay before the retry number `attempt` (from 0).
+    ///
+    /// It is between half and all of the exponential backoff, to spread the retries of the clients.
+    fn delay(&self, attempt: u32, rng: &mut impl Rand) -> Duration {
+        let backoff = self
+            .backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_BACKOFF);
+        let half = u64::try_from(backoff.as_millis() / 2).unwrap_or(u64::MAX);
+        Duration::from_millis(half + rng.next_lim_u64(half + 1))
+    }
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Returns a random generator for the jitter.
+fn jitter_rng() -> StdRand {
+    StdRand::seed(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() ^ u64::from(time.subsec_nanos())),
+    )
+}
+
+/// Is it worth retrying after this error?
+///
+/// The network errors and the temporary server errors can be retried.
+fn is_retryable(err: &ureq::Error) -> bool {
+    match err {
+        ureq::Error::Status(status, _) => matches!(status, 408 | 429 | 500 | 502 | 503 | 504),
+        ureq::Error::Transport(transport) => !matches!(
+            transport.kind(),
+            ureq::ErrorKind::InvalidUrl
+                | ureq::ErrorKind::UnknownScheme
+                | ureq::ErrorKind::InvalidProxyUrl
+                | ureq::ErrorKind::ProxyUnauthorized
+        ),
+    }
+}
+
+/// Returns the delay requested by the `Retry-After` header of an error (in seconds), if there is one.
+fn retry_after(err: &ureq::Error) -> Option<Duration> {
+    match err {
+        ureq::Error::Status(_, response) => response
+            .header("Retry-After")
+            .and_then(|seconds| seconds.trim().parse().ok())
+            .map(Duration::from_secs),
+        ureq::Error::Transport(_) => None,
+    }
+}
+
+/// Is this error a Basic, Digest or Bearer challenge of the server?
+fn is_challenge(err: &ureq::Error) -> bool {
+    match err {
+        ureq::Error::Status(401, response) => {
+            response.all("WWW-Authenticate").iter().any(|challenge| {
+                ["Basic", "Digest", "Bearer"]
+                    .iter()
+                    .any(|scheme| is_scheme(challenge, scheme))
+            })
+        }
+        _ => false,
+    }
+}
+
+/// Sends a request and reads its response, with retries.
+///
+/// `request` creates the request of each attempt and `read` reads the response:
+/// the attempt is retried if its read fails.
+/// A Basic, Digest or Bearer challenge is answered at once by another attempt (see [`agent`](crate::agent)).
+///
+/// # Errors
+/// Fails if the last attempt fails or if the error can't be retried.
+pub fn fetch<T>(
+    network: &NetworkOptions,
+    request: impl Fn() -> Request,
+    mut read: impl FnMut(Response) -> io::Result<T>,
+) -> Result<T, EBox> {
+    let mut rng = jitter_rng();
+    let mut attempt = 0;
+    let mut challenged = false;
+    loop {
+        let (err, delay) = match request().call() {
+            Ok(response) => match read(response) {
+                Ok(value) => return Ok(value),
+                Err(err) => (EBox::from(err), None),
+            },
+            Err(err) if !challenged && is_challenge(&err) => {
+                challenged = true;
+                continue;
+            }
+            Err(err) if is_retryable(&err) => {
+                let delay = retry_after(&err);
+                (err.into(), delay)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if attempt >= network.retries {
+            return Err(err);
+        }
+        sleep(
+            delay
+                .unwrap_or_else(|| network.delay(attempt, &mut rng))
+                .min(MAX_BACKOFF),
+        );
+        attempt += 1;
+    }
+}
+
+/// Downloads the file at `url` into `data`, with retries.
+///
+/// If the download is interrupted, it resumes at the end of `data` with a `Range` request
+/// (or it starts over if the server doesn't support it).
+///
+/// # Errors
+/// Fails if the last attempt fails or if the error can't be retried.
+pub fn download(
+    agent: &Agent,
+    url: &Url,
+    network: &NetworkOptions,
+    data: &mut Vec<u8>,
+) -> Result<(), EBox> {
+    let mut rng = jitter_rng();
+    let mut attempt = 0;
+    loop {
+        let err = match fetch(
+            network,
+            || {
+                let request = agent.request_url("GET", url);
+                if data.is_empty() {
+                    request
+                } else {
+                    request.set("Range", &format!("bytes={}-", data.len()))
+                }
+            },
+            Ok,
+        ) {
+            Ok(response) => {
+                let start = format!("bytes {}-", data.len());
+                let resumed = response.status() == 206
+                    && response
+                        .header("Content-Range")
+                        .is_some_and(|range| range.starts_with(&start));
+                if resumed || response.status() == 200 {
+                    if !resumed {
+                        // The server sends the whole file
+                        data.clear();
+                    }
+                    match response.into_reader().read_to_end(data) {
+                        Ok(_) => return Ok(()),
+                        Err(err) => err,
+                    }
+                } else {
+                    // The server sends another part of the file: start over without a range
+                    data.clear();
+                    io::Error::other(format!("unexpected response: status {}", response.status()))
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        if attempt >= network.retries {
+            return Err(err.into());
+        }
+        sleep(network.delay(attempt, &mut rng));
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{SocketAddr, TcpListener},
+        sync::mpsc::{channel, Receiver},
+        thread::spawn,
+        time::Duration,
+    };
+
+    use tinyrand::{Seeded, StdRand};
+    use url::Url;
+
+    use super::{download, fetch, NetworkOptions, MAX_BACKOFF};
+
+    #[test]
+    fn backoff() {
+        let network = NetworkOptions::default();
+        let mut rng = StdRand::seed(0);
+        for attempt in 0..40 {
+            let delay = network.delay(attempt, &mut rng);
+            let backoff = (network.backoff * 2_u32.pow(attempt.min(16))).min(MAX_BACKOFF);
+            assert!(
+                delay >= backoff / 2 && delay <= backoff,
+                "{attempt}: {delay:?}"
+            );
+        }
+    }
+
+    /// Starts a flaky server of `song`.
+    ///
+    /// Returns its address and the path and the start of the range of its requests.
+    fn start_server(song: &[u8]) -> (SocketAddr, Receiver<(String, Option<usize>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests_tx, requests_rx) = channel();
+        let server_song = song.to_vec();
+        spawn(move || {
+            let mut shifted_cut = false;
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                let mut path = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(request_path) = line.strip_prefix("GET ") {
+                        path = request_path.split(' ').next().unwrap().to_owned();
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        range = Some(value.trim().trim_end_matches('-').parse::<usize>().unwrap());
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                requests_tx.send((path.clone(), range)).unwrap();
+
+                let length = server_song.len();
+                let response = match (path.as_str(), index, range) {
+                    ("/missing.mp3", ..) => {
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned()
+                    }
+                    // The server resumes at the wrong place after a first cut
+                    ("/shifted.mp3", _, Some(start)) => {
+                        let start = start / 2;
+                        write!(
+                            stream,
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{length}\r\nContent-Length: {}\r\n\r\n",
+                            length - 1,
+                            length - start
+                        )
+                        .unwrap();
+                        stream.write_all(&server_song[start..]).unwrap();
+                        continue;
+                    }
+                    // The connection is closed in the middle of the song
+                    (_, 0, None) | ("/shifted.mp3", _, None) if !shifted_cut => {
+                        shifted_cut = path == "/shifted.mp3";
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Length: {length}\r\n\r\n"
+                        )
+                        .unwrap();
+                        stream.write_all(&server_song[..length / 2]).unwrap();
+                        continue;
+                    }
+                    // The server is overloaded
+                    (_, 1, _) => {
+                        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_owned()
+                    }
+                    (_, _, Some(start)) => {
+                        write!(
+                            stream,
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{length}\r\nContent-Length: {}\r\n\r\n",
+                            length - 1,
+                            length - start
+                        )
+                        .unwrap();
+                        stream.write_all(&server_song[start..]).unwrap();
+                        continue;
+                    }
+                    (_, _, None) => {
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Length: {length}\r\n\r\n"
+                        )
+                        .unwrap();
+                        stream.write_all(&server_song).unwrap();
+                        continue;
+                    }
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (address, requests_rx)
+    }
+
+    #[test]
+    fn flaky_server() {
+        let song: Vec<u8> = (0..100_000_u32)
+            .map(|index| u8::try_from(index % 251).unwrap())
+            .collect();
+        let (address, requests_rx) = start_server(&song);
+        let agent = ureq::agent();
+        let network = NetworkOptions {
+            backoff: Duration::from_millis(10),
+            ..NetworkOptions::default()
+        };
+        let url = Url::parse(&format!("http://{address}/song.mp3")).unwrap();
+        let mut data = vec![];
+        download(&agent, &url, &network, &mut data).unwrap();
+        assert!(data == song, "The song should be complete");
+        assert_eq!(
+            requests_rx.try_iter().collect::<Vec<_>>(),
+            [
+                ("/song.mp3".to_owned(), None),
+                ("/song.mp3".to_owned(), Some(song.len() / 2)),
+                ("/song.mp3".to_owned(), Some(song.len() / 2)),
+            ]
+        );
+
+        // A part that doesn't start where the download stopped isn't appended
+        let shifted = url.join("shifted.mp3").unwrap();
+        let mut data = vec![];
+        download(&agent, &shifted, &network, &mut data).unwrap();
+        assert!(data == song, "The song should be downloaded again");
+        assert_eq!(
+            requests_rx.try_iter().collect::<Vec<_>>(),
+            [
+                ("/shifted.mp3".to_owned(), None),
+                ("/shifted.mp3".to_owned(), Some(song.len() / 2)),
+                ("/shifted.mp3".to_owned(), None),
+            ]
+        );
+
+        // The missing files are not retried
+        let url = url.join("missing.mp3").unwrap();
+        assert!(fetch(&network, || agent.request_url("GET", &url), |_| Ok(())).is_err());
+        assert_eq!(requests_rx.try_iter().count(), 1);
+    }
+}
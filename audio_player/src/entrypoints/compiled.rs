@@ -5,14 +5,16 @@
 macro_rules! compiled {
     ($folder:tt) => {
         use macros::include_songs;
+        use $crate::options::Options;
         use $crate::player::play_songs;
         use $crate::song::{Compiled, EBox};
 
         static MUSIC_DIR: &[Compiled] = include_songs!($folder);
 
         fn main() -> Result<(), EBox> {
+            let options = Options::from_env()?;
             let mut songs = MUSIC_DIR.to_vec();
-            play_songs(&mut songs[..])
+            play_songs(&mut songs, &options)
         }
     };
 }
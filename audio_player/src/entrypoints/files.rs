@@ -7,12 +7,14 @@ macro_rules! files {
         use std::path::Path;
 
         use files::RecurseFilesIterator;
+        use $crate::options::Options;
         use $crate::player::play_songs;
         use $crate::song::{EBox, File};
 
         const FOLDER: &str = $folder;
 
         fn main() -> Result<(), EBox> {
+            let options = Options::from_env()?;
             let files =
                 RecurseFilesIterator::new(Path::new(FOLDER))?.collect::<Result<Vec<_>, _>>()?;
             let mut songs = files
@@ -21,7 +23,7 @@ macro_rules! files {
                 .map(|file| File::new(file))
                 .collect::<Vec<_>>();
 
-            play_songs(&mut songs[..])
+            play_songs(&mut songs, &options)
         }
     };
 }
@@ -1,60 +1,78 @@
 //! Macro that generates an entry point for a player using the internet.
 
 /// Plays the songs from a specified URL.
+///
+/// The TLS settings and the credentials are read at runtime (see [`agent`](crate::agent)).
+/// The certificate authority given as PEM bytes (e.g. with `include_bytes!`) is trusted
+/// with the other ones, unless `--no-bundled-ca` is given.
+/// The library is cached to be played offline (see [`cache`](crate::cache)).
 #[macro_export]
 macro_rules! web {
-    (impl true) => {
-        {
-            use rustls_pki_types::pem::PemObject;
-            use ureq::Agent;
-
-            let cert = rustls_pki_types::CertificateDer::from_pem_slice(include_bytes!("cert.pem"))?;
-            let mut root_store = rustls::RootCertStore::empty();
-            root_store.add(cert)?;
-
-            let tls_config = rustls::ClientConfig::builder_with_provider(
-                rustls::crypto::ring::default_provider().into(),
-            )
-            .with_protocol_versions(&[&rustls::version::TLS12, &rustls::version::TLS13])?
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-            ureq::builder().tls_config(Arc::new(tls_config)).build()
-        }
-    };
-    (impl false) => {
-        {
-            use ureq::Agent;
-
-            Agent::new()
-        }
-    };
-    (impl) => {
-        {
-            use ureq::Agent;
-
-            Agent::new()
-        }
-    };
-    ($url:expr, $($freebox:tt)*) => {
+    (impl $url:expr, $bundled_ca:expr) => {
         use std::sync::Arc;
-        use ureq::Agent;
         use url::Url;
-        use $crate::player::play_songs;
+        use $crate::agent::{build_agent, AgentConfig};
+        use $crate::cache::{get_library, LibraryCache, LibraryWatcher};
+        use $crate::options::Options;
+        use $crate::player::Player;
+        use $crate::secrets::commands::secret_plugins;
         use $crate::song::{EBox, Web};
-        use $crate::web_utils::get_files;
 
         const URL: &str = $url;
 
         fn main() -> Result<(), EBox> {
-            let agent: Agent = web!(impl $($freebox)*);
+            let mut options = Options::from_env()?;
+            options.tls.bundled = $bundled_ca;
             let url = Url::parse(URL)?;
-            let files = get_files(&agent, &url)?;
+            let config = AgentConfig::load(
+                &url,
+                &options.tls,
+                options.web_config.as_deref(),
+                options.netrc.as_deref(),
+            )?;
+            let agent = build_agent(&config, &options.network)?;
+            let cache = options.cache.as_deref().map(|folder| {
+                Arc::new(LibraryCache::new(folder, &url).with_max_size(options.cache_size))
+            });
+            let files = get_library(&agent, &url, &options, cache.as_deref())?;
 
             let mut songs = files
                 .iter()
-                .map(|url| Web::new(url, &agent))
+                .map(|url| {
+                    Web::new(url, &agent)
+                        .with_network(options.network)
+                        .with_cache(cache.as_deref())
+                })
                 .collect::<Vec<_>>();
-            play_songs(&mut songs[..])
+            let player = Player::new(&mut songs)
+                .with_options(options.clone())
+                .with_plugins(secret_plugins())
+                .with_new_songs(|path| {
+                    // The URLs of the songs added while playing are kept until the end
+                    let url: &Url = Box::leak(Box::new(Url::parse(path).ok()?));
+                    Some(
+                        Web::new(url, &agent)
+                            .with_network(options.network)
+                            .with_cache(cache.as_deref()),
+                    )
+                });
+            match &cache {
+                Some(cache) => player
+                    .with_plugin(LibraryWatcher::new(
+                        agent.clone(),
+                        url.clone(),
+                        &options,
+                        Arc::clone(cache),
+                    ))
+                    .play(),
+                None => player.play(),
+            }
         }
     };
+    ($url:expr) => {
+        $crate::web!(impl $url, None);
+    };
+    ($url:expr, $ca:expr) => {
+        $crate::web!(impl $url, Some($ca));
+    };
 }
@@ -0,0 +1,302 @@
+//! Parsing of the podcast feeds (RSS and Atom).
+//!
+//! The episodes are the items (or entries) with an audio enclosure:
+//! `<enclosure url="..."/>` in RSS or `<link rel="enclosure" href="..."/>` in Atom.
+//! The fields are read from the elements of RSS or Atom, and the duration from `itunes:duration`,
+//! so the elements of the other namespaces (like `itunes:title`) don't replace them.
+use std::{cmp::Reverse, time::Duration};
+
+use chrono::{DateTime, FixedOffset};
+use quick_xml::{
+    events::{BytesStart, Event},
+    name::{Namespace, ResolveResult},
+    NsReader,
+};
+use ureq::{Agent, Response};
+use url::Url;
+
+use crate::{
+    download::{fetch, NetworkOptions},
+    song::EBox,
+};
+
+/// The namespace of Atom.
+const ATOM_NAMESPACE: &[u8] = b"http://www.w3.org/2005/Atom";
+/// The namespace of the iTunes extensions of RSS.
+const ITUNES_NAMESPACE: &[u8] = b"http://www.itunes.com/dtds/podcast-1.0.dtd";
+
+/// An episode of a podcast.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Episode {
+    /// The unique ID of the episode (its GUID, or the URL of its enclosure if it has none).
+    pub id: String,
+    /// The title of the episode.
+    pub title: String,
+    /// The URL of the audio file.
+    pub url: Url,
+    /// The publication date of the episode.
+    pub published: Option<DateTime<FixedOffset>>,
+    /// The duration of the episode, according to the feed.
+    pub duration: Option<Duration>,
+}
+
+/// The fields of an episode that is being parsed.
+#[derive(Default)]
+struct EpisodeFields {
+    /// The GUID (RSS) or the ID (Atom).
+    id: Option<String>,
+    /// The title.
+    title: Option<String>,
+    /// The URL of the enclosure.
+    url: Option<Url>,
+    /// The publication date.
+    published: Option<DateTime<FixedOffset>>,
+    /// The date of the last update (Atom), used if there is no publication date.
+    updated: Option<DateTime<FixedOffset>>,
+    /// The duration (`itunes:duration`).
+    duration: Option<Duration>,
+}
+
+impl EpisodeFields {
+    /// Returns the episode, if it has an enclosure.
+    fn episode(self) -> Option<Episode> {
+        let url = self.url?;
+        Some(Episode {
+            id: self.id.unwrap_or_else(|| url.to_string()),
+            title: self.title.unwrap_or_else(|| url.to_string()),
+            published: self.published.or(self.updated),
+            duration: self.duration,
+            url,
+        })
+    }
+}
+
+/// Parses a date of RSS (RFC 2822) or Atom (RFC 3339).
+fn parse_date(text: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc2822(text)
+        .or_else(|_| DateTime::parse_from_rfc3339(text))
+        .ok()
+}
+
+/// Parses an `itunes:duration`: a number of seconds, `MM:SS` or `HH:MM:SS`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    text.split(':')
+        .try_fold(0, |seconds: u64, part| {
+            Some(seconds * 60 + part.trim().parse::<u64>().ok()?)
+        })
+        .map(Duration::from_secs)
+}
+
+/// Returns the URL of the audio enclosure of a tag, if it's one.
+///
+/// # Errors
+/// Fails if an attribute or the URL is invalid.
+fn enclosure(tag: &BytesStart, feed_url: &Url) -> Result<Option<Url>, EBox> {
+    let attribute = |name: &str| -> Result<Option<String>, EBox> {
+        Ok(match tag.try_get_attribute(name)? {
+            Some(attribute) => Some(attribute.unescape_value()?.into_owned()),
+            None => None,
+        })
+    };
+    let url = match tag.local_name().as_ref() {
+        b"enclosure" => attribute("url")?,
+        b"link" if attribute("rel")?.as_deref() == Some("enclosure") => attribute("href")?,
+        _ => return Ok(None),
+    };
+    // The video episodes are skipped
+    if attribute("type")?.is_some_and(|mime_type| !mime_type.starts_with("audio/")) {
+        return Ok(None);
+    }
+    Ok(match url {
+        Some(url) => Some(feed_url.join(url.trim())?),
+        None => None,
+    })
+}
+
+/// Parses an RSS or Atom feed located at `url`.
+///
+/// Returns the episodes that have an audio enclosure, newest first.
+///
+/// # Errors
+/// Fails if the feed isn't valid XML or if it's not an RSS or Atom feed.
+pub fn parse_feed(url: &Url, content: &str) -> Result<Vec<Episode>, EBox> {
+    let mut reader = NsReader::from_str(content);
+    reader.config_mut().trim_text(true);
+
+    let mut is_feed = false;
+    let mut episodes = vec![];
+    let mut fields: Option<EpisodeFields> = None;
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(tag) | Event::Empty(tag) => {
+                text.clear();
+                match tag.local_name().as_ref() {
+                    b"rss" | b"feed" => is_feed = true,
+                    b"item" | b"entry" => fields = Some(EpisodeFields::default()),
+                    _ => {
+                        if let Some(fields) = &mut fields {
+                            if let Some(enclosure) = enclosure(&tag, url)? {
+                                fields.url = Some(enclosure);
+                            }
+                        }
+                    }
+                }
+            }
+            Event::Text(content) => text.push_str(&content.unescape()?),
+            Event::CData(content) => text.push_str(&String::from_utf8_lossy(&content)),
+            Event::End(tag) => {
+                let (namespace, name) = reader.resolve_element(tag.name());
+                if matches!(name.as_ref(), b"item" | b"entry") {
+                    episodes.extend(fields.take().and_then(EpisodeFields::episode));
+                }
+                let Some(fields) = &mut fields else {
+                    continue;
+                };
+                let value = text.trim();
+                match (namespace, name.as_ref()) {
+                    (ResolveResult::Bound(Namespace(ITUNES_NAMESPACE)), b"duration") => {
+                        fields.duration = parse_duration(value);
+                    }
+                    // RSS has no namespace
+                    (
+                        ResolveResult::Bound(Namespace(ATOM_NAMESPACE)) | ResolveResult::Unbound,
+                        name,
+                    ) => match name {
+                        b"guid" | b"id" => fields.id = Some(value.to_owned()),
+                        b"title" => fields.title = Some(value.to_owned()),
+                        b"pubDate" | b"published" => fields.published = parse_date(value),
+                        b"updated" => fields.updated = parse_date(value),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if !is_feed {
+        return Err("Not an RSS or Atom feed".into());
+    }
+    // The episodes without a date stay at the end, in the order of the feed
+    episodes.sort_by_key(|episode| Reverse(episode.published));
+    Ok(episodes)
+}
+
+/// Fetches and parses the feed at `url`.
+///
+/// # Errors
+/// Fails if the feed can't be fetched or if it's invalid.
+pub fn get_episodes(
+    agent: &Agent,
+    url: &Url,
+    network: &NetworkOptions,
+) -> Result<Vec<Episode>, EBox> {
+    let content = fetch(
+        network,
+        || agent.request_url("GET", url),
+        Response::into_string,
+    )?;
+    parse_feed(url, &content).map_err(|err| format!("Invalid feed {url}: {err}").into())
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::time::Duration;
+
+    use url::Url;
+
+    use super::parse_feed;
+
+    #[test]
+    fn rss() {
+        let url = Url::parse("https://example.com/podcast/feed.rss").unwrap();
+        let episodes = parse_feed(&url, include_str!("../fixtures/feeds/podcast.rss")).unwrap();
+        assert_eq!(
+            episodes
+                .iter()
+                .map(|episode| (
+                    episode.id.as_str(),
+                    episode.title.as_str(),
+                    episode.url.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    "https://example.com/episodes/3",
+                    "Episode 3 <Live>",
+                    "https://cdn.example.com/3.mp3"
+                ),
+                (
+                    "https://example.com/podcast/episodes/2.mp3",
+                    "Episode 2",
+                    "https://example.com/podcast/episodes/2.mp3"
+                ),
+                (
+                    "episode-1",
+                    "Episode 1: The & Beginning",
+                    "https://example.com/podcast/episodes/1.mp3"
+                ),
+            ]
+        );
+        assert_eq!(
+            episodes
+                .iter()
+                .map(|episode| episode.duration)
+                .collect::<Vec<_>>(),
+            [
+                Some(Duration::from_secs(754)),
+                Some(Duration::from_secs(754)),
+                Some(Duration::from_secs(3723))
+            ]
+        );
+        assert_eq!(
+            episodes[0].published.unwrap().to_rfc3339(),
+            "2025-01-20T08:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn atom() {
+        let url = Url::parse("https://example.com/feed.atom").unwrap();
+        let episodes = parse_feed(&url, include_str!("../fixtures/feeds/podcast.atom")).unwrap();
+        assert_eq!(
+            episodes
+                .iter()
+                .map(|episode| (
+                    episode.id.as_str(),
+                    episode.title.as_str(),
+                    episode.url.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    "urn:uuid:2",
+                    "Second <b>episode</b>",
+                    "https://example.com/audio/2.mp3"
+                ),
+                (
+                    "urn:uuid:1",
+                    "First episode",
+                    "https://example.com/audio/1.mp3"
+                ),
+            ]
+        );
+        // The publication date is preferred to the update date
+        assert_eq!(
+            episodes[0].published.unwrap().to_rfc3339(),
+            "2025-01-20T10:00:00+01:00"
+        );
+        assert_eq!(episodes[1].duration, None);
+    }
+
+    #[test]
+    fn invalid_feeds() {
+        let url = Url::parse("https://example.com/feed").unwrap();
+        assert!(parse_feed(&url, "<html><body></body></html>").is_err());
+        assert!(parse_feed(&url, "<rss><channel></item></rss>").is_err());
+    }
+}
@@ -0,0 +1,1314 @@
+//! HLS streams: audio published as playlists of segments (`.m3u8`).
+//!
+//! A master playlist lists the variants of a stream: an audio rendition is preferred,
+//! then the audio-only variant with the highest bandwidth, then the cheapest variant.
+//! A media playlist lists the segments (packed AAC or MP3, or MPEG-TS that is demuxed).
+//!
+//! The segments of a VOD playlist (with `#EXT-X-ENDLIST`) are downloaded when they are read,
+//! one segment ahead, and the last ones are kept so they can be seeked.
+//! Its duration is the sum of the durations of its segments, and it's seeked on a background
+//! thread by decoding the segment that contains the position (without downloading the previous
+//! ones), so the seek doesn't stop the audio thread.
+//! A live playlist is read ahead by a [`Prefetch`] and reloaded when all its segments have been played.
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    mem,
+    ops::Range,
+    path::Path,
+    sync::Arc,
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rodio::{source::SeekError, Decoder, Source};
+use ureq::{Agent, Response};
+use url::Url;
+
+use crate::{
+    download::{download, fetch, NetworkOptions},
+    lyrics::Lyrics,
+    prefetch::Prefetch,
+    song::{AudioSource, EBox, Song},
+    tags::Tags,
+};
+
+/// The number of segments played from the end of a live playlist when it starts.
+const LIVE_SEGMENTS: usize = 3;
+
+/// The number of downloaded segments of a VOD playlist that are kept.
+const VOD_SEGMENTS: usize = 3;
+
+/// The size of the buffer of a live playlist (a few segments).
+const LIVE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The size of an MPEG-TS packet.
+const TS_PACKET_SIZE: usize = 188;
+
+/// Is the URL an HLS playlist (`.m3u8`)?
+#[must_use]
+pub fn is_playlist(url: &Url) -> bool {
+    Path::new(url.path())
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("m3u8"))
+}
+
+/// A segment of a media playlist.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    /// The URL of the segment.
+    pub url: Url,
+    /// The duration of the segment.
+    pub duration: Duration,
+    /// The media sequence number of the segment.
+    pub sequence: u64,
+    /// The bytes of the segment in the resource at its URL, if it's only a part of it
+    /// (`#EXT-X-BYTERANGE`).
+    pub range: Option<Range<u64>>,
+}
+
+/// A media playlist.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MediaPlaylist {
+    /// The maximum duration of a segment.
+    pub target_duration: Duration,
+    /// The segments.
+    pub segments: Vec<Segment>,
+    /// Is it the end of the playlist (i.e. a VOD playlist or a finished live playlist)?
+    pub ended: bool,
+}
+
+/// An HLS playlist.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Playlist {
+    /// A master playlist, with the URL of the chosen variant.
+    Master(Url),
+    /// A media playlist.
+    Media(MediaPlaylist),
+}
+
+/// Parses the attributes of a tag (`NAME=value,NAME="quoted, value"`).
+fn attributes(list: &str) -> HashMap<&str, &str> {
+    let mut attributes = HashMap::new();
+    let mut rest = list;
+    while let Some((name, value)) = rest.split_once('=') {
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, next) = quoted.split_once('"').unwrap_or((quoted, ""));
+                (value, next.split_once(',').map_or("", |(_, next)| next))
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        attributes.insert(name.trim(), value);
+        rest = next;
+    }
+    attributes
+}
+
+/// Does the variant contain only audio (according to its codecs)?
+fn is_audio_only(codecs: Option<&str>) -> bool {
+    codecs.is_some_and(|codecs| {
+        codecs
+            .split(',')
+            .all(|codec| codec.trim().starts_with("mp4a") || codec.trim().starts_with("mp3"))
+    })
+}
+
+/// Parses a duration in seconds.
+///
+/// # Errors
+/// Fails if the duration is invalid.
+fn seconds(value: &str) -> Result<Duration, EBox> {
+    Ok(Duration::try_from_secs_f64(value.trim().parse()?)?)
+}
+
+/// Parses the value of an `#EXT-X-BYTERANGE` tag (`<length>[@<offset>]`).
+///
+/// # Errors
+/// Fails if the length or the offset is invalid.
+fn byte_range(value: &str) -> Result<(u64, Option<u64>), EBox> {
+    let (length, offset) = value
+        .split_once('@')
+        .map_or((value, None), |(length, offset)| (length, Some(offset)));
+    let offset = offset.map(|offset| offset.trim().parse()).transpose()?;
+    Ok((length.trim().parse()?, offset))
+}
+
+/// Parses a playlist located at `url`.
+///
+/// # Errors
+/// Fails if the playlist is invalid or if its segments are encrypted or in fragmented MP4.
+pub fn parse_playlist(url: &Url, content: &str) -> Result<Playlist, EBox> {
+    let mut lines = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err("Not an HLS playlist".into());
+    }
+
+    // (bandwidth, audio only, URL)
+    let mut variants: Vec<(u64, bool, Url)> = vec![];
+    let mut renditions: Vec<(bool, Url)> = vec![];
+    let mut playlist = MediaPlaylist::default();
+    let mut sequence = 0;
+    let mut variant = None;
+    let mut duration = Duration::ZERO;
+    let mut range = None;
+
+    for line in lines {
+        let (tag, value) = line.split_once(':').unwrap_or((line, ""));
+        match tag {
+            "#EXT-X-STREAM-INF" => {
+                let attributes = attributes(value);
+                let bandwidth = attributes
+                    .get("BANDWIDTH")
+                    .and_then(|bandwidth| bandwidth.parse().ok())
+                    .unwrap_or(0);
+                variant = Some((bandwidth, is_audio_only(attributes.get("CODECS").copied())));
+            }
+            "#EXT-X-MEDIA" => {
+                let attributes = attributes(value);
+                if let (Some(&"AUDIO"), Some(uri)) = (attributes.get("TYPE"), attributes.get("URI"))
+                {
+                    renditions.push((attributes.get("DEFAULT") == Some(&"YES"), url.join(uri)?));
+                }
+            }
+            "#EXT-X-TARGETDURATION" => playlist.target_duration = seconds(value)?,
+            "#EXT-X-MEDIA-SEQUENCE" => sequence = value.trim().parse()?,
+            "#EXTINF" => duration = seconds(value.split(',').next().unwrap_or_default())?,
+            "#EXT-X-BYTERANGE" => range = Some(byte_range(value)?),
+            "#EXT-X-ENDLIST" => playlist.ended = true,
+            "#EXT-X-KEY" if attributes(value).get("METHOD") != Some(&"NONE") => {
+                return Err("The encrypted HLS streams are not supported".into());
+            }
+            "#EXT-X-MAP" => return Err("The fragmented MP4 HLS streams are not supported".into()),
+            _ if line.starts_with('#') => {}
+            _ => {
+                if let Some((bandwidth, audio_only)) = variant.take() {
+                    variants.push((bandwidth, audio_only, url.join(line)?));
+                } else {
+                    let url = url.join(line)?;
+                    let range = match range.take() {
+                        Some((length, offset)) => {
+                            // Without an offset, the range follows the one of the previous segment
+                            let start = offset
+                                .or_else(|| {
+                                    let previous = playlist.segments.last()?;
+                                    previous
+                                        .range
+                                        .as_ref()
+                                        .filter(|_| previous.url == url)
+                                        .map(|range| range.end)
+                                })
+                                .ok_or("An HLS byte range has no offset")?;
+                            let end = start.checked_add(length).ok_or("Invalid HLS byte range")?;
+                            Some(start..end)
+                        }
+                        None => None,
+                    };
+                    playlist.segments.push(Segment {
+                        url,
+                        duration,
+                        sequence,
+                        range,
+                    });
+                    sequence += 1;
+                    duration = Duration::ZERO;
+                }
+            }
+        }
+    }
+
+    if let Some((_, url)) = renditions
+        .iter()
+        .find(|(default, _)| *default)
+        .or_else(|| renditions.first())
+    {
+        return Ok(Playlist::Master(url.clone()));
+    }
+    // The variants with a video have the same audio: the cheapest one is played
+    let variant = variants
+        .iter()
+        .filter(|(_, audio_only, _)| *audio_only)
+        .max_by_key(|(bandwidth, ..)| *bandwidth)
+        .or_else(|| variants.iter().min_by_key(|(bandwidth, ..)| *bandwidth));
+    match variant {
+        Some((.., url)) => Ok(Playlist::Master(url.clone())),
+        None => Ok(Playlist::Media(playlist)),
+    }
+}
+
+/// Fetches the media playlist at `url`, or the one of the chosen variant if it's a master playlist.
+///
+/// Returns the URL of the media playlist and the playlist.
+///
+/// # Errors
+/// Fails if a playlist can't be fetched or is invalid.
+pub fn load_playlist(
+    agent: &Agent,
+    url: &Url,
+    network: &NetworkOptions,
+) -> Result<(Url, MediaPlaylist), EBox> {
+    let mut url = url.clone();
+    // A master playlist can't link to another master playlist
+    for _ in 0..2 {
+        let content = fetch(
+            network,
+            || agent.request_url("GET", &url),
+            Response::into_string,
+        )?;
+        match parse_playlist(&url, &content)? {
+            Playlist::Master(variant) => url = variant,
+            Playlist::Media(playlist) => return Ok((url, playlist)),
+        }
+    }
+    Err("The master playlist doesn't link to a media playlist".into())
+}
+
+/// Returns the payload of an MPEG-TS packet (after its adaptation field), if there is one.
+fn ts_payload(packet: &[u8]) -> Option<&[u8]> {
+    let adaptation = (packet.get(3)? >> 4) & 0b11;
+    let payload = packet.get(4..)?;
+    match adaptation {
+        0b01 => Some(payload),
+        0b11 => payload.get(1 + usize::from(*payload.first()?)..),
+        _ => None,
+    }
+}
+
+/// Returns the content of a PSI section (PAT or PMT), without its header and its CRC.
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let section = payload.get(1 + usize::from(*payload.first()?)..)?;
+    let length = usize::from(u16::from_be_bytes([*section.get(1)?, *section.get(2)?]) & 0x0fff);
+    section.get(8..(3 + length).checked_sub(4)?)
+}
+
+/// Returns a 13-bit PID.
+fn pid(bytes: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]) & 0x1fff)
+}
+
+/// Returns the PID of the PMT of the first program of a PAT.
+fn parse_pat(payload: &[u8]) -> Option<u16> {
+    psi_section(payload)?
+        .chunks_exact(4)
+        .find(|program| program[..2] != [0, 0])
+        .and_then(|program| pid(&program[2..]))
+}
+
+/// Returns the PID of the first MP3 or AAC (ADTS) stream of a PMT.
+fn parse_pmt(payload: &[u8]) -> Option<u16> {
+    let section = psi_section(payload)?;
+    let info_length =
+        usize::from(u16::from_be_bytes([*section.get(2)?, *section.get(3)?]) & 0x0fff);
+    let mut streams = section.get(4 + info_length..)?;
+    while let [stream_type, pid1, pid2, length1, length2, rest @ ..] = streams {
+        if matches!(stream_type, 0x03 | 0x04 | 0x0f) {
+            return pid(&[*pid1, *pid2]);
+        }
+        let length = usize::from(u16::from_be_bytes([*length1, *length2]) & 0x0fff);
+        streams = rest.get(length..)?;
+    }
+    None
+}
+
+/// Returns the data of a PES packet (after its header).
+fn pes_payload(payload: &[u8]) -> Option<&[u8]> {
+    if payload.get(..3)? != [0, 0, 1] {
+        return None;
+    }
+    payload.get(9 + usize::from(*payload.get(8)?)..)
+}
+
+/// Extracts the audio stream (MP3 or AAC) of an MPEG-TS segment.
+///
+/// # Errors
+/// Fails if the segment is invalid or if it has no audio stream.
+pub fn demux_ts(data: &[u8]) -> Result<Vec<u8>, EBox> {
+    let mut pmt_pid = None;
+    let mut audio_pid = None;
+    let mut audio = vec![];
+    for packet in data.chunks_exact(TS_PACKET_SIZE) {
+        if packet[0] != 0x47 {
+            return Err("Invalid MPEG-TS packet".into());
+        }
+        let start = packet[1] & 0x40 != 0;
+        let (Some(packet_pid), Some(payload)) = (pid(&packet[1..]), ts_payload(packet)) else {
+            continue;
+        };
+        if packet_pid == 0 && start {
+            pmt_pid = parse_pat(payload);
+        } else if Some(packet_pid) == pmt_pid && start {
+            audio_pid = parse_pmt(payload);
+        } else if Some(packet_pid) == audio_pid {
+            let payload = if start {
+                pes_payload(payload).ok_or("Invalid PES packet")?
+            } else {
+                payload
+            };
+            audio.extend_from_slice(payload);
+        }
+    }
+    if audio_pid.is_none() {
+        return Err("The MPEG-TS segment has no MP3 or AAC stream".into());
+    }
+    Ok(audio)
+}
+
+/// Returns the audio data of a segment: the audio stream of an MPEG-TS segment,
+/// or the packed audio without its ID3 tag.
+///
+/// # Errors
+/// Fails if the MPEG-TS segment is invalid.
+pub fn segment_audio(data: Vec<u8>) -> Result<Vec<u8>, EBox> {
+    if data.first() == Some(&0x47) && data.get(TS_PACKET_SIZE).is_none_or(|byte| *byte == 0x47) {
+        return demux_ts(&data);
+    }
+    match data.get(..10) {
+        Some([b'I', b'D', b'3', _, _, flags, size @ ..]) => {
+            // The size is made of 4 syncsafe bytes
+            let size = size
+                .iter()
+                .fold(0, |size, byte| (size << 7) | usize::from(byte & 0x7f));
+            let footer = if flags & 0x10 == 0 { 0 } else { 10 };
+            Ok(data.get(10 + size + footer..).unwrap_or_default().to_vec())
+        }
+        _ => Ok(data),
+    }
+}
+
+/// Downloads a segment and returns its audio data.
+///
+/// # Errors
+/// Fails if the segment can't be downloaded or is invalid.
+fn download_segment(
+    agent: &Agent,
+    segment: &Segment,
+    network: &NetworkOptions,
+) -> Result<Vec<u8>, EBox> {
+    let Some(range) = &segment.range else {
+        let mut data = vec![];
+        download(agent, &segment.url, network, &mut data)?;
+        return segment_audio(data);
+    };
+    let data = fetch(
+        network,
+        || {
+            agent.request_url("GET", &segment.url).set(
+                "Range",
+                &format!("bytes={}-{}", range.start, range.end.saturating_sub(1)),
+            )
+        },
+        |response| {
+            let partial = response.status() == 206;
+            let mut data = vec![];
+            response.into_reader().read_to_end(&mut data)?;
+            if partial {
+                return Ok(data);
+            }
+            // The server sends the whole resource
+            let start = usize::try_from(range.start).unwrap_or(usize::MAX);
+            let end = usize::try_from(range.end).unwrap_or(usize::MAX);
+            Ok(data
+                .get(start..end.min(data.len()))
+                .unwrap_or_default()
+                .to_vec())
+        },
+    )?;
+    segment_audio(data)
+}
+
+/// The audio data of a live playlist, reloaded when all its segments have been played.
+struct LiveReader {
+    /// The [`Agent`] that fetches the playlist and the segments.
+    agent: Agent,
+    /// The URL of the media playlist.
+    url: Url,
+    /// The timeouts and the retries of the requests.
+    network: NetworkOptions,
+    /// The segments that haven't been played yet.
+    pending: VecDeque<Segment>,
+    /// The media sequence number of the next new segment.
+    next_sequence: u64,
+    /// The maximum duration of a segment.
+    target_duration: Duration,
+    /// Is it the end of the playlist?
+    ended: bool,
+    /// When the playlist can be reloaded.
+    next_reload: Instant,
+    /// The audio data of the current segment.
+    segment: Cursor<Vec<u8>>,
+}
+
+impl LiveReader {
+    /// Creates a reader that starts near the end of a live playlist.
+    fn new(agent: Agent, url: Url, network: NetworkOptions, playlist: &MediaPlaylist) -> Self {
+        let start = playlist.segments.len().saturating_sub(LIVE_SEGMENTS);
+        let mut reader = Self {
+            agent,
+            url,
+            network,
+            pending: VecDeque::new(),
+            next_sequence: 0,
+            target_duration: playlist.target_duration,
+            ended: playlist.ended,
+            next_reload: Instant::now() + playlist.target_duration,
+            segment: Cursor::new(vec![]),
+        };
+        reader.add_segments(&playlist.segments[start..]);
+        reader
+    }
+
+    /// Adds the segments that haven't been seen yet.
+    fn add_segments(&mut self, segments: &[Segment]) {
+        for segment in segments {
+            if segment.sequence >= self.next_sequence {
+                self.next_sequence = segment.sequence + 1;
+                self.pending.push_back(segment.clone());
+            }
+        }
+    }
+
+    /// Loads the next segment, reloading the playlist until there is one.
+    ///
+    /// Returns `false` if the playlist has ended.
+    ///
+    /// # Errors
+    /// Fails if the playlist or the segment can't be fetched.
+    fn next_segment(&mut self) -> Result<bool, EBox> {
+        loop {
+            if let Some(segment) = self.pending.pop_front() {
+                let audio = download_segment(&self.agent, &segment, &self.network)?;
+                self.segment = Cursor::new(audio);
+                return Ok(true);
+            }
+            if self.ended {
+                return Ok(false);
+            }
+            if let Some(delay) = self.next_reload.checked_duration_since(Instant::now()) {
+                sleep(delay);
+            }
+            // The playlist is reloaded more often if it hasn't changed
+            self.next_reload = Instant::now() + self.target_duration / 2;
+            let (_, playlist) = load_playlist(&self.agent, &self.url, &self.network)?;
+            self.target_duration = playlist.target_duration;
+            self.ended = playlist.ended;
+            self.add_segments(&playlist.segments);
+        }
+    }
+}
+
+impl Read for LiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.segment.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            if !self.next_segment().map_err(io::Error::other)? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+/// The download of the audio data of a segment on a background thread.
+type SegmentDownload = JoinHandle<Result<Vec<u8>, EBox>>;
+
+/// The audio data of a VOD playlist, downloaded when it's read.
+///
+/// The segments are placed one after the other: the start of a segment is known
+/// once the previous ones have been downloaded.
+struct VodReader {
+    /// The [`Agent`] that fetches the segments.
+    agent: Agent,
+    /// The timeouts and the retries of the requests.
+    network: NetworkOptions,
+    /// The segments of the playlist.
+    segments: Vec<Segment>,
+    /// The start of each segment whose previous segments have been downloaded
+    /// (and the end of the last one when they have all been downloaded).
+    offsets: Vec<u64>,
+    /// The last downloaded segments, with their index.
+    loaded: VecDeque<(usize, Vec<u8>)>,
+    /// The download of the next segment, with its index.
+    next: Option<(usize, SegmentDownload)>,
+    /// The position in the audio data.
+    position: u64,
+}
+
+impl VodReader {
+    /// Creates a reader of the segments of a VOD playlist, and starts downloading the first one.
+    fn new(agent: Agent, network: NetworkOptions, segments: Vec<Segment>) -> Self {
+        let mut reader = Self {
+            agent,
+            network,
+            segments,
+            offsets: vec![0],
+            loaded: VecDeque::new(),
+            next: None,
+            position: 0,
+        };
+        reader.download_next(0);
+        reader
+    }
+
+    /// Starts downloading a segment on a background thread, if it's not loaded.
+    fn download_next(&mut self, index: usize) {
+        let Some(segment) = self.segments.get(index).cloned() else {
+            return;
+        };
+        if self.loaded.iter().any(|(loaded, _)| *loaded == index) {
+            return;
+        }
+        let (agent, network) = (self.agent.clone(), self.network);
+        self.next = Some((
+            index,
+            thread::spawn(move || download_segment(&agent, &segment, &network)),
+        ));
+    }
+
+    /// Loads a segment (and starts downloading the next one).
+    ///
+    /// Returns its audio data.
+    ///
+    /// # Errors
+    /// Fails if the segment doesn't exist, can't be downloaded or is invalid.
+    fn load(&mut self, index: usize) -> Result<&[u8], EBox> {
+        if let Some(position) = self.loaded.iter().position(|(loaded, _)| *loaded == index) {
+            return Ok(&self.loaded[position].1);
+        }
+        if self.next.as_ref().is_none_or(|(next, _)| *next != index) {
+            // After a seek, the segment is downloaded like the next ones
+            self.download_next(index);
+        }
+        let audio = match self.next.take() {
+            Some((next, download)) if next == index => download
+                .join()
+                .unwrap_or_else(|_| Err("The download of the segment panicked".into()))?,
+            _ => return Err(format!("The HLS playlist has no segment {index}").into()),
+        };
+        if index + 1 == self.offsets.len() {
+            self.offsets.push(self.offsets[index] + audio.len() as u64);
+        }
+        if self.loaded.len() >= VOD_SEGMENTS {
+            self.loaded.pop_front();
+        }
+        self.loaded.push_back((index, audio));
+        self.download_next(index + 1);
+        Ok(&self.loaded[self.loaded.len() - 1].1)
+    }
+}
+
+impl Read for VodReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // The segment that contains the position (or the first one whose end isn't known)
+            let index = self
+                .offsets
+                .partition_point(|offset| *offset <= self.position)
+                .saturating_sub(1);
+            if index >= self.segments.len() {
+                return Ok(0);
+            }
+            let start = self.position - self.offsets[index];
+            let last = index + 1 == self.segments.len();
+            let audio = self.load(index).map_err(io::Error::other)?;
+            let rest = usize::try_from(start)
+                .ok()
+                .and_then(|start| audio.get(start..))
+                .unwrap_or_default();
+            if rest.is_empty() && !buf.is_empty() && !last {
+                // The position is after this segment, whose end is now known
+                continue;
+            }
+            let read = rest.len().min(buf.len());
+            buf[..read].copy_from_slice(&rest[..read]);
+            self.position += read as u64;
+            return Ok(read);
+        }
+    }
+}
+
+impl Seek for VodReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                // The length is known once all the segments have been downloaded
+                for index in self.offsets.len() - 1..self.segments.len() {
+                    self.load(index).map_err(io::Error::other)?;
+                }
+                self.offsets
+                    .last()
+                    .and_then(|length| length.checked_add_signed(offset))
+            }
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// The segments of a VOD playlist, shared with the seeks.
+struct VodPlaylist {
+    /// The [`Agent`] that fetches the segments.
+    agent: Agent,
+    /// The timeouts and the retries of the requests.
+    network: NetworkOptions,
+    /// The segments of the playlist.
+    segments: Vec<Segment>,
+    /// The start of each segment (the sum of the durations of the previous ones),
+    /// and the duration of the playlist.
+    starts: Vec<Duration>,
+}
+
+impl VodPlaylist {
+    /// Returns the index of the segment that contains a position.
+    fn segment_at(&self, position: Duration) -> usize {
+        self.starts
+            .partition_point(|start| *start <= position)
+            .saturating_sub(1)
+            .min(self.segments.len().saturating_sub(1))
+    }
+
+    /// Creates a decoder of the playlist from a segment.
+    ///
+    /// # Errors
+    /// Fails if the segment can't be downloaded or decoded.
+    fn decode(&self, first: usize) -> Result<VodDecoder, EBox> {
+        let reader = VodReader::new(
+            self.agent.clone(),
+            self.network,
+            self.segments[first..].to_vec(),
+        );
+        Ok(VodDecoder {
+            decoder: Decoder::new(reader)?,
+            first,
+            samples: 0,
+        })
+    }
+
+    /// Seeks a decoder (or a new one) to a position.
+    ///
+    /// # Errors
+    /// Fails if the segment of the position can't be downloaded or decoded.
+    fn seek(&self, decoder: Option<VodDecoder>, pos: Duration) -> Result<VodDecoder, EBox> {
+        let index = self.segment_at(pos);
+        let mut decoder = match decoder {
+            // Forwards in the segment or in the next one, the decoder goes on
+            Some(decoder)
+                if pos >= decoder.current_time(&self.starts)
+                    && index <= self.segment_at(decoder.current_time(&self.starts)) + 1 =>
+            {
+                decoder
+            }
+            // Backwards or after the next segment, decode from the segment of the position
+            _ => self.decode(index)?,
+        };
+        // The packed audio has no index: the samples are decoded until the position
+        let channels = u64::from(decoder.decoder.channels().max(1));
+        let target = decoder.samples_of(pos.saturating_sub(self.starts[decoder.first]));
+        let target = target - target % channels;
+        while decoder.samples < target && decoder.next().is_some() {}
+        Ok(decoder)
+    }
+}
+
+/// The decoder of a VOD playlist, from one of its segments.
+struct VodDecoder {
+    /// The decoder of the segments, from the segment `first`.
+    decoder: Decoder<VodReader>,
+    /// The index of the segment where the decoder starts.
+    first: usize,
+    /// The number of samples returned by the decoder.
+    samples: u64,
+}
+
+impl VodDecoder {
+    /// Returns the number of samples decoded per second.
+    fn rate(&self) -> u128 {
+        u128::from(self.decoder.sample_rate()) * u128::from(self.decoder.channels())
+    }
+
+    /// Returns the number of samples of a duration.
+    fn samples_of(&self, duration: Duration) -> u64 {
+        u64::try_from(duration.as_nanos() * self.rate() / 1_000_000_000).unwrap_or(u64::MAX)
+    }
+
+    /// Returns the position of the decoder in the playlist, whose segments start at `starts`.
+    fn current_time(&self, starts: &[Duration]) -> Duration {
+        let nanos = u128::from(self.samples) * 1_000_000_000 / self.rate().max(1);
+        starts[self.first] + Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+}
+
+impl Iterator for VodDecoder {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.decoder.next()?;
+        self.samples += 1;
+        Some(sample)
+    }
+}
+
+/// The seek of a VOD playlist on a background thread, which returns the seeked decoder.
+type VodSeek = JoinHandle<Result<VodDecoder, EBox>>;
+
+/// The state of the decoder of a [`VodSource`].
+enum VodState {
+    /// The decoder plays the playlist.
+    Playing(VodDecoder),
+    /// The decoder is seeked on a background thread.
+    Seeking(VodSeek),
+    /// The seek has failed: the playlist has ended.
+    Failed,
+}
+
+/// The decoded audio of a VOD playlist.
+///
+/// It's seeked on a background thread, by decoding the playlist from the segment that contains
+/// the position (so the segments before it aren't downloaded). Silence is played meanwhile,
+/// and as many samples are skipped after the seek, so the position of the player stays right.
+struct VodSource {
+    /// The segments of the playlist.
+    playlist: Arc<VodPlaylist>,
+    /// The decoder, or its seek.
+    state: VodState,
+    /// The number of channels before the seek.
+    channels: u16,
+    /// The sample rate before the seek.
+    sample_rate: u32,
+    /// The number of silent samples played during the seek.
+    silence: u64,
+}
+
+impl VodSource {
+    /// Creates a source that decodes the segments of a VOD playlist.
+    ///
+    /// # Errors
+    /// Fails if the first segment can't be decoded.
+    fn new(agent: Agent, network: NetworkOptions, segments: Vec<Segment>) -> Result<Self, EBox> {
+        let mut starts = vec![Duration::ZERO];
+        for segment in &segments {
+            starts.push(starts[starts.len() - 1] + segment.duration);
+        }
+        let playlist = VodPlaylist {
+            agent,
+            network,
+            segments,
+            starts,
+        };
+        let decoder = playlist.decode(0)?;
+        Ok(Self {
+            playlist: Arc::new(playlist),
+            channels: decoder.decoder.channels(),
+            sample_rate: decoder.decoder.sample_rate(),
+            state: VodState::Playing(decoder),
+            silence: 0,
+        })
+    }
+
+    /// Plays the seeked decoder, once the seek has ended.
+    fn finish_seek(&mut self, seek: VodSeek) {
+        let result = seek
+            .join()
+            .unwrap_or_else(|_| Err("The seek of the playlist panicked".into()));
+        self.state = match result {
+            Ok(mut decoder) => {
+                // The silence played during the seek replaces the first samples
+                while self.silence > 0 && decoder.next().is_some() {
+                    self.silence -= 1;
+                }
+                VodState::Playing(decoder)
+            }
+            Err(_) => VodState::Failed,
+        };
+        self.silence = 0;
+    }
+}
+
+impl Iterator for VodSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if let VodState::Seeking(seek) = &self.state {
+            // Silence is played until the end of the seek, which starts a new frame
+            if !seek.is_finished() || !self.silence.is_multiple_of(u64::from(self.channels.max(1)))
+            {
+                self.silence += 1;
+                return Some(0);
+            }
+            if let VodState::Seeking(seek) = mem::replace(&mut self.state, VodState::Failed) {
+                self.finish_seek(seek);
+            }
+        }
+        match &mut self.state {
+            VodState::Playing(decoder) => decoder.next(),
+            VodState::Seeking(_) | VodState::Failed => None,
+        }
+    }
+}
+
+impl Source for VodSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        match &self.state {
+            VodState::Playing(decoder) => decoder.decoder.current_frame_len(),
+            // The silence is played one frame at a time
+            VodState::Seeking(_) => {
+                let channels = u64::from(self.channels.max(1));
+                usize::try_from(channels - self.silence % channels).ok()
+            }
+            VodState::Failed => Some(0),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        match &self.state {
+            VodState::Playing(decoder) => decoder.decoder.channels(),
+            VodState::Seeking(_) | VodState::Failed => self.channels,
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match &self.state {
+            VodState::Playing(decoder) => decoder.decoder.sample_rate(),
+            VodState::Seeking(_) | VodState::Failed => self.sample_rate,
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.playlist.starts.last().copied()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        // The segment is downloaded and decoded off the audio thread, after the previous seek
+        let previous = mem::replace(&mut self.state, VodState::Failed);
+        if let VodState::Playing(decoder) = &previous {
+            self.channels = decoder.decoder.channels();
+            self.sample_rate = decoder.decoder.sample_rate();
+        }
+        let playlist = Arc::clone(&self.playlist);
+        self.state = VodState::Seeking(thread::spawn(move || {
+            let decoder = match previous {
+                VodState::Playing(decoder) => Some(decoder),
+                VodState::Seeking(seek) => seek.join().ok().and_then(Result::ok),
+                VodState::Failed => None,
+            };
+            playlist.seek(decoder, pos)
+        }));
+        self.silence = 0;
+        Ok(())
+    }
+}
+
+/// The audio data of an [`Hls`] song.
+enum HlsData {
+    /// The segments of a VOD playlist.
+    Vod(Box<VodReader>),
+    /// The segments of a live playlist, read ahead.
+    Live(Prefetch),
+}
+
+impl Read for HlsData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Vod(data) => data.read(buf),
+            Self::Live(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for HlsData {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Vod(data) => data.seek(pos),
+            Self::Live(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// A song or a stream published as an HLS playlist.
+pub struct Hls<'name, 'agent> {
+    /// The URL of the playlist.
+    url: &'name Url,
+    /// The [`Agent`] that will be used to fetch the playlist and the segments.
+    agent: &'agent Agent,
+    /// The timeouts and the retries of the requests.
+    network: NetworkOptions,
+    /// The URL of the media playlist and the playlist, once they are loaded.
+    playlist: Option<(Url, MediaPlaylist)>,
+}
+
+impl<'name, 'agent> Hls<'name, 'agent> {
+    /// Creates a new [`Hls`] song.
+    #[must_use]
+    pub const fn new(url: &'name Url, agent: &'agent Agent) -> Self {
+        Self {
+            url,
+            agent,
+            network: NetworkOptions::DEFAULT,
+            playlist: None,
+        }
+    }
+
+    /// Sets the [`NetworkOptions`] of the requests.
+    #[must_use]
+    pub const fn with_network(mut self, network: NetworkOptions) -> Self {
+        self.network = network;
+        self
+    }
+}
+
+impl<'name> Song<'name> for Hls<'name, '_> {
+    fn get_data(&mut self) -> Result<impl Read + Seek + Send + Sync + 'static, EBox> {
+        self.preload()?;
+        match &self.playlist {
+            Some((_, playlist)) if playlist.ended => Ok(HlsData::Vod(Box::new(VodReader::new(
+                self.agent.clone(),
+                self.network,
+                playlist.segments.clone(),
+            )))),
+            Some((url, playlist)) => Ok(HlsData::Live(Prefetch::new(
+                LiveReader::new(self.agent.clone(), url.clone(), self.network, playlist),
+                LIVE_BUFFER_SIZE,
+            ))),
+            None => Err("The playlist isn't loaded".into()),
+        }
+    }
+    fn get_source(&mut self) -> Result<Option<AudioSource>, EBox> {
+        self.preload()?;
+        match &self.playlist {
+            Some((_, playlist)) if playlist.ended => Ok(Some(Box::new(VodSource::new(
+                self.agent.clone(),
+                self.network,
+                playlist.segments.clone(),
+            )?))),
+            // The live playlists are decoded from their data
+            _ => Ok(None),
+        }
+    }
+    fn preload(&mut self) -> Result<(), EBox> {
+        // A live playlist is reloaded each time it's played
+        if self
+            .playlist
+            .as_ref()
+            .is_none_or(|(_, playlist)| !playlist.ended)
+        {
+            self.playlist = Some(load_playlist(self.agent, self.url, &self.network)?);
+        }
+        Ok(())
+    }
+    fn get_path(&self) -> &'name str {
+        self.url.as_str()
+    }
+    fn is_live(&self) -> bool {
+        self.playlist
+            .as_ref()
+            .is_some_and(|(_, playlist)| !playlist.ended)
+    }
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        Ok(None)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        // The segments have no tags
+        Ok(Tags::default())
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        fmt::Write,
+        fs,
+        io::{Read, Seek, SeekFrom},
+        net::TcpListener,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use rodio::Source;
+    use tiny_http::{Response, Server};
+    use url::Url;
+
+    use super::{
+        demux_ts, load_playlist, parse_playlist, segment_audio, Hls, Playlist, Segment, VodSource,
+        VodState,
+    };
+    use crate::{download::NetworkOptions, song::Song};
+
+    /// Returns the content of a fixture.
+    fn fixture(name: &str) -> Vec<u8> {
+        fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures/hls")
+                .join(name),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn playlists() {
+        let url = Url::parse("https://example.com/radio/master.m3u8").unwrap();
+        let master = String::from_utf8(fixture("master.m3u8")).unwrap();
+        assert_eq!(
+            parse_playlist(&url, &master).unwrap(),
+            Playlist::Master(url.join("vod.m3u8").unwrap())
+        );
+        let video_only = master.replace("CODECS=\"mp4a.40.2\"", "CODECS=\"avc1,mp4a.40.2\"");
+        assert_eq!(
+            parse_playlist(&url, &video_only).unwrap(),
+            Playlist::Master(url.join("audio/low.m3u8").unwrap())
+        );
+        let renditions = format!(
+            "{master}#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",NAME=\"English, stereo\",DEFAULT=YES,URI=\"audio/en.m3u8\"\n"
+        );
+        assert_eq!(
+            parse_playlist(&url, &renditions).unwrap(),
+            Playlist::Master(url.join("audio/en.m3u8").unwrap())
+        );
+
+        let Playlist::Media(playlist) =
+            parse_playlist(&url, &String::from_utf8(fixture("vod.m3u8")).unwrap()).unwrap()
+        else {
+            panic!("The playlist should be a media playlist");
+        };
+        assert_eq!(playlist.target_duration, Duration::from_secs(10));
+        assert!(playlist.ended);
+        assert_eq!(
+            playlist.segments,
+            [
+                Segment {
+                    url: url.join("segment0.aac").unwrap(),
+                    duration: Duration::from_millis(9500),
+                    sequence: 0,
+                    range: None
+                },
+                Segment {
+                    url: url.join("segment1.ts").unwrap(),
+                    duration: Duration::from_secs(10),
+                    sequence: 1,
+                    range: None
+                }
+            ]
+        );
+
+        // The byte ranges without an offset follow the previous ones
+        let ranges = "#EXTM3U\n#EXTINF:1,\n#EXT-X-BYTERANGE:100@20\na.aac\n\
+            #EXTINF:1,\n#EXT-X-BYTERANGE:50\na.aac\n#EXTINF:1,\nb.aac\n";
+        let Playlist::Media(playlist) = parse_playlist(&url, ranges).unwrap() else {
+            panic!("The playlist should be a media playlist");
+        };
+        assert_eq!(
+            playlist
+                .segments
+                .iter()
+                .map(|segment| segment.range.clone())
+                .collect::<Vec<_>>(),
+            [Some(20..120), Some(120..170), None]
+        );
+        assert!(parse_playlist(&url, "#EXTM3U\n#EXT-X-BYTERANGE:100\na.aac\n").is_err());
+
+        assert!(parse_playlist(&url, "<html></html>").is_err());
+        assert!(parse_playlist(
+            &url,
+            "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\"\n#EXTINF:10,\na.ts\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn segments() {
+        assert_eq!(
+            demux_ts(&fixture("segment1.ts")).unwrap(),
+            b"segment 1 audio ".repeat(20)
+        );
+        assert_eq!(
+            segment_audio(fixture("segment0.aac")).unwrap(),
+            b"segment 0 audio ".repeat(10)
+        );
+        assert!(demux_ts(&fixture("segment0.aac")).is_err());
+    }
+
+    #[test]
+    fn vod_and_live() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let server_reloads = Arc::clone(&reloads);
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let server_downloads = Arc::clone(&downloads);
+        spawn(move || {
+            for request in server.incoming_requests() {
+                if request.url() == "/segment0.aac" {
+                    server_downloads.fetch_add(1, Ordering::Relaxed);
+                }
+                let response = match request.url() {
+                    // The live playlist gets a new segment and ends at the second reload
+                    "/live.m3u8" => {
+                        let reload = server_reloads.fetch_add(1, Ordering::Relaxed);
+                        let mut playlist = format!(
+                            "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:{reload}\n"
+                        );
+                        for sequence in reload..reload + 4 {
+                            write!(playlist, "#EXTINF:1,\nlive{sequence}.aac\n").unwrap();
+                        }
+                        if reload == 1 {
+                            playlist += "#EXT-X-ENDLIST\n";
+                        }
+                        Response::from_data(playlist)
+                    }
+                    path if path.starts_with("/live") => {
+                        Response::from_data(path.trim_start_matches('/').as_bytes())
+                    }
+                    path => match fs::read(
+                        Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("fixtures/hls{path}")),
+                    ) {
+                        Ok(data) => Response::from_data(data),
+                        Err(_) => Response::from_data("").with_status_code(404),
+                    },
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        let agent = ureq::agent();
+        let network = NetworkOptions {
+            retries: 0,
+            ..NetworkOptions::default()
+        };
+
+        let url = Url::parse(&format!("http://{address}/master.m3u8")).unwrap();
+        let mut song = Hls::new(&url, &agent).with_network(network);
+        let mut data = song.get_data().unwrap();
+        assert!(!song.is_live());
+        let mut audio = vec![];
+        data.read_to_end(&mut audio).unwrap();
+        let mut expected = b"segment 0 audio ".repeat(10);
+        expected.extend(b"segment 1 audio ".repeat(20));
+        assert_eq!(audio, expected);
+
+        // The segments are placed one after the other
+        data.seek(SeekFrom::Start(170)).unwrap();
+        let mut part = [0; 16];
+        data.read_exact(&mut part).unwrap();
+        assert_eq!(part, expected[170..186]);
+        data.seek(SeekFrom::Start(0)).unwrap();
+        data.read_exact(&mut part).unwrap();
+        assert_eq!(part, expected[..16]);
+        // The loaded segments are not downloaded again
+        assert_eq!(downloads.load(Ordering::Relaxed), 1);
+        assert_eq!(data.seek(SeekFrom::End(-16)).unwrap(), 464);
+        data.read_exact(&mut part).unwrap();
+        assert_eq!(part, expected[464..]);
+        assert_eq!(data.read(&mut part).unwrap(), 0);
+        assert!(data.seek(SeekFrom::Current(-500)).is_err());
+
+        // Each reader downloads its own segments
+        let mut data = song.get_data().unwrap();
+        data.seek(SeekFrom::Start(10)).unwrap();
+        data.read_exact(&mut part).unwrap();
+        assert_eq!(part, expected[10..26]);
+        assert_eq!(downloads.load(Ordering::Relaxed), 2);
+
+        let url = Url::parse(&format!("http://{address}/live.m3u8")).unwrap();
+        let mut song = Hls::new(&url, &agent).with_network(network);
+        let mut data = song.get_data().unwrap();
+        assert!(song.is_live());
+        let mut audio = String::new();
+        data.read_to_string(&mut audio).unwrap();
+        // The stream starts 3 segments from the end
+        assert_eq!(audio, "live1.aaclive2.aaclive3.aaclive4.aac");
+        assert_eq!(reloads.load(Ordering::Relaxed), 2);
+        assert!(data.seek(SeekFrom::Start(0)).is_err());
+    }
+
+    #[test]
+    fn vod_seek() {
+        // A silent MP3 frame (MPEG-1 layer III, 128 kbps, 48 kHz, mono): 1152 samples in 24 ms
+        let mut frame = vec![0; 384];
+        frame[..4].copy_from_slice(&[0xff, 0xfb, 0x94, 0xc0]);
+        // 6 segments of 10 frames in the same file
+        let audio = frame.repeat(60);
+        let mut playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:1\n".to_owned();
+        for index in 0..6 {
+            let offset = if index == 0 { "@0" } else { "" };
+            write!(
+                playlist,
+                "#EXTINF:0.24,\n#EXT-X-BYTERANGE:3840{offset}\naudio.mp3\n"
+            )
+            .unwrap();
+        }
+        playlist += "#EXT-X-ENDLIST\n";
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let server_ranges = Arc::clone(&ranges);
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Range"))
+                    .map(|header| header.value.to_string());
+                let response = match (request.url(), range) {
+                    ("/vod.m3u8", _) => Response::from_data(playlist.as_bytes()),
+                    ("/audio.mp3", Some(range)) => {
+                        let (start, end) =
+                            range.trim_start_matches("bytes=").split_once('-').unwrap();
+                        let (start, end): (usize, usize) =
+                            (start.parse().unwrap(), end.parse().unwrap());
+                        server_ranges.lock().unwrap().push(start / 3840);
+                        Response::from_data(&audio[start..=end]).with_status_code(206)
+                    }
+                    _ => Response::from_data("").with_status_code(404),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        let agent = ureq::agent();
+        let url = Url::parse(&format!("http://{address}/vod.m3u8")).unwrap();
+        let (_, playlist) = load_playlist(&agent, &url, &NetworkOptions::DEFAULT).unwrap();
+        let mut source = VodSource::new(agent, NetworkOptions::DEFAULT, playlist.segments).unwrap();
+        // The duration is the sum of the durations of the segments
+        assert_eq!(source.total_duration(), Some(Duration::from_millis(1440)));
+        // The seeks end on a background thread, so no silence is played in the counts
+        let wait_for_seek = |source: &VodSource| {
+            while matches!(&source.state, VodState::Seeking(seek) if !seek.is_finished()) {
+                sleep(Duration::from_millis(1));
+            }
+        };
+
+        // The segments before the position aren't downloaded
+        source.try_seek(Duration::from_secs(1)).unwrap();
+        assert!(matches!(source.state, VodState::Seeking(_)));
+        wait_for_seek(&source);
+        assert_eq!(source.by_ref().count(), 21_120);
+        let mut ranges = ranges.lock().unwrap().clone();
+        ranges.sort_unstable();
+        ranges.dedup();
+        assert_eq!(ranges, [0, 1, 4, 5]);
+
+        // The playlist can be seeked backwards
+        source.try_seek(Duration::from_millis(240)).unwrap();
+        wait_for_seek(&source);
+        assert_eq!(source.count(), 57_600);
+    }
+}
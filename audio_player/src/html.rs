@@ -0,0 +1,272 @@
+//! A small HTML tokenizer, to find the tags of a webpage.
+//!
+//! It is tolerant: it never fails and it handles the comments, the `<script>` and `<style>` contents,
+//! the uppercase names, the unquoted attributes and the character references in the attribute values.
+
+/// The elements whose content is not HTML.
+const RAW_TEXT_ELEMENTS: [&str; 2] = ["script", "style"];
+
+/// An opening tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    /// The name of the tag, in lowercase.
+    pub name: String,
+    /// The attributes of the tag: names in lowercase and decoded values.
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Tag {
+    /// Returns the value of the attribute with the given (lowercase) name.
+    #[must_use]
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An iterator over the opening [`Tag`]s of an HTML document.
+pub struct Tags<'content> {
+    /// The content that hasn't been parsed yet.
+    content: &'content str,
+}
+
+impl<'content> Tags<'content> {
+    /// Creates a new [`Tags`] iterator on the given HTML `content`.
+    #[must_use]
+    pub fn new(content: &'content str) -> Self {
+        Self { content }
+    }
+
+    /// Removes the content until the end of `pattern` (or all the content if it's not found).
+    fn skip_past(&mut self, pattern: &str) {
+        self.content = self
+            .content
+            .find(pattern)
+            .map_or("", |index| &self.content[index + pattern.len()..]);
+    }
+
+    /// Removes the characters that match `predicate` at the start of the content and returns them.
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'content str {
+        let end = self
+            .content
+            .find(|char: char| !predicate(char))
+            .unwrap_or(self.content.len());
+        let (taken, rest) = self.content.split_at(end);
+        self.content = rest;
+        taken
+    }
+
+    /// Parses the value of an attribute, after the `=`.
+    fn attribute_value(&mut self) -> &'content str {
+        self.take_while(char::is_whitespace);
+        match self.content.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                self.content = &self.content[1..];
+                let value = self.take_while(|char| char != quote);
+                self.content = self.content.get(1..).unwrap_or("");
+                value
+            }
+            _ => self.take_while(|char| !char.is_whitespace() && char != '>'),
+        }
+    }
+
+    /// Parses the attributes of a tag, until the end of the tag.
+    fn attributes(&mut self) -> Vec<(String, String)> {
+        let mut attributes = vec![];
+        loop {
+            self.take_while(|char| char.is_whitespace() || char == '/');
+            match self.content.chars().next() {
+                None => break,
+                Some('>') => {
+                    self.content = &self.content[1..];
+                    break;
+                }
+                Some(_) => {}
+            }
+
+            let name =
+                self.take_while(|char| !char.is_whitespace() && !matches!(char, '=' | '>' | '/'));
+            if name.is_empty() {
+                // Invalid character, skip it
+                let mut chars = self.content.chars();
+                chars.next();
+                self.content = chars.as_str();
+                continue;
+            }
+            self.take_while(char::is_whitespace);
+            let value = if self.content.starts_with('=') {
+                self.content = &self.content[1..];
+                decode_entities(self.attribute_value())
+            } else {
+                String::new()
+            };
+            attributes.push((name.to_ascii_lowercase(), value));
+        }
+        attributes
+    }
+}
+
+impl Iterator for Tags<'_> {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.skip_past("<");
+            if self.content.is_empty() {
+                return None;
+            }
+
+            if self.content.starts_with("!--") {
+                self.skip_past("-->");
+                continue;
+            }
+            // Closing tags, doctypes and processing instructions
+            if self.content.starts_with(['/', '!', '?']) {
+                self.skip_past(">");
+                continue;
+            }
+            if !self
+                .content
+                .starts_with(|char: char| char.is_ascii_alphabetic())
+            {
+                // A lone `<`
+                continue;
+            }
+
+            let name = self
+                .take_while(|char| !char.is_whitespace() && !matches!(char, '/' | '>'))
+                .to_ascii_lowercase();
+            let attributes = self.attributes();
+
+            if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+                // The tag names are ASCII, so the indexes are the same in lowercase
+                let end = self
+                    .content
+                    .to_ascii_lowercase()
+                    .find(&format!("</{name}"))
+                    .unwrap_or(self.content.len());
+                self.content = &self.content[end..];
+            }
+
+            return Some(Tag { name, attributes });
+        }
+    }
+}
+
+/// Returns the character of a named character reference (only the most common ones).
+fn named_entity(name: &str) -> Option<char> {
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        _ => return None,
+    })
+}
+
+/// Decodes the character references (`&amp;`, `&#38;`, `&#x26;`...) of some text.
+///
+/// The unknown or invalid references are kept as is.
+///
+/// # Examples
+/// ```
+/// # use audio_player::html::decode_entities;
+/// assert_eq!(decode_entities("a.mp3?x=1&amp;y=2"), "a.mp3?x=1&y=2");
+/// assert_eq!(decode_entities("&#65;&#x42;&unknown;"), "AB&unknown;");
+/// ```
+#[must_use]
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let character = rest.find(';').and_then(|end| {
+            let name = &rest[1..end];
+            let character = match name.strip_prefix('#') {
+                Some(number) => match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => number.parse().ok(),
+                }
+                .and_then(char::from_u32),
+                None => named_entity(name),
+            };
+            character.map(|character| (character, end))
+        });
+        if let Some((character, end)) = character {
+            decoded.push(character);
+            rest = &rest[end + 1..];
+        } else {
+            decoded.push('&');
+            rest = &rest[1..];
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use super::{Tag, Tags};
+
+    /// Returns the names of the tags and their `href` attributes.
+    fn hrefs(html: &str) -> Vec<(String, Option<String>)> {
+        Tags::new(html)
+            .map(|tag| {
+                let href = tag.attribute("href").map(str::to_owned);
+                (tag.name, href)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(
+            Tags::new(r#"<A CLASS="x" data-empty HREF = 'a b.mp3' title=x&amp;y>"#).next(),
+            Some(Tag {
+                name: "a".to_owned(),
+                attributes: vec![
+                    ("class".to_owned(), "x".to_owned()),
+                    ("data-empty".to_owned(), String::new()),
+                    ("href".to_owned(), "a b.mp3".to_owned()),
+                    ("title".to_owned(), "x&y".to_owned()),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn skipped_content() {
+        let html = r#"<!DOCTYPE html>
+            <!-- <a href="comment"> -->
+            <script>document.write('<a href="script">');</SCRIPT>
+            <p>1 < 2</p>
+            <a href=/a/b/c>c</a>
+            <br/><?xml version="1.0"?>
+            <a href="unterminated"#;
+        assert_eq!(
+            hrefs(html),
+            [
+                ("script".to_owned(), None),
+                ("p".to_owned(), None),
+                ("a".to_owned(), Some("/a/b/c".to_owned())),
+                ("br".to_owned(), None),
+                ("a".to_owned(), Some("unterminated".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        assert_eq!(
+            hrefs("<a href=a><!-- <a href=b>"),
+            [("a".to_owned(), Some("a".to_owned()))]
+        );
+    }
+}
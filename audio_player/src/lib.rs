@@ -1,9 +1,26 @@
 //! Automatic and random audio player.
 
+pub mod agent;
+pub mod cache;
+pub mod download;
 pub mod entrypoints;
+pub mod feed;
 pub mod generic_error;
+pub mod hls;
+pub mod html;
+pub mod lyrics;
+pub mod manifest;
+pub mod options;
 pub mod player;
+pub mod podcast;
+pub mod prefetch;
+pub mod radio;
+pub mod s3;
 pub mod scroll_position;
 pub mod secrets;
+pub mod sftp;
 pub mod song;
+pub mod subsonic;
+pub mod tags;
 pub mod web_utils;
+pub mod webdav;
@@ -0,0 +1,292 @@
+//! Synchronized lyrics, read from `.lrc` files or from the tags embedded in the songs.
+use std::{
+    io::{Read, Seek},
+    time::Duration,
+};
+
+use lofty::{
+    config::ParseOptions,
+    file::{AudioFile, FileType, TaggedFileExt},
+    id3::v2::{Frame, SynchronizedTextFrame, TimestampFormat},
+    mpeg::MpegFile,
+    probe::Probe,
+    tag::ItemKey,
+};
+
+use crate::song::EBox;
+
+/// A line of lyrics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LyricsLine {
+    /// The time when the line starts (or [`None`] if the lyrics are not synchronized).
+    pub time: Option<Duration>,
+    /// The text of the line.
+    pub text: String,
+}
+
+/// The lyrics of a song.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Lyrics {
+    /// The lines of the lyrics, sorted by time if they are synchronized.
+    lines: Vec<LyricsLine>,
+}
+
+/// Parses an LRC timestamp (`mm:ss`, `mm:ss.xx` or `mm:ss.xxx`).
+///
+/// # Examples
+/// ```
+/// # use std::time::Duration;
+/// # use audio_player::lyrics::parse_timestamp;
+/// assert_eq!(parse_timestamp("01:02.50"), Some(Duration::from_millis(62_500)));
+/// assert_eq!(parse_timestamp("00:07"), Some(Duration::from_secs(7)));
+/// assert_eq!(parse_timestamp("ar:Someone"), None);
+/// ```
+#[must_use]
+pub fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let (minutes, rest) = timestamp.trim().split_once(':')?;
+    let (seconds, fraction) = rest.split_once(['.', ':']).unwrap_or((rest, ""));
+
+    let minutes: u64 = minutes.parse().ok()?;
+    let seconds: u64 = seconds.parse().ok()?;
+    if !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // Keep only the milliseconds ("5" -> 500, "05" -> 50, "0512" -> 51)
+    let millis = format!("{fraction:0<3}")[..3].parse::<u64>().ok()?;
+
+    Some(Duration::from_secs(minutes * 60 + seconds) + Duration::from_millis(millis))
+}
+
+impl Lyrics {
+    /// Parses lyrics in the LRC format.
+    ///
+    /// If the text doesn't contain any timestamp, the lyrics are considered as not synchronized
+    /// and every line is kept as is.
+    #[must_use]
+    pub fn parse(content: &str) -> Self {
+        let mut lines = vec![];
+        let mut plain_lines = vec![];
+        // The offset in milliseconds (a positive offset shows the lines sooner)
+        let mut offset: i64 = 0;
+
+        for line in content.lines() {
+            let mut rest = line.trim();
+            let mut times = vec![];
+
+            // Read all the tags at the start of the line
+            while let Some((tag, after)) =
+                rest.strip_prefix('[').and_then(|tag| tag.split_once(']'))
+            {
+                if let Some(time) = parse_timestamp(tag) {
+                    times.push(time);
+                } else if let Some(value) = tag.strip_prefix("offset:") {
+                    offset = value.trim().parse().unwrap_or_default();
+                }
+                rest = after;
+            }
+
+            let text = strip_word_timestamps(rest).trim().to_owned();
+            if times.is_empty() {
+                if !line.trim_start().starts_with('[') {
+                    plain_lines.push(text);
+                }
+                continue;
+            }
+            for time in times {
+                let time = if offset >= 0 {
+                    time.saturating_sub(Duration::from_millis(offset.unsigned_abs()))
+                } else {
+                    time.saturating_add(Duration::from_millis(offset.unsigned_abs()))
+                };
+                lines.push(LyricsLine {
+                    time: Some(time),
+                    text: text.clone(),
+                });
+            }
+        }
+
+        if lines.is_empty() {
+            return Self::plain(&plain_lines.join("\n"));
+        }
+
+        lines.sort_by_key(|line| line.time);
+        Self { lines }
+    }
+
+    /// Creates not synchronized lyrics from some text.
+    #[must_use]
+    pub fn plain(text: &str) -> Self {
+        let lines: Vec<_> = text
+            .trim()
+            .lines()
+            .map(|line| LyricsLine {
+                time: None,
+                text: line.trim().to_owned(),
+            })
+            .collect();
+        Self { lines }
+    }
+
+    /// Reads the lyrics embedded in the tags of a song
+    /// (`ID3v2` `SYLT` and `USLT` frames, Vorbis `LYRICS` comments, ...).
+    ///
+    /// # Errors
+    /// Fails if the song data cannot be read or if the tags are malformed.
+    pub fn from_tags(mut data: impl Read + Seek) -> Result<Option<Self>, EBox> {
+        let tagged_file = Probe::new(&mut data).guess_file_type()?.read()?;
+
+        // Synchronized lyrics are only available with the `ID3v2` specific API
+        if tagged_file.file_type() == FileType::Mpeg {
+            data.rewind()?;
+            let file = MpegFile::read_from(&mut data, ParseOptions::new())?;
+            if let Some(lyrics) = file.id3v2().and_then(|tag| {
+                tag.into_iter().find_map(|frame| match frame {
+                    Frame::Binary(frame) if frame.id().as_str() == "SYLT" => {
+                        SynchronizedTextFrame::parse(&frame.data, frame.flags()).ok()
+                    }
+                    _ => None,
+                })
+            }) {
+                return Ok(Some(Self::from_synchronized_text(&lyrics)));
+            }
+        }
+
+        Ok(tagged_file
+            .tags()
+            .iter()
+            .find_map(|tag| tag.get_string(ItemKey::Lyrics))
+            .map(Self::parse)
+            .filter(|lyrics| !lyrics.is_empty()))
+    }
+
+    /// Converts an `ID3v2` `SYLT` frame to [`Lyrics`].
+    fn from_synchronized_text(frame: &SynchronizedTextFrame) -> Self {
+        let mut lines: Vec<_> = frame
+            .content
+            .iter()
+            .map(|(time, text)| LyricsLine {
+                time: match frame.timestamp_format {
+                    TimestampFormat::MS => Some(Duration::from_millis((*time).into())),
+                    // We can't convert MPEG frames to a time without decoding the song
+                    TimestampFormat::MPEG => None,
+                },
+                text: text.trim().to_owned(),
+            })
+            .collect();
+        lines.sort_by_key(|line| line.time);
+        Self { lines }
+    }
+
+    /// Returns the lines of the lyrics.
+    #[must_use]
+    pub fn lines(&self) -> &[LyricsLine] {
+        &self.lines
+    }
+
+    /// Returns `true` if there are no lines in the lyrics.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Returns `true` if the lyrics have timestamps.
+    #[must_use]
+    pub fn is_synchronized(&self) -> bool {
+        self.lines.iter().any(|line| line.time.is_some())
+    }
+
+    /// Returns the index of the line that is sung at the given `time`.
+    #[must_use]
+    pub fn current_line(&self, time: Duration) -> Option<usize> {
+        if !self.is_synchronized() {
+            return None;
+        }
+        self.lines
+            .iter()
+            .rposition(|line| line.time.is_some_and(|line_time| line_time <= time))
+    }
+}
+
+/// Removes the word timestamps of the enhanced LRC format (`<mm:ss.xx>`) from a line.
+fn strip_word_timestamps(line: &str) -> String {
+    let mut ret = String::with_capacity(line.len());
+    let mut remaining = line;
+    while let Some(start) = remaining.find('<') {
+        ret.push_str(&remaining[..start]);
+        match remaining[start..].split_once('>') {
+            Some((tag, after)) if parse_timestamp(&tag[1..]).is_some() => remaining = after,
+            _ => {
+                ret.push('<');
+                remaining = &remaining[start + 1..];
+            }
+        }
+    }
+    ret.push_str(remaining);
+    ret
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Lyrics, LyricsLine};
+
+    /// Returns a synchronized [`LyricsLine`].
+    fn line(millis: u64, text: &str) -> LyricsLine {
+        LyricsLine {
+            time: Some(Duration::from_millis(millis)),
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn lrc() {
+        let lyrics = Lyrics::parse(
+            "[ar:Someone]
+            [ti:Something]
+            [00:01.00]Jingle bells
+            [00:03.50]Jingle all the way",
+        );
+        assert_eq!(
+            lyrics.lines(),
+            &[line(1000, "Jingle bells"), line(3500, "Jingle all the way")]
+        );
+    }
+
+    #[test]
+    fn repeated_lines() {
+        let lyrics = Lyrics::parse("[00:10.00][00:30.00]Chorus\n[00:20.00]Verse");
+        assert_eq!(
+            lyrics.lines(),
+            &[
+                line(10_000, "Chorus"),
+                line(20_000, "Verse"),
+                line(30_000, "Chorus")
+            ]
+        );
+    }
+
+    #[test]
+    fn offset_and_word_timestamps() {
+        let lyrics = Lyrics::parse("[offset:500]\n[00:02.00]<00:02.00>Silent <00:02.80>night");
+        assert_eq!(lyrics.lines(), &[line(1500, "Silent night")]);
+    }
+
+    #[test]
+    fn plain_lyrics() {
+        let lyrics = Lyrics::parse("Silent night\nHoly night");
+        assert!(!lyrics.is_synchronized());
+        assert_eq!(lyrics.lines().len(), 2);
+        assert_eq!(lyrics.current_line(Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn current_line() {
+        let lyrics = Lyrics::parse("[00:01.00]a\n[00:02.00]b\n[00:03.00]c");
+        assert_eq!(lyrics.current_line(Duration::from_millis(500)), None);
+        assert_eq!(lyrics.current_line(Duration::from_secs(1)), Some(0));
+        assert_eq!(lyrics.current_line(Duration::from_millis(2500)), Some(1));
+        assert_eq!(lyrics.current_line(Duration::from_secs(59)), Some(2));
+    }
+}
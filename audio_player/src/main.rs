@@ -0,0 +1,56 @@
+//! Control a running player and prepare the songs from the command line.
+use std::{env, process::exit};
+
+#[cfg(unix)]
+use audio_player::player::control::ctl;
+#[cfg(not(unix))]
+use audio_player::song::EBox;
+use audio_player::{
+    manifest::manifest, podcast::podcast, radio::radio, s3::s3, sftp::sftp, subsonic::subsonic,
+    webdav::webdav,
+};
+
+/// The usage of the program.
+const USAGE: &str = "Usage: audio-player <COMMAND>
+
+Commands:
+  ctl       Control a running player (see audio-player ctl --help)
+  manifest  Write the manifest of a folder of songs (see audio-player manifest --help)
+  podcast   Play the episodes of podcasts (see audio-player podcast --help)
+  radio     Play internet radios (see audio-player radio --help)
+  s3        Play the songs of an S3 bucket (see audio-player s3 --help)
+  sftp      Play the songs of a remote machine over SFTP (see audio-player sftp --help)
+  subsonic  Play the library of a Subsonic server (see audio-player subsonic --help)
+  webdav    Play the songs of a WebDAV server (see audio-player webdav --help)";
+
+/// Runs the `ctl` command.
+///
+/// # Errors
+/// Always fails: the control socket is only available on Unix.
+#[cfg(not(unix))]
+fn ctl(_args: impl Iterator<Item = String>) -> Result<(), EBox> {
+    Err("The control socket is only available on Unix".into())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("ctl") => ctl(args),
+        Some("manifest") => manifest(args),
+        Some("podcast") => podcast(args),
+        Some("radio") => radio(args),
+        Some("s3") => s3(args),
+        Some("sftp") => sftp(args),
+        Some("subsonic") => subsonic(args),
+        Some("webdav") => webdav(args),
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(USAGE.into()),
+    };
+    if let Err(err) = result {
+        eprintln!("{err}");
+        exit(1);
+    }
+}
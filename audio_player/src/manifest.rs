@@ -0,0 +1,269 @@
+//! The manifest of a web folder, which lists its songs so they don't need to be crawled.
+//!
+//! The manifest is a `manifest.json` file at the root of the folder, containing a JSON list:
+//! ```json
+//! [
+//!     {"path": "Rock & Roll/song.mp3", "size": 4718592, "sha256": "9f86d08...", "title": "Song"},
+//!     {"path": "jingle.mp3", "size": 1258291, "sha256": "60303ae..."}
+//! ]
+//! ```
+//! The paths are relative to the folder and separated by `/`. The tags (`title`, `artist` and `album`) are optional.
+//!
+//! The downloaded songs are checked against the size and the hash of their entry,
+//! and the tags of the entry are used when the song has none.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Component, Path},
+    sync::{Mutex, PoisonError},
+};
+
+use files::RecurseFilesIterator;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{options::args_or_help, song::EBox, tags::Tags};
+
+/// The name of the manifest file.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// The usage of the `manifest` command.
+pub const USAGE: &str = "Usage: audio-player manifest <FOLDER> [--output <PATH>]
+
+Writes the manifest of the songs of a folder (to <FOLDER>/manifest.json by default, or - for the standard output)";
+
+/// A song of a manifest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The path of the song, relative to the folder of the manifest and separated by `/`.
+    pub path: String,
+    /// The size of the song in bytes.
+    pub size: u64,
+    /// The SHA-256 hash of the song, in hexadecimal.
+    pub sha256: String,
+    /// The title of the song.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// The artist of the song.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    /// The album of the song.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+}
+
+impl ManifestEntry {
+    /// Checks that `data` is the song of this entry (same size and same hash).
+    ///
+    /// # Errors
+    /// Fails if the size or the hash is different.
+    pub fn check(&self, data: &[u8]) -> Result<(), EBox> {
+        if data.len() as u64 != self.size {
+            return Err(format!(
+                "The song {} has {} bytes instead of {} (manifest)",
+                self.path,
+                data.len(),
+                self.size
+            )
+            .into());
+        }
+        if !format!("{:x}", Sha256::digest(data)).eq_ignore_ascii_case(&self.sha256) {
+            return Err(format!(
+                "The hash of the song {} doesn't match the manifest",
+                self.path
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fills the missing tags of `tags` with the tags of this entry.
+    #[must_use]
+    pub fn fill_tags(&self, tags: Tags) -> Tags {
+        Tags {
+            title: tags.title.or_else(|| self.title.clone()),
+            artist: tags.artist.or_else(|| self.artist.clone()),
+            album: tags.album.or_else(|| self.album.clone()),
+            ..tags
+        }
+    }
+}
+
+/// The entries of the fetched manifests, by song URL.
+static FETCHED_ENTRIES: Mutex<BTreeMap<String, ManifestEntry>> = Mutex::new(BTreeMap::new());
+
+/// Remembers the entry of the song at `url`, to check the song when it's downloaded.
+pub fn remember_entry(url: &Url, entry: &ManifestEntry) {
+    FETCHED_ENTRIES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(url.to_string(), entry.clone());
+}
+
+/// Returns the entry of the song at `url`, if it's in a fetched manifest.
+#[must_use]
+pub fn fetched_entry(url: &Url) -> Option<ManifestEntry> {
+    FETCHED_ENTRIES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(url.as_str())
+        .cloned()
+}
+
+/// Returns the manifest entry of a song.
+///
+/// The tags are omitted if they can't be read.
+///
+/// # Errors
+/// Fails if the song can't be read or if its path is not valid UTF-8.
+fn entry(folder: &Path, path: &Path) -> Result<ManifestEntry, EBox> {
+    let relative_path = path
+        .strip_prefix(folder)?
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name
+                .to_str()
+                .ok_or_else(|| format!("Invalid file name: {}", path.display())),
+            _ => Err(format!("Invalid path: {}", path.display())),
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .join("/");
+
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    let tags = Tags::from_data(File::open(path)?).unwrap_or_default();
+
+    Ok(ManifestEntry {
+        path: relative_path,
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+        title: tags.title,
+        artist: tags.artist,
+        album: tags.album,
+    })
+}
+
+/// Returns the manifest of the songs (MP3 files) in a folder and its subfolders, sorted by path.
+///
+/// # Errors
+/// Fails if the folder or a song can't be read.
+pub fn generate(folder: &Path) -> Result<Vec<ManifestEntry>, EBox> {
+    let mut manifest = RecurseFilesIterator::new(folder)?
+        .filter(|file| {
+            file.as_ref().map_or(true, |file| {
+                file.extension().is_some_and(|ext| ext == "mp3")
+            })
+        })
+        .map(|file| entry(folder, &file?))
+        .collect::<Result<Vec<_>, _>>()?;
+    manifest.sort_by(|entry1, entry2| entry1.path.cmp(&entry2.path));
+    Ok(manifest)
+}
+
+/// Runs the `manifest` command with the given arguments.
+///
+/// # Errors
+/// Fails if the arguments are invalid, if the manifest can't be generated or if it can't be written.
+pub fn manifest(args: impl IntoIterator<Item = String>) -> Result<(), EBox> {
+    let Some(args) = args_or_help(args, USAGE) else {
+        return Ok(());
+    };
+    let mut folder = None;
+    let mut output = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => output = Some(args.next().ok_or(USAGE)?),
+            _ if arg.starts_with("--output=") => output = Some(arg["--output=".len()..].to_owned()),
+            _ if folder.is_none() && !arg.starts_with('-') => folder = Some(arg),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let folder = folder.ok_or(USAGE)?;
+
+    let manifest = serde_json::to_string_pretty(&generate(Path::new(&folder))?)?;
+    match output.as_deref() {
+        Some("-") => writeln!(io::stdout(), "{manifest}")?,
+        Some(path) => fs::write(path, manifest + "\n")?,
+        None => fs::write(Path::new(&folder).join(MANIFEST_FILE_NAME), manifest + "\n")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{env::temp_dir, fs, process};
+
+    use super::{generate, manifest, ManifestEntry, MANIFEST_FILE_NAME};
+    use crate::tags::Tags;
+
+    #[test]
+    fn generation() {
+        let folder = temp_dir().join(format!("audio-player-test-manifest-{}", process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(folder.join("Rock & Roll")).unwrap();
+        fs::write(folder.join("Rock & Roll").join("b.mp3"), "abc").unwrap();
+        fs::write(folder.join("a.mp3"), "").unwrap();
+        fs::write(folder.join("cover.jpg"), "").unwrap();
+
+        let expected = [
+            ManifestEntry {
+                path: "Rock & Roll/b.mp3".to_owned(),
+                size: 3,
+                sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_owned(),
+                title: None,
+                artist: None,
+                album: None,
+            },
+            ManifestEntry {
+                path: "a.mp3".to_owned(),
+                size: 0,
+                sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                    .to_owned(),
+                title: None,
+                artist: None,
+                album: None,
+            },
+        ];
+        assert_eq!(generate(&folder).unwrap(), expected);
+
+        manifest([folder.to_str().unwrap().to_owned()]).unwrap();
+        let written = fs::read_to_string(folder.join(MANIFEST_FILE_NAME)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<ManifestEntry>>(&written).unwrap(),
+            expected
+        );
+        assert!(!written.contains("title"));
+
+        assert!(manifest(["--output".to_owned()]).is_err());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn check() {
+        let entry = ManifestEntry {
+            path: "song.mp3".to_owned(),
+            size: 3,
+            sha256: "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_owned(),
+            title: Some("Song".to_owned()),
+            artist: None,
+            album: None,
+        };
+        entry.check(b"abc").unwrap();
+        assert!(entry.check(b"ab").is_err());
+        assert!(entry.check(b"abd").is_err());
+
+        let tags = Tags {
+            artist: Some("Artist".to_owned()),
+            ..Tags::default()
+        };
+        let tags = entry.fill_tags(tags);
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.album, None);
+    }
+}
@@ -0,0 +1,595 @@
+//! Command-line options of the player.
+use std::{
+    env::{self, temp_dir},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use url::Url;
+
+use crate::{
+    agent::TlsConfig, download::NetworkOptions, player::hooks::Hook, song::EBox,
+    web_utils::CrawlOptions,
+};
+
+/// The usage of the command-line options.
+pub const USAGE: &str = "Options:
+  --headless         Don't show the terminal UI and log the messages instead
+  --log-file <PATH>  Append the log to a file instead of the standard error (headless mode only)
+  --socket <PATH>    Listen for commands on this socket
+                     (default: $XDG_RUNTIME_DIR/audio-player.sock, none without $XDG_RUNTIME_DIR)
+  --no-socket        Don't listen for commands on a socket
+  --http <ADDRESS>   Serve the remote control web page and API (for example 0.0.0.0:8080)
+  --http-token <TOKEN>
+                     Require this token in the API requests (open the page with ?token=<TOKEN>)
+  --mpd <ADDRESS>    Accept the MPD clients (for example 127.0.0.1:6600)
+  --listenbrainz <URL>
+                     Submit the listens to a ListenBrainz compatible API (for example https://api.listenbrainz.org)
+                     with the token of $LISTENBRAINZ_TOKEN
+  --hook <EVENT>=<COMMAND>
+                     Run a shell command on an event (start, track, pause, resume or quit),
+                     with the song in the $AUDIO_PLAYER_* variables (can be repeated)
+  --script <PATH>    Run a Rhai script on the events of the player (can be repeated)
+  --no-shuffle       Play the songs in order instead of shuffling them
+
+Web folders:
+  --max-depth <N>    Don't crawl deeper than N subfolders (default: 16)
+  --workers <N>      Fetch at most N folders at the same time (default: 8)
+  --include <PATTERN>
+                     Only play the files whose path matches a pattern, like \"Rock/*\" (can be repeated)
+  --exclude <PATTERN>
+                     Skip the files and folders whose path matches a pattern, like \"*/Live/*\" (can be repeated)
+  --extension <EXT>  Only play the files with an extension, like mp3 (can be repeated)
+  --web-config <PATH>
+                     Read the TLS settings and the credentials from this file
+                     (default: $XDG_CONFIG_HOME/audio-player/web.json if it exists)
+  --netrc <PATH>     Read the credentials from this netrc file (default: $NETRC or ~/.netrc if it exists)
+  --ca <PATH>        Trust the certificate authorities of a PEM file or folder (can be repeated)
+  --no-system-roots  Don't trust the certificate authorities of the system
+  --no-bundled-ca    Don't trust the certificate authority bundled with the program (if any)
+  --pin <SHA256>     Require a certificate with this fingerprint in the chain of the server (can be repeated)
+  --client-cert <PATH>
+                     Authenticate with the certificate chain of a PEM file (requires --client-key)
+  --client-key <PATH>
+                     The private key of the client certificate (PEM)
+  --connect-timeout <SECONDS>
+                     Give up a connection after this time (default: 10)
+  --read-timeout <SECONDS>
+                     Give up a request after this time without data (default: 30)
+  --retries <N>      Retry the failed requests N times, with an exponential backoff (default: 4)
+  --cache <PATH>     Keep the listing and the songs in this folder, to play them offline
+                     (default: $XDG_CACHE_HOME/audio-player or ~/.cache/audio-player, none without them)
+  --cache-size <MB>  Remove the least recently played songs when the cache is bigger (default: 256)
+  --no-cache         Don't keep the listing and the songs
+
+Podcasts:
+  --episodes <PATH>  Remember the played episodes and the positions in this file
+                     (default: $XDG_DATA_HOME/audio-player/episodes.json)
+
+SFTP:
+  --identity <PATH>  Authenticate with this private key
+                     (default: the SSH agent, then ~/.ssh/id_ed25519, ~/.ssh/id_ecdsa and ~/.ssh/id_rsa)
+  --known-hosts <PATH>
+                     Check the key of the server in this file (default: ~/.ssh/known_hosts)";
+
+/// Returns the default path of the control socket.
+///
+/// It is in `$XDG_RUNTIME_DIR` (which is only readable by the user). There is none without it:
+/// another user could take the path in a shared folder (like the temporary one).
+#[must_use]
+pub fn default_socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(|runtime| PathBuf::from(runtime).join("audio-player.sock"))
+}
+
+/// Returns the arguments of a command, or [`None`] after printing its `usage`
+/// if they ask for help (`-h` or `--help`).
+#[must_use]
+pub fn args_or_help(args: impl IntoIterator<Item = String>, usage: &str) -> Option<Vec<String>> {
+    let args: Vec<String> = args.into_iter().collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{usage}");
+        return None;
+    }
+    Some(args)
+}
+
+/// Returns the path of the journal where the listens are kept until they can be submitted.
+///
+/// It is in `$XDG_DATA_HOME`, in `~/.local/share` or in the temporary directory.
+#[must_use]
+pub fn default_journal_path() -> PathBuf {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_else(temp_dir)
+        .join("audio-player")
+        .join("listens.jsonl")
+}
+
+/// Returns the default path of the file where the states of the podcast episodes are kept.
+///
+/// It is in `$XDG_DATA_HOME`, in `~/.local/share` or in the temporary directory.
+#[must_use]
+pub fn default_episodes_path() -> PathBuf {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_else(temp_dir)
+        .join("audio-player")
+        .join("episodes.json")
+}
+
+/// The default maximum size of the songs of the cache (256 MiB).
+pub const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Returns the default folder of the cache of the web libraries.
+///
+/// It is in `$XDG_CACHE_HOME` or in `~/.cache`. There is none without them:
+/// another user could put songs in a shared folder (like the temporary one).
+#[must_use]
+pub fn default_cache_path() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|cache| cache.join("audio-player"))
+}
+
+/// The options of the player.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Should we run without the terminal UI?
+    ///
+    /// The player can then only be controlled with the media controls
+    /// and the messages are logged to [`Options::log_file`] or to the standard error.
+    pub headless: bool,
+    /// The file where the log is appended in headless mode.
+    pub log_file: Option<PathBuf>,
+    /// The path of the control socket (or [`None`] if it's disabled).
+    ///
+    /// The socket is only available on Unix.
+    pub socket: Option<PathBuf>,
+    /// The address of the HTTP remote control (or [`None`] if it's disabled).
+    ///
+    /// Without [`Options::http_token`], the API only answers the requests sent to this address
+    /// (or `localhost`) by its own web page.
+    pub http: Option<SocketAddr>,
+    /// The token that the requests to the HTTP remote control must send.
+    pub http_token: Option<String>,
+    /// The address of the MPD server (or [`None`] if it's disabled).
+    pub mpd: Option<SocketAddr>,
+    /// The base URL of the `ListenBrainz` compatible API where the listens are submitted
+    /// (or [`None`] if the scrobbling is disabled).
+    pub listenbrainz: Option<Url>,
+    /// Should the songs be shuffled? Otherwise, they are played in the given order.
+    pub shuffle: bool,
+    /// The commands that are run on the events of the player.
+    pub hooks: Vec<Hook>,
+    /// The [Rhai](https://rhai.rs/book/) scripts that are run on the events of the player.
+    pub scripts: Vec<PathBuf>,
+    /// The limits and the filters of the crawling of the web folders.
+    pub crawl: CrawlOptions,
+    /// The TLS settings of the web songs, added to the ones of the configuration file.
+    pub tls: TlsConfig,
+    /// The configuration file of the web songs (or [`None`] for the default one, if it exists).
+    pub web_config: Option<PathBuf>,
+    /// The netrc file (or [`None`] for the default one, if it exists).
+    pub netrc: Option<PathBuf>,
+    /// The timeouts and the retries of the requests to the web songs.
+    pub network: NetworkOptions,
+    /// The folder where the web libraries are cached (or [`None`] if the cache is disabled).
+    pub cache: Option<PathBuf>,
+    /// The maximum size of the songs of the cache, in bytes.
+    pub cache_size: u64,
+    /// The file where the played podcast episodes and the positions in them are kept.
+    pub episodes: PathBuf,
+    /// The private key of the SFTP user (or [`None`] for the SSH agent and the default keys).
+    pub identity: Option<PathBuf>,
+    /// The known hosts of the SFTP servers (or [`None`] for the default ones).
+    pub known_hosts: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            headless: false,
+            log_file: None,
+            socket: default_socket_path(),
+            http: None,
+            http_token: None,
+            mpd: None,
+            listenbrainz: None,
+            shuffle: true,
+            hooks: vec![],
+            scripts: vec![],
+            crawl: CrawlOptions::default(),
+            tls: TlsConfig::default(),
+            web_config: None,
+            netrc: None,
+            network: NetworkOptions::DEFAULT,
+            cache: default_cache_path(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            episodes: default_episodes_path(),
+            identity: None,
+            known_hosts: None,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the options from the command-line arguments of the program.
+    ///
+    /// # Errors
+    /// Fails if an option is unknown or if a value is missing.
+    pub fn from_env() -> Result<Self, EBox> {
+        Self::parse(env::args().skip(1))
+    }
+
+    /// Parses the options from a list of arguments (without the program name).
+    ///
+    /// # Errors
+    /// Fails if an option is unknown or if a value is missing.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, EBox> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (arg.as_str(), None),
+            };
+            match name {
+                "--headless" if value.is_none() => options.headless = true,
+                "--log-file" | "--socket" | "--http" | "--http-token" | "--mpd"
+                | "--listenbrainz" | "--hook" | "--script" | "--max-depth" | "--workers"
+                | "--include" | "--exclude" | "--extension" | "--web-config" | "--netrc"
+                | "--ca" | "--pin" | "--client-cert" | "--client-key" | "--connect-timeout"
+                | "--read-timeout" | "--retries" | "--cache" | "--cache-size" | "--episodes"
+                | "--identity" | "--known-hosts" => {
+                    let value = value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| format!("Missing value for {name}\n\n{USAGE}"))?;
+                    options.set(name, value)?;
+                }
+                "--no-socket" if value.is_none() => options.socket = None,
+                "--no-system-roots" if value.is_none() => options.tls.system_roots = false,
+                "--no-bundled-ca" if value.is_none() => options.tls.bundled_ca = false,
+                "--no-cache" if value.is_none() => options.cache = None,
+                "--no-shuffle" if value.is_none() => options.shuffle = false,
+                _ => return Err(format!("Unknown option: {arg}\n\n{USAGE}").into()),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Sets the option `name` (that takes a value) to `value`.
+    ///
+    /// # Errors
+    /// Fails if the value is invalid.
+    fn set(&mut self, name: &str, value: String) -> Result<(), EBox> {
+        match name {
+            "--log-file" => self.log_file = Some(value.into()),
+            "--socket" => self.socket = Some(value.into()),
+            "--http-token" => self.http_token = Some(value),
+            "--hook" => self.hooks.push(value.parse()?),
+            "--script" => self.scripts.push(value.into()),
+            "--web-config" => self.web_config = Some(value.into()),
+            "--netrc" => self.netrc = Some(value.into()),
+            "--cache" => self.cache = Some(value.into()),
+            "--episodes" => self.episodes = value.into(),
+            "--identity" => self.identity = Some(value.into()),
+            "--known-hosts" => self.known_hosts = Some(value.into()),
+            "--ca" => self.tls.ca.push(value.into()),
+            "--pin" => self.tls.pins.push(value),
+            "--client-cert" => self.tls.client_cert = Some(value.into()),
+            "--client-key" => self.tls.client_key = Some(value.into()),
+            "--include" => self.crawl.include.push(value),
+            "--exclude" => self.crawl.exclude.push(value),
+            "--extension" => self
+                .crawl
+                .extensions
+                .push(value.trim_start_matches('.').to_lowercase()),
+            "--connect-timeout" | "--read-timeout" => {
+                let seconds: f64 = value
+                    .parse()
+                    .ok()
+                    .filter(|&seconds: &f64| seconds > 0.0 && seconds.is_finite())
+                    .ok_or_else(|| format!("Invalid duration {value}"))?;
+                let timeout = Duration::from_secs_f64(seconds);
+                if name == "--connect-timeout" {
+                    self.network.connect_timeout = timeout;
+                } else {
+                    self.network.read_timeout = timeout;
+                }
+            }
+            "--retries" | "--cache-size" => {
+                let number = value
+                    .parse()
+                    .map_err(|err| format!("Invalid number {value}: {err}"))?;
+                if name == "--retries" {
+                    self.network.retries = number;
+                } else {
+                    self.cache_size = u64::from(number).saturating_mul(1024 * 1024);
+                }
+            }
+            "--max-depth" | "--workers" => {
+                let number = value
+                    .parse()
+                    .ok()
+                    .filter(|&number| number > 0 || name == "--max-depth")
+                    .ok_or_else(|| format!("Invalid number {value}"))?;
+                if name == "--max-depth" {
+                    self.crawl.max_depth = number;
+                } else {
+                    self.crawl.workers = number;
+                }
+            }
+            "--listenbrainz" => {
+                self.listenbrainz =
+                    Some(Url::parse(&value).map_err(|err| format!("Invalid URL {value}: {err}"))?);
+            }
+            _ => {
+                let address = value
+                    .parse()
+                    .map_err(|err| format!("Invalid address {value}: {err}"))?;
+                if name == "--http" {
+                    self.http = Some(address);
+                } else {
+                    self.mpd = Some(address);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{args_or_help, Options};
+    use crate::{agent::TlsConfig, web_utils::CrawlOptions};
+
+    /// Parses a list of string slices.
+    ///
+    /// # Errors
+    /// Fails if the options are invalid.
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|&arg| arg.to_owned())).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(parse(&[]).unwrap(), Options::default());
+    }
+
+    #[test]
+    fn help() {
+        let args = |args: &[&str]| args.iter().map(|&arg| arg.to_owned()).collect::<Vec<_>>();
+        assert_eq!(
+            args_or_help(args(&["https://example.com", "--headless"]), "Usage"),
+            Some(args(&["https://example.com", "--headless"]))
+        );
+        assert_eq!(
+            args_or_help(args(&["https://example.com", "-h"]), "Usage"),
+            None
+        );
+        assert_eq!(args_or_help(args(&["--help"]), "Usage"), None);
+    }
+
+    #[test]
+    fn headless() {
+        let expected = Options {
+            headless: true,
+            log_file: Some(PathBuf::from("/var/log/player.log")),
+            ..Options::default()
+        };
+        assert_eq!(
+            parse(&["--headless", "--log-file", "/var/log/player.log"]).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse(&["--log-file=/var/log/player.log", "--headless"]).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn socket() {
+        assert_eq!(
+            parse(&["--socket", "/run/player.sock"]).unwrap().socket,
+            Some(PathBuf::from("/run/player.sock"))
+        );
+        assert_eq!(parse(&["--no-socket"]).unwrap().socket, None);
+    }
+
+    #[test]
+    fn cache() {
+        assert_eq!(
+            parse(&["--cache", "/var/cache/player"]).unwrap().cache,
+            Some(PathBuf::from("/var/cache/player"))
+        );
+        assert_eq!(parse(&["--no-cache"]).unwrap().cache, None);
+        assert_eq!(
+            parse(&["--cache-size=10"]).unwrap().cache_size,
+            10 * 1024 * 1024
+        );
+        assert!(parse(&["--cache-size", "-1"]).is_err());
+    }
+
+    #[test]
+    fn http() {
+        assert_eq!(
+            parse(&["--http", "0.0.0.0:8080"]).unwrap().http,
+            Some(([0, 0, 0, 0], 8080).into())
+        );
+        assert!(parse(&["--http", "localhost"])
+            .unwrap_err()
+            .starts_with("Invalid address"));
+        assert_eq!(
+            parse(&["--http-token=secret"])
+                .unwrap()
+                .http_token
+                .as_deref(),
+            Some("secret")
+        );
+    }
+
+    #[test]
+    fn listenbrainz() {
+        assert_eq!(
+            parse(&["--listenbrainz=https://api.listenbrainz.org"])
+                .unwrap()
+                .listenbrainz
+                .unwrap()
+                .as_str(),
+            "https://api.listenbrainz.org/"
+        );
+        assert!(parse(&["--listenbrainz", "listenbrainz"])
+            .unwrap_err()
+            .starts_with("Invalid URL"));
+    }
+
+    #[test]
+    fn hooks() {
+        let options = parse(&["--hook", "start=echo start", "--hook=quit=echo a=b"]).unwrap();
+        assert_eq!(
+            options
+                .hooks
+                .iter()
+                .map(|hook| hook.command.as_str())
+                .collect::<Vec<_>>(),
+            ["echo start", "echo a=b"]
+        );
+        assert!(parse(&["--hook", "echo"])
+            .unwrap_err()
+            .starts_with("Invalid hook"));
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            parse(&["--script", "lyrics.rhai", "--script=scrobble.rhai"])
+                .unwrap()
+                .scripts,
+            [PathBuf::from("lyrics.rhai"), PathBuf::from("scrobble.rhai")]
+        );
+    }
+
+    #[test]
+    fn crawl() {
+        let crawl = parse(&[
+            "--max-depth=0",
+            "--workers",
+            "2",
+            "--include",
+            "Rock/*",
+            "--exclude=*/Live/*",
+            "--extension",
+            ".MP3",
+        ])
+        .unwrap()
+        .crawl;
+        assert_eq!(
+            crawl,
+            CrawlOptions {
+                workers: 2,
+                max_depth: 0,
+                include: vec!["Rock/*".to_owned()],
+                exclude: vec!["*/Live/*".to_owned()],
+                extensions: vec!["mp3".to_owned()],
+            }
+        );
+        assert!(parse(&["--workers", "0"])
+            .unwrap_err()
+            .starts_with("Invalid number"));
+    }
+
+    #[test]
+    fn tls() {
+        let options = parse(&[
+            "--no-system-roots",
+            "--no-bundled-ca",
+            "--ca",
+            "/etc/ca.pem",
+            "--pin=ab:cd",
+            "--client-cert",
+            "client.pem",
+            "--client-key",
+            "client.key",
+            "--web-config",
+            "web.json",
+        ])
+        .unwrap();
+        assert_eq!(
+            options.tls,
+            TlsConfig {
+                system_roots: false,
+                bundled_ca: false,
+                bundled: None,
+                ca: vec![PathBuf::from("/etc/ca.pem")],
+                pins: vec!["ab:cd".to_owned()],
+                client_cert: Some(PathBuf::from("client.pem")),
+                client_key: Some(PathBuf::from("client.key")),
+            }
+        );
+        assert_eq!(options.web_config, Some(PathBuf::from("web.json")));
+    }
+
+    #[test]
+    fn network() {
+        let network = parse(&[
+            "--connect-timeout=2.5",
+            "--read-timeout",
+            "60",
+            "--retries",
+            "0",
+        ])
+        .unwrap()
+        .network;
+        assert_eq!(network.connect_timeout, Duration::from_millis(2500));
+        assert_eq!(network.read_timeout, Duration::from_mins(1));
+        assert_eq!(network.retries, 0);
+        assert!(parse(&["--read-timeout", "-1"])
+            .unwrap_err()
+            .starts_with("Invalid duration"));
+    }
+
+    #[test]
+    fn podcasts() {
+        let options = parse(&["--no-shuffle", "--episodes=/tmp/episodes.json"]).unwrap();
+        assert!(!options.shuffle);
+        assert_eq!(options.episodes, PathBuf::from("/tmp/episodes.json"));
+        assert!(parse(&[]).unwrap().shuffle);
+    }
+
+    #[test]
+    fn sftp() {
+        let options = parse(&[
+            "--identity",
+            "/home/me/.ssh/music",
+            "--known-hosts=/home/me/.ssh/known_hosts",
+        ])
+        .unwrap();
+        assert_eq!(options.identity, Some(PathBuf::from("/home/me/.ssh/music")));
+        assert_eq!(
+            options.known_hosts,
+            Some(PathBuf::from("/home/me/.ssh/known_hosts"))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse(&["--log-file"])
+            .unwrap_err()
+            .starts_with("Missing value"));
+        assert!(parse(&["--headless=yes"])
+            .unwrap_err()
+            .starts_with("Unknown option"));
+        assert!(parse(&["--verbose"])
+            .unwrap_err()
+            .starts_with("Unknown option"));
+    }
+}
@@ -0,0 +1,370 @@
+//! The local control socket and its command-line client.
+//!
+//! See the [`protocol`](super::protocol) module for the messages.
+use std::{
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{
+        mpsc::{Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    options::{args_or_help, default_socket_path},
+    song::EBox,
+};
+
+use super::{
+    protocol::{parse_duration, Request, Response, StatusReport},
+    shared_status::SharedStatus,
+    Command,
+};
+
+/// The interval at which the server checks if the player has stopped.
+static ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The usage of the `ctl` subcommand.
+pub const USAGE: &str = "Usage: audio-player ctl [--socket <PATH>] <COMMAND> [ARGUMENT]
+
+Commands:
+  status [--json]      Show the current song and the queue
+  play, pause, play-pause, next, previous, quit
+  seek <POSITION>      Seek to a position (1:30, 90), or relatively (+10, -10)
+  message <TEXT>       Display a message
+  <COMMAND> [ARGUMENT] Send any other command (toggle-log, seek-right 30, play-song 3, set-shuffle true...)";
+
+/// Listens on the control socket at `path` until the player stops.
+///
+/// Each connection is handled in its own thread.
+///
+/// # Errors
+/// Fails if the socket is used by another player, if the path is not a socket
+/// or if the socket can't be created.
+pub fn control_socket(
+    path: &Path,
+    tx: &Sender<Command>,
+    status: &Arc<SharedStatus>,
+    stop_rx: &Receiver<()>,
+) -> Result<(), EBox> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("Another player is listening on {}", path.display()).into());
+        }
+        // The socket has been left by a player that has crashed
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+
+    while let Err(TryRecvError::Empty) = stop_rx.try_recv() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let tx = tx.clone();
+                let status = status.clone();
+                // The connection threads are not joined, they end with the process
+                spawn(move || handle_connection(stream, &tx, &status));
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => sleep(ACCEPT_POLL_INTERVAL),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Answers the requests of a client until it disconnects.
+///
+/// # Errors
+/// Fails if the connection is closed unexpectedly.
+fn handle_connection(
+    stream: UnixStream,
+    tx: &Sender<Command>,
+    status: &SharedStatus,
+) -> Result<(), EBox> {
+    stream.set_nonblocking(false)?;
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match Request::parse(&line) {
+            Ok(Request::Status) => Response::ok(Some(status.report())),
+            Ok(Request::Command(command)) => match tx.send(command) {
+                Ok(()) => Response::ok(None),
+                Err(_) => Response::error("The player has stopped".to_owned()),
+            },
+            Err(err) => Response::error(err.to_string()),
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Sends a request to the player listening on `path` and returns its response.
+///
+/// # Errors
+/// Fails if the player can't be reached or if the request fails.
+pub fn send_request(path: &Path, request: &Value) -> Result<Response, EBox> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|err| format!("Can't connect to the player on {}: {err}", path.display()))?;
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Response = serde_json::from_str(&line)?;
+    if let Some(error) = response.error {
+        return Err(error.into());
+    }
+    Ok(response)
+}
+
+/// Converts the arguments of the `ctl` subcommand (after `--socket`) to a request.
+///
+/// # Errors
+/// Fails if the command is missing or if the position of `seek` is invalid.
+fn build_request(args: &[String]) -> Result<Value, EBox> {
+    let Some((command, args)) = args.split_first() else {
+        return Err(USAGE.into());
+    };
+    let command = command.replace('-', "_");
+    let argument = args.join(" ");
+
+    Ok(match command.as_str() {
+        "status" => json!({ "command": "status" }),
+        "message" | "display_message" => {
+            json!({ "command": "display_message", "argument": argument })
+        }
+        "seek" => {
+            let (command, position) = if let Some(offset) = argument.strip_prefix('+') {
+                ("seek_right", offset)
+            } else if let Some(offset) = argument.strip_prefix('-') {
+                ("seek_left", offset)
+            } else {
+                ("seek_to", argument.as_str())
+            };
+            let position =
+                parse_duration(position).ok_or_else(|| format!("Invalid position: {argument}"))?;
+            json!({ "command": command, "argument": position.as_secs_f64() })
+        }
+        _ if argument.is_empty() => json!({ "command": command }),
+        _ => {
+            // Keep integers as is for the positions in the queue
+            let argument = match (argument.as_str(), argument.parse::<u64>()) {
+                (_, Ok(number)) => json!(number),
+                ("true", _) => json!(true),
+                ("false", _) => json!(false),
+                // The player rejects the invalid arguments
+                _ => parse_duration(&argument)
+                    .map_or_else(|| json!(argument), |duration| json!(duration.as_secs_f64())),
+            };
+            json!({ "command": command, "argument": argument })
+        }
+    })
+}
+
+/// Formats a number of seconds as `m:ss`.
+fn format_seconds(seconds: f64) -> String {
+    let duration = Duration::try_from_secs_f64(seconds).unwrap_or_default();
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Runs the `ctl` subcommand with the given arguments (without `ctl`).
+///
+/// # Errors
+/// Fails if the arguments are invalid or if the request fails.
+pub fn ctl(args: impl IntoIterator<Item = String>) -> Result<(), EBox> {
+    let Some(mut args) = args_or_help(args, USAGE) else {
+        return Ok(());
+    };
+    let mut socket = None;
+    if let Some(index) = args.iter().position(|arg| arg == "--socket") {
+        if index + 1 >= args.len() {
+            return Err(USAGE.into());
+        }
+        socket = Some(args.remove(index + 1).into());
+        args.remove(index);
+    }
+    let json_output =
+        args.first().is_some_and(|arg| arg == "status") && args.iter().any(|arg| arg == "--json");
+    args.retain(|arg| arg != "--json");
+
+    let path = socket
+        .or_else(default_socket_path)
+        .ok_or("No default control socket without $XDG_RUNTIME_DIR: use --socket <PATH>")?;
+    let response = send_request(&path, &build_request(&args)?)?;
+
+    if let Some(status) = response.status {
+        if json_output {
+            println!("{}", serde_json::to_string(&status)?);
+        } else {
+            print_status(&status);
+        }
+    }
+    Ok(())
+}
+
+/// Prints a human-readable status.
+fn print_status(status: &StatusReport) {
+    let Some(song) = &status.song else {
+        println!("Not playing");
+        return;
+    };
+    println!(
+        "[{}] {song}",
+        if status.paused { "paused" } else { "playing" }
+    );
+    println!(
+        "{} / {} (song {} of {})",
+        format_seconds(status.time),
+        format_seconds(status.total_time),
+        status.position + 1,
+        status.queue.len()
+    );
+    if let Some(message) = &status.message {
+        println!("{}: {}", message.severity, message.text);
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs, process,
+        sync::{
+            mpsc::{channel, sync_channel},
+            Arc,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use serde_json::json;
+
+    use super::{build_request, control_socket, send_request};
+    use crate::player::{shared_status::SharedStatus, terminal_ui::PartialStatus, Command};
+
+    /// Builds a request from string slices.
+    fn request(args: &[&str]) -> serde_json::Value {
+        build_request(&args.iter().map(|&arg| arg.to_owned()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn requests() {
+        assert_eq!(request(&["next"]), json!({ "command": "next" }));
+        assert_eq!(request(&["play-pause"]), json!({ "command": "play_pause" }));
+        assert_eq!(
+            request(&["seek", "1:30"]),
+            json!({ "command": "seek_to", "argument": 90.0 })
+        );
+        assert_eq!(
+            request(&["seek", "-10"]),
+            json!({ "command": "seek_left", "argument": 10.0 })
+        );
+        assert_eq!(
+            request(&["seek-right", "2.5"]),
+            json!({ "command": "seek_right", "argument": 2.5 })
+        );
+        assert_eq!(
+            request(&["play-song", "3"]),
+            json!({ "command": "play_song", "argument": 3 })
+        );
+        assert_eq!(
+            request(&["message", "Hello", "world"]),
+            json!({ "command": "display_message", "argument": "Hello world" })
+        );
+        assert_eq!(
+            request(&["set-shuffle", "true"]),
+            json!({ "command": "set_shuffle", "argument": true })
+        );
+        assert_eq!(
+            request(&["set-offline", "false"]),
+            json!({ "command": "set_offline", "argument": false })
+        );
+        assert_eq!(
+            request(&["set-title", "Live", "show"]),
+            json!({ "command": "set_title", "argument": "Live show" })
+        );
+        assert!(build_request(&["seek".to_owned(), "soon".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn not_a_socket() {
+        let path = temp_dir().join(format!("audio-player-test-{}.txt", process::id()));
+        fs::write(&path, "notes").unwrap();
+        let (tx, _rx) = channel();
+        let (_stop_tx, stop_rx) = sync_channel(1);
+        let err = control_socket(&path, &tx, &Arc::new(SharedStatus::default()), &stop_rx)
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("is not a socket"));
+        // The file is kept
+        assert_eq!(fs::read_to_string(&path).unwrap(), "notes");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn socket() {
+        let path = temp_dir().join(format!("audio-player-test-{}.sock", process::id()));
+        let (tx, rx) = channel();
+        let status = Arc::new(SharedStatus::default());
+        status.set(PartialStatus {
+            song_names: Arc::new(["a.mp3".to_owned(), "b.mp3".to_owned()]),
+            position: 1,
+            scrollbar_position: 1,
+            time: Duration::from_secs(12),
+            total_time: Duration::from_secs(90),
+            paused: true,
+            shuffle: true,
+            ..PartialStatus::default()
+        });
+        let (stop_tx, stop_rx) = sync_channel(1);
+
+        let server_path = path.clone();
+        let server_status = status.clone();
+        let handle = spawn(move || {
+            control_socket(&server_path, &tx, &server_status, &stop_rx)
+                .map_err(|err| err.to_string())
+        });
+        while !path.exists() {
+            sleep(Duration::from_millis(10));
+        }
+
+        send_request(&path, &json!({ "command": "next" })).unwrap();
+        assert!(matches!(rx.recv().unwrap(), Command::Next));
+
+        let response = send_request(&path, &json!({ "command": "status" })).unwrap();
+        let report = response.status.unwrap();
+        assert_eq!(report.song.as_deref(), Some("b.mp3"));
+        assert!(report.paused);
+        assert!(report.shuffle);
+        assert!((report.time - 12.0).abs() < f64::EPSILON);
+        assert_eq!(report.queue, ["a.mp3", "b.mp3"]);
+
+        assert!(send_request(&path, &json!({ "command": "dance" })).is_err());
+
+        // The server also stops when the sender is dropped
+        drop(stop_tx);
+        handle.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,137 @@
+//! Logging of the player status when there is no terminal UI.
+use std::{
+    io::Write,
+    sync::{mpsc::Receiver, Arc},
+};
+
+use crate::song::EBox;
+
+use super::{terminal_ui::PartialStatus, StatusMessage};
+
+/// Writes the messages, the songs that are played and the titles of the streams to `output`
+/// until the player stops.
+///
+/// The status must be sent with the log (i.e. [`PartialStatus::log`] must be [`Some`]).
+///
+/// # Errors
+/// Fails if the log can't be written.
+pub fn headless_ui(
+    status_rx: &Receiver<PartialStatus>,
+    mut output: impl Write,
+) -> Result<(), EBox> {
+    let mut written = 0;
+    let mut current_song = None;
+    let mut current_title = None;
+
+    for status in status_rx {
+        if let Some(log) = &status.log {
+            for message in log.since(written) {
+                writeln!(output, "{message}")?;
+            }
+            written = written.max(log.total());
+        }
+
+        // The song names are recomputed each time the queue is shuffled
+        let song = (
+            status.position,
+            Arc::as_ptr(&status.song_names).cast::<String>(),
+        );
+        if current_song != Some(song) {
+            current_song = Some(song);
+            if let Some(name) = status.song_names.get(status.position) {
+                let message = StatusMessage::five_seconds(format!("Playing {name}"));
+                writeln!(output, "{message}")?;
+            }
+        }
+        if status.stream_title != current_title {
+            current_title.clone_from(&status.stream_title);
+            if let Some(title) = &current_title {
+                let message = StatusMessage::five_seconds(format!("Now playing {title}"));
+                writeln!(output, "{message}")?;
+            }
+        }
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::sync::{mpsc::channel, Arc};
+
+    use super::headless_ui;
+    use crate::player::{terminal_ui::PartialStatus, Log, StatusMessage, MAX_LOG_LENGTH};
+
+    /// Returns a [`PartialStatus`] with the given position and log.
+    fn status(song_names: &Arc<[String]>, position: usize, log: &Arc<Log>) -> PartialStatus {
+        PartialStatus {
+            song_names: song_names.clone(),
+            position,
+            scrollbar_position: position,
+            log: Some(log.clone()),
+            ..PartialStatus::default()
+        }
+    }
+
+    #[test]
+    fn log() {
+        let song_names: Arc<[String]> = Arc::new(["a.mp3".to_owned(), "b.mp3".to_owned()]);
+        let mut log = Arc::new(Log::default());
+        Arc::make_mut(&mut log).push(StatusMessage::warning("Oops".to_owned()));
+
+        let (tx, rx) = channel();
+        tx.send(status(&song_names, 0, &log)).unwrap();
+        tx.send(status(&song_names, 0, &log)).unwrap();
+        Arc::make_mut(&mut log).push(StatusMessage::error("Failed".to_owned()));
+        tx.send(status(&song_names, 1, &log)).unwrap();
+        let mut live_status = status(&song_names, 1, &log);
+        live_status.stream_title = Some("Artist - Title".to_owned());
+        tx.send(live_status.clone()).unwrap();
+        tx.send(live_status).unwrap();
+        drop(tx);
+
+        let mut output = vec![];
+        headless_ui(&rx, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output
+            .lines()
+            .map(|line| line.split_once(' ').map_or(line, |(_, rest)| rest))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "WARNING Oops",
+                "INFO    Playing a.mp3",
+                "ERROR   Failed",
+                "INFO    Playing b.mp3",
+                "INFO    Now playing Artist - Title"
+            ]
+        );
+    }
+
+    #[test]
+    fn full_log() {
+        let song_names: Arc<[String]> = Arc::new([]);
+        let mut log = Arc::new(Log::default());
+        let (tx, rx) = channel();
+        for i in 0..=MAX_LOG_LENGTH {
+            Arc::make_mut(&mut log).push(StatusMessage::warning(i.to_string()));
+        }
+        tx.send(status(&song_names, 0, &log)).unwrap();
+        // The oldest messages are dropped, the new ones are still written
+        Arc::make_mut(&mut log).push(StatusMessage::warning("Last".to_owned()));
+        assert_eq!(log.len(), MAX_LOG_LENGTH);
+        tx.send(status(&song_names, 0, &log)).unwrap();
+        drop(tx);
+
+        let mut output = vec![];
+        headless_ui(&rx, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), MAX_LOG_LENGTH + 1);
+        assert!(lines[0].ends_with(" 1"));
+        assert!(lines[MAX_LOG_LENGTH].ends_with(" Last"));
+    }
+}
@@ -0,0 +1,349 @@
+//! The commands run by the player on some events.
+//!
+//! The commands are run by the shell (`sh -c` or `cmd /C` on Windows) with these environment variables:
+//! * `AUDIO_PLAYER_EVENT`: the name of the [`HookEvent`]
+//! * `AUDIO_PLAYER_PATH`, `AUDIO_PLAYER_TITLE`: the path and the title of the current song
+//! * `AUDIO_PLAYER_POSITION`: the position in the current song, in seconds
+//! * `AUDIO_PLAYER_DURATION`: the duration of the current song in seconds (if it's known)
+//!
+//! The song variables are not set if no song has been played yet.
+use std::{
+    fmt::{self, Display, Formatter},
+    process::{Child, Command as Process, Stdio},
+    str::FromStr,
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use crate::song::EBox;
+
+use super::{Command, MediaUpdate, Metadata, StatusMessage};
+
+/// The interval at which the hooks check if the commands have ended.
+static POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An event of the player.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookEvent {
+    /// The player has started.
+    Start,
+    /// A new song is playing.
+    Track,
+    /// The song has been paused.
+    Pause,
+    /// The song has been resumed.
+    Resume,
+    /// The player is quitting.
+    Quit,
+}
+
+impl HookEvent {
+    /// The names of the events.
+    const NAMES: [(Self, &str); 5] = [
+        (Self::Start, "start"),
+        (Self::Track, "track"),
+        (Self::Pause, "pause"),
+        (Self::Resume, "resume"),
+        (Self::Quit, "quit"),
+    ];
+}
+
+impl Display for HookEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = Self::NAMES
+            .iter()
+            .find(|(event, _)| event == self)
+            .map_or("", |(_, name)| name);
+        f.write_str(name)
+    }
+}
+
+impl FromStr for HookEvent {
+    type Err = EBox;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::NAMES
+            .iter()
+            .find(|(_, event_name)| *event_name == name)
+            .map(|(event, _)| *event)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::NAMES.iter().map(|(_, name)| *name).collect();
+                format!(
+                    "Unknown event {name} (expected one of: {})",
+                    names.join(", ")
+                )
+                .into()
+            })
+    }
+}
+
+/// A command that is run on an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hook {
+    /// The event that triggers the command.
+    pub event: HookEvent,
+    /// The shell command.
+    pub command: String,
+}
+
+impl FromStr for Hook {
+    type Err = EBox;
+
+    /// Parses a hook written as `<EVENT>=<COMMAND>`.
+    fn from_str(hook: &str) -> Result<Self, Self::Err> {
+        let (event, command) = hook
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid hook {hook} (expected <EVENT>=<COMMAND>)"))?;
+        Ok(Self {
+            event: event.parse()?,
+            command: command.to_owned(),
+        })
+    }
+}
+
+/// The state of the player, passed to the commands.
+#[derive(Default)]
+struct State {
+    /// The current song.
+    metadata: Option<Metadata>,
+    /// Is the player paused?
+    paused: bool,
+    /// The position in the current song.
+    position: Duration,
+    /// The moment when `position` has been received.
+    timestamp: Option<Instant>,
+}
+
+impl State {
+    /// Returns the position in the current song.
+    fn position(&self) -> Duration {
+        match self.timestamp {
+            Some(timestamp) if !self.paused => self.position + timestamp.elapsed(),
+            _ => self.position,
+        }
+    }
+}
+
+/// Starts the commands of an event.
+///
+/// Returns the commands that are running.
+/// The commands that can't be started are reported.
+fn run_hooks(
+    hooks: &[Hook],
+    event: HookEvent,
+    state: &State,
+    tx: &Sender<Command>,
+) -> Vec<(String, Child)> {
+    hooks
+        .iter()
+        .filter(|hook| hook.event == event)
+        .filter_map(|hook| {
+            #[cfg(windows)]
+            let mut process = {
+                let mut process = Process::new("cmd");
+                process.arg("/C");
+                process
+            };
+            #[cfg(not(windows))]
+            let mut process = {
+                let mut process = Process::new("sh");
+                process.arg("-c");
+                process
+            };
+            process
+                .arg(&hook.command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .env("AUDIO_PLAYER_EVENT", event.to_string())
+                .env(
+                    "AUDIO_PLAYER_POSITION",
+                    state.position().as_secs_f64().to_string(),
+                );
+            if let Some(metadata) = &state.metadata {
+                process
+                    .env("AUDIO_PLAYER_PATH", &metadata.path)
+                    .env("AUDIO_PLAYER_TITLE", &metadata.title);
+                if let Some(duration) = metadata.duration {
+                    process.env("AUDIO_PLAYER_DURATION", duration.as_secs_f64().to_string());
+                }
+            }
+
+            match process.spawn() {
+                Ok(child) => Some((hook.command.clone(), child)),
+                Err(err) => {
+                    report(tx, &hook.command, &err.to_string());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Displays the failure of a command in the player.
+fn report(tx: &Sender<Command>, command: &str, error: &str) {
+    let _ = tx.send(Command::DisplayMessage(StatusMessage::error(format!(
+        "Hook `{command}` failed: {error}"
+    ))));
+}
+
+/// Reports the commands that have failed and removes the commands that have ended.
+fn check_children(children: &mut Vec<(String, Child)>, tx: &Sender<Command>) {
+    children.retain_mut(|(command, child)| match child.try_wait() {
+        Ok(None) => true,
+        Ok(Some(status)) => {
+            if !status.success() {
+                report(tx, command, &status.to_string());
+            }
+            false
+        }
+        Err(err) => {
+            report(tx, command, &err.to_string());
+            false
+        }
+    });
+}
+
+/// Runs the hooks on the events received from `updates_rx` until the player stops.
+///
+/// The commands are not waited for, their failures are displayed in the player.
+pub fn hooks(
+    hooks: &[Hook],
+    tx: &Sender<Command>,
+    updates_rx: &Receiver<MediaUpdate>,
+    stop_rx: &Receiver<()>,
+) {
+    let mut state = State::default();
+    let mut children = run_hooks(hooks, HookEvent::Start, &state, tx);
+
+    loop {
+        let event = match updates_rx.recv_timeout(POLL_INTERVAL) {
+            Ok(MediaUpdate::Metadata(metadata)) => {
+                state = State {
+                    metadata: Some(metadata),
+                    timestamp: Some(Instant::now()),
+                    ..State::default()
+                };
+                Some(HookEvent::Track)
+            }
+            Ok(MediaUpdate::Playback {
+                paused, position, ..
+            }) => {
+                let event = match (state.paused, paused) {
+                    (false, true) => Some(HookEvent::Pause),
+                    (true, false) => Some(HookEvent::Resume),
+                    _ => None,
+                };
+                state.paused = paused;
+                state.position = position;
+                state.timestamp = Some(Instant::now());
+                event
+            }
+            Ok(MediaUpdate::Finished) | Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Some(event) = event {
+            children.extend(run_hooks(hooks, event, &state, tx));
+        }
+        check_children(&mut children, tx);
+        if let Ok(()) | Err(TryRecvError::Disconnected) = stop_rx.try_recv() {
+            break;
+        }
+    }
+
+    // The quit commands are not waited for
+    run_hooks(hooks, HookEvent::Quit, &state, tx);
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs, process,
+        sync::mpsc::{channel, sync_channel},
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use super::{hooks, Hook, HookEvent};
+    use crate::player::{Command, MediaUpdate, Metadata};
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "track=notify-send \"$AUDIO_PLAYER_TITLE\""
+                .parse::<Hook>()
+                .unwrap(),
+            Hook {
+                event: HookEvent::Track,
+                command: "notify-send \"$AUDIO_PLAYER_TITLE\"".to_owned(),
+            }
+        );
+        assert!("stop=true".parse::<Hook>().is_err());
+        assert!("track".parse::<Hook>().is_err());
+        assert_eq!(HookEvent::Resume.to_string(), "resume");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn events() {
+        let output = temp_dir().join(format!("audio-player-test-hooks-{}", process::id()));
+        let _ = fs::remove_file(&output);
+        let log = format!(
+            "echo \"$AUDIO_PLAYER_EVENT $AUDIO_PLAYER_TITLE $AUDIO_PLAYER_DURATION\" >> {}",
+            output.display()
+        );
+        let hook_list = ["start", "track", "pause", "resume", "quit"]
+            .iter()
+            .map(|event| format!("{event}={log}").parse().unwrap())
+            .chain(["track=exit 3".parse().unwrap()])
+            .collect::<Vec<Hook>>();
+
+        let (tx, rx) = channel();
+        let (updates_tx, updates_rx) = channel();
+        let (stop_tx, stop_rx) = sync_channel(1);
+        let handle = spawn(move || hooks(&hook_list, &tx, &updates_rx, &stop_rx));
+
+        let playback = |paused| MediaUpdate::Playback {
+            paused,
+            position: Duration::from_secs(1),
+            shuffle: false,
+            volume: 1.0,
+            live: false,
+        };
+        // Wait between the events, to keep the order of the lines
+        for update in [
+            MediaUpdate::Metadata(Metadata {
+                path: "a.mp3".to_owned(),
+                title: "A".to_owned(),
+                artist: None,
+                album: None,
+                cover_url: None,
+                duration: Some(Duration::from_secs(90)),
+            }),
+            playback(false),
+            playback(true),
+            playback(true),
+            playback(false),
+        ] {
+            sleep(Duration::from_millis(200));
+            updates_tx.send(update).unwrap();
+        }
+        sleep(Duration::from_millis(200));
+        stop_tx.send(()).unwrap();
+        handle.join().unwrap();
+
+        let Command::DisplayMessage(message) = rx.recv().unwrap() else {
+            panic!("A message should be displayed");
+        };
+        assert_eq!(message.message, "Hook `exit 3` failed: exit status: 3");
+
+        sleep(Duration::from_millis(200));
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "start  \ntrack A 90\npause A 90\nresume A 90\nquit A 90\n"
+        );
+        fs::remove_file(&output).unwrap();
+    }
+}
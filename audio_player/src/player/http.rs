@@ -0,0 +1,509 @@
+//! The HTTP remote control, with a small web page.
+//!
+//! The API answers with the JSON [`Response`]s of the [`protocol`](super::protocol) module:
+//! * `GET /`: the web page
+//! * `GET /api/status`: the status of the player
+//! * `GET /api/events`: a stream of [Server-Sent Events](https://html.spec.whatwg.org/multipage/server-sent-events.html)
+//!   with the status of the player, each time it changes
+//! * `POST /api/play`, `/api/pause`, `/api/play-pause`, `/api/next`, `/api/previous`
+//! * `POST /api/seek?to=1:30` or `/api/seek?by=-10`: seeks to a position or relatively
+//! * `POST /api/select?index=3`: plays a song of the queue
+//! * `POST /api/command`: sends a request of the JSON protocol (in the body)
+//!
+//! Without a token, the API requests are rejected if their `Origin` isn't the server
+//! or if their `Host` isn't its address (or `localhost`), so the other web sites can't use it,
+//! even by pointing one of their names to the server (DNS rebinding).
+//! The `POST` requests must also have the `application/json` content type,
+//! so they can't be sent by a form.
+//!
+//! If a token is set, every API request must send it instead, in an `Authorization: Bearer`
+//! header or in a `token` parameter (the web page passes on its own `token` parameter),
+//! and the server can then be reached by any name.
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        mpsc::{Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread::spawn,
+    time::Duration,
+};
+
+use tiny_http::{Header, Method, Request as HttpRequest, Response as HttpResponse, Server};
+use url::{Host, Url};
+
+use crate::song::EBox;
+
+use super::{
+    protocol::{parse_duration, Request, Response},
+    shared_status::SharedStatus,
+    Command,
+};
+
+/// The web page of the remote control.
+static PAGE: &str = include_str!("remote.html");
+
+/// The interval at which the server checks if the player has stopped.
+static RECV_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The interval at which a comment is sent in the event streams to detect the closed connections.
+static KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The maximum size of a request body.
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Serves the HTTP remote control on `address` until the player stops.
+///
+/// If `token` is set, the API requests must send it.
+/// Each request is handled in its own thread.
+///
+/// # Errors
+/// Fails if the server can't listen on `address`.
+pub fn http_server(
+    address: SocketAddr,
+    token: Option<&str>,
+    tx: &Sender<Command>,
+    status: &Arc<SharedStatus>,
+    stop_rx: &Receiver<()>,
+) -> Result<(), EBox> {
+    let server = Server::http(address)?;
+    let token: Option<Arc<str>> = token.map(Into::into);
+
+    while let Err(TryRecvError::Empty) = stop_rx.try_recv() {
+        if let Some(request) = server.recv_timeout(RECV_POLL_INTERVAL)? {
+            let tx = tx.clone();
+            let status = status.clone();
+            let token = token.clone();
+            // The request threads are not joined, the event streams end with the process
+            spawn(move || handle_request(request, address, token.as_deref(), &tx, &status));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the value of a header of the request.
+fn header_value<'request>(
+    request: &'request HttpRequest,
+    field: &'static str,
+) -> Option<&'request str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(field))
+        .map(|header| header.value.as_str())
+}
+
+/// Checks that a request has been sent to `address` by the web page of the server,
+/// and not by another web site.
+///
+/// # Errors
+/// Fails with an HTTP status code and a message if the `Host` isn't `address` (or `localhost`)
+/// or if the `Origin` isn't the server.
+fn check_origin(request: &HttpRequest, address: SocketAddr) -> Result<(), (u16, String)> {
+    let forbidden = |name: &str| (403, format!("Forbidden {name}"));
+    let host = header_value(request, "Host").ok_or_else(|| forbidden("host"))?;
+    let host_url = Url::parse(&format!("http://{host}/")).map_err(|_| forbidden("host"))?;
+    let known_host = match host_url.host() {
+        Some(Host::Domain(name)) => {
+            name.eq_ignore_ascii_case("localhost")
+                && (address.ip().is_loopback() || address.ip().is_unspecified())
+        }
+        Some(Host::Ipv4(ip)) => address.ip().is_unspecified() || IpAddr::V4(ip) == address.ip(),
+        Some(Host::Ipv6(ip)) => address.ip().is_unspecified() || IpAddr::V6(ip) == address.ip(),
+        None => false,
+    };
+    if !known_host || host_url.port_or_known_default() != Some(address.port()) {
+        return Err(forbidden("host"));
+    }
+
+    // The browsers send the origin with the POST requests and the cross-origin requests
+    if let Some(origin) = header_value(request, "Origin") {
+        let same_origin = Url::parse(origin).is_ok_and(|url| url.origin() == host_url.origin());
+        if !same_origin {
+            return Err(forbidden("origin"));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that an API request can control the player.
+///
+/// If `token` is set, the request must send it (in `sent_token` or in an `Authorization` header).
+/// Otherwise, the request must come from the web page of the server ([`check_origin`])
+/// and the `POST` requests must have the `application/json` content type.
+///
+/// # Errors
+/// Fails with an HTTP status code and a message if the request is rejected.
+fn check_access(
+    request: &HttpRequest,
+    address: SocketAddr,
+    token: Option<&str>,
+    sent_token: Option<String>,
+) -> Result<(), (u16, String)> {
+    if let Some(token) = token {
+        let sent_token = header_value(request, "Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_owned)
+            .or(sent_token);
+        if sent_token.as_deref() != Some(token) {
+            return Err((401, "Missing or invalid token".to_owned()));
+        }
+        return Ok(());
+    }
+
+    check_origin(request, address)?;
+    // The forms can't send JSON, and the other web sites can't send it without our permission
+    let json = header_value(request, "Content-Type")
+        .is_some_and(|value| value.split(';').next() == Some("application/json"));
+    if *request.method() == Method::Post && !json {
+        return Err((415, "The content type must be application/json".to_owned()));
+    }
+    Ok(())
+}
+
+/// Creates a [`Header`].
+///
+/// # Errors
+/// Fails if the header is invalid.
+fn header(field: &str, value: &str) -> Result<Header, EBox> {
+    Header::from_bytes(field, value).map_err(|()| format!("Invalid header: {field}").into())
+}
+
+/// Answers a request with a JSON [`Response`].
+///
+/// # Errors
+/// Fails if the response can't be sent.
+fn respond(request: HttpRequest, code: u16, response: &Response) -> Result<(), EBox> {
+    let body = serde_json::to_string(response)?;
+    request.respond(
+        HttpResponse::from_string(body)
+            .with_status_code(code)
+            .with_header(header("Content-Type", "application/json")?),
+    )?;
+    Ok(())
+}
+
+/// Returns the [`Command`] of an API endpoint that accepts `POST` requests.
+///
+/// # Errors
+/// Fails with an HTTP status code and a message if the endpoint doesn't exist
+/// or if a parameter is invalid.
+fn api_command(
+    path: &str,
+    parameter: impl Fn(&str) -> Option<String>,
+) -> Result<Command, (u16, String)> {
+    let invalid = |name: &str| (400, format!("Missing or invalid parameter: {name}"));
+    Ok(match path {
+        "/api/play" => Command::Play,
+        "/api/pause" => Command::Pause,
+        "/api/play-pause" => Command::PlayPause,
+        "/api/next" => Command::Next,
+        "/api/previous" => Command::Previous,
+        "/api/seek" => match (parameter("to"), parameter("by")) {
+            (Some(to), _) => Command::SeekTo(parse_duration(&to).ok_or_else(|| invalid("to"))?),
+            (None, Some(by)) => {
+                let offset = parse_duration(by.trim_start_matches(['+', '-']))
+                    .ok_or_else(|| invalid("by"))?;
+                if by.starts_with('-') {
+                    Command::SeekLeft(offset)
+                } else {
+                    Command::SeekRight(offset)
+                }
+            }
+            (None, None) => return Err(invalid("to")),
+        },
+        "/api/select" => Command::PlaySong(
+            parameter("index")
+                .and_then(|index| index.parse().ok())
+                .ok_or_else(|| invalid("index"))?,
+        ),
+        _ => return Err((404, "Not found".to_owned())),
+    })
+}
+
+/// Answers a request sent to `address`.
+///
+/// # Errors
+/// Fails if the request can't be read or if the response can't be sent.
+fn handle_request(
+    mut request: HttpRequest,
+    address: SocketAddr,
+    token: Option<&str>,
+    tx: &Sender<Command>,
+    status: &SharedStatus,
+) -> Result<(), EBox> {
+    let url = Url::parse("http://localhost/")?.join(request.url())?;
+    let parameter = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| Cow::into_owned(value))
+    };
+
+    if url.path().starts_with("/api/") {
+        if let Err((code, error)) = check_access(&request, address, token, parameter("token")) {
+            return respond(request, code, &Response::error(error));
+        }
+    }
+
+    let command = match (request.method(), url.path()) {
+        (Method::Get, "/") => {
+            let response = HttpResponse::from_string(PAGE)
+                .with_header(header("Content-Type", "text/html; charset=utf-8")?);
+            request.respond(response)?;
+            return Ok(());
+        }
+        (Method::Get, "/api/status") => {
+            return respond(request, 200, &Response::ok(Some(status.report())));
+        }
+        (Method::Get, "/api/events") => return event_stream(request, status),
+        (Method::Post, "/api/command") => {
+            let mut body = String::new();
+            request
+                .as_reader()
+                .take(MAX_BODY_SIZE)
+                .read_to_string(&mut body)?;
+            match Request::parse(&body) {
+                Ok(Request::Status) => {
+                    return respond(request, 200, &Response::ok(Some(status.report())));
+                }
+                Ok(Request::Command(command)) => Ok(command),
+                Err(err) => Err((400, err.to_string())),
+            }
+        }
+        (Method::Post, path) => api_command(path, parameter),
+        (_, path) if path.starts_with("/api/") => Err((405, "Method not allowed".to_owned())),
+        _ => Err((404, "Not found".to_owned())),
+    };
+
+    match command {
+        Ok(command) => match tx.send(command) {
+            Ok(()) => respond(request, 200, &Response::ok(None)),
+            Err(_) => respond(
+                request,
+                503,
+                &Response::error("The player has stopped".to_owned()),
+            ),
+        },
+        Err((code, error)) => respond(request, code, &Response::error(error)),
+    }
+}
+
+/// Sends the status of the player each time it changes, until the client disconnects.
+///
+/// # Errors
+/// Fails when the connection is closed.
+fn event_stream(request: HttpRequest, status: &SharedStatus) -> Result<(), EBox> {
+    // Write the response by hand, the chunked responses of `tiny_http` are buffered
+    let mut writer = request.into_writer();
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\r\n",
+    )?;
+
+    let mut version = status.get().0;
+    loop {
+        let report = serde_json::to_string(&status.report())?;
+        writeln!(writer, "data: {report}\n")?;
+        writer.flush()?;
+
+        loop {
+            let new_version = status.wait_for_change(version, KEEPALIVE_INTERVAL).0;
+            if new_version != version {
+                version = new_version;
+                break;
+            }
+            writer.write_all(b": keepalive\n\n")?;
+            writer.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::{SocketAddr, TcpListener},
+        sync::{
+            mpsc::{channel, sync_channel},
+            Arc,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use super::http_server;
+    use crate::player::{
+        protocol::Response, shared_status::SharedStatus, terminal_ui::PartialStatus, Command,
+    };
+
+    /// Returns the status code of a response.
+    fn status_code(result: Result<ureq::Response, ureq::Error>) -> u16 {
+        match result {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(code, _)) => code,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Returns a [`PartialStatus`] where the given song is playing.
+    fn status(position: usize) -> PartialStatus {
+        PartialStatus {
+            song_names: Arc::new(["a.mp3".to_owned(), "b.mp3".to_owned()]),
+            position,
+            scrollbar_position: position,
+            time: Duration::from_secs(12),
+            total_time: Duration::from_secs(90),
+            paused: true,
+            ..PartialStatus::default()
+        }
+    }
+
+    #[test]
+    fn api() {
+        // Find a free port
+        let address: SocketAddr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let base = format!("http://{address}");
+        let (tx, rx) = channel();
+        let shared_status = Arc::new(SharedStatus::default());
+        shared_status.set(status(0));
+        let (stop_tx, stop_rx) = sync_channel(1);
+
+        let server_status = shared_status.clone();
+        let handle = spawn(move || {
+            http_server(address, None, &tx, &server_status, &stop_rx).map_err(|err| err.to_string())
+        });
+        let agent = ureq::agent();
+        // Returns the status code of a POST request
+        let post = |path: &str| {
+            let request = agent
+                .post(&format!("{base}{path}"))
+                .set("Content-Type", "application/json");
+            status_code(request.call())
+        };
+        // Wait for the server to start
+        while agent.get(&format!("{base}/")).call().is_err() {
+            sleep(Duration::from_millis(10));
+        }
+
+        let page = agent.get(&format!("{base}/")).call().unwrap();
+        assert_eq!(page.content_type(), "text/html");
+        assert!(page.into_string().unwrap().contains("EventSource"));
+
+        let response = agent
+            .get(&format!("{base}/api/status"))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        let response: Response = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.status.unwrap().song.as_deref(), Some("a.mp3"));
+
+        assert_eq!(post("/api/next"), 200);
+        assert!(matches!(rx.recv().unwrap(), Command::Next));
+        assert_eq!(post("/api/seek?to=1:30"), 200);
+        assert!(matches!(
+            rx.recv().unwrap(),
+            Command::SeekTo(position) if position == Duration::from_secs(90)
+        ));
+        assert_eq!(post("/api/seek?by=-10"), 200);
+        assert!(matches!(
+            rx.recv().unwrap(),
+            Command::SeekLeft(offset) if offset == Duration::from_secs(10)
+        ));
+        assert_eq!(post("/api/select?index=1"), 200);
+        assert!(matches!(rx.recv().unwrap(), Command::PlaySong(1)));
+        agent
+            .post(&format!("{base}/api/command"))
+            .set("Content-Type", "application/json")
+            .set("Origin", &base)
+            .send_string(r#"{"command": "toggle_log"}"#)
+            .unwrap();
+        assert!(matches!(rx.recv().unwrap(), Command::ToggleLog));
+
+        // The other web sites can't control the player
+        let form = agent
+            .post(&format!("{base}/api/command"))
+            .set("Content-Type", "text/plain")
+            .send_string(r#"{"command": "quit"}"#);
+        assert_eq!(status_code(form), 415);
+        let request = agent
+            .post(&format!("{base}/api/next"))
+            .set("Content-Type", "application/json")
+            .set("Origin", "http://example.com");
+        assert_eq!(status_code(request.call()), 403);
+        // Even with DNS rebinding
+        let request = agent
+            .get(&format!("{base}/api/status"))
+            .set("Host", &format!("example.com:{}", address.port()));
+        assert_eq!(status_code(request.call()), 403);
+        let request = agent
+            .get(&format!("{base}/api/status"))
+            .set("Host", &format!("localhost:{}", address.port()));
+        assert_eq!(status_code(request.call()), 200);
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(post("/api/dance"), 404);
+        assert_eq!(post("/api/select?index=first"), 400);
+        assert!(matches!(
+            agent.get(&format!("{base}/api/next")).call(),
+            Err(ureq::Error::Status(405, _))
+        ));
+
+        let events = agent.get(&format!("{base}/api/events")).call().unwrap();
+        assert_eq!(events.content_type(), "text/event-stream");
+        let mut lines = BufReader::new(events.into_reader()).lines();
+        assert!(lines.next().unwrap().unwrap().contains(r#""song":"a.mp3""#));
+        shared_status.set(status(1));
+        let event = lines
+            .map(Result::unwrap)
+            .find(|line| line.starts_with("data:"))
+            .unwrap();
+        assert!(event.contains(r#""song":"b.mp3""#));
+
+        // The server also stops when the sender is dropped
+        drop(stop_tx);
+        handle.join().unwrap().unwrap();
+    }
+    #[test]
+    fn token() {
+        let address: SocketAddr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let base = format!("http://{address}");
+        let (tx, rx) = channel();
+        let (stop_tx, stop_rx) = sync_channel(1);
+        let handle = spawn(move || {
+            let status = Arc::new(SharedStatus::default());
+            http_server(address, Some("secret"), &tx, &status, &stop_rx)
+                .map_err(|err| err.to_string())
+        });
+        let agent = ureq::agent();
+        while agent.get(&format!("{base}/")).call().is_err() {
+            sleep(Duration::from_millis(10));
+        }
+
+        let post = |path: &str| status_code(agent.post(&format!("{base}{path}")).call());
+        assert_eq!(post("/api/next"), 401);
+        assert_eq!(post("/api/next?token=wrong"), 401);
+        assert_eq!(post("/api/next?token=secret"), 200);
+        assert!(matches!(rx.recv().unwrap(), Command::Next));
+        // The server can be reached by any name with the token
+        let request = agent
+            .get(&format!("{base}/api/status"))
+            .set("Host", "player.example.com")
+            .set("Authorization", "Bearer secret");
+        assert_eq!(status_code(request.call()), 200);
+
+        drop(stop_tx);
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -3,15 +3,21 @@ use std::{sync::mpsc::Sender, time::Duration};
 
 use crossterm::event::{poll, read, Event, KeyCode, KeyEvent};
 
-use crate::{secrets::commands::check_secrets, song::EBox};
+use crate::song::EBox;
 
 use super::{Command, SEEK_STEP};
 
 /// Wait at most 100 milliseconds for an event and handle it.
 ///
+/// The pressed keys are sent to the plugins (if any) through `keys_tx`.
+///
 /// # Errors
-/// Fails if sending a command fails or if a secret feature fails.
-pub fn handle_events(stack: &mut String, tx: &Sender<Command>) -> Result<(), EBox> {
+/// Fails if sending a command fails.
+pub fn handle_events(
+    stack: &mut String,
+    tx: &Sender<Command>,
+    keys_tx: &Sender<String>,
+) -> Result<(), EBox> {
     while poll(Duration::from_millis(100))? {
         let event = read()?;
         if let Event::Key(KeyEvent { code: keycode, .. }) = event {
@@ -21,6 +27,9 @@ pub fn handle_events(stack: &mut String, tx: &Sender<Command>) -> Result<(), EBo
                         ' ' => {
                             tx.send(Command::PlayPause)?;
                         }
+                        'l' => {
+                            tx.send(Command::ToggleLog)?;
+                        }
                         'n' => {
                             tx.send(Command::Next)?;
                         }
@@ -58,7 +67,8 @@ pub fn handle_events(stack: &mut String, tx: &Sender<Command>) -> Result<(), EBo
                 }
                 _ => {}
             }
-            check_secrets(tx, stack)?;
+            // There may be no plugins
+            let _ = keys_tx.send(stack.clone());
         }
     }
     Ok(())
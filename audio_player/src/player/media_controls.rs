@@ -1,21 +1,29 @@
-//! Implementation for the media controls.
+//! Implementation for the media controls on Windows and macOS.
+//!
+//! Linux and the BSDs use the [`mpris`](super::mpris) module instead.
 use std::{
-    sync::mpsc::{sync_channel, Receiver, Sender},
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
     time::Duration,
 };
 
-use souvlaki::{MediaControlEvent, MediaControls, MediaMetadata, PlatformConfig, SeekDirection};
+use souvlaki::{
+    MediaControlEvent, MediaControls, MediaMetadata, MediaPlayback, MediaPosition, PlatformConfig,
+    SeekDirection,
+};
 
 use crate::{generic_error::GenericError, player::SEEK_STEP, song::EBox};
 
-use super::{Command, Metadata};
+use super::{Command, MediaUpdate};
 
 /// Register media controls.
 ///
 /// Inspired from <https://github.com/Sinono3/souvlaki#example>.
+///
+/// # Errors
+/// Fails if the media controls can't be created or updated.
 pub fn media_controls(
     tx: Sender<Command>,
-    status_rx: &Receiver<Metadata>,
+    updates_rx: &Receiver<MediaUpdate>,
     stop_rx: &Receiver<()>,
 ) -> Result<(), EBox> {
     #[cfg(target_os = "windows")]
@@ -80,18 +88,39 @@ pub fn media_controls(
         if let Ok(err) = rx_error.try_recv() {
             err?;
         }
-        if let Ok(metadata) = status_rx.try_recv() {
+        match updates_rx.recv_timeout(Duration::from_millis(100)) {
             // Update the media metadata
-            controls
+            Ok(MediaUpdate::Metadata(metadata)) => controls
                 .set_metadata(MediaMetadata {
                     title: Some(metadata.title.as_str()),
-                    ..Default::default()
+                    artist: metadata.artist.as_deref(),
+                    album: metadata.album.as_deref(),
+                    cover_url: metadata.cover_url.as_deref(),
+                    duration: metadata.duration,
                 })
-                .map_err(GenericError::from)?;
+                .map_err(GenericError::from)?,
+            // Update the playback status and position
+            Ok(MediaUpdate::Playback {
+                paused, position, ..
+            }) => {
+                let progress = Some(MediaPosition(position));
+                controls
+                    .set_playback(if paused {
+                        MediaPlayback::Paused { progress }
+                    } else {
+                        MediaPlayback::Playing { progress }
+                    })
+                    .map_err(GenericError::from)?;
+            }
+            Ok(MediaUpdate::Finished) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
-        if stop_rx.recv_timeout(Duration::from_millis(100)).is_ok() {
+        if let Ok(()) | Err(TryRecvError::Disconnected) = stop_rx.try_recv() {
             break;
         }
     }
+    controls
+        .set_playback(MediaPlayback::Stopped)
+        .map_err(GenericError::from)?;
     Ok(())
 }
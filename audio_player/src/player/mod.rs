@@ -1,24 +1,63 @@
 //! The code for the random player.
 use std::{
-    sync::mpsc::{channel, sync_channel},
-    thread::scope,
-    time::{Duration, SystemTime},
+    collections::{HashSet, VecDeque},
+    env,
+    fmt::{self, Display, Formatter},
+    fs::OpenOptions,
+    io::{stderr, Write},
+    mem,
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender},
+        Arc,
+    },
+    thread::{scope, Scope},
+    time::{Duration, Instant, SystemTime},
 };
 
+use chrono::{DateTime, Local};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, is_raw_mode_enabled};
+use headless::headless_ui;
+use hooks::hooks;
+use http::http_server;
+#[cfg(not(all(unix, not(target_os = "macos"))))]
 use media_controls::media_controls;
-use rodio::{Decoder, OutputStream, Sink, Source};
+use mpd::mpd_server;
+#[cfg(all(unix, not(target_os = "macos")))]
+use mpris::media_controls;
+use plugin::{plugins, Plugin};
+use rodio::{source::EmptyCallback, Decoder, OutputStream, Sink, Source};
+use scripting::ScriptPlugin;
+use scrobbler::{scrobbler, Scrobbler};
+use serde::{Deserialize, Serialize};
+use shared_status::SharedStatus;
 use terminal_ui::{terminal_ui, PartialStatus};
 use tinyrand::{Rand, Seeded, StdRand, Wyrand};
 
 use crate::{
+    lyrics::Lyrics,
+    options::{default_journal_path, Options},
     scroll_position::Scrollable,
-    secrets::commands::check_secrets_once,
-    song::{check_double_songs, EBox, Song},
+    secrets::commands::secret_plugins,
+    song::{check_double_songs, AudioSource, EBox, Song},
+    tags::Tags,
 };
 
+#[cfg(unix)]
+pub mod control;
+mod headless;
+pub mod hooks;
+mod http;
 mod keyboard_controls;
+#[cfg(not(all(unix, not(target_os = "macos"))))]
 mod media_controls;
+mod mpd;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod mpris;
+pub mod plugin;
+pub mod protocol;
+pub mod scripting;
+mod scrobbler;
+mod shared_status;
 mod terminal_ui;
 #[cfg(windows)]
 pub mod window;
@@ -38,6 +77,30 @@ macro_rules! println_not_raw {
     };
 }
 
+/// The severity of a [`StatusMessage`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// An informational message.
+    #[default]
+    Info,
+    /// Something went wrong but the player can continue normally.
+    Warning,
+    /// An operation failed.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Use `pad` to support the alignment in the log
+        f.pad(match self {
+            Self::Info => "INFO",
+            Self::Warning => "WARNING",
+            Self::Error => "ERROR",
+        })
+    }
+}
+
 /// A status message.
 #[derive(Clone)]
 #[must_use]
@@ -46,6 +109,10 @@ pub struct StatusMessage {
     message: String,
     /// The time when the message will be cleared (represented by a [`Duration`]).
     max_time: SystemTime,
+    /// The severity of the message.
+    severity: Severity,
+    /// The time when the message has been created.
+    time: SystemTime,
 }
 
 impl Default for StatusMessage {
@@ -53,6 +120,8 @@ impl Default for StatusMessage {
         Self {
             message: String::new(),
             max_time: SystemTime::UNIX_EPOCH,
+            severity: Severity::default(),
+            time: SystemTime::UNIX_EPOCH,
         }
     }
 }
@@ -60,7 +129,12 @@ impl Default for StatusMessage {
 impl StatusMessage {
     /// Creates a new [`StatusMessage`].
     pub fn new(message: String, max_time: SystemTime) -> Self {
-        Self { message, max_time }
+        Self {
+            message,
+            max_time,
+            severity: Severity::default(),
+            time: SystemTime::now(),
+        }
     }
 
     /// Creates a new [`StatusMessage`] that is cleared after the given [`Duration`].
@@ -75,50 +149,282 @@ impl StatusMessage {
 
     /// Creates a new infinite [`StatusMessage`].
     pub fn infinite(message: String) -> Self {
-        Self {
+        // Quite infinite...
+        Self::new(
             message,
-            // Quite infinite...
-            max_time: SystemTime::now() + Duration::from_secs(365 * 86400),
+            SystemTime::now() + Duration::from_secs(365 * 86400),
+        )
+    }
+
+    /// Creates a new warning [`StatusMessage`] that is cleared after 5 seconds.
+    pub fn warning(message: String) -> Self {
+        Self::five_seconds(message).with_severity(Severity::Warning)
+    }
+
+    /// Creates a new error [`StatusMessage`] that is cleared after 5 seconds.
+    pub fn error(message: String) -> Self {
+        Self::five_seconds(message).with_severity(Severity::Error)
+    }
+
+    /// Changes the severity of the [`StatusMessage`].
+    pub const fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+}
+
+impl Display for StatusMessage {
+    /// Formats the message as a log line (`HH:MM:SS SEVERITY message`).
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<7} {}",
+            DateTime::<Local>::from(self.time).format("%H:%M:%S"),
+            self.severity,
+            self.message
+        )
+    }
+}
+
+/// The maximum number of messages kept in the [`Log`].
+const MAX_LOG_LENGTH: usize = 1000;
+
+/// The history of the messages, limited to the last [`MAX_LOG_LENGTH`] ones.
+#[derive(Clone, Default)]
+pub struct Log {
+    /// The last messages.
+    messages: VecDeque<StatusMessage>,
+    /// The number of older messages that have been dropped.
+    dropped: usize,
+}
+
+impl Log {
+    /// Adds a message, and drops the oldest one if the log is full.
+    ///
+    /// Returns `true` if a message has been dropped.
+    fn push(&mut self, message: StatusMessage) -> bool {
+        let full = self.messages.len() >= MAX_LOG_LENGTH;
+        if full {
+            self.messages.pop_front();
+            self.dropped += 1;
         }
+        self.messages.push_back(message);
+        full
+    }
+
+    /// Returns the number of messages in the log.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Is the log empty?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Returns the number of messages since the start of the player, including the dropped ones.
+    #[must_use]
+    pub fn total(&self) -> usize {
+        self.dropped + self.messages.len()
+    }
+
+    /// Returns the messages of the log, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &StatusMessage> {
+        self.messages.iter()
+    }
+
+    /// Returns the messages that came after the first `count` messages since the start of the player.
+    pub fn since(&self, count: usize) -> impl Iterator<Item = &StatusMessage> {
+        self.messages
+            .iter()
+            .skip(count.saturating_sub(self.dropped))
     }
 }
 
 /// The status of an active player.
+#[expect(clippy::struct_excessive_bools, reason = "these are independent flags")]
 pub(crate) struct Status {
     /// Should we go to the next song when the current one is finished?
     pub go_next: bool,
     /// The length of the queue.
     pub length: usize,
+    /// Is the current song an endless stream?
+    pub live: bool,
+    /// The lyrics of the current song.
+    pub lyrics: Option<Arc<Lyrics>>,
+    /// The history of the last messages.
+    pub log: Arc<Log>,
+    /// The position of the currently pointed message in the log.
+    pub log_position: usize,
     /// The messages stack.
     pub messages: Vec<StatusMessage>,
+    /// The metadata of the current song, once it's loaded (see [`Command::SetSongInfo`]).
+    pub metadata: Option<Metadata>,
+    /// The paths of the songs to append to the queue (see [`Command::AppendSongs`]).
+    pub new_songs: Vec<String>,
+    /// Is the library offline?
+    pub offline: bool,
     /// The actual position in the queue.
     pub position: usize,
     /// The random number generator to shuffle the queue.
     pub rng: Wyrand,
     /// The position of the currently pointed element.
     pub scrollbar_position: usize,
+    /// Is the log panel visible?
+    pub show_log: bool,
+    /// Should the queue be shuffled when it's restarted?
+    pub shuffle: bool,
+    /// The number of songs skipped in a row.
+    pub skipped: usize,
+    /// Has the current song ended (or been skipped)?
+    pub song_ended: bool,
+    /// The names of the songs in the queue.
+    pub song_names: Arc<[String]>,
+    /// The number of the current song, to ignore the ends of the previous songs.
+    pub song_number: usize,
     /// Should we stop the player?
     pub stop: bool,
+    /// The title sent by the current stream, if it's live.
+    pub stream_title: Option<String>,
+    /// The duration of the current song.
+    pub total_time: Duration,
     /// Was the song paused before the call to [`Command::ForcePause`]?
     pub was_paused: bool,
 }
 
 impl Status {
-    /// Shuffle the queue if `status.position == status.length`.
-    fn shuffle_if_needed<'queue, 'name, T: Song<'name> + 'name>(&mut self, queue: &'queue mut [T]) {
+    /// Creates the initial [`Status`] of a player with a queue of the given length.
+    ///
+    /// # Errors
+    /// Fails if the current time cannot be determined.
+    fn new(length: usize, options: &Options) -> Result<Self, EBox> {
+        let rng = StdRand::seed(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+        );
+        Ok(Self {
+            go_next: true,
+            length,
+            live: false,
+            log: Arc::new(Log::default()),
+            log_position: 0,
+            lyrics: None,
+            messages: vec![],
+            metadata: None,
+            new_songs: vec![],
+            offline: false,
+            position: length,
+            scrollbar_position: 0,
+            rng,
+            // The log is always needed to write it in headless mode
+            show_log: options.headless,
+            shuffle: options.shuffle,
+            skipped: 0,
+            song_ended: false,
+            song_names: Arc::new([]),
+            song_number: 0,
+            stop: false,
+            stream_title: None,
+            total_time: Duration::ZERO,
+            was_paused: false,
+        })
+    }
+
+    /// Restarts the queue if `status.position == status.length`,
+    /// and shuffles it unless [`Options::shuffle`] is disabled.
+    ///
+    /// Returns `true` if the queue has been restarted.
+    fn shuffle_if_needed<'queue, 'name, T: Song<'name> + 'name>(
+        &mut self,
+        queue: &'queue mut [T],
+    ) -> bool {
         if self.position == self.length {
             self.position = 0;
-            // Shuffle the queue
-            for i in (1..queue.len()).rev() {
-                queue.swap(i, self.rng.next_lim_usize(i + 1));
+            if self.shuffle {
+                for i in (1..queue.len()).rev() {
+                    queue.swap(i, self.rng.next_lim_usize(i + 1));
+                }
+                check_double_songs(queue);
             }
-            check_double_songs(queue);
+            return true;
+        }
+        false
+    }
+
+    /// Returns the paths of [`Status::new_songs`] that aren't in the queue yet, without duplicates.
+    fn take_new_songs(&mut self) -> Vec<String> {
+        let mut known: HashSet<String> = self.song_names.iter().cloned().collect();
+        let mut paths = mem::take(&mut self.new_songs);
+        paths.retain(|path| known.insert(path.clone()));
+        paths
+    }
+
+    /// Removes the expired messages and returns the message that should be displayed.
+    ///
+    /// The expired messages are still available in the log.
+    fn current_message(&mut self) -> Option<&StatusMessage> {
+        let now = SystemTime::now();
+        self.messages.retain(|message| message.max_time >= now);
+        self.messages.first()
+    }
+
+    /// Returns the status that will be sent to the UI.
+    fn partial_status(&mut self, sink: &Sink) -> PartialStatus {
+        PartialStatus {
+            song_names: self.song_names.clone(),
+            position: self.position,
+            scrollbar_position: self.scrollbar_position,
+            time: sink.get_pos(),
+            timestamp: Instant::now(),
+            total_time: self.total_time,
+            paused: sink.is_paused(),
+            message: self.current_message().cloned(),
+            other_messages: self.messages.len().saturating_sub(1),
+            lyrics: self.lyrics.clone(),
+            log: self.show_log.then(|| self.log.clone()),
+            log_position: self.log_position,
+            volume: sink.volume(),
+            offline: self.offline,
+            live: self.live,
+            stream_title: self.stream_title.clone(),
+            shuffle: self.shuffle,
         }
     }
+
+    /// Returns the maximum time the player can wait for a command.
+    fn wake_up_delay(&mut self) -> Duration {
+        // The song has been skipped, wait until the sink is empty
+        if !self.go_next || self.stop {
+            return SKIP_POLL_INTERVAL;
+        }
+        // Update the status when a message expires
+        self.current_message();
+        self.messages
+            .iter()
+            .map(|message| message.max_time)
+            .min()
+            .map_or(END_POLL_INTERVAL, |max_time| {
+                max_time
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .min(END_POLL_INTERVAL)
+            })
+    }
 }
 
 /// A command that can be sent to an active player to change its behavior.
+///
+/// See the [`protocol`] module for the JSON representation.
+#[derive(Deserialize)]
+#[serde(tag = "command", content = "argument", rename_all = "snake_case")]
 pub enum Command {
+    /// Appends the songs at the given paths to the queue, except the ones already in it
+    /// (sent by the library).
+    #[serde(skip)]
+    AppendSongs(Vec<String>),
     /// Displays a message.
     DisplayMessage(StatusMessage),
     /// Pauses the player.
@@ -133,6 +439,8 @@ pub enum Command {
     PlayPause,
     /// Plays the selected song.
     PlaySelected,
+    /// Plays the song at the given position in the queue.
+    PlaySong(usize),
     /// Plays the previous song.
     Previous,
     /// Closes the player.
@@ -141,21 +449,47 @@ pub enum Command {
     ResetScroll,
     /// Plays the player if it was previously playing before the [`Command::ForcePause`] command.
     RestorePlayback,
-    /// Selects one element down.
+    /// Selects one element down (in the queue or in the log).
     ScrollDown,
-    /// Selects one element up.
+    /// Selects one element up (in the queue or in the log).
     ScrollUp,
+    /// Shows or hides the offline mode (sent by the library).
+    #[serde(skip)]
+    SetOffline(bool),
+    /// Enables or disables the shuffling of the queue when it's restarted.
+    SetShuffle(bool),
+    /// Sets the lyrics and the metadata of the song with the given number
+    /// (loaded in the background when it starts).
+    #[serde(skip)]
+    SetSongInfo(usize, Option<Arc<Lyrics>>, Box<Metadata>),
+    /// Sets the title of the current stream (sent by the radios).
+    #[serde(skip)]
+    SetTitle(String),
+    /// Sets the volume (in percent, from 0 to 100).
+    SetVolume(u8),
     /// Seeks backwards of the given duration.
-    SeekLeft(Duration),
+    SeekLeft(#[serde(deserialize_with = "protocol::seconds")] Duration),
     /// Seeks forwards of the given duration.
-    SeekRight(Duration),
+    SeekRight(#[serde(deserialize_with = "protocol::seconds")] Duration),
     /// Seeks to a given position.
-    SeekTo(Duration),
+    SeekTo(#[serde(deserialize_with = "protocol::seconds")] Duration),
+    /// Signals that the song with the given number has ended (sent by the sink itself).
+    #[serde(skip)]
+    SongEnded(usize),
+    /// Shows or hides the log panel.
+    ToggleLog,
 }
 
 /// The seek step when seeking with arrow keys.
 static SEEK_STEP: Duration = Duration::from_secs(5);
 
+/// The interval at which the player checks if a skipped song has been removed from the sink.
+static SKIP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The interval at which the player checks if the sink is empty, in case the end of a song
+/// isn't signaled.
+static END_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 impl Command {
     /// Apply a command on a [`Sink`] and on a [`Status`].
     fn handle(self, sink: &Sink, status: &mut Status) {
@@ -163,7 +497,19 @@ impl Command {
         let old_position = status.position;
 
         match self {
-            Self::DisplayMessage(message) => status.messages.insert(0, message),
+            Self::AppendSongs(paths) => status.new_songs.extend(paths),
+            Self::DisplayMessage(message) => {
+                let log = Arc::make_mut(&mut status.log);
+                let was_last = status.log_position + 1 == log.len();
+                if log.push(message.clone()) {
+                    status.log_position = status.log_position.saturating_sub(1);
+                }
+                // Select the new message if the last one was selected
+                if was_last {
+                    status.log_position = log.len() - 1;
+                }
+                status.messages.insert(0, message);
+            }
             Self::ForcePause => sink.pause(),
             Self::Next => {
                 status.go_next = false;
@@ -178,20 +524,24 @@ impl Command {
                 sink.play();
                 status.was_paused = false;
             }
-            Self::PlayPause => {
-                if sink.is_paused() {
-                    Self::Play
-                } else {
-                    Self::Pause
-                }
-                .handle(sink, status);
-            }
+            Self::PlayPause if sink.is_paused() => Self::Play.handle(sink, status),
+            Self::PlayPause => Self::Pause.handle(sink, status),
             Self::PlaySelected => {
                 status.position = status.scrollbar_position;
                 status.go_next = false;
                 sink.skip_one();
                 Self::Play.handle(sink, status);
             }
+            Self::PlaySong(position) if position < status.length => {
+                status.scrollbar_position = position;
+                Self::PlaySelected.handle(sink, status);
+            }
+            Self::PlaySong(position) => {
+                Self::DisplayMessage(StatusMessage::warning(format!(
+                    "There is no song at position {position}"
+                )))
+                .handle(sink, status);
+            }
             Self::Previous => {
                 status.go_next = false;
                 status.position = status.position.previous(status.length);
@@ -207,9 +557,15 @@ impl Command {
                     sink.play();
                 }
             }
+            Self::ScrollDown if status.show_log && !status.log.is_empty() => {
+                status.log_position = status.log_position.next(status.log.len());
+            }
             Self::ScrollDown => {
                 status.scrollbar_position = status.scrollbar_position.next(status.length);
             }
+            Self::ScrollUp if status.show_log && !status.log.is_empty() => {
+                status.log_position = status.log_position.previous(status.log.len());
+            }
             Self::ScrollUp => {
                 status.scrollbar_position = status.scrollbar_position.previous(status.length);
             }
@@ -225,6 +581,19 @@ impl Command {
                 Self::try_seek(sink, sink.get_pos().saturating_add(duration), status);
             }
             Self::SeekTo(pos) => Self::try_seek(sink, pos, status),
+            Self::SetOffline(offline) => status.offline = offline,
+            Self::SetShuffle(shuffle) => status.shuffle = shuffle,
+            // The information of the previous songs is ignored
+            Self::SetSongInfo(number, lyrics, metadata) if number == status.song_number => {
+                status.lyrics = lyrics;
+                status.metadata = Some(*metadata);
+            }
+            Self::SetSongInfo(..) => {}
+            Self::SetTitle(title) => status.stream_title = Some(title),
+            Self::SetVolume(volume) => sink.set_volume(f32::from(volume.min(100)) / 100.0),
+            // The sink may not be empty yet, but nothing else will be played
+            Self::SongEnded(number) => status.song_ended |= number == status.song_number,
+            Self::ToggleLog => status.show_log = !status.show_log,
         }
 
         if old_position != status.position && update_scrollbar_position {
@@ -233,138 +602,821 @@ impl Command {
     }
 
     fn try_seek(sink: &Sink, pos: Duration, status: &mut Status) {
-        if let Err(err) = sink.try_seek(pos) {
-            Self::DisplayMessage(StatusMessage::five_seconds(format!("Seek failed: {err:?}")))
+        if status.live {
+            Self::DisplayMessage(StatusMessage::warning(
+                "A live stream can't be seeked".to_owned(),
+            ))
+            .handle(sink, status);
+        } else if let Err(err) = sink.try_seek(pos) {
+            Self::DisplayMessage(StatusMessage::error(format!("Seek failed: {err:?}")))
                 .handle(sink, status);
         }
     }
 }
 
 /// Owned metadata for a [`Song`].
+#[derive(Clone)]
 pub struct Metadata {
-    /// The title of the [`Song`] (currently the file name).
-    title: String,
+    /// The path of the [`Song`].
+    pub path: String,
+    /// The title of the [`Song`] (from its tags, or its path).
+    pub title: String,
+    /// The artist of the [`Song`].
+    pub artist: Option<String>,
+    /// The album of the [`Song`].
+    pub album: Option<String>,
+    /// The URL of the cover of the [`Song`].
+    pub cover_url: Option<String>,
+    /// The duration of the [`Song`], if it's known.
+    pub duration: Option<Duration>,
+}
+
+/// An update of the state published by the media controls and used by the scrobbler.
+#[derive(Clone)]
+pub enum MediaUpdate {
+    /// A new song is playing.
+    Metadata(Metadata),
+    /// The playback has been paused, resumed or moved.
+    Playback {
+        /// Is the player paused?
+        paused: bool,
+        /// The position in the current song.
+        position: Duration,
+        /// Is the queue shuffled when it's restarted?
+        shuffle: bool,
+        /// The volume (1 is 100%).
+        volume: f32,
+        /// Is the current song an endless stream, which can't be seeked?
+        live: bool,
+    },
+    /// The current song has been played until its end (it hasn't been skipped).
+    Finished,
 }
 
-/// Plays the given list of [`Song`]s.
+/// Sends a [`MediaUpdate`] to each of its receivers.
+///
+/// The receivers may be unavailable, this doesn't stop the player.
+fn send_media_update(media_txs: &[Sender<MediaUpdate>], update: &MediaUpdate) {
+    for tx in media_txs {
+        let _ = tx.send(update.clone());
+    }
+}
+
+/// Loads the lyrics and the [`Metadata`] of a [`Song`] and sends them to the player
+/// (see [`Command::SetSongInfo`]), or displays a message if they cannot be loaded.
+///
+/// This runs in the background, so the commands are handled while the song data is read.
+fn load_song_info<'name>(
+    song: &mut impl Song<'name>,
+    number: usize,
+    duration: Option<Duration>,
+    tx: &Sender<Command>,
+) {
+    let warn = |message| {
+        let _ = tx.send(Command::DisplayMessage(StatusMessage::warning(message)));
+    };
+    let tags = song.get_tags().unwrap_or_else(|err| {
+        warn(format!("Tags could not be loaded: {err}"));
+        Tags::default()
+    });
+    let lyrics = song.get_lyrics().unwrap_or_else(|err| {
+        warn(format!("Lyrics could not be loaded: {err}"));
+        None
+    });
+    let metadata = Metadata {
+        path: song.get_path().to_owned(),
+        title: tags.title.unwrap_or_else(|| song.get_path().to_owned()),
+        artist: tags.artist,
+        album: tags.album,
+        cover_url: tags.cover_url,
+        duration,
+    };
+    let _ = tx.send(Command::SetSongInfo(
+        number,
+        lyrics.map(Arc::new),
+        Box::new(metadata),
+    ));
+}
+
+/// Decodes a [`Song`] and appends it to the [`Sink`], or skips it if it's unavailable or if it can't be decoded.
+///
+/// Returns `false` if the song has been skipped. The unavailable songs are skipped silently.
 ///
 /// # Errors
-/// Fails:
-/// * if the current time cannot be determined
-/// * if the output stream or sink cannot be created
-/// * if a song cannot be fetched
-/// * if a song cannot be decoded
-pub fn play_songs<'name, T: Song<'name> + 'name>(songs: &mut [T]) -> Result<(), EBox> {
-    scope(|s| -> Result<(), EBox> {
-        let mut stop_list = vec![];
-        let mut get_stop_rx = || {
-            let (stop_tx, stop_rx) = sync_channel(1);
-            stop_list.push(stop_tx);
-            stop_rx
+/// Fails if all the songs of the queue have been skipped in a row.
+fn append_song<'name>(
+    song: &mut impl Song<'name>,
+    sink: &Sink,
+    status: &mut Status,
+) -> Result<bool, EBox> {
+    let available = song.is_available();
+    let result = if available {
+        song.get_source().and_then(|source| match source {
+            Some(source) => Ok(source),
+            None => Ok(Box::new(Decoder::new(song.get_data()?)?) as AudioSource),
+        })
+    } else {
+        Err("No song is available".into())
+    };
+    match result {
+        Ok(source) => {
+            status.skipped = 0;
+            status.live = song.is_live();
+            status.stream_title = None;
+            status.total_time = source.total_duration().unwrap_or(Duration::ZERO);
+            sink.append(source);
+            Ok(true)
+        }
+        Err(err) if status.skipped + 1 >= status.length => Err(err),
+        Err(err) => {
+            status.skipped += 1;
+            if available {
+                Command::DisplayMessage(StatusMessage::warning(format!(
+                    "Skipped {}: {err}",
+                    song.get_path()
+                )))
+                .handle(sink, status);
+            }
+            status.position += 1;
+            Ok(false)
+        }
+    }
+}
+
+/// Wakes up the player when the current song ends.
+fn append_end_callback(sink: &Sink, status: &mut Status, end_tx: Sender<Command>) {
+    status.song_number += 1;
+    status.song_ended = false;
+    let number = status.song_number;
+    sink.append(EmptyCallback::<f32>::new(Box::new(move || {
+        let _ = end_tx.send(Command::SongEnded(number));
+    })));
+}
+
+/// Handles the commands until the current song ends.
+///
+/// The status is sent to the UI, to the control socket and to the receivers of the [`MediaUpdate`]s
+/// only when something changes. The metadata of the song is sent once it's loaded, and again when
+/// the title of a stream changes.
+///
+/// # Errors
+/// Fails if the commands or the status can't be received or sent.
+fn wait_for_song_end(
+    sink: &Sink,
+    status: &mut Status,
+    commands_rx: &Receiver<Command>,
+    status_tx: &SyncSender<PartialStatus>,
+    media_txs: &[Sender<MediaUpdate>],
+    shared_status: &SharedStatus,
+) -> Result<(), EBox> {
+    let mut metadata_sent = false;
+    let mut changed = true;
+    let mut last_message_count = 0;
+    while !status.song_ended && !sink.empty() {
+        let partial_status = status.partial_status(sink);
+        if changed {
+            send_media_update(
+                media_txs,
+                &MediaUpdate::Playback {
+                    paused: partial_status.paused,
+                    position: partial_status.time,
+                    shuffle: partial_status.shuffle,
+                    volume: partial_status.volume,
+                    live: partial_status.live,
+                },
+            );
+        }
+        if changed || status.messages.len() != last_message_count {
+            last_message_count = status.messages.len();
+            shared_status.set(partial_status.clone());
+            status_tx.send(partial_status)?;
+        }
+
+        // Wait for a command, for the end of the song or for the current message to expire
+        let command = match commands_rx.recv_timeout(status.wake_up_delay()) {
+            Ok(command) => Some(command),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(err) => return Err(err.into()),
         };
+        changed = command.is_some();
+        if let Some(command) = command {
+            command.handle(sink, status);
+            // Handle the other pending commands at once
+            while let Ok(command) = commands_rx.try_recv() {
+                command.handle(sink, status);
+            }
+            if let Some(metadata) = &mut status.metadata {
+                let title = status
+                    .stream_title
+                    .as_ref()
+                    .filter(|title| **title != metadata.title);
+                if let Some(title) = title {
+                    metadata.title.clone_from(title);
+                }
+                if !metadata_sent || title.is_some() {
+                    metadata_sent = true;
+                    send_media_update(media_txs, &MediaUpdate::Metadata(metadata.clone()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
-        let (metadata_tx, metadata_rx) = sync_channel(1);
+/// Displays the error of a background task (if any) in the player.
+fn report_error<T>(tx: &Sender<Command>, context: &str, result: Result<T, EBox>) {
+    if let Err(err) = result {
+        let _ = tx.send(Command::DisplayMessage(StatusMessage::error(format!(
+            "{context}: {err}"
+        ))));
+    }
+}
 
-        let (commands_tx, commands_rx) = channel();
-        let commands_tx2 = commands_tx.clone();
+/// Loads the information of the current song and preloads the next one, in the background.
+fn spawn_song_loaders<'scope, 'name: 'scope, T: Song<'name> + 'name>(
+    s: &'scope Scope<'scope, '_>,
+    queue: &'scope mut [T],
+    status: &Status,
+    tx: &Sender<Command>,
+) {
+    let (played, pending) = queue.split_at_mut(status.position + 1);
+    let song = &mut played[status.position];
+    let info_tx = tx.clone();
+    let number = status.song_number;
+    let duration = (!status.total_time.is_zero()).then_some(status.total_time);
+    s.spawn(move || load_song_info(song, number, duration, &info_tx));
 
-        check_secrets_once(&commands_tx.clone())?;
+    if let Some(pending_song) = pending.first_mut().filter(|song| song.is_available()) {
+        let errors_tx = tx.clone();
+        s.spawn(move || {
+            report_error(&errors_tx, "Preloading failed", pending_song.preload());
+        });
+    }
+}
 
-        let stop_rx1 = get_stop_rx();
-        s.spawn(move || media_controls(commands_tx2, &metadata_rx, &stop_rx1));
+/// Stops the player when it receives `SIGINT` or `SIGTERM` (or `Ctrl+C` on Windows).
+///
+/// A warning is displayed if the handler can't be set.
+fn handle_signals(commands_tx: &Sender<Command>) {
+    let quit_tx = commands_tx.clone();
+    if let Err(err) = ctrlc::set_handler(move || {
+        let _ = quit_tx.send(Command::Quit);
+    }) {
+        let _ = commands_tx.send(Command::DisplayMessage(StatusMessage::warning(format!(
+            "The signal handler could not be set: {err}"
+        ))));
+    }
+}
 
-        let rng = StdRand::seed(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs(),
+/// Asks the threads of the player to stop.
+///
+/// The threads that have already stopped (for example a server that couldn't start)
+/// have dropped their receiver, so they are skipped.
+fn stop_threads(stop_list: Vec<SyncSender<()>>) {
+    for tx in stop_list {
+        let _ = tx.send(());
+    }
+}
+
+/// Starts the control socket, the HTTP server and the MPD server if they are enabled in the [`Options`].
+///
+/// Their errors are displayed in the player.
+fn spawn_remote_controls<'scope>(
+    s: &'scope Scope<'scope, '_>,
+    options: &Options,
+    commands_tx: &Sender<Command>,
+    shared_status: &Arc<SharedStatus>,
+    get_stop_rx: &mut impl FnMut() -> Receiver<()>,
+) {
+    #[cfg(unix)]
+    if let Some(path) = options.socket.clone() {
+        let stop_rx = get_stop_rx();
+        let socket_tx = commands_tx.clone();
+        let shared_status = shared_status.clone();
+        s.spawn(move || {
+            let result = control::control_socket(&path, &socket_tx, &shared_status, &stop_rx);
+            report_error(&socket_tx, "Control socket failed", result);
+        });
+    }
+
+    if let Some(address) = options.http {
+        let stop_rx = get_stop_rx();
+        let http_tx = commands_tx.clone();
+        let shared_status = shared_status.clone();
+        let token = options.http_token.clone();
+        s.spawn(move || {
+            let result = http_server(
+                address,
+                token.as_deref(),
+                &http_tx,
+                &shared_status,
+                &stop_rx,
+            );
+            report_error(&http_tx, "HTTP server failed", result);
+        });
+    }
+
+    if let Some(address) = options.mpd {
+        let stop_rx = get_stop_rx();
+        let mpd_tx = commands_tx.clone();
+        let shared_status = shared_status.clone();
+        s.spawn(move || {
+            let result = mpd_server(address, &mpd_tx, &shared_status, &stop_rx);
+            report_error(&mpd_tx, "MPD server failed", result);
+        });
+    }
+}
+
+/// Starts the hooks if some are set in the [`Options`].
+///
+/// Returns the [`Sender`] of the [`MediaUpdate`]s for the hooks.
+fn spawn_hooks<'scope>(
+    s: &'scope Scope<'scope, '_>,
+    options: &Options,
+    commands_tx: &Sender<Command>,
+    get_stop_rx: &mut impl FnMut() -> Receiver<()>,
+) -> Option<Sender<MediaUpdate>> {
+    if options.hooks.is_empty() {
+        return None;
+    }
+    let hook_list = options.hooks.clone();
+    let (updates_tx, updates_rx) = channel();
+    let stop_rx = get_stop_rx();
+    let hooks_tx = commands_tx.clone();
+    s.spawn(move || hooks(&hook_list, &hooks_tx, &updates_rx, &stop_rx));
+    Some(updates_tx)
+}
+
+/// Starts the scrobbler if it's enabled in the [`Options`].
+///
+/// Returns the [`Sender`] of the [`MediaUpdate`]s for the scrobbler.
+/// Its errors are displayed in the player.
+fn spawn_scrobbler<'scope>(
+    s: &'scope Scope<'scope, '_>,
+    options: &Options,
+    commands_tx: &Sender<Command>,
+    get_stop_rx: &mut impl FnMut() -> Receiver<()>,
+) -> Option<Sender<MediaUpdate>> {
+    let base_url = options.listenbrainz.as_ref()?;
+    let new_scrobbler = env::var("LISTENBRAINZ_TOKEN")
+        .map_err(|err| format!("LISTENBRAINZ_TOKEN: {err}").into())
+        .and_then(|token| Scrobbler::new(base_url, token, default_journal_path()));
+    let new_scrobbler = match new_scrobbler {
+        Ok(new_scrobbler) => new_scrobbler,
+        Err(err) => {
+            report_error::<()>(commands_tx, "Scrobbling disabled", Err(err));
+            return None;
+        }
+    };
+
+    let (updates_tx, updates_rx) = channel();
+    let stop_rx = get_stop_rx();
+    let scrobbler_tx = commands_tx.clone();
+    s.spawn(move || scrobbler(new_scrobbler, &scrobbler_tx, &updates_rx, &stop_rx));
+    Some(updates_tx)
+}
+
+/// Opens the output of the log in headless mode.
+///
+/// # Errors
+/// Fails if the log file can't be opened.
+fn log_output(options: &Options) -> Result<Box<dyn Write + Send>, EBox> {
+    Ok(match &options.log_file {
+        Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(stderr()),
+    })
+}
+
+/// Starts the plugins if there are some.
+///
+/// Returns the [`Sender`] of the [`MediaUpdate`]s for the plugins.
+fn spawn_plugins<'scope>(
+    s: &'scope Scope<'scope, '_>,
+    plugin_list: Vec<Box<dyn Plugin>>,
+    commands_tx: &Sender<Command>,
+    shared_status: &Arc<SharedStatus>,
+    keys_rx: Receiver<String>,
+    get_stop_rx: &mut impl FnMut() -> Receiver<()>,
+) -> Option<Sender<MediaUpdate>> {
+    if plugin_list.is_empty() {
+        return None;
+    }
+    let (updates_tx, updates_rx) = channel();
+    let stop_rx = get_stop_rx();
+    let plugins_tx = commands_tx.clone();
+    let shared_status = shared_status.clone();
+    s.spawn(move || {
+        plugins(
+            plugin_list,
+            plugins_tx,
+            shared_status,
+            &updates_rx,
+            &keys_rx,
+            &stop_rx,
         );
-        let (_stream, stream_handle) = OutputStream::try_default()?;
-        let sink = Sink::try_new(&stream_handle)?;
+    });
+    Some(updates_tx)
+}
 
-        let queue = &mut *songs;
+/// Loads the scripts of the [`Options`].
+///
+/// # Errors
+/// Fails if a script can't be loaded.
+fn load_scripts(options: &Options) -> Result<Vec<Box<dyn Plugin>>, EBox> {
+    options
+        .scripts
+        .iter()
+        .map(|path| Ok(Box::new(ScriptPlugin::from_file(path)?) as Box<dyn Plugin>))
+        .collect()
+}
+
+/// Appends the songs of [`Command::AppendSongs`] to the queue, if they can be created.
+fn append_new_songs<'name, T: Song<'name> + 'name>(
+    queue: &mut Vec<T>,
+    status: &mut Status,
+    new_song: Option<&mut NewSong<'_, T>>,
+) {
+    let new_songs = status.take_new_songs();
+    let Some(new_song) = new_song.filter(|_| !new_songs.is_empty()) else {
+        return;
+    };
+    queue.extend(new_songs.iter().filter_map(|path| new_song(path)));
+    status.length = queue.len();
+    status.song_names = queue.iter().map(|x| x.get_path().to_string()).collect();
+}
+
+/// Plays the given list of [`Song`]s with the given [`Options`] and the secret features.
+///
+/// See [`Player`] to add some [`Plugin`]s.
+///
+/// # Errors
+/// See [`Player::play`].
+pub fn play_songs<'name, T: Song<'name> + 'name>(
+    songs: &mut Vec<T>,
+    options: &Options,
+) -> Result<(), EBox> {
+    Player::new(songs)
+        .with_options(options.clone())
+        .with_plugins(secret_plugins())
+        .play()
+}
 
-        if queue.is_empty() {
-            println_not_raw!("No songs to play");
-            return Ok(());
+/// A builder for the player.
+///
+/// # Examples
+/// ```no_run
+/// # use audio_player::{options::Options, player::{plugin::{Plugin, PluginContext}, Command, Player}, song::{EBox, Song}};
+/// struct AutoPlay;
+///
+/// impl Plugin for AutoPlay {
+///     fn on_start(&mut self, context: &PluginContext) -> Result<(), EBox> {
+///         context.send(Command::Play)
+///     }
+/// }
+///
+/// # fn play<'name>(songs: &mut Vec<impl Song<'name> + 'name>) -> Result<(), EBox> {
+/// Player::new(songs)
+///     .with_options(Options::from_env()?)
+///     .with_plugin(AutoPlay)
+///     .play()
+/// # }
+/// ```
+pub struct Player<'songs, T> {
+    /// The songs to play.
+    songs: &'songs mut Vec<T>,
+    /// The options of the player.
+    options: Options,
+    /// The plugins of the player.
+    plugins: Vec<Box<dyn Plugin>>,
+    /// Creates the songs of [`Command::AppendSongs`] from their paths.
+    new_song: Option<Box<NewSong<'songs, T>>>,
+}
+
+/// A function that creates a song from its path, if it can be played.
+type NewSong<'songs, T> = dyn FnMut(&str) -> Option<T> + 'songs;
+
+impl<'songs, 'name, T: Song<'name> + 'name> Player<'songs, T> {
+    /// Creates a player for the given list of [`Song`]s, with the default [`Options`] and without plugins.
+    pub fn new(songs: &'songs mut Vec<T>) -> Self {
+        Self {
+            songs,
+            options: Options::default(),
+            plugins: vec![],
+            new_song: None,
         }
+    }
 
-        let mut status = Status {
-            go_next: true,
-            length: queue.len(),
-            messages: vec![],
-            position: queue.len(),
-            scrollbar_position: 0,
-            rng,
-            stop: false,
-            was_paused: false,
-        };
+    /// Sets the [`Options`] of the player.
+    #[must_use]
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Adds a [`Plugin`].
+    #[must_use]
+    pub fn with_plugin(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
 
-        let (status_tx, status_rx) = sync_channel(1);
-        let stop_rx2 = get_stop_rx();
-        s.spawn(move || terminal_ui(&status_rx, &stop_rx2, &commands_tx));
+    /// Adds some [`Plugin`]s.
+    #[must_use]
+    pub fn with_plugins(mut self, plugins: impl IntoIterator<Item = Box<dyn Plugin>>) -> Self {
+        self.plugins.extend(plugins);
+        self
+    }
 
-        let mut song_names: Vec<String>;
+    /// Sets how the songs sent by [`Command::AppendSongs`] are created from their paths.
+    ///
+    /// Without it, the songs sent by this command are ignored.
+    #[must_use]
+    pub fn with_new_songs(mut self, new_song: impl FnMut(&str) -> Option<T> + 'songs) -> Self {
+        self.new_song = Some(Box::new(new_song));
+        self
+    }
 
-        'mainloop: loop {
-            status.shuffle_if_needed(queue);
-            song_names = queue.iter().map(|x| x.get_path().to_string()).collect();
-            let song = &mut queue[status.position];
+    /// Plays the [`Song`]s.
+    ///
+    /// In headless mode, the terminal UI is replaced by a log
+    /// and the player stops cleanly when it receives `SIGTERM`.
+    ///
+    /// # Errors
+    /// Fails:
+    /// * if a script cannot be loaded
+    /// * if the current time cannot be determined
+    /// * if the log file cannot be opened
+    /// * if the output stream or sink cannot be created
+    /// * if no song of the queue can be fetched and decoded (the other ones are skipped)
+    pub fn play(self) -> Result<(), EBox> {
+        let Self {
+            songs,
+            ref options,
+            mut plugins,
+            mut new_song,
+        } = self;
+        plugins.extend(load_scripts(options)?);
+        scope(|s| -> Result<(), EBox> {
+            let mut stop_list = vec![];
+            let mut get_stop_rx = || {
+                let (stop_tx, stop_rx) = sync_channel(1);
+                stop_list.push(stop_tx);
+                stop_rx
+            };
 
-            let source = Decoder::new_mp3(song.get_data()?)?;
-            let total_time = source.total_duration().unwrap_or(Duration::ZERO);
-            sink.append(source);
+            let (media_tx, media_rx) = channel();
+            let mut media_txs = vec![media_tx];
+
+            let (commands_tx, commands_rx) = channel();
+            let commands_tx2 = commands_tx.clone();
+
+            handle_signals(&commands_tx);
+
+            let stop_rx1 = get_stop_rx();
+            let errors_tx = commands_tx.clone();
+            s.spawn(move || {
+                let result = media_controls(commands_tx2, &media_rx, &stop_rx1);
+                report_error(&errors_tx, "Media controls failed", result);
+            });
 
-            metadata_tx.send(Metadata {
-                title: song.get_path().to_owned(),
-            })?;
+            let shared_status = Arc::new(SharedStatus::default());
+            spawn_remote_controls(s, options, &commands_tx, &shared_status, &mut get_stop_rx);
+            media_txs.extend(spawn_scrobbler(s, options, &commands_tx, &mut get_stop_rx));
+            media_txs.extend(spawn_hooks(s, options, &commands_tx, &mut get_stop_rx));
+            let (keys_tx, keys_rx) = channel();
+            media_txs.extend(spawn_plugins(
+                s,
+                plugins,
+                &commands_tx,
+                &shared_status,
+                keys_rx,
+                &mut get_stop_rx,
+            ));
 
-            scope(|s2| -> Result<(), EBox> {
-                status.go_next = true;
+            let (_stream, stream_handle) = OutputStream::try_default()?;
+            let sink = Sink::try_new(&stream_handle)?;
 
-                if status.position + 1 != status.length {
-                    let pending_song = &mut queue[status.position + 1];
-                    s2.spawn(move || pending_song.preload());
+            let queue = songs;
+
+            if queue.is_empty() {
+                println_not_raw!("No songs to play");
+                return Ok(());
+            }
+
+            let mut status = Status::new(queue.len(), options)?;
+
+            let (status_tx, status_rx) = sync_channel(1);
+            let player_tx = commands_tx.clone();
+            if options.headless {
+                let output = log_output(options)?;
+                s.spawn(move || headless_ui(&status_rx, output));
+            } else {
+                let stop_rx2 = get_stop_rx();
+                s.spawn(move || terminal_ui(&status_rx, &stop_rx2, &commands_tx, &keys_tx));
+            }
+
+            'mainloop: loop {
+                append_new_songs(queue, &mut status, new_song.as_deref_mut());
+                if status.shuffle_if_needed(queue) {
+                    status.song_names = queue.iter().map(|x| x.get_path().to_string()).collect();
+                }
+                if !append_song(&mut queue[status.position], &sink, &mut status)? {
+                    continue;
                 }
+                append_end_callback(&sink, &mut status, player_tx.clone());
+                status.lyrics = None;
+                status.metadata = None;
 
-                let mut last_time = sink.get_pos();
-                while !sink.empty() {
-                    while let Ok(resp) = commands_rx.try_recv() {
-                        resp.handle(&sink, &mut status);
-                        last_time = Duration::MAX; // force update
-                    }
-                    if (last_time.abs_diff(sink.get_pos())) > Duration::from_secs(1) {
-                        last_time = sink.get_pos();
-                        let mut message = String::new();
-                        while !status.messages.is_empty() {
-                            if SystemTime::now() <= status.messages[0].max_time {
-                                message.clone_from(&status.messages[0].message);
-                                break;
-                            }
-                            let _ = status.messages.remove(0);
-                        }
-                        status_tx.send(PartialStatus {
-                            song_names: song_names.clone(),
-                            position: status.position,
-                            scrollbar_position: status.scrollbar_position,
-                            time: sink.get_pos(),
-                            total_time,
-                            paused: sink.is_paused(),
-                            message,
-                        })?;
+                scope(|s2| -> Result<(), EBox> {
+                    status.go_next = true;
+
+                    spawn_song_loaders(s2, queue, &status, &player_tx);
+
+                    wait_for_song_end(
+                        &sink,
+                        &mut status,
+                        &commands_rx,
+                        &status_tx,
+                        &media_txs,
+                        &shared_status,
+                    )?;
+                    if status.go_next {
+                        send_media_update(&media_txs, &MediaUpdate::Finished);
+                        Command::Next.handle(&sink, &mut status);
                     }
+                    Ok(())
+                })?;
+                if status.stop {
+                    stop_threads(stop_list);
+                    break 'mainloop;
                 }
-                if status.go_next {
-                    Command::Next.handle(&sink, &mut status);
-                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        sync::{
+            mpsc::{channel, sync_channel, Sender},
+            Arc,
+        },
+        thread::spawn,
+        time::Duration,
+    };
+
+    use rodio::{source::Zero, Sink};
+
+    use super::{wait_for_song_end, Command, MediaUpdate, Metadata, SharedStatus, Status};
+    use crate::options::Options;
+    #[cfg(unix)]
+    use crate::song::EBox;
+
+    #[test]
+    fn song_ended_before_the_sink_is_empty() {
+        let (done_tx, done_rx) = channel();
+        spawn(move || {
+            // The idle sink is never played, so it's never empty
+            let (sink, _queue) = Sink::new_idle();
+            sink.append(Zero::<f32>::new(2, 44_100));
+            let mut status = Status::new(1, &Options::default()).unwrap();
+            status.song_number = 2;
+            let (commands_tx, commands_rx) = channel();
+            let (status_tx, _status_rx) = sync_channel(1);
+            let (media_tx, media_rx) = channel();
+            let metadata = |title: &str| Metadata {
+                path: "song.mp3".to_owned(),
+                title: title.to_owned(),
+                artist: None,
+                album: None,
+                cover_url: None,
+                duration: None,
+            };
+
+            // The end and the information of the previous song are ignored
+            let old_info = Box::new(metadata("Old song"));
+            commands_tx
+                .send(Command::SetSongInfo(1, None, old_info))
+                .unwrap();
+            let info = Box::new(metadata("Song"));
+            commands_tx
+                .send(Command::SetSongInfo(2, None, info))
+                .unwrap();
+            commands_tx.send(Command::SongEnded(1)).unwrap();
+            commands_tx.send(Command::SongEnded(2)).unwrap();
+            wait_for_song_end(
+                &sink,
+                &mut status,
+                &commands_rx,
+                &status_tx,
+                &[media_tx],
+                &SharedStatus::default(),
+            )
+            .unwrap();
+            let titles: Vec<_> = media_rx
+                .try_iter()
+                .filter_map(|update| match update {
+                    MediaUpdate::Metadata(metadata) => Some(metadata.title),
+                    _ => None,
+                })
+                .collect();
+            done_tx.send((status.song_ended, titles)).unwrap();
+        });
+        let (song_ended, titles) = done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(song_ended);
+        assert_eq!(titles, ["Song"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn quit_when_the_socket_is_in_use() {
+        use std::{env::temp_dir, fs, os::unix::net::UnixListener, process, thread::scope};
+
+        use super::{
+            plugin::{Plugin, PluginContext},
+            spawn_plugins, spawn_remote_controls, stop_threads,
+        };
+
+        struct Shutdown(Sender<()>);
+
+        impl Plugin for Shutdown {
+            fn on_shutdown(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+                self.0.send(())?;
                 Ok(())
-            })?;
-            if status.stop {
-                for tx in stop_list {
-                    tx.send(())?;
-                }
-                break 'mainloop;
             }
         }
-        Ok(())
-    })
+
+        let path = temp_dir().join(format!("audio-player-test-quit-{}.sock", process::id()));
+        let _other_player = UnixListener::bind(&path).unwrap();
+        let options = Options {
+            socket: Some(path.clone()),
+            ..Options::default()
+        };
+        let (done_tx, done_rx) = channel();
+        let (shutdown_tx, shutdown_rx) = channel();
+        spawn(move || {
+            scope(|s| {
+                let mut stop_list = vec![];
+                let mut get_stop_rx = || {
+                    let (stop_tx, stop_rx) = sync_channel(1);
+                    stop_list.push(stop_tx);
+                    stop_rx
+                };
+                let (commands_tx, commands_rx) = channel();
+                let shared_status = Arc::new(SharedStatus::default());
+                let (_keys_tx, keys_rx) = channel();
+                // The control socket is the first thread to stop
+                spawn_remote_controls(s, &options, &commands_tx, &shared_status, &mut get_stop_rx);
+                let _updates_tx = spawn_plugins(
+                    s,
+                    vec![Box::new(Shutdown(shutdown_tx))],
+                    &commands_tx,
+                    &shared_status,
+                    keys_rx,
+                    &mut get_stop_rx,
+                );
+                // Wait for the error of the control socket
+                let Ok(Command::DisplayMessage(message)) =
+                    commands_rx.recv_timeout(Duration::from_secs(5))
+                else {
+                    panic!("The control socket should fail");
+                };
+                assert!(message.message.contains("Another player is listening"));
+
+                let (sink, _queue) = Sink::new_idle();
+                let mut status = Status::new(1, &Options::default()).unwrap();
+                Command::Quit.handle(&sink, &mut status);
+                assert!(status.stop);
+                stop_threads(stop_list);
+            });
+            done_tx.send(()).unwrap();
+        });
+        // The other threads are stopped
+        shutdown_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        // The socket of the other player is kept
+        assert!(path.exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn new_songs() {
+        let (sink, _queue) = Sink::new_idle();
+        let mut status = Status::new(2, &Options::default()).unwrap();
+        status.song_names = Arc::new(["a.mp3".to_owned(), "b.mp3".to_owned()]);
+        Command::AppendSongs(vec!["b.mp3".to_owned(), "c.mp3".to_owned()])
+            .handle(&sink, &mut status);
+        Command::AppendSongs(vec![
+            "a.mp3".to_owned(),
+            "c.mp3".to_owned(),
+            "d.mp3".to_owned(),
+        ])
+        .handle(&sink, &mut status);
+        // The songs of the queue and the songs sent twice are skipped
+        assert_eq!(status.take_new_songs(), ["c.mp3", "d.mp3"]);
+        assert!(status.take_new_songs().is_empty());
+    }
 }
@@ -0,0 +1,672 @@
+//! A subset of the [Music Player Daemon protocol](https://mpd.readthedocs.io/en/latest/protocol.html),
+//! so the MPD clients can control the player.
+//!
+//! The supported commands are `status`, `currentsong`, `playlistinfo`, `play`, `playid`,
+//! `pause`, `next`, `previous`, `seekcur`, `setvol`, `idle`, `noidle`, `ping`, `close`
+//! and the command lists.
+use std::{
+    fmt::Write as _,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    mem,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use crate::song::EBox;
+
+use super::{
+    protocol::parse_duration, shared_status::SharedStatus, terminal_ui::PartialStatus, Command,
+};
+
+/// The greeting sent to the clients (with the version of the protocol).
+const GREETING: &str = "OK MPD 0.23.0\n";
+
+/// The interval at which the server checks if the player has stopped.
+static ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The interval at which `idle` checks if the client has sent `noidle`.
+static IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The maximum difference between the expected and the actual position before we consider
+/// that the player has seeked.
+static SEEK_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// An error of the protocol (`ACK`).
+struct Ack {
+    /// The error code.
+    code: u8,
+    /// The error message.
+    message: String,
+}
+
+/// The error code for invalid arguments.
+const ACK_ERROR_ARG: u8 = 2;
+/// The error code for unknown commands.
+const ACK_ERROR_UNKNOWN: u8 = 5;
+/// The error code for songs that don't exist.
+const ACK_ERROR_NO_EXIST: u8 = 50;
+
+impl Ack {
+    /// Creates an error for an invalid argument.
+    fn argument(message: &str) -> Self {
+        Self {
+            code: ACK_ERROR_ARG,
+            message: message.to_owned(),
+        }
+    }
+
+    /// Formats the error for the `index`-th command of a list.
+    fn format(&self, index: usize, command: &str) -> String {
+        format!(
+            "ACK [{}@{index}] {{{command}}} {}\n",
+            self.code, self.message
+        )
+    }
+}
+
+/// Serves the MPD protocol on `address` until the player stops.
+///
+/// Each connection is handled in its own thread.
+///
+/// # Errors
+/// Fails if the server can't listen on `address`.
+pub fn mpd_server(
+    address: SocketAddr,
+    tx: &Sender<Command>,
+    status: &Arc<SharedStatus>,
+    stop_rx: &Receiver<()>,
+) -> Result<(), EBox> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+
+    while let Err(TryRecvError::Empty) = stop_rx.try_recv() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let tx = tx.clone();
+                let status = status.clone();
+                // The connection threads are not joined, they end with the process
+                spawn(move || Connection::new(stream, tx, status)?.run());
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => sleep(ACCEPT_POLL_INTERVAL),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Splits a request into its arguments, with the quoted arguments of the protocol.
+///
+/// # Errors
+/// Fails if a quote is not closed.
+///
+/// # Examples
+/// ```text
+/// play 3            -> ["play", "3"]
+/// seekcur "+5"      -> ["seekcur", "+5"]
+/// find "a \"b\""    -> ["find", "a \"b\""]
+/// ```
+fn split_arguments(line: &str) -> Result<Vec<String>, Ack> {
+    let mut arguments = vec![];
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut argument = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => argument.extend(chars.next()),
+                    Some(c) => argument.push(c),
+                    None => return Err(Ack::argument("Missing closing quote")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                argument.push(c);
+            }
+        }
+        arguments.push(argument);
+    }
+    Ok(arguments)
+}
+
+/// Returns a version of the queue that changes when the queue changes.
+fn playlist_version(song_names: &[String]) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    song_names.hash(&mut hasher);
+    #[expect(clippy::cast_possible_truncation, reason = "this is a hash")]
+    let version = hasher.finish() as u32;
+    version
+}
+
+/// Returns the subsystems (as in the `idle` command) that have changed between two statuses.
+fn changed_subsystems(old: Option<&PartialStatus>, new: &PartialStatus) -> Vec<&'static str> {
+    let Some(old) = old else {
+        return vec!["playlist", "player", "mixer"];
+    };
+    let mut subsystems = vec![];
+
+    let same_queue = Arc::ptr_eq(&old.song_names, &new.song_names);
+    if !same_queue {
+        subsystems.push("playlist");
+    }
+    let expected_time = if old.paused {
+        old.time
+    } else {
+        old.time + new.timestamp.saturating_duration_since(old.timestamp)
+    };
+    if !same_queue
+        || old.position != new.position
+        || old.paused != new.paused
+        || expected_time.abs_diff(new.time) > SEEK_THRESHOLD
+    {
+        subsystems.push("player");
+    }
+    if old.volume_percent() != new.volume_percent() {
+        subsystems.push("mixer");
+    }
+    subsystems
+}
+
+/// A connection with an MPD client.
+struct Connection {
+    /// The stream used to read the requests.
+    reader: BufReader<TcpStream>,
+    /// The stream used to write the responses.
+    writer: TcpStream,
+    /// The line that is being read.
+    line: String,
+    /// The channel used to send the commands to the player.
+    tx: Sender<Command>,
+    /// The status of the player.
+    status: Arc<SharedStatus>,
+}
+
+impl Connection {
+    /// Creates a new [`Connection`].
+    ///
+    /// # Errors
+    /// Fails if the stream can't be configured.
+    fn new(
+        stream: TcpStream,
+        tx: Sender<Command>,
+        status: Arc<SharedStatus>,
+    ) -> Result<Self, EBox> {
+        stream.set_nonblocking(false)?;
+        Ok(Self {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            line: String::new(),
+            tx,
+            status,
+        })
+    }
+
+    /// Reads a line, waiting at most `timeout` (or forever if it's [`None`]).
+    ///
+    /// Returns `Ok(None)` if no complete line has been received before the timeout.
+    ///
+    /// # Errors
+    /// Fails if the connection is closed.
+    fn read_line(&mut self, timeout: Option<Duration>) -> Result<Option<String>, EBox> {
+        self.reader.get_ref().set_read_timeout(timeout)?;
+        match self.reader.read_line(&mut self.line) {
+            Ok(0) => Err("Connection closed".into()),
+            // The incomplete lines are kept in `self.line` and completed on the next call
+            Ok(_) if self.line.ends_with('\n') => Ok(Some(mem::take(&mut self.line))),
+            Ok(_) => Ok(None),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Answers the requests until the client disconnects.
+    ///
+    /// # Errors
+    /// Fails if the connection is closed unexpectedly.
+    fn run(mut self) -> Result<(), EBox> {
+        self.writer.write_all(GREETING.as_bytes())?;
+
+        loop {
+            let Some(line) = self.read_line(None)? else {
+                continue;
+            };
+            let arguments = match split_arguments(&line) {
+                Ok(arguments) => arguments,
+                Err(ack) => {
+                    self.writer.write_all(ack.format(0, "").as_bytes())?;
+                    continue;
+                }
+            };
+            let Some((command, arguments)) = arguments.split_first() else {
+                continue;
+            };
+
+            let response = match command.as_str() {
+                "close" => return Ok(()),
+                "idle" => self.idle(arguments)?,
+                "command_list_begin" | "command_list_ok_begin" => {
+                    self.command_list(command == "command_list_ok_begin")?
+                }
+                _ => match self.execute(command, arguments) {
+                    Ok(response) => response + "OK\n",
+                    Err(ack) => ack.format(0, command),
+                },
+            };
+            self.writer.write_all(response.as_bytes())?;
+        }
+    }
+
+    /// Reads the commands of a list until `command_list_end` and executes them.
+    ///
+    /// # Errors
+    /// Fails if the connection is closed.
+    fn command_list(&mut self, list_ok: bool) -> Result<String, EBox> {
+        let mut commands = vec![];
+        loop {
+            let Some(line) = self.read_line(None)? else {
+                continue;
+            };
+            if line.trim() == "command_list_end" {
+                break;
+            }
+            commands.push(line);
+        }
+
+        let mut response = String::new();
+        for (index, line) in commands.iter().enumerate() {
+            let arguments = match split_arguments(line) {
+                Ok(arguments) => arguments,
+                Err(ack) => return Ok(response + ack.format(index, "").as_str()),
+            };
+            let Some((command, arguments)) = arguments.split_first() else {
+                continue;
+            };
+            match self.execute(command, arguments) {
+                Ok(output) => response += &output,
+                Err(ack) => return Ok(response + ack.format(index, command).as_str()),
+            }
+            if list_ok {
+                response += "list_OK\n";
+            }
+        }
+        Ok(response + "OK\n")
+    }
+
+    /// Waits until a subsystem changes or until the client sends `noidle`.
+    ///
+    /// # Errors
+    /// Fails if the connection is closed.
+    fn idle(&mut self, subsystems: &[String]) -> Result<String, EBox> {
+        let (mut version, mut old) = self.status.get();
+        loop {
+            if let Some(line) = self.read_line(Some(IDLE_POLL_INTERVAL))? {
+                if line.trim() != "noidle" {
+                    return Err("Only noidle is allowed during idle".into());
+                }
+                return Ok("OK\n".to_owned());
+            }
+
+            let (new_version, new) = self.status.wait_for_change(version, IDLE_POLL_INTERVAL);
+            if new_version == version {
+                continue;
+            }
+            version = new_version;
+            let Some(new) = new else {
+                continue;
+            };
+            let changed: Vec<_> = changed_subsystems(old.as_ref(), &new)
+                .into_iter()
+                .filter(|subsystem| {
+                    subsystems.is_empty() || subsystems.iter().any(|s| s == subsystem)
+                })
+                .collect();
+            old = Some(new);
+            if !changed.is_empty() {
+                let mut response = String::new();
+                for subsystem in changed {
+                    let _ = writeln!(response, "changed: {subsystem}");
+                }
+                response += "OK\n";
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Sends a command to the player.
+    ///
+    /// # Errors
+    /// Fails if the player has stopped.
+    fn send(&self, command: Command) -> Result<String, Ack> {
+        self.tx.send(command).map_err(|_| Ack {
+            code: ACK_ERROR_UNKNOWN,
+            message: "The player has stopped".to_owned(),
+        })?;
+        Ok(String::new())
+    }
+
+    /// Executes a command (except `idle`, `close` and the command lists)
+    /// and returns its output (without `OK`).
+    ///
+    /// # Errors
+    /// Fails if the command is unknown or if an argument is invalid.
+    fn execute(&self, command: &str, arguments: &[String]) -> Result<String, Ack> {
+        let argument = arguments.first().map(String::as_str);
+        let status = self.status.get().1;
+
+        match command {
+            "ping" => Ok(String::new()),
+            "status" => Ok(status
+                .map(|status| format_status(&status))
+                .unwrap_or_default()),
+            "currentsong" => Ok(status
+                .and_then(|status| format_song(&status, status.position))
+                .unwrap_or_default()),
+            "playlistinfo" => {
+                let Some(status) = status else {
+                    return Ok(String::new());
+                };
+                match argument {
+                    Some(position) => {
+                        let position = position
+                            .parse()
+                            .map_err(|_| Ack::argument("Invalid song position"))?;
+                        format_song(&status, position).ok_or(Ack {
+                            code: ACK_ERROR_NO_EXIST,
+                            message: "Bad song index".to_owned(),
+                        })
+                    }
+                    None => Ok((0..status.song_names.len())
+                        .filter_map(|position| format_song(&status, position))
+                        .collect()),
+                }
+            }
+            "play" | "playid" => match argument {
+                Some(position) => self.send(Command::PlaySong(
+                    position
+                        .parse()
+                        .map_err(|_| Ack::argument("Invalid song position"))?,
+                )),
+                None => self.send(Command::Play),
+            },
+            "pause" => match argument {
+                Some("1") => self.send(Command::Pause),
+                Some("0") => self.send(Command::Play),
+                Some(_) => Err(Ack::argument("The argument must be 0 or 1")),
+                None => self.send(Command::PlayPause),
+            },
+            "next" => self.send(Command::Next),
+            "previous" => self.send(Command::Previous),
+            "seekcur" => {
+                let time = argument.ok_or_else(|| Ack::argument("Missing time"))?;
+                let duration =
+                    |time: &str| parse_duration(time).ok_or_else(|| Ack::argument("Invalid time"));
+                if let Some(offset) = time.strip_prefix('+') {
+                    self.send(Command::SeekRight(duration(offset)?))
+                } else if let Some(offset) = time.strip_prefix('-') {
+                    self.send(Command::SeekLeft(duration(offset)?))
+                } else {
+                    self.send(Command::SeekTo(duration(time)?))
+                }
+            }
+            "setvol" => self.send(Command::SetVolume(
+                argument
+                    .and_then(|volume| volume.parse().ok())
+                    .filter(|volume| *volume <= 100)
+                    .ok_or_else(|| Ack::argument("Invalid volume"))?,
+            )),
+            _ => Err(Ack {
+                code: ACK_ERROR_UNKNOWN,
+                message: format!("unknown command \"{command}\""),
+            }),
+        }
+    }
+}
+
+/// Formats the response of the `status` command.
+fn format_status(status: &PartialStatus) -> String {
+    let time = status.current_time();
+    let mut response = format!(
+        "volume: {}\n\
+        repeat: 1\n\
+        random: {}\n\
+        single: 0\n\
+        consume: 0\n\
+        playlist: {}\n\
+        playlistlength: {}\n\
+        state: {}\n",
+        status.volume_percent(),
+        u8::from(status.shuffle),
+        playlist_version(&status.song_names),
+        status.song_names.len(),
+        if status.paused { "pause" } else { "play" },
+    );
+    if status.position < status.song_names.len() {
+        let _ = write!(
+            response,
+            "song: {0}\n\
+            songid: {0}\n\
+            time: {1}:{2}\n\
+            elapsed: {3:.3}\n\
+            duration: {4:.3}\n",
+            status.position,
+            time.as_secs(),
+            status.total_time.as_secs(),
+            time.as_secs_f64(),
+            status.total_time.as_secs_f64(),
+        );
+    }
+    response
+}
+
+/// Formats the information about a song of the queue
+/// (or returns [`None`] if there is no song at this `position`).
+fn format_song(status: &PartialStatus, position: usize) -> Option<String> {
+    let name = status.song_names.get(position)?;
+    let mut response = format!("file: {name}\nTitle: {name}\nPos: {position}\nId: {position}\n");
+    if position == status.position && !status.total_time.is_zero() {
+        let _ = write!(
+            response,
+            "Time: {}\nduration: {:.3}\n",
+            status.total_time.as_secs(),
+            status.total_time.as_secs_f64()
+        );
+    }
+    Some(response)
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{
+            mpsc::{channel, sync_channel},
+            Arc,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use super::{mpd_server, split_arguments};
+    use crate::player::{shared_status::SharedStatus, terminal_ui::PartialStatus, Command};
+
+    /// Returns a [`PartialStatus`] where the given song is playing.
+    fn status(song_names: &Arc<[String]>, position: usize) -> PartialStatus {
+        PartialStatus {
+            song_names: song_names.clone(),
+            position,
+            scrollbar_position: position,
+            time: Duration::from_secs(12),
+            total_time: Duration::from_secs(90),
+            paused: true,
+            volume: 0.5,
+            ..PartialStatus::default()
+        }
+    }
+
+    /// A client of the MPD server.
+    struct Client {
+        /// The stream used to read the responses.
+        reader: BufReader<TcpStream>,
+        /// The stream used to write the requests.
+        writer: TcpStream,
+    }
+
+    impl Client {
+        /// Sends a request and returns the lines of the response (including `OK` or `ACK`).
+        fn request(&mut self, request: &str) -> Vec<String> {
+            self.writer.write_all(request.as_bytes()).unwrap();
+            self.writer.write_all(b"\n").unwrap();
+            let mut lines = vec![];
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_owned();
+                let end = line == "OK" || line.starts_with("ACK");
+                lines.push(line);
+                if end {
+                    return lines;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(
+            split_arguments(r#"find  "a \"b\"" c"#).ok(),
+            Some(vec![
+                "find".to_owned(),
+                r#"a "b""#.to_owned(),
+                "c".to_owned()
+            ])
+        );
+        assert!(split_arguments(r#"play "3"#).is_err());
+    }
+
+    #[test]
+    fn protocol() {
+        // Find a free port
+        let address: SocketAddr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (tx, rx) = channel();
+        let song_names: Arc<[String]> = Arc::new(["a.mp3".to_owned(), "b.mp3".to_owned()]);
+        let shared_status = Arc::new(SharedStatus::default());
+        shared_status.set(status(&song_names, 0));
+        let (stop_tx, stop_rx) = sync_channel(1);
+
+        let server_status = shared_status.clone();
+        let handle = spawn(move || {
+            mpd_server(address, &tx, &server_status, &stop_rx).map_err(|err| err.to_string())
+        });
+        let stream = loop {
+            if let Ok(stream) = TcpStream::connect(address) {
+                break stream;
+            }
+            sleep(Duration::from_millis(10));
+        };
+        let mut client = Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        let mut greeting = String::new();
+        client.reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("OK MPD "));
+
+        let status_lines = client.request("status");
+        for line in [
+            "volume: 50",
+            "random: 0",
+            "state: pause",
+            "song: 0",
+            "playlistlength: 2",
+            "OK",
+        ] {
+            assert!(
+                status_lines.iter().any(|status_line| status_line == line),
+                "{line} not in {status_lines:?}"
+            );
+        }
+        assert_eq!(
+            client.request("currentsong"),
+            [
+                "file: a.mp3",
+                "Title: a.mp3",
+                "Pos: 0",
+                "Id: 0",
+                "Time: 90",
+                "duration: 90.000",
+                "OK"
+            ]
+        );
+        assert_eq!(
+            client
+                .request("playlistinfo")
+                .iter()
+                .filter(|line| line.starts_with("file:"))
+                .count(),
+            2
+        );
+        assert_eq!(
+            client.request("playlistinfo 5").last().unwrap(),
+            "ACK [50@0] {playlistinfo} Bad song index"
+        );
+
+        assert_eq!(client.request("play \"1\""), ["OK"]);
+        assert!(matches!(rx.recv().unwrap(), Command::PlaySong(1)));
+        assert_eq!(client.request("pause 1"), ["OK"]);
+        assert!(matches!(rx.recv().unwrap(), Command::Pause));
+        assert_eq!(client.request("seekcur +5"), ["OK"]);
+        assert!(
+            matches!(rx.recv().unwrap(), Command::SeekRight(offset) if offset == Duration::from_secs(5))
+        );
+        assert_eq!(client.request("setvol 30"), ["OK"]);
+        assert!(matches!(rx.recv().unwrap(), Command::SetVolume(30)));
+        assert_eq!(
+            client.request("setvol 300"),
+            ["ACK [2@0] {setvol} Invalid volume"]
+        );
+        assert_eq!(
+            client.request("dance"),
+            ["ACK [5@0] {dance} unknown command \"dance\""]
+        );
+
+        assert_eq!(
+            client.request("command_list_ok_begin\nnext\nprevious\ndance\nnext\ncommand_list_end"),
+            [
+                "list_OK",
+                "list_OK",
+                "ACK [5@2] {dance} unknown command \"dance\""
+            ]
+        );
+        assert!(matches!(rx.recv().unwrap(), Command::Next));
+        assert!(matches!(rx.recv().unwrap(), Command::Previous));
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(client.request("idle\nnoidle"), ["OK"]);
+        let idle = spawn(move || client.request("idle player"));
+        sleep(Duration::from_millis(200));
+        shared_status.set(status(&song_names, 1));
+        assert_eq!(idle.join().unwrap(), ["changed: player", "OK"]);
+
+        // The server also stops when the sender is dropped
+        drop(stop_tx);
+        handle.join().unwrap().unwrap();
+    }
+}
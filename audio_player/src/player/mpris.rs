@@ -0,0 +1,682 @@
+//! Implementation for the media controls on Linux and the BSDs.
+//!
+//! The player serves the MPRIS objects itself (instead of using `souvlaki`, like the other
+//! platforms), to publish its shuffle and loop states.
+//! See <https://specifications.freedesktop.org/mpris-spec/latest/>.
+use std::{
+    sync::mpsc::{Receiver, Sender, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use dbus::{
+    arg::{PropMap, Variant},
+    blocking::{stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged, Connection},
+    channel::Sender as _,
+    message::SignalArgs,
+    Message, MethodErr, Path,
+};
+use dbus_crossroads::{Crossroads, IfaceBuilder};
+
+use crate::song::EBox;
+
+use super::{Command, MediaUpdate, Metadata};
+
+/// The name of the player on the session bus.
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.audio_player";
+
+/// The path of the MPRIS object.
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// The MPRIS interface that describes the player.
+const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+
+/// The MPRIS interface that contains the playback state.
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// The loop status of the player: it always loops over the queue.
+const LOOP_STATUS: &str = "Playlist";
+
+/// The interval at which the D-Bus messages and the updates are handled.
+static POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The difference between the expected and the reported positions above which a seek is signaled.
+static SEEK_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Converts a [`Duration`] to the microseconds used by MPRIS.
+fn micros(duration: Duration) -> i64 {
+    i64::try_from(duration.as_micros()).unwrap_or(i64::MAX)
+}
+
+/// The state of the player, as published over MPRIS.
+struct State {
+    /// The [`Sender`] of the commands of the player.
+    tx: Sender<Command>,
+    /// The metadata of the current song.
+    metadata: Option<Metadata>,
+    /// The number of the current song, for its track ID.
+    track: u64,
+    /// Is the player paused? [`None`] if it's stopped.
+    paused: Option<bool>,
+    /// The position in the current song, at `updated`.
+    position: Duration,
+    /// When `position` was updated.
+    updated: Instant,
+    /// Is the queue shuffled when it's restarted?
+    shuffle: bool,
+    /// The volume, from 0 to 1.
+    volume: f64,
+    /// Is the current song an endless stream, which can't be seeked?
+    live: bool,
+}
+
+impl State {
+    /// Creates the state of a stopped player, that sends its commands to `tx`.
+    fn new(tx: Sender<Command>) -> Self {
+        Self {
+            tx,
+            metadata: None,
+            track: 0,
+            paused: None,
+            position: Duration::ZERO,
+            updated: Instant::now(),
+            shuffle: false,
+            volume: 1.0,
+            live: false,
+        }
+    }
+
+    /// Sends a [`Command`] to the player.
+    ///
+    /// # Errors
+    /// Fails if the player has stopped.
+    fn send(&self, command: Command) -> Result<(), MethodErr> {
+        self.tx
+            .send(command)
+            .map_err(|_| MethodErr::failed("The player has stopped"))
+    }
+
+    /// Returns the MPRIS playback status.
+    fn playback_status(&self) -> &'static str {
+        match self.paused {
+            None => "Stopped",
+            Some(true) => "Paused",
+            Some(false) => "Playing",
+        }
+    }
+
+    /// Returns the current position, which moves since the last update if the player is playing.
+    fn current_position(&self) -> Duration {
+        if self.paused == Some(false) {
+            self.position + self.updated.elapsed()
+        } else {
+            self.position
+        }
+    }
+
+    /// Returns the track ID of the current song.
+    fn track_id(&self) -> Path<'static> {
+        Path::from(format!("{OBJECT_PATH}/track/{}", self.track))
+    }
+
+    /// Returns the MPRIS metadata of the current song.
+    fn metadata(&self) -> PropMap {
+        let mut map = PropMap::new();
+        let Some(metadata) = &self.metadata else {
+            return map;
+        };
+        map.insert(
+            "mpris:trackid".to_owned(),
+            Variant(Box::new(self.track_id())),
+        );
+        map.insert(
+            "xesam:title".to_owned(),
+            Variant(Box::new(metadata.title.clone())),
+        );
+        if let Some(artist) = &metadata.artist {
+            map.insert(
+                "xesam:artist".to_owned(),
+                Variant(Box::new(vec![artist.clone()])),
+            );
+        }
+        if let Some(album) = &metadata.album {
+            map.insert("xesam:album".to_owned(), Variant(Box::new(album.clone())));
+        }
+        if let Some(cover_url) = &metadata.cover_url {
+            map.insert(
+                "mpris:artUrl".to_owned(),
+                Variant(Box::new(cover_url.clone())),
+            );
+        }
+        if let Some(duration) = metadata.duration {
+            map.insert(
+                "mpris:length".to_owned(),
+                Variant(Box::new(micros(duration))),
+            );
+        }
+        map
+    }
+
+    /// Applies a [`MediaUpdate`] and adds the properties that it changes to `changed`.
+    ///
+    /// Returns the new position if the player has seeked.
+    fn update(&mut self, update: MediaUpdate, changed: &mut PropMap) -> Option<Duration> {
+        match update {
+            MediaUpdate::Metadata(metadata) => {
+                self.metadata = Some(metadata);
+                self.track += 1;
+                self.position = Duration::ZERO;
+                self.updated = Instant::now();
+                changed.insert("Metadata".to_owned(), Variant(Box::new(self.metadata())));
+                None
+            }
+            MediaUpdate::Playback {
+                paused,
+                position,
+                shuffle,
+                volume,
+                live,
+            } => {
+                let seeked = self.current_position().abs_diff(position) > SEEK_THRESHOLD;
+                if self.paused != Some(paused) {
+                    self.paused = Some(paused);
+                    changed.insert(
+                        "PlaybackStatus".to_owned(),
+                        Variant(Box::new(self.playback_status().to_owned())),
+                    );
+                }
+                if self.shuffle != shuffle {
+                    self.shuffle = shuffle;
+                    changed.insert("Shuffle".to_owned(), Variant(Box::new(shuffle)));
+                }
+                let volume = f64::from(volume).clamp(0.0, 1.0);
+                if (self.volume - volume).abs() > f64::EPSILON {
+                    self.volume = volume;
+                    changed.insert("Volume".to_owned(), Variant(Box::new(volume)));
+                }
+                if self.live != live {
+                    self.live = live;
+                    changed.insert("CanSeek".to_owned(), Variant(Box::new(!live)));
+                }
+                self.position = position;
+                self.updated = Instant::now();
+                seeked.then_some(position)
+            }
+            MediaUpdate::Finished => None,
+        }
+    }
+}
+
+/// Converts an MPRIS volume (from 0 to 1) to a percentage.
+#[expect(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    reason = "the volume is between 0 and 100"
+)]
+fn volume_percent(volume: f64) -> u8 {
+    (volume * 100.0).round().clamp(0.0, 100.0) as u8
+}
+
+/// Registers the root interface of MPRIS.
+fn register_root(builder: &mut IfaceBuilder<State>) {
+    builder.property("CanQuit").get(|_, _| Ok(true));
+    builder.property("CanRaise").get(|_, _| Ok(false));
+    builder.property("HasTrackList").get(|_, _| Ok(false));
+    builder
+        .property("Identity")
+        .get(|_, _| Ok("audio-player".to_owned()));
+    builder
+        .property("SupportedUriSchemes")
+        .get(|_, _| Ok(Vec::<String>::new()));
+    builder
+        .property("SupportedMimeTypes")
+        .get(|_, _| Ok(Vec::<String>::new()));
+    builder.method("Raise", (), (), |_, _, ()| Ok(()));
+    builder.method("Quit", (), (), |_, state: &mut State, ()| {
+        state.send(Command::Quit)
+    });
+}
+
+/// Registers the player interface of MPRIS.
+fn register_player(builder: &mut IfaceBuilder<State>) {
+    register_player_properties(builder);
+    builder.signal::<(i64,), _>("Seeked", ("Position",));
+
+    builder.method("Next", (), (), |_, state: &mut State, ()| {
+        state.send(Command::Next)
+    });
+    builder.method("Previous", (), (), |_, state: &mut State, ()| {
+        state.send(Command::Previous)
+    });
+    builder.method("Pause", (), (), |_, state: &mut State, ()| {
+        state.send(Command::Pause)
+    });
+    builder.method("PlayPause", (), (), |_, state: &mut State, ()| {
+        state.send(Command::PlayPause)
+    });
+    builder.method("Stop", (), (), |_, state: &mut State, ()| {
+        state.send(Command::Quit)
+    });
+    builder.method("Play", (), (), |_, state: &mut State, ()| {
+        state.send(Command::Play)
+    });
+    builder.method(
+        "Seek",
+        ("Offset",),
+        (),
+        |_, state: &mut State, (offset,): (i64,)| {
+            let duration = Duration::from_micros(offset.unsigned_abs());
+            state.send(if offset < 0 {
+                Command::SeekLeft(duration)
+            } else {
+                Command::SeekRight(duration)
+            })
+        },
+    );
+    // The positions of another song or outside of the song are ignored
+    builder.method(
+        "SetPosition",
+        ("TrackId", "Position"),
+        (),
+        |_, state: &mut State, (track_id, position): (Path<'static>, i64)| {
+            let Ok(position) = u64::try_from(position).map(Duration::from_micros) else {
+                return Ok(());
+            };
+            let duration = state
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.duration);
+            if track_id == state.track_id() && duration.is_none_or(|duration| position <= duration)
+            {
+                state.send(Command::SeekTo(position))?;
+            }
+            Ok(())
+        },
+    );
+    builder.method(
+        "OpenUri",
+        ("Uri",),
+        (),
+        |_, _, (_uri,): (String,)| -> Result<(), MethodErr> {
+            Err(MethodErr::failed("Opening a URI isn't supported"))
+        },
+    );
+}
+
+/// Registers the properties of the player interface of MPRIS.
+fn register_player_properties(builder: &mut IfaceBuilder<State>) {
+    builder
+        .property("PlaybackStatus")
+        .get(|_, state| Ok(state.playback_status().to_owned()));
+    builder
+        .property("LoopStatus")
+        .get(|_, _| Ok(LOOP_STATUS.to_owned()))
+        .set(|_, _, status: String| {
+            if status == LOOP_STATUS {
+                Ok(None)
+            } else {
+                Err(MethodErr::failed("The player always loops over the queue"))
+            }
+        });
+    builder.property("Rate").get(|_, _| Ok(1.0));
+    builder.property("MinimumRate").get(|_, _| Ok(1.0));
+    builder.property("MaximumRate").get(|_, _| Ok(1.0));
+    builder
+        .property("Shuffle")
+        .get(|_, state| Ok(state.shuffle))
+        .set(|_, state, shuffle: bool| {
+            state.send(Command::SetShuffle(shuffle))?;
+            state.shuffle = shuffle;
+            Ok(Some(shuffle))
+        });
+    builder
+        .property("Metadata")
+        .get(|_, state| Ok(state.metadata()));
+    builder
+        .property("Volume")
+        .get(|_, state| Ok(state.volume))
+        .set(|_, state, volume: f64| {
+            let volume = volume.clamp(0.0, 1.0);
+            state.send(Command::SetVolume(volume_percent(volume)))?;
+            state.volume = volume;
+            Ok(Some(volume))
+        });
+    builder
+        .property("Position")
+        .get(|_, state| Ok(micros(state.current_position())))
+        .emits_changed_false();
+    // The live streams can't be seeked
+    builder.property("CanSeek").get(|_, state| Ok(!state.live));
+    for name in [
+        "CanGoNext",
+        "CanGoPrevious",
+        "CanPlay",
+        "CanPause",
+        "CanControl",
+    ] {
+        builder.property(name).get(|_, _| Ok(true));
+    }
+}
+
+/// Register media controls.
+///
+/// # Errors
+/// Fails if the session bus isn't available or if the media controls can't be published.
+pub fn media_controls(
+    tx: Sender<Command>,
+    updates_rx: &Receiver<MediaUpdate>,
+    stop_rx: &Receiver<()>,
+) -> Result<(), EBox> {
+    serve(&Connection::new_session()?, tx, updates_rx, stop_rx)
+}
+
+/// Serves the MPRIS objects on `connection` until the player stops.
+///
+/// # Errors
+/// Fails if the name of the player can't be requested or if the connection is closed.
+fn serve(
+    connection: &Connection,
+    tx: Sender<Command>,
+    updates_rx: &Receiver<MediaUpdate>,
+    stop_rx: &Receiver<()>,
+) -> Result<(), EBox> {
+    connection.request_name(BUS_NAME, false, true, false)?;
+
+    let path = Path::from(OBJECT_PATH);
+    let mut crossroads = Crossroads::new();
+    let root = crossroads.register(ROOT_INTERFACE, register_root);
+    let player = crossroads.register(PLAYER_INTERFACE, register_player);
+    crossroads.insert(path.clone(), &[root, player], State::new(tx));
+
+    let emit = |changed: PropMap| -> Result<(), EBox> {
+        let signal = PropertiesPropertiesChanged {
+            interface_name: PLAYER_INTERFACE.to_owned(),
+            changed_properties: changed,
+            invalidated_properties: vec![],
+        };
+        connection
+            .send(signal.to_emit_message(&path))
+            .map_err(|()| "The D-Bus connection is closed")?;
+        Ok(())
+    };
+
+    let mut running = true;
+    while running {
+        connection
+            .channel()
+            .read_write(Some(POLL_INTERVAL))
+            .map_err(|()| "The D-Bus connection is closed")?;
+        while let Some(message) = connection.channel().pop_message() {
+            // The messages that aren't method calls are ignored
+            let _ = crossroads.handle_message(message, connection);
+        }
+
+        let state = crossroads
+            .data_mut::<State>(&path)
+            .ok_or("The MPRIS object is missing")?;
+        let mut changed = PropMap::new();
+        loop {
+            match updates_rx.try_recv() {
+                Ok(update) => {
+                    if let Some(position) = state.update(update, &mut changed) {
+                        let seeked = Message::new_signal(&*path, PLAYER_INTERFACE, "Seeked")?
+                            .append1(micros(position));
+                        connection
+                            .send(seeked)
+                            .map_err(|()| "The D-Bus connection is closed")?;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    running = false;
+                    break;
+                }
+            }
+        }
+        if !changed.is_empty() {
+            emit(changed)?;
+        }
+        if let Ok(()) | Err(TryRecvError::Disconnected) = stop_rx.try_recv() {
+            running = false;
+        }
+    }
+
+    if let Some(state) = crossroads.data_mut::<State>(&path) {
+        state.paused = None;
+        let mut changed = PropMap::new();
+        changed.insert(
+            "PlaybackStatus".to_owned(),
+            Variant(Box::new(state.playback_status().to_owned())),
+        );
+        emit(changed)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command as Process, Stdio},
+        sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        thread::{sleep, spawn, JoinHandle},
+        time::Duration,
+    };
+
+    use dbus::{
+        arg::{PropMap, RefArg},
+        blocking::{stdintf::org_freedesktop_dbus::Properties, Connection},
+        channel::Channel,
+    };
+
+    use super::{serve, BUS_NAME, OBJECT_PATH, PLAYER_INTERFACE};
+    use crate::player::{Command, MediaUpdate, Metadata};
+
+    /// A private D-Bus session bus that is stopped when dropped.
+    struct PrivateBus(Child);
+
+    impl PrivateBus {
+        /// Starts a private session bus and returns it with its address.
+        fn start() -> (Self, String) {
+            let mut child = Process::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("dbus-daemon should be available");
+            let mut address = String::new();
+            BufReader::new(child.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            (Self(child), address.trim().to_owned())
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Connects to the bus at `address`.
+    fn connect(address: &str) -> Connection {
+        let mut channel = Channel::open_private(address).unwrap();
+        channel.register().unwrap();
+        Connection::from(channel)
+    }
+
+    /// Calls `f` until it returns [`Some`] value (or panics after 5 seconds).
+    fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> T {
+        for _ in 0..50 {
+            if let Some(value) = f() {
+                return value;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("timed out");
+    }
+
+    /// The channels of an MPRIS server, and its thread.
+    type Server = (
+        Receiver<Command>,
+        Sender<MediaUpdate>,
+        SyncSender<()>,
+        JoinHandle<Result<(), String>>,
+    );
+
+    /// Serves the MPRIS objects on the bus at `address`, on a background thread.
+    fn start_server(address: &str) -> Server {
+        let (commands_tx, commands_rx) = channel();
+        let (updates_tx, updates_rx) = channel();
+        let (stop_tx, stop_rx) = sync_channel(1);
+        let server_address = address.to_owned();
+        let handle = spawn(move || {
+            serve(
+                &connect(&server_address),
+                commands_tx,
+                &updates_rx,
+                &stop_rx,
+            )
+            .map_err(|err| err.to_string())
+        });
+        (commands_rx, updates_tx, stop_tx, handle)
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn mpris_state() {
+        let (_bus, address) = PrivateBus::start();
+        let (commands_rx, updates_tx, stop_tx, handle) = start_server(&address);
+
+        updates_tx
+            .send(MediaUpdate::Metadata(Metadata {
+                path: "jingle_bells.mp3".to_owned(),
+                title: "Jingle Bells".to_owned(),
+                artist: Some("Someone".to_owned()),
+                album: Some("Christmas".to_owned()),
+                cover_url: Some("file:///tmp/cover.jpg".to_owned()),
+                duration: Some(Duration::from_secs(150)),
+            }))
+            .unwrap();
+        updates_tx
+            .send(MediaUpdate::Playback {
+                paused: true,
+                position: Duration::from_secs(42),
+                shuffle: true,
+                volume: 1.0,
+                live: false,
+            })
+            .unwrap();
+
+        let connection = connect(&address);
+        let proxy = connection.with_proxy(BUS_NAME, OBJECT_PATH, Duration::from_secs(5));
+
+        wait_for(|| {
+            proxy
+                .get::<String>(PLAYER_INTERFACE, "PlaybackStatus")
+                .ok()
+                .filter(|status| status == "Paused")
+        });
+        let position: i64 = proxy.get(PLAYER_INTERFACE, "Position").unwrap();
+        assert_eq!(position, 42_000_000);
+
+        let metadata: PropMap = proxy.get(PLAYER_INTERFACE, "Metadata").unwrap();
+        assert_eq!(metadata["xesam:title"].0.as_str(), Some("Jingle Bells"));
+        assert_eq!(
+            metadata["xesam:artist"]
+                .0
+                .as_iter()
+                .and_then(|mut artists| artists.next().and_then(|x| x.as_str().map(str::to_owned))),
+            Some("Someone".to_owned())
+        );
+        assert_eq!(metadata["xesam:album"].0.as_str(), Some("Christmas"));
+        assert_eq!(
+            metadata["mpris:artUrl"].0.as_str(),
+            Some("file:///tmp/cover.jpg")
+        );
+        assert_eq!(metadata["mpris:length"].0.as_i64(), Some(150_000_000));
+
+        // The shuffle and loop states
+        assert!(proxy.get::<bool>(PLAYER_INTERFACE, "Shuffle").unwrap());
+        assert_eq!(
+            proxy.get::<String>(PLAYER_INTERFACE, "LoopStatus").unwrap(),
+            "Playlist"
+        );
+        proxy.set(PLAYER_INTERFACE, "Shuffle", false).unwrap();
+        assert!(matches!(
+            commands_rx.recv().unwrap(),
+            Command::SetShuffle(false)
+        ));
+        assert!(!proxy.get::<bool>(PLAYER_INTERFACE, "Shuffle").unwrap());
+        assert!(proxy
+            .set(PLAYER_INTERFACE, "LoopStatus", "None".to_owned())
+            .is_err());
+
+        // The methods send their commands
+        proxy
+            .method_call::<(), _, _, _>(PLAYER_INTERFACE, "Next", ())
+            .unwrap();
+        assert!(matches!(commands_rx.recv().unwrap(), Command::Next));
+        proxy
+            .method_call::<(), _, _, _>(PLAYER_INTERFACE, "Seek", (-5_000_000_i64,))
+            .unwrap();
+        assert!(matches!(
+            commands_rx.recv().unwrap(),
+            Command::SeekLeft(duration) if duration == Duration::from_secs(5)
+        ));
+
+        updates_tx
+            .send(MediaUpdate::Playback {
+                paused: false,
+                position: Duration::from_secs(43),
+                shuffle: false,
+                volume: 1.0,
+                live: false,
+            })
+            .unwrap();
+        wait_for(|| {
+            proxy
+                .get::<String>(PLAYER_INTERFACE, "PlaybackStatus")
+                .ok()
+                .filter(|status| status == "Playing")
+        });
+
+        stop_tx.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn volume_and_seek() {
+        let (_bus, address) = PrivateBus::start();
+        let (_commands_rx, updates_tx, stop_tx, handle) = start_server(&address);
+        let connection = connect(&address);
+        let proxy = connection.with_proxy(BUS_NAME, OBJECT_PATH, Duration::from_secs(5));
+
+        // The volume comes from the player, and a live stream can't be seeked
+        for (volume, live) in [(0.5, false), (0.8, true)] {
+            updates_tx
+                .send(MediaUpdate::Playback {
+                    paused: false,
+                    position: Duration::ZERO,
+                    shuffle: false,
+                    volume,
+                    live,
+                })
+                .unwrap();
+            wait_for(|| {
+                proxy
+                    .get::<f64>(PLAYER_INTERFACE, "Volume")
+                    .ok()
+                    .filter(|current| (current - f64::from(volume)).abs() < 1e-6)
+            });
+            assert_eq!(
+                proxy.get::<bool>(PLAYER_INTERFACE, "CanSeek").unwrap(),
+                !live
+            );
+        }
+
+        stop_tx.send(()).unwrap();
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+//! The plugins of the player.
+//!
+//! A [`Plugin`] is called on the events of the player (in its own thread),
+//! can read the status of the player and can send [`Command`]s to it.
+//!
+//! The plugins are registered with [`Player::with_plugin`](super::Player::with_plugin).
+use std::{
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::song::EBox;
+
+use super::{
+    protocol::StatusReport, shared_status::SharedStatus, Command, MediaUpdate, Metadata,
+    StatusMessage,
+};
+
+/// The interval at which [`Plugin::on_tick`] is called.
+pub static TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the plugins can do with the player.
+///
+/// It can be cloned to send commands from another thread.
+#[derive(Clone)]
+pub struct PluginContext {
+    /// The [`Sender`] of the commands of the player.
+    tx: Sender<Command>,
+    /// The status of the player.
+    status: Arc<SharedStatus>,
+}
+
+impl PluginContext {
+    /// Creates a context that sends the commands to `tx`, with an empty status.
+    #[cfg(test)]
+    pub(crate) fn new(tx: Sender<Command>) -> Self {
+        Self {
+            tx,
+            status: Arc::default(),
+        }
+    }
+
+    /// Sends a [`Command`] to the player.
+    ///
+    /// # Errors
+    /// Fails if the player has stopped.
+    pub fn send(&self, command: Command) -> Result<(), EBox> {
+        self.tx.send(command)?;
+        Ok(())
+    }
+
+    /// Returns the status of the player.
+    #[must_use]
+    pub fn status(&self) -> StatusReport {
+        self.status.report()
+    }
+}
+
+/// A plugin of the player.
+///
+/// All the callbacks do nothing by default.
+/// The errors they return are displayed in the player.
+pub trait Plugin: Send {
+    /// Called when the player starts.
+    ///
+    /// # Errors
+    /// Depends on the implementation.
+    fn on_start(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+        Ok(())
+    }
+
+    /// Called when a key is pressed in the terminal UI, with the latest pressed keys.
+    ///
+    /// # Errors
+    /// Depends on the implementation.
+    fn on_keys(&mut self, _context: &PluginContext, _keys: &str) -> Result<(), EBox> {
+        Ok(())
+    }
+
+    /// Called when a new song is playing.
+    ///
+    /// # Errors
+    /// Depends on the implementation.
+    fn on_track_change(
+        &mut self,
+        _context: &PluginContext,
+        _metadata: &Metadata,
+    ) -> Result<(), EBox> {
+        Ok(())
+    }
+
+    /// Called when the current song has been played until its end (and not skipped),
+    /// before the next song starts.
+    ///
+    /// # Errors
+    /// Depends on the implementation.
+    fn on_track_finished(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+        Ok(())
+    }
+
+    /// Called every [`TICK_INTERVAL`].
+    ///
+    /// # Errors
+    /// Depends on the implementation.
+    fn on_tick(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+        Ok(())
+    }
+
+    /// Called when the player stops.
+    ///
+    /// # Errors
+    /// Depends on the implementation.
+    fn on_shutdown(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+        Ok(())
+    }
+}
+
+/// Calls a callback on each plugin and displays the errors in the player.
+fn call_all(
+    plugins: &mut [Box<dyn Plugin>],
+    context: &PluginContext,
+    mut callback: impl FnMut(&mut dyn Plugin, &PluginContext) -> Result<(), EBox>,
+) {
+    for plugin in plugins {
+        if let Err(err) = callback(plugin.as_mut(), context) {
+            let _ = context.send(Command::DisplayMessage(StatusMessage::error(format!(
+                "Plugin failed: {err}"
+            ))));
+        }
+    }
+}
+
+/// Runs the plugins until the player stops.
+///
+/// The [`MediaUpdate`]s come from `updates_rx` and the pressed keys from `keys_rx`.
+pub fn plugins(
+    mut plugins: Vec<Box<dyn Plugin>>,
+    tx: Sender<Command>,
+    status: Arc<SharedStatus>,
+    updates_rx: &Receiver<MediaUpdate>,
+    keys_rx: &Receiver<String>,
+    stop_rx: &Receiver<()>,
+) {
+    let context = PluginContext { tx, status };
+    call_all(&mut plugins, &context, |plugin, context| {
+        plugin.on_start(context)
+    });
+
+    let mut next_tick = Instant::now() + TICK_INTERVAL;
+    loop {
+        for keys in keys_rx.try_iter() {
+            call_all(&mut plugins, &context, |plugin, context| {
+                plugin.on_keys(context, &keys)
+            });
+        }
+
+        match updates_rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(MediaUpdate::Metadata(metadata)) => {
+                call_all(&mut plugins, &context, |plugin, context| {
+                    plugin.on_track_change(context, &metadata)
+                });
+            }
+            Ok(MediaUpdate::Finished) => {
+                call_all(&mut plugins, &context, |plugin, context| {
+                    plugin.on_track_finished(context)
+                });
+            }
+            Ok(MediaUpdate::Playback { .. }) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if Instant::now() >= next_tick {
+            next_tick += TICK_INTERVAL;
+            call_all(&mut plugins, &context, |plugin, context| {
+                plugin.on_tick(context)
+            });
+        }
+
+        if let Ok(()) | Err(TryRecvError::Disconnected) = stop_rx.try_recv() {
+            break;
+        }
+    }
+
+    call_all(&mut plugins, &context, |plugin, context| {
+        plugin.on_shutdown(context)
+    });
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        sync::{
+            mpsc::{channel, sync_channel, Sender},
+            Arc,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use super::{plugins, Plugin, PluginContext, TICK_INTERVAL};
+    use crate::{
+        player::{shared_status::SharedStatus, Command, MediaUpdate, Metadata},
+        song::EBox,
+    };
+
+    /// A plugin that records its events.
+    struct Recorder(Sender<String>);
+
+    impl Plugin for Recorder {
+        fn on_start(&mut self, context: &PluginContext) -> Result<(), EBox> {
+            self.0.send("start".to_owned())?;
+            context.send(Command::Pause)
+        }
+
+        fn on_keys(&mut self, _context: &PluginContext, keys: &str) -> Result<(), EBox> {
+            self.0.send(format!("keys {keys}"))?;
+            Ok(())
+        }
+
+        fn on_track_change(
+            &mut self,
+            context: &PluginContext,
+            metadata: &Metadata,
+        ) -> Result<(), EBox> {
+            self.0.send(format!(
+                "track {} {}",
+                metadata.title,
+                context.status().queue.len()
+            ))?;
+            Err("Track failed".into())
+        }
+
+        fn on_track_finished(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+            self.0.send("finished".to_owned())?;
+            Ok(())
+        }
+
+        fn on_tick(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+            self.0.send("tick".to_owned())?;
+            Ok(())
+        }
+
+        fn on_shutdown(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+            self.0.send("shutdown".to_owned())?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events() {
+        let (events_tx, events_rx) = channel();
+        let (tx, rx) = channel();
+        let (updates_tx, updates_rx) = channel();
+        let (keys_tx, keys_rx) = channel();
+        let (stop_tx, stop_rx) = sync_channel(1);
+        let handle = spawn(move || {
+            plugins(
+                vec![Box::new(Recorder(events_tx))],
+                tx,
+                Arc::new(SharedStatus::default()),
+                &updates_rx,
+                &keys_rx,
+                &stop_rx,
+            );
+        });
+
+        assert_eq!(events_rx.recv().unwrap(), "start");
+        assert!(matches!(rx.recv().unwrap(), Command::Pause));
+
+        keys_tx.send("ab".to_owned()).unwrap();
+        assert_eq!(events_rx.recv().unwrap(), "keys ab");
+
+        updates_tx
+            .send(MediaUpdate::Metadata(Metadata {
+                path: "a.mp3".to_owned(),
+                title: "A".to_owned(),
+                artist: None,
+                album: None,
+                cover_url: None,
+                duration: None,
+            }))
+            .unwrap();
+        assert_eq!(events_rx.recv().unwrap(), "track A 0");
+        let Command::DisplayMessage(message) = rx.recv().unwrap() else {
+            panic!("The error should be displayed");
+        };
+        assert_eq!(message.message, "Plugin failed: Track failed");
+        updates_tx.send(MediaUpdate::Finished).unwrap();
+        assert_eq!(events_rx.recv().unwrap(), "finished");
+
+        sleep(TICK_INTERVAL + Duration::from_millis(100));
+        assert_eq!(events_rx.recv().unwrap(), "tick");
+
+        stop_tx.send(()).unwrap();
+        handle.join().unwrap();
+        assert_eq!(events_rx.try_iter().last().unwrap(), "shutdown");
+    }
+}
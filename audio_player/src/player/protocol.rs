@@ -0,0 +1,229 @@
+//! The line-delimited JSON protocol used to control the player.
+//!
+//! Each request is a JSON object on a single line, with a `command` and an optional `argument`:
+//! ```json
+//! {"command": "next"}
+//! {"command": "seek_to", "argument": 90.5}
+//! {"command": "display_message", "argument": "Hello"}
+//! {"command": "status"}
+//! ```
+//!
+//! The command names are the [`Command`] variants in `snake_case`.
+//! Durations are given in seconds.
+//!
+//! Each request gets a response on a single line:
+//! ```json
+//! {"ok": true}
+//! {"ok": false, "error": "unknown variant `foo`"}
+//! {"ok": true, "status": {"song": "a.mp3", "position": 0, "time": 12.5, ...}}
+//! ```
+use std::time::Duration;
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::lyrics::parse_timestamp;
+
+use super::{terminal_ui::PartialStatus, Command, Severity, StatusMessage};
+
+/// Deserializes a [`Duration`] from a number of seconds.
+///
+/// # Errors
+/// Fails if the value is not a positive number.
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let seconds = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(seconds).map_err(D::Error::custom)
+}
+
+/// Deserializes a [`StatusMessage`] (that is cleared after 5 seconds) from a string.
+impl<'de> Deserialize<'de> for StatusMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::five_seconds)
+    }
+}
+
+/// Parses a duration in seconds (`90` or `90.5`) or in minutes and seconds (`1:30`).
+///
+/// # Examples
+/// ```
+/// # use std::time::Duration;
+/// # use audio_player::player::protocol::parse_duration;
+/// assert_eq!(parse_duration("1:30"), Some(Duration::from_secs(90)));
+/// assert_eq!(parse_duration("2.5"), Some(Duration::from_millis(2500)));
+/// assert_eq!(parse_duration("-1"), None);
+/// ```
+#[must_use]
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    parse_timestamp(duration).or_else(|| {
+        duration
+            .parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+    })
+}
+
+/// A request sent to the player.
+pub enum Request {
+    /// Returns the status of the player.
+    Status,
+    /// Sends a [`Command`] to the player.
+    Command(Command),
+}
+
+impl Request {
+    /// Parses a request from a line of JSON.
+    ///
+    /// # Errors
+    /// Fails if the line is not valid JSON or if the command is unknown.
+    pub fn parse(line: &str) -> Result<Self, serde_json::Error> {
+        let value: Value = serde_json::from_str(line)?;
+        if value.get("command").and_then(Value::as_str) == Some("status") {
+            return Ok(Self::Status);
+        }
+        Command::deserialize(value).map(Self::Command)
+    }
+}
+
+/// A response sent by the player.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    /// Has the request succeeded?
+    pub ok: bool,
+    /// The error, if the request has failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The status of the player, for [`Request::Status`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<StatusReport>,
+}
+
+impl Response {
+    /// Creates a successful [`Response`].
+    #[must_use]
+    pub fn ok(status: Option<StatusReport>) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            status,
+        }
+    }
+
+    /// Creates a failed [`Response`].
+    #[must_use]
+    pub fn error(error: String) -> Self {
+        Self {
+            ok: false,
+            error: Some(error),
+            status: None,
+        }
+    }
+}
+
+/// A message displayed by the player.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReportMessage {
+    /// The text of the message.
+    pub text: String,
+    /// The severity of the message.
+    pub severity: Severity,
+}
+
+/// The status of the player, as sent to the clients.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[expect(clippy::struct_excessive_bools, reason = "these are independent flags")]
+pub struct StatusReport {
+    /// The name of the current song.
+    pub song: Option<String>,
+    /// The position of the current song in the queue.
+    pub position: usize,
+    /// The position in the current song, in seconds.
+    pub time: f64,
+    /// The duration of the current song in seconds (0 if it's unknown).
+    pub total_time: f64,
+    /// Is the player paused?
+    pub paused: bool,
+    /// The message that is displayed.
+    pub message: Option<ReportMessage>,
+    /// The number of other messages that are not expired.
+    pub other_messages: usize,
+    /// The volume in percent.
+    pub volume: u8,
+    /// The names of the songs in the queue.
+    pub queue: Vec<String>,
+    /// Is the library offline?
+    #[serde(default)]
+    pub offline: bool,
+    /// Is the current song an endless stream (that can't be seeked)?
+    #[serde(default)]
+    pub live: bool,
+    /// The title sent by the current stream.
+    #[serde(default)]
+    pub stream_title: Option<String>,
+    /// Is the queue shuffled when it's restarted?
+    #[serde(default)]
+    pub shuffle: bool,
+}
+
+impl From<&PartialStatus> for StatusReport {
+    fn from(status: &PartialStatus) -> Self {
+        Self {
+            song: status.song_names.get(status.position).cloned(),
+            position: status.position,
+            time: status.current_time().as_secs_f64(),
+            total_time: status.total_time.as_secs_f64(),
+            paused: status.paused,
+            message: status.message.as_ref().map(|message| ReportMessage {
+                text: message.message.clone(),
+                severity: message.severity,
+            }),
+            other_messages: status.other_messages,
+            volume: status.volume_percent(),
+            queue: status.song_names.to_vec(),
+            offline: status.offline,
+            live: status.live,
+            stream_title: status.stream_title.clone(),
+            shuffle: status.shuffle,
+        }
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Request;
+    use crate::player::Command;
+
+    #[test]
+    fn requests() {
+        assert!(matches!(
+            Request::parse(r#"{"command": "status"}"#).unwrap(),
+            Request::Status
+        ));
+        assert!(matches!(
+            Request::parse(r#"{"command": "play_pause"}"#).unwrap(),
+            Request::Command(Command::PlayPause)
+        ));
+        assert!(matches!(
+            Request::parse(r#"{"command": "seek_to", "argument": 90.5}"#).unwrap(),
+            Request::Command(Command::SeekTo(duration)) if duration == Duration::from_millis(90_500)
+        ));
+        assert!(matches!(
+            Request::parse(r#"{"command": "display_message", "argument": "Hello"}"#).unwrap(),
+            Request::Command(Command::DisplayMessage(message)) if message.message == "Hello"
+        ));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(Request::parse("next").is_err());
+        assert!(Request::parse(r#"{"command": "dance"}"#).is_err());
+        assert!(Request::parse(r#"{"command": "seek_to"}"#).is_err());
+        assert!(Request::parse(r#"{"command": "seek_to", "argument": -1}"#).is_err());
+        // Only the library can set the offline mode
+        assert!(Request::parse(r#"{"command": "set_offline", "argument": true}"#).is_err());
+        // Only the radios can set the title
+        assert!(Request::parse(r#"{"command": "set_title", "argument": "Title"}"#).is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! The [Rhai](https://rhai.rs/book/) scripts, run as [`Plugin`]s.
+//!
+//! The top-level statements of a script are run when the player starts.
+//! The script can then define some of these functions, which are called on the events of the player:
+//! * `on_start()`
+//! * `on_keys(keys)`: with the keys that have been pressed (the last key is at the end)
+//! * `on_track_change(track)`: with a map containing `path`, `title`, `artist`, `album` and `duration`
+//! * `on_tick()`: every second
+//! * `on_shutdown()`
+//!
+//! The functions can keep a state in `this` (an object map that is kept between the calls).
+//!
+//! The scripts can use these functions:
+//! * `status()`: returns the status of the player (as in the [`protocol`](super::protocol))
+//! * `command(name)` and `command(name, argument)`: send a command of the [`protocol`](super::protocol)
+//! * `print(text)`: displays a message
+//!
+//! For example:
+//! ```rhai
+//! fn on_start() { this.count = 0; }
+//! fn on_track_change(track) {
+//!     this.count += 1;
+//!     if this.count % 10 == 0 { print("That's the " + this.count + "th song!"); }
+//! }
+//! fn on_keys(keys) {
+//!     if keys.ends_with("xmas") { command("display_message", "Merry Christmas!"); }
+//! }
+//! ```
+//!
+//! The scripts can't access the files or the network
+//! and each call is stopped after [`MAX_DURATION`] or [`MAX_OPERATIONS`].
+use std::{
+    fs, mem,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use rhai::{
+    module_resolvers::DummyModuleResolver,
+    serde::{from_dynamic, to_dynamic},
+    CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::song::EBox;
+
+use super::{
+    plugin::{Plugin, PluginContext},
+    protocol::StatusReport,
+    Command, Metadata, StatusMessage,
+};
+
+/// The maximum duration of a call to a script.
+pub static MAX_DURATION: Duration = Duration::from_millis(200);
+
+/// The maximum number of operations in a call to a script.
+pub const MAX_OPERATIONS: u64 = 5_000_000;
+
+/// The number of operations between two checks of the duration of a call.
+const OPERATIONS_BETWEEN_CHECKS: u64 = 1000;
+
+/// The data shared between a [`ScriptPlugin`] and its [`Engine`].
+struct Shared {
+    /// The commands sent by the script during the current call.
+    commands: Vec<Command>,
+    /// The status of the player when the current call has started.
+    status: StatusReport,
+    /// The moment when the current call must be stopped.
+    deadline: Instant,
+}
+
+/// Locks the [`Shared`] data, even if a thread has panicked while holding it.
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Parses a command of the [`protocol`](super::protocol).
+///
+/// # Errors
+/// Fails if the command is unknown or if the argument is invalid.
+fn parse_command(name: &str, argument: Option<&Dynamic>) -> Result<Command, Box<EvalAltResult>> {
+    let mut request = json!({ "command": name });
+    if let Some(argument) = argument {
+        request["argument"] = from_dynamic::<Value>(argument)?;
+    }
+    Command::deserialize(request).map_err(|err| err.to_string().into())
+}
+
+/// Creates a sandboxed [`Engine`] whose functions use the [`Shared`] data.
+fn engine(shared: &Arc<Mutex<Shared>>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(64)
+        .set_max_expr_depths(64, 64)
+        .set_max_string_size(1024 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        // The default resolver would let the scripts `import` any file
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval");
+
+    let progress_shared = shared.clone();
+    engine.on_progress(move |operations| {
+        (operations % OPERATIONS_BETWEEN_CHECKS == 0
+            && Instant::now() > lock(&progress_shared).deadline)
+            .then(|| "Time limit exceeded".into())
+    });
+    let print_shared = shared.clone();
+    engine.on_print(move |text| {
+        lock(&print_shared)
+            .commands
+            .push(Command::DisplayMessage(StatusMessage::five_seconds(
+                text.to_owned(),
+            )));
+    });
+    engine.on_debug(|_, _, _| {});
+
+    let command_shared = shared.clone();
+    engine.register_fn(
+        "command",
+        move |name: &str| -> Result<(), Box<EvalAltResult>> {
+            let command = parse_command(name, None)?;
+            lock(&command_shared).commands.push(command);
+            Ok(())
+        },
+    );
+    let command_shared = shared.clone();
+    engine.register_fn(
+        "command",
+        move |name: &str, argument: Dynamic| -> Result<(), Box<EvalAltResult>> {
+            let command = parse_command(name, Some(&argument))?;
+            lock(&command_shared).commands.push(command);
+            Ok(())
+        },
+    );
+    let status_shared = shared.clone();
+    engine.register_fn("status", move || to_dynamic(&lock(&status_shared).status));
+
+    engine
+}
+
+/// A [Rhai](https://rhai.rs/book/) script.
+pub struct ScriptPlugin {
+    /// The name of the script (used in the error messages).
+    name: String,
+    /// The engine that runs the script.
+    engine: Engine,
+    /// The compiled script.
+    ast: AST,
+    /// The state of the script (`this`).
+    state: Dynamic,
+    /// The data shared with the engine.
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl ScriptPlugin {
+    /// Compiles a script.
+    ///
+    /// # Errors
+    /// Fails if the script is invalid.
+    pub fn new(name: String, source: &str) -> Result<Self, EBox> {
+        let shared = Arc::new(Mutex::new(Shared {
+            commands: vec![],
+            status: StatusReport::default(),
+            deadline: Instant::now(),
+        }));
+        let engine = engine(&shared);
+        let ast = engine
+            .compile(source)
+            .map_err(|err| format!("{name}: {err}"))?;
+        Ok(Self {
+            name,
+            engine,
+            ast,
+            state: Dynamic::from_map(Map::new()),
+            shared,
+        })
+    }
+
+    /// Loads and compiles a script file.
+    ///
+    /// # Errors
+    /// Fails if the file can't be read or if the script is invalid.
+    pub fn from_file(path: &Path) -> Result<Self, EBox> {
+        let source =
+            fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+        Self::new(path.display().to_string(), &source)
+    }
+
+    /// Prepares a call to the script.
+    fn start_call(&self, context: &PluginContext) {
+        let mut shared = lock(&self.shared);
+        shared.commands.clear();
+        shared.status = context.status();
+        shared.deadline = Instant::now() + MAX_DURATION;
+    }
+
+    /// Sends the commands of the script and returns the result of the call.
+    ///
+    /// # Errors
+    /// Fails if the call has failed or if the player has stopped.
+    fn end_call<T>(
+        &self,
+        context: &PluginContext,
+        result: Result<T, Box<EvalAltResult>>,
+    ) -> Result<(), EBox> {
+        let commands = mem::take(&mut lock(&self.shared).commands);
+        for command in commands {
+            context.send(command)?;
+        }
+        result.map_err(|err| format!("{}: {err}", self.name))?;
+        Ok(())
+    }
+
+    /// Calls a function of the script if it's defined.
+    ///
+    /// # Errors
+    /// Fails if the function fails.
+    fn call(
+        &mut self,
+        context: &PluginContext,
+        function: &str,
+        args: impl FuncArgs,
+        arg_count: usize,
+    ) -> Result<(), EBox> {
+        if !self
+            .ast
+            .iter_functions()
+            .any(|metadata| metadata.name == function && metadata.params.len() == arg_count)
+        {
+            return Ok(());
+        }
+        self.start_call(context);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(&mut self.state),
+            &mut Scope::new(),
+            &self.ast,
+            function,
+            args,
+        );
+        self.end_call(context, result)
+    }
+}
+
+/// Returns the track map passed to `on_track_change`.
+fn track_map(metadata: &Metadata) -> Map {
+    let optional = |value: &Option<String>| value.clone().map_or(Dynamic::UNIT, Dynamic::from);
+    let mut track = Map::new();
+    track.insert("path".into(), metadata.path.clone().into());
+    track.insert("title".into(), metadata.title.clone().into());
+    track.insert("artist".into(), optional(&metadata.artist));
+    track.insert("album".into(), optional(&metadata.album));
+    track.insert(
+        "duration".into(),
+        metadata
+            .duration
+            .map_or(Dynamic::UNIT, |duration| duration.as_secs_f64().into()),
+    );
+    track
+}
+
+impl Plugin for ScriptPlugin {
+    fn on_start(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        self.start_call(context);
+        let result = self.engine.run_ast(&self.ast);
+        self.end_call(context, result)?;
+        self.call(context, "on_start", (), 0)
+    }
+
+    fn on_keys(&mut self, context: &PluginContext, keys: &str) -> Result<(), EBox> {
+        self.call(context, "on_keys", (keys.to_owned(),), 1)
+    }
+
+    fn on_track_change(
+        &mut self,
+        context: &PluginContext,
+        metadata: &Metadata,
+    ) -> Result<(), EBox> {
+        self.call(context, "on_track_change", (track_map(metadata),), 1)
+    }
+
+    fn on_tick(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        self.call(context, "on_tick", (), 0)
+    }
+
+    fn on_shutdown(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        self.call(context, "on_shutdown", (), 0)
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs, process,
+        sync::{
+            mpsc::{channel, sync_channel},
+            Arc,
+        },
+        thread::spawn,
+        time::Duration,
+    };
+
+    use super::ScriptPlugin;
+    use crate::player::{
+        plugin::plugins, shared_status::SharedStatus, Command, MediaUpdate, Metadata,
+    };
+
+    /// Returns the message of a [`Command::DisplayMessage`].
+    fn message(command: Command) -> String {
+        let Command::DisplayMessage(message) = command else {
+            panic!("A message should be displayed");
+        };
+        message.message
+    }
+
+    #[test]
+    fn script() {
+        let script = ScriptPlugin::new(
+            "test.rhai".to_owned(),
+            r#"
+                command("pause");
+                fn on_start() { this.count = 0; }
+                fn on_track_change(track) {
+                    this.count += 1;
+                    if this.count % 2 == 0 {
+                        command("play_song", 0);
+                        print(track.title + " " + status().queue.len());
+                    }
+                }
+                fn on_keys(keys) {
+                    if keys.ends_with("xmas") { command("seek_to", 1.5); }
+                    if keys == "x" { command("dance"); }
+                }
+                fn on_tick() { loop {} }
+            "#,
+        )
+        .unwrap();
+
+        let (tx, rx) = channel();
+        let (updates_tx, updates_rx) = channel();
+        let (keys_tx, keys_rx) = channel();
+        let (stop_tx, stop_rx) = sync_channel(1);
+        let handle = spawn(move || {
+            plugins(
+                vec![Box::new(script)],
+                tx,
+                Arc::new(SharedStatus::default()),
+                &updates_rx,
+                &keys_rx,
+                &stop_rx,
+            );
+        });
+        assert!(matches!(rx.recv().unwrap(), Command::Pause));
+
+        for title in ["A", "B"] {
+            updates_tx
+                .send(MediaUpdate::Metadata(Metadata {
+                    path: format!("{title}.mp3"),
+                    title: title.to_owned(),
+                    artist: None,
+                    album: None,
+                    cover_url: None,
+                    duration: None,
+                }))
+                .unwrap();
+        }
+        assert!(matches!(rx.recv().unwrap(), Command::PlaySong(0)));
+        assert_eq!(message(rx.recv().unwrap()), "B 0");
+
+        keys_tx.send("x".to_owned()).unwrap();
+        assert!(message(rx.recv().unwrap()).contains("unknown variant `dance`"));
+        keys_tx.send("xmas".to_owned()).unwrap();
+        assert!(matches!(
+            rx.recv().unwrap(),
+            Command::SeekTo(position) if position == Duration::from_millis(1500)
+        ));
+
+        // The infinite loop is stopped
+        assert!(message(rx.recv().unwrap()).starts_with("Plugin failed: test.rhai: "));
+
+        stop_tx.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn no_import() {
+        let path = temp_dir().join(format!("audio-player-test-{}.rhai", process::id()));
+        fs::write(&path, "export const SECRET = 42;").unwrap();
+        let script = ScriptPlugin::new(
+            "test.rhai".to_owned(),
+            &format!(
+                "import {:?} as secrets; print(secrets::SECRET);",
+                path.display()
+            ),
+        )
+        .unwrap();
+
+        let (tx, rx) = channel();
+        let (_updates_tx, updates_rx) = channel();
+        let (_keys_tx, keys_rx) = channel();
+        let (stop_tx, stop_rx) = sync_channel(1);
+        stop_tx.send(()).unwrap();
+        plugins(
+            vec![Box::new(script)],
+            tx,
+            Arc::new(SharedStatus::default()),
+            &updates_rx,
+            &keys_rx,
+            &stop_rx,
+        );
+        let error = message(rx.recv().unwrap());
+        assert!(error.starts_with("Plugin failed: test.rhai: "));
+        assert!(!error.contains("42"));
+        assert!(rx.try_recv().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_script() {
+        assert!(ScriptPlugin::new("test.rhai".to_owned(), "fn on_start( {")
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("test.rhai: "));
+    }
+}
@@ -0,0 +1,507 @@
+//! Submission of the listens to a [ListenBrainz](https://listenbrainz.readthedocs.io/en/latest/users/api/core.html)
+//! compatible API.
+//!
+//! A listen is submitted when more than half of the song (or 4 minutes) has been played.
+//! The listens that can't be submitted are kept in a journal and submitted later.
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_json::{json, Value};
+use ureq::Agent;
+use url::Url;
+
+use crate::song::EBox;
+
+use super::{Command, MediaUpdate, Metadata, StatusMessage};
+
+/// The maximum time of listening needed to submit a listen.
+static MAX_LISTENING_TIME: Duration = Duration::from_mins(4);
+
+/// The interval at which the listens of the journal are submitted again.
+static RETRY_INTERVAL: Duration = Duration::from_mins(5);
+
+/// The timeout of the requests to the API.
+static REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of listens in a request.
+const MAX_LISTENS_PER_REQUEST: usize = 1000;
+
+/// The artist name used when the tags don't contain an artist.
+const UNKNOWN_ARTIST: &str = "[unknown]";
+
+/// Why a submission failed.
+enum SubmitError {
+    /// The server can't be reached or is unavailable, the listens should be submitted later.
+    Unavailable(EBox),
+    /// The server has rejected the listens.
+    Rejected(EBox),
+}
+
+impl From<ureq::Error> for SubmitError {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Status(code, _) if code != 429 && code < 500 => Self::Rejected(err.into()),
+            _ => Self::Unavailable(err.into()),
+        }
+    }
+}
+
+/// The song that is being played.
+struct Track {
+    /// The metadata of the song.
+    metadata: Metadata,
+    /// The time when the song has started.
+    started_at: SystemTime,
+    /// The time during which the song has been played, before `playing_since`.
+    played: Duration,
+    /// The moment when the song has been resumed (or [`None`] if it's paused).
+    playing_since: Option<Instant>,
+}
+
+impl Track {
+    /// Returns the time during which the song has been played.
+    fn played(&self) -> Duration {
+        self.played
+            + self
+                .playing_since
+                .map_or(Duration::ZERO, |since| since.elapsed())
+    }
+
+    /// Returns `true` if the song has been played long enough to be submitted.
+    fn should_submit(&self) -> bool {
+        let needed = self
+            .metadata
+            .duration
+            .map_or(MAX_LISTENING_TIME, |duration| {
+                (duration / 2).min(MAX_LISTENING_TIME)
+            });
+        !needed.is_zero() && self.played() >= needed
+    }
+}
+
+/// A client of a `ListenBrainz` compatible API.
+pub struct Scrobbler {
+    /// The agent used for the requests.
+    agent: Agent,
+    /// The URL of the `submit-listens` endpoint.
+    submit_url: Url,
+    /// The user token.
+    token: String,
+    /// The file where the listens that can't be submitted are kept.
+    journal: PathBuf,
+    /// The song that is being played.
+    track: Option<Track>,
+}
+
+impl Scrobbler {
+    /// Creates a new [`Scrobbler`] for the API at `base_url` (for example `https://api.listenbrainz.org`).
+    ///
+    /// # Errors
+    /// Fails if the URL is invalid.
+    pub fn new(base_url: &Url, token: String, journal: PathBuf) -> Result<Self, EBox> {
+        let mut base_url = base_url.clone();
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Ok(Self {
+            agent: ureq::builder().timeout(REQUEST_TIMEOUT).build(),
+            submit_url: base_url.join("1/submit-listens")?,
+            token,
+            journal,
+            track: None,
+        })
+    }
+
+    /// Returns the payload of a listen (without `listened_at`) for the given [`Metadata`].
+    fn track_metadata(metadata: &Metadata) -> Value {
+        let mut additional_info = json!({
+            "media_player": "audio-player",
+            "submission_client": "audio-player",
+            "submission_client_version": env!("CARGO_PKG_VERSION"),
+        });
+        if let Some(duration) = metadata.duration {
+            additional_info["duration_ms"] = json!(duration.as_millis());
+        }
+        let mut track_metadata = json!({
+            "artist_name": metadata.artist.as_deref().unwrap_or(UNKNOWN_ARTIST),
+            "track_name": metadata.title,
+            "additional_info": additional_info,
+        });
+        if let Some(album) = &metadata.album {
+            track_metadata["release_name"] = json!(album);
+        }
+        json!({ "track_metadata": track_metadata })
+    }
+
+    /// Submits some listens.
+    ///
+    /// # Errors
+    /// Fails if the server can't be reached or if it rejects the listens.
+    fn submit(&self, listen_type: &str, payload: &[Value]) -> Result<(), SubmitError> {
+        let body = json!({ "listen_type": listen_type, "payload": payload });
+        self.agent
+            .request_url("POST", &self.submit_url)
+            .set("Authorization", &format!("Token {}", self.token))
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string())?;
+        Ok(())
+    }
+
+    /// Adds a listen to the journal.
+    ///
+    /// # Errors
+    /// Fails if the journal can't be written.
+    fn queue(&self, listen: &Value) -> Result<(), EBox> {
+        if let Some(parent) = self.journal.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.journal)?;
+        writeln!(file, "{listen}")?;
+        Ok(())
+    }
+
+    /// Replaces the listens of the journal (which is removed if there are none).
+    ///
+    /// # Errors
+    /// Fails if the journal can't be written.
+    fn rewrite_journal(&self, listens: &[Value]) -> Result<(), EBox> {
+        if listens.is_empty() {
+            return Ok(fs::remove_file(&self.journal)?);
+        }
+        let mut file = fs::File::create(&self.journal)?;
+        for listen in listens {
+            writeln!(file, "{listen}")?;
+        }
+        Ok(())
+    }
+
+    /// Submits the listens of the journal and empties it.
+    ///
+    /// Returns the number of listens that have been submitted.
+    ///
+    /// # Errors
+    /// Fails if the journal can't be read or written or if the submission fails.
+    /// The rejected listens are removed from the journal, and the listens after them are kept.
+    pub fn flush_journal(&self) -> Result<usize, EBox> {
+        let file = match fs::File::open(&self.journal) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let listens = BufReader::new(file)
+            .lines()
+            .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<Result<Vec<Value>, EBox>>()?;
+
+        let mut submitted = 0;
+        for chunk in listens.chunks(MAX_LISTENS_PER_REQUEST) {
+            match self.submit("import", chunk) {
+                Ok(()) => submitted += chunk.len(),
+                Err(SubmitError::Unavailable(err)) => {
+                    // Keep the listens that have not been submitted
+                    self.rewrite_journal(&listens[submitted..])?;
+                    return Err(err);
+                }
+                Err(SubmitError::Rejected(err)) => {
+                    // Only drop the rejected listens
+                    self.rewrite_journal(&listens[submitted + chunk.len()..])?;
+                    return Err(format!("{} listens have been rejected: {err}", chunk.len()).into());
+                }
+            }
+        }
+        fs::remove_file(&self.journal)?;
+        Ok(submitted)
+    }
+
+    /// Submits the current song if it has been played long enough.
+    ///
+    /// # Errors
+    /// Fails if the listen is rejected or if it can't be added to the journal.
+    pub fn finish_track(&mut self) -> Result<(), EBox> {
+        let Some(track) = self.track.take() else {
+            return Ok(());
+        };
+        if !track.should_submit() {
+            return Ok(());
+        }
+
+        let mut listen = Self::track_metadata(&track.metadata);
+        listen["listened_at"] = json!(track
+            .started_at
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs());
+        match self.submit("single", &[listen.clone()]) {
+            Ok(()) => Ok(()),
+            Err(SubmitError::Unavailable(err)) => {
+                self.queue(&listen)?;
+                Err(format!("The listen will be submitted later: {err}").into())
+            }
+            Err(SubmitError::Rejected(err)) => Err(err),
+        }
+    }
+
+    /// Submits the previous song if needed and sends a "now playing" notification for the new song.
+    ///
+    /// # Errors
+    /// Fails if the listen of the previous song is rejected or can't be added to the journal.
+    /// The "now playing" notification errors are ignored.
+    pub fn start_track(&mut self, metadata: Metadata) -> Result<(), EBox> {
+        let result = self.finish_track();
+        let playing_now = Self::track_metadata(&metadata);
+        // The song is timed from its start, not from the end of the notification
+        self.track = Some(Track {
+            metadata,
+            started_at: SystemTime::now(),
+            played: Duration::ZERO,
+            playing_since: Some(Instant::now()),
+        });
+        let _ = self.submit("playing_now", &[playing_now]);
+        result
+    }
+
+    /// Pauses or resumes the current song.
+    pub fn set_paused(&mut self, paused: bool) {
+        let Some(track) = &mut self.track else {
+            return;
+        };
+        match (paused, track.playing_since) {
+            (true, Some(since)) => {
+                track.played += since.elapsed();
+                track.playing_since = None;
+            }
+            (false, None) => track.playing_since = Some(Instant::now()),
+            _ => {}
+        }
+    }
+}
+
+/// Submits the listens of the songs that are received from `updates_rx` until the player stops.
+///
+/// The errors are displayed in the player.
+pub fn scrobbler(
+    mut scrobbler: Scrobbler,
+    tx: &Sender<Command>,
+    updates_rx: &Receiver<MediaUpdate>,
+    stop_rx: &Receiver<()>,
+) {
+    let warn = |result: Result<(), EBox>| {
+        if let Err(err) = result {
+            let _ = tx.send(Command::DisplayMessage(StatusMessage::warning(format!(
+                "Scrobbling: {err}"
+            ))));
+        }
+    };
+
+    warn(scrobbler.flush_journal().map(drop));
+    let mut last_flush = Instant::now();
+    loop {
+        match updates_rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(MediaUpdate::Metadata(metadata)) => warn(scrobbler.start_track(metadata)),
+            Ok(MediaUpdate::Playback { paused, .. }) => scrobbler.set_paused(paused),
+            Ok(MediaUpdate::Finished) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if let Ok(()) | Err(TryRecvError::Disconnected) = stop_rx.try_recv() {
+            break;
+        }
+        if last_flush.elapsed() >= RETRY_INTERVAL {
+            last_flush = Instant::now();
+            warn(scrobbler.flush_journal().map(drop));
+        }
+    }
+    warn(scrobbler.finish_track());
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs, process,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::channel,
+            Arc,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use serde_json::Value;
+    use tiny_http::{Response, Server};
+    use url::Url;
+
+    use super::{Scrobbler, MAX_LISTENS_PER_REQUEST};
+    use crate::player::Metadata;
+
+    /// Returns the [`Metadata`] of a short song.
+    fn metadata(title: &str) -> Metadata {
+        Metadata {
+            path: format!("{title}.mp3"),
+            title: title.to_owned(),
+            artist: Some("Someone".to_owned()),
+            album: None,
+            cover_url: None,
+            duration: Some(Duration::from_millis(200)),
+        }
+    }
+
+    #[test]
+    fn offline_queue() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base_url = Url::parse(&format!("http://{}/api", server.server_addr())).unwrap();
+        let available = Arc::new(AtomicBool::new(false));
+        let (requests_tx, requests_rx) = channel();
+
+        let server_available = available.clone();
+        spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let body: Value = serde_json::from_str(&body).unwrap();
+                let authorization = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Authorization"))
+                    .map(|header| header.value.to_string());
+                let code = if server_available.load(Ordering::SeqCst) {
+                    requests_tx
+                        .send((request.url().to_owned(), authorization, body))
+                        .unwrap();
+                    200
+                } else {
+                    503
+                };
+                let _ = request.respond(Response::empty(code));
+            }
+        });
+
+        let journal = temp_dir().join(format!("audio-player-test-listens-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&journal);
+        let mut scrobbler = Scrobbler::new(&base_url, "token".to_owned(), journal.clone()).unwrap();
+
+        // The server is down, the listen is queued
+        scrobbler.start_track(metadata("a")).unwrap();
+        sleep(Duration::from_millis(150));
+        assert!(scrobbler.start_track(metadata("b")).is_err());
+        assert_eq!(fs::read_to_string(&journal).unwrap().lines().count(), 1);
+
+        // The song is skipped before the half, it's not submitted
+        available.store(true, Ordering::SeqCst);
+        scrobbler.start_track(metadata("c")).unwrap();
+        let (url, authorization, body) = requests_rx.recv().unwrap();
+        assert_eq!(url, "/api/1/submit-listens");
+        assert_eq!(authorization.as_deref(), Some("Token token"));
+        assert_eq!(body["listen_type"], "playing_now");
+        assert_eq!(body["payload"][0]["track_metadata"]["track_name"], "c");
+
+        // The queued listen is submitted when the server is back
+        assert_eq!(scrobbler.flush_journal().unwrap(), 1);
+        let (_, _, body) = requests_rx.recv().unwrap();
+        assert_eq!(body["listen_type"], "import");
+        assert_eq!(body["payload"][0]["track_metadata"]["track_name"], "a");
+        assert_eq!(
+            body["payload"][0]["track_metadata"]["artist_name"],
+            "Someone"
+        );
+        assert!(body["payload"][0]["listened_at"].is_u64());
+        assert!(!journal.exists());
+
+        // The paused time doesn't count
+        scrobbler.set_paused(true);
+        sleep(Duration::from_millis(150));
+        scrobbler.finish_track().unwrap();
+        sleep(Duration::from_millis(50));
+        assert!(requests_rx.try_recv().is_err());
+
+        scrobbler.start_track(metadata("d")).unwrap();
+        let _ = requests_rx.recv().unwrap();
+        sleep(Duration::from_millis(150));
+        scrobbler.finish_track().unwrap();
+        let (_, _, body) = requests_rx.recv().unwrap();
+        assert_eq!(body["listen_type"], "single");
+        assert_eq!(body["payload"][0]["track_metadata"]["track_name"], "d");
+    }
+
+    #[test]
+    fn rejected_listens() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base_url = Url::parse(&format!("http://{}/api", server.server_addr())).unwrap();
+        let (requests_tx, requests_rx) = channel();
+        spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let body: Value = serde_json::from_str(&body).unwrap();
+                let listens = body["payload"].as_array().unwrap().len();
+                let rejected = body["payload"][0]["track_metadata"]["track_name"] == "bad";
+                requests_tx.send(listens).unwrap();
+                let _ = request.respond(Response::empty(if rejected { 400 } else { 200 }));
+            }
+        });
+
+        let journal = temp_dir().join(format!(
+            "audio-player-test-rejected-{}.jsonl",
+            process::id()
+        ));
+        let scrobbler = Scrobbler::new(&base_url, "token".to_owned(), journal.clone()).unwrap();
+        let listen = |title: &str| Scrobbler::track_metadata(&metadata(title));
+        let mut listens = vec![listen("bad"); MAX_LISTENS_PER_REQUEST];
+        listens.extend([listen("a"), listen("b")]);
+        scrobbler.rewrite_journal(&listens).unwrap();
+
+        // The rejected chunk is dropped, the next listens are kept
+        assert!(scrobbler
+            .flush_journal()
+            .unwrap_err()
+            .to_string()
+            .starts_with(&format!(
+                "{MAX_LISTENS_PER_REQUEST} listens have been rejected"
+            )));
+        assert_eq!(requests_rx.recv().unwrap(), MAX_LISTENS_PER_REQUEST);
+        assert_eq!(fs::read_to_string(&journal).unwrap().lines().count(), 2);
+
+        assert_eq!(scrobbler.flush_journal().unwrap(), 2);
+        assert_eq!(requests_rx.recv().unwrap(), 2);
+        assert!(!journal.exists());
+    }
+
+    #[test]
+    fn slow_playing_now() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base_url = Url::parse(&format!("http://{}/api", server.server_addr())).unwrap();
+        let (requests_tx, requests_rx) = channel();
+        spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let body: Value = serde_json::from_str(&body).unwrap();
+                if body["listen_type"] == "playing_now" {
+                    sleep(Duration::from_millis(150));
+                }
+                requests_tx.send(body).unwrap();
+                let _ = request.respond(Response::empty(200));
+            }
+        });
+
+        let journal = temp_dir().join(format!("audio-player-test-slow-{}.jsonl", process::id()));
+        let mut scrobbler = Scrobbler::new(&base_url, "token".to_owned(), journal).unwrap();
+
+        // The song plays during the notification
+        scrobbler.start_track(metadata("a")).unwrap();
+        scrobbler.finish_track().unwrap();
+        assert_eq!(requests_rx.recv().unwrap()["listen_type"], "playing_now");
+        let body = requests_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(body["listen_type"], "single");
+        assert_eq!(body["payload"][0]["track_metadata"]["track_name"], "a");
+    }
+}
@@ -0,0 +1,54 @@
+//! The latest status of the player, shared with the threads that are not the UI.
+use std::{
+    sync::{Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+
+use super::{protocol::StatusReport, terminal_ui::PartialStatus};
+
+/// The latest [`PartialStatus`] of the player, with a counter that is incremented on each update.
+#[derive(Default)]
+pub struct SharedStatus {
+    /// The number of updates and the latest status.
+    status: Mutex<(u64, Option<PartialStatus>)>,
+    /// Notified when the status is updated.
+    changed: Condvar,
+}
+
+impl SharedStatus {
+    /// Replaces the status and wakes up the threads that are waiting for a change.
+    pub fn set(&self, status: PartialStatus) {
+        let mut guard = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        guard.0 += 1;
+        guard.1 = Some(status);
+        self.changed.notify_all();
+    }
+
+    /// Returns the number of updates and the latest status (if the player has started).
+    pub fn get(&self) -> (u64, Option<PartialStatus>) {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns the latest status as it is sent to the clients.
+    pub fn report(&self) -> StatusReport {
+        self.get()
+            .1
+            .as_ref()
+            .map(StatusReport::from)
+            .unwrap_or_default()
+    }
+
+    /// Waits until the status is updated after the update number `version`
+    /// or until `timeout` is elapsed, and returns the latest status.
+    pub fn wait_for_change(&self, version: u64, timeout: Duration) -> (u64, Option<PartialStatus>) {
+        let guard = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        let (guard, _) = self
+            .changed
+            .wait_timeout_while(guard, timeout, |(current, _)| *current == version)
+            .unwrap_or_else(PoisonError::into_inner);
+        guard.clone()
+    }
+}
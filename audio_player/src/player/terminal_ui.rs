@@ -1,6 +1,6 @@
 //! Ratatui test.
 use ratatui::{
-    layout::{Constraint, Layout, Margin},
+    layout::{Constraint, Layout, Margin, Rect},
     style::{Style, Stylize},
     text::{Line, Text},
     widgets::{
@@ -10,53 +10,137 @@ use ratatui::{
     Frame,
 };
 use std::{
-    sync::mpsc::{Receiver, Sender},
-    time::Duration,
+    sync::{
+        mpsc::{Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::song::EBox;
+use crate::{lyrics::Lyrics, song::EBox};
 
-use super::{keyboard_controls::handle_events, Command};
+use super::{keyboard_controls::handle_events, Command, Log, Severity, StatusMessage};
 
+#[derive(Clone)]
+#[expect(clippy::struct_excessive_bools, reason = "these are independent flags")]
 pub struct PartialStatus {
-    pub song_names: Vec<String>,
+    pub song_names: Arc<[String]>,
     pub position: usize,
     pub scrollbar_position: usize,
+    /// The position in the song when the status has been sent.
     pub time: Duration,
+    /// The moment when the status has been sent.
+    pub timestamp: Instant,
     pub total_time: Duration,
     pub paused: bool,
-    pub message: String,
+    /// The message that should be displayed.
+    pub message: Option<StatusMessage>,
+    /// The number of other messages that are not expired.
+    pub other_messages: usize,
+    pub lyrics: Option<Arc<Lyrics>>,
+    /// The message history, if the log panel is visible.
+    pub log: Option<Arc<Log>>,
+    pub log_position: usize,
+    /// The volume of the player (1.0 is the normal volume).
+    pub volume: f32,
+    /// Is the library offline?
+    pub offline: bool,
+    /// Is the current song an endless stream?
+    pub live: bool,
+    /// The title sent by the current stream.
+    pub stream_title: Option<String>,
+    /// Is the queue shuffled when it's restarted?
+    pub shuffle: bool,
+}
+
+impl Default for PartialStatus {
+    fn default() -> Self {
+        Self {
+            song_names: Arc::new([]),
+            position: 0,
+            scrollbar_position: 0,
+            time: Duration::ZERO,
+            timestamp: Instant::now(),
+            total_time: Duration::ZERO,
+            paused: false,
+            message: None,
+            other_messages: 0,
+            lyrics: None,
+            log: None,
+            log_position: 0,
+            volume: 1.0,
+            offline: false,
+            live: false,
+            stream_title: None,
+            shuffle: false,
+        }
+    }
+}
+
+impl PartialStatus {
+    /// Returns the volume in percent (from 0 to 100).
+    pub fn volume_percent(&self) -> u8 {
+        #[expect(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            reason = "the volume is between 0 and 100"
+        )]
+        let volume = (self.volume * 100.0).round().clamp(0.0, 100.0) as u8;
+        volume
+    }
+
+    /// Returns the current position in the song.
+    ///
+    /// The status is only sent when something changes, so we deduce the position
+    /// from the time elapsed since the status has been sent.
+    pub fn current_time(&self) -> Duration {
+        if self.paused {
+            return self.time;
+        }
+        let time = self.time + self.timestamp.elapsed();
+        if self.total_time.is_zero() {
+            time
+        } else {
+            time.min(self.total_time)
+        }
+    }
 }
 
 /// Runs the terminal UI.
 ///
+/// The pressed keys are sent to `keys_tx`.
+///
 /// # Errors
 /// Fails if the terminal can't be opened, if the metadata can't be received or if [`handle_events`] fails.
 pub fn terminal_ui(
     status_rx: &Receiver<PartialStatus>,
     stop_rx: &Receiver<()>,
     tx: &Sender<Command>,
+    keys_tx: &Sender<String>,
 ) -> Result<(), EBox> {
     let mut terminal = ratatui::try_init()?;
 
     let mut stack = String::with_capacity(50);
-    let mut status = status_rx.recv()?;
+    // The terminal is restored even if the UI fails
+    let result = (|| -> Result<(), EBox> {
+        let mut status = status_rx.recv()?;
 
-    loop {
-        handle_events(&mut stack, tx)?;
+        loop {
+            handle_events(&mut stack, tx, keys_tx)?;
 
-        if let Ok(status_inner) = status_rx.try_recv() {
-            status = status_inner;
-        }
-        terminal.draw(|frame| ui(frame, &status))?;
+            if let Ok(status_inner) = status_rx.try_recv() {
+                status = status_inner;
+            }
+            terminal.draw(|frame| ui(frame, &status))?;
 
-        if stop_rx.try_recv().is_ok() {
-            break;
+            if let Ok(()) | Err(TryRecvError::Disconnected) = stop_rx.try_recv() {
+                return Ok(());
+            }
         }
-    }
+    })();
 
     ratatui::try_restore()?;
-    Ok(())
+    result
 }
 
 fn format_duration(d: Duration) -> String {
@@ -77,10 +161,16 @@ fn ui(frame: &mut Frame, status: &PartialStatus) {
         })
         .collect();
     let mut state = ListState::default().with_selected(Some(status.scrollbar_position));
+    let time = status.current_time();
 
+    let title = if status.offline {
+        "Audio player by lfavole (offline)"
+    } else {
+        "Audio player by lfavole"
+    };
     frame.render_widget(
         Block::bordered()
-            .title(Line::from("Audio player by lfavole").centered())
+            .title(Line::from(title).centered())
             .border_type(BorderType::Rounded),
         frame.area(),
     );
@@ -92,6 +182,28 @@ fn ui(frame: &mut Frame, status: &PartialStatus) {
     ])
     .areas(frame.area().inner(Margin::new(1, 1)));
 
+    // Show the log below the queue if it's visible
+    let main_area = if let Some(log) = &status.log {
+        let [queue_area, log_area] =
+            Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main_area);
+        log_ui(frame, log_area, log, status.log_position);
+        queue_area
+    } else {
+        main_area
+    };
+
+    // Show the lyrics next to the queue if there are some
+    let main_area = if let Some(lyrics) = &status.lyrics {
+        let [queue_area, lyrics_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(main_area);
+        lyrics_ui(frame, lyrics_area, lyrics, time);
+        queue_area
+    } else {
+        main_area
+    };
+
     let widget = List::new(items).highlight_style(Style::new().on_gray());
 
     let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight);
@@ -100,7 +212,17 @@ fn ui(frame: &mut Frame, status: &PartialStatus) {
 
     frame.render_stateful_widget(widget, main_area, &mut state);
 
-    frame.render_widget(Text::from(status.message.clone()), message_area);
+    if let Some(message) = &status.message {
+        let text = if status.other_messages > 0 {
+            format!("{} (+{} more)", message.message, status.other_messages)
+        } else {
+            message.message.clone()
+        };
+        frame.render_widget(
+            Text::from(text).style(severity_style(message.severity)),
+            message_area,
+        );
+    }
 
     frame.render_stateful_widget(
         scrollbar,
@@ -110,13 +232,31 @@ fn ui(frame: &mut Frame, status: &PartialStatus) {
         }),
         &mut scrollbar_state,
     );
-    if !status.total_time.is_zero() {
-        let ratio = status.time.as_nanos() as f64 / status.total_time.as_nanos() as f64;
+    progress_ui(frame, status_area, status, time);
+}
+
+/// Draws the progress of the song (or the time since the start of a stream).
+fn progress_ui(frame: &mut Frame, area: Rect, status: &PartialStatus, time: Duration) {
+    if status.live {
+        // A stream has no duration, show the time since it started
+        let title = status
+            .stream_title
+            .as_ref()
+            .map(|title| format!(" - {title}"))
+            .unwrap_or_default();
+        let label = format!(
+            "{}Live {}{title}",
+            if status.paused { "Paused " } else { "" },
+            format_duration(time)
+        );
+        frame.render_widget(Text::from(label).blue(), area);
+    } else if !status.total_time.is_zero() {
+        let ratio = time.as_nanos() as f64 / status.total_time.as_nanos() as f64;
         if (0.0..=1.0).contains(&ratio) {
             let label = format!(
                 "{}{} / {}",
                 if status.paused { "Paused " } else { "" },
-                format_duration(status.time),
+                format_duration(time),
                 format_duration(status.total_time)
             );
             frame.render_widget(
@@ -125,8 +265,59 @@ fn ui(frame: &mut Frame, status: &PartialStatus) {
                     .filled_style(Style::default().blue())
                     .unfilled_style(Style::default().gray())
                     .label(label),
-                status_area,
+                area,
             );
         }
     }
 }
+
+/// Draws the lyrics pane, with the current line highlighted and scrolled into view.
+fn lyrics_ui(frame: &mut Frame, area: Rect, lyrics: &Lyrics, time: Duration) {
+    let items: Vec<ListItem> = lyrics
+        .lines()
+        .iter()
+        .map(|line| ListItem::new(line.text.clone()))
+        .collect();
+    let current_line = lyrics.current_line(time);
+    let mut state = ListState::default().with_selected(current_line);
+
+    let widget = List::new(items)
+        .block(
+            Block::bordered()
+                .title(Line::from("Lyrics").centered())
+                .border_type(BorderType::Rounded),
+        )
+        .highlight_style(Style::new().bold().yellow())
+        // Keep some lines around the current line visible
+        .scroll_padding(usize::from(area.height / 2).saturating_sub(1));
+
+    frame.render_stateful_widget(widget, area, &mut state);
+}
+
+/// Returns the style of a message with the given [`Severity`].
+fn severity_style(severity: Severity) -> Style {
+    match severity {
+        Severity::Info => Style::new(),
+        Severity::Warning => Style::new().yellow(),
+        Severity::Error => Style::new().red(),
+    }
+}
+
+/// Draws the log panel, with the last messages since the start of the player.
+fn log_ui(frame: &mut Frame, area: Rect, log: &Log, position: usize) {
+    let items: Vec<ListItem> = log
+        .iter()
+        .map(|message| ListItem::new(message.to_string()).style(severity_style(message.severity)))
+        .collect();
+    let mut state = ListState::default().with_selected((!log.is_empty()).then_some(position));
+
+    let widget = List::new(items)
+        .block(
+            Block::bordered()
+                .title(Line::from("Log").centered())
+                .border_type(BorderType::Rounded),
+        )
+        .highlight_style(Style::new().on_gray());
+
+    frame.render_stateful_widget(widget, area, &mut state);
+}
@@ -0,0 +1,412 @@
+//! Playing the episodes of podcasts.
+//!
+//! The episodes of the feeds are played newest first, and the [`EpisodeTracker`] remembers
+//! where we stopped in each of them in an [`EpisodeStore`]. The played episodes are skipped
+//! the next time, and the other ones resume where they were stopped.
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    agent::{build_agent, AgentConfig},
+    feed::{get_episodes, Episode},
+    options::{args_or_help, Options, USAGE as OPTIONS_USAGE},
+    player::{
+        plugin::{Plugin, PluginContext},
+        Command, Metadata, Player, StatusMessage,
+    },
+    secrets::commands::secret_plugins,
+    song::{EBox, Web},
+};
+
+/// The usage of the `podcast` command.
+pub const USAGE: &str = "Usage: audio-player podcast <URL>... [OPTIONS]
+
+Plays the episodes of the podcast feeds (RSS or Atom) at the given URLs, newest first,
+with the options of the player.
+The played episodes are skipped and the other ones resume where they were stopped.";
+
+/// An episode is played when we stopped less than this time before its end.
+const END_MARGIN: f64 = 30.0;
+
+/// The player has resumed an episode when it's less than this time before the resume position.
+const RESUME_MARGIN: f64 = 1.0;
+
+/// The interval between two saves of the positions in the episodes.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What we know about an episode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeState {
+    /// Has the episode been played until its end?
+    pub played: bool,
+    /// Where we stopped in the episode, in seconds.
+    pub position: f64,
+}
+
+/// The states of the episodes, kept in a JSON file.
+pub struct EpisodeStore {
+    /// The path of the file.
+    path: PathBuf,
+    /// The states of the episodes, by ID.
+    episodes: HashMap<String, EpisodeState>,
+}
+
+impl EpisodeStore {
+    /// Loads the store from a file, or creates an empty one if the file doesn't exist.
+    ///
+    /// # Errors
+    /// Fails if the file can't be read or if it's invalid.
+    pub fn load(path: &Path) -> Result<Self, EBox> {
+        let episodes = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|err| format!("Invalid episodes file {}: {err}", path.display()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            episodes,
+        })
+    }
+
+    /// Writes the store to its file atomically.
+    ///
+    /// # Errors
+    /// Fails if the file can't be written.
+    pub fn save(&self) -> Result<(), EBox> {
+        if let Some(folder) = self.path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        let temp_path = self.path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&temp_path, serde_json::to_vec(&self.episodes)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Returns the state of an episode.
+    #[must_use]
+    pub fn get(&self, id: &str) -> EpisodeState {
+        self.episodes.get(id).copied().unwrap_or_default()
+    }
+
+    /// Has an episode been played until its end?
+    #[must_use]
+    pub fn is_played(&self, id: &str) -> bool {
+        self.get(id).played
+    }
+
+    /// Sets the state of an episode.
+    pub fn set(&mut self, id: &str, state: EpisodeState) {
+        self.episodes.insert(id.to_owned(), state);
+    }
+}
+
+/// The episode that is being played.
+struct CurrentEpisode {
+    /// The URL of the episode.
+    url: String,
+    /// The ID of the episode.
+    id: String,
+    /// The duration of the episode, if it's known.
+    duration: Option<Duration>,
+    /// The last known position in the episode, in seconds.
+    position: f64,
+    /// The position where the episode resumes, until the player has seeked there.
+    resume: Option<f64>,
+    /// Has the episode been played until its end?
+    finished: bool,
+}
+
+/// A [`Plugin`] that resumes the episodes where they were stopped and remembers the played ones.
+pub struct EpisodeTracker {
+    /// The states of the episodes.
+    store: EpisodeStore,
+    /// The IDs and the durations of the episodes, by URL.
+    episodes: HashMap<String, (String, Option<Duration>)>,
+    /// The episode that is being played.
+    current: Option<CurrentEpisode>,
+    /// When the positions should be saved.
+    next_save: Instant,
+}
+
+impl EpisodeTracker {
+    /// Creates a tracker of the given episodes.
+    #[must_use]
+    pub fn new(store: EpisodeStore, episodes: &[Episode]) -> Self {
+        Self {
+            store,
+            episodes: episodes
+                .iter()
+                .map(|episode| {
+                    (
+                        episode.url.to_string(),
+                        (episode.id.clone(), episode.duration),
+                    )
+                })
+                .collect(),
+            current: None,
+            next_save: Instant::now() + SAVE_INTERVAL,
+        }
+    }
+
+    /// Starts tracking the episode at `url`.
+    ///
+    /// Returns the position where it should resume, if it was stopped before its end.
+    fn start(&mut self, url: &str, duration: Option<Duration>) -> Option<f64> {
+        let (id, feed_duration) = self.episodes.get(url)?;
+        let state = self.store.get(id);
+        let resume = (!state.played && state.position > 0.0).then_some(state.position);
+        self.current = Some(CurrentEpisode {
+            url: url.to_owned(),
+            id: id.clone(),
+            duration: duration.or(*feed_duration),
+            position: state.position,
+            resume,
+            finished: false,
+        });
+        resume
+    }
+
+    /// Records the position of the player in the current episode, if it's playing.
+    ///
+    /// Returns whether the position has been recorded: it isn't until the player has seeked
+    /// to the resume position, so the saved position isn't replaced by the start of the episode.
+    fn record_position(&mut self, song: Option<&str>, time: f64) -> bool {
+        let Some(episode) = &mut self.current else {
+            return false;
+        };
+        if song != Some(episode.url.as_str()) {
+            return false;
+        }
+        if let Some(resume) = episode.resume {
+            if time + RESUME_MARGIN < resume {
+                return false;
+            }
+            episode.resume = None;
+        }
+        episode.position = time;
+        true
+    }
+
+    /// Stops tracking the current episode, which is played if it has been played until its end
+    /// or if we stopped near its end.
+    ///
+    /// # Errors
+    /// Fails if the store can't be saved.
+    fn finish(&mut self) -> Result<(), EBox> {
+        let Some(episode) = self.current.take() else {
+            return Ok(());
+        };
+        let played = episode.finished
+            || episode
+                .duration
+                .is_some_and(|duration| episode.position + END_MARGIN >= duration.as_secs_f64());
+        let state = if played {
+            EpisodeState {
+                played,
+                position: 0.0,
+            }
+        } else {
+            EpisodeState {
+                played: self.store.is_played(&episode.id),
+                position: episode.position,
+            }
+        };
+        self.store.set(&episode.id, state);
+        self.store.save()
+    }
+}
+
+impl Plugin for EpisodeTracker {
+    fn on_track_change(
+        &mut self,
+        context: &PluginContext,
+        metadata: &Metadata,
+    ) -> Result<(), EBox> {
+        self.finish()?;
+        if let Some(position) = self.start(&metadata.path, metadata.duration) {
+            let time = Duration::from_secs_f64(position);
+            context.send(Command::SeekTo(time))?;
+            let seconds = time.as_secs();
+            context.send(Command::DisplayMessage(StatusMessage::five_seconds(
+                format!("Resuming at {:02}:{:02}", seconds / 60, seconds % 60),
+            )))?;
+        }
+        Ok(())
+    }
+
+    fn on_track_finished(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+        // The end of the track is also the end of the episodes with an unknown duration
+        if let Some(episode) = &mut self.current {
+            episode.finished = true;
+        }
+        Ok(())
+    }
+
+    fn on_tick(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        let status = context.status();
+        if !self.record_position(status.song.as_deref(), status.time) {
+            return Ok(());
+        }
+        let Some(episode) = &self.current else {
+            return Ok(());
+        };
+        if Instant::now() < self.next_save {
+            return Ok(());
+        }
+        self.next_save = Instant::now() + SAVE_INTERVAL;
+        let state = EpisodeState {
+            played: self.store.is_played(&episode.id),
+            position: episode.position,
+        };
+        self.store.set(&episode.id, state);
+        self.store.save()
+    }
+
+    fn on_shutdown(&mut self, _context: &PluginContext) -> Result<(), EBox> {
+        self.finish()
+    }
+}
+
+/// Runs the `podcast` command with the given arguments.
+///
+/// # Errors
+/// Fails if the arguments are invalid, if a feed can't be fetched,
+/// if there is no episode to play or if the player fails.
+pub fn podcast(args: impl IntoIterator<Item = String>) -> Result<(), EBox> {
+    let Some(args) = args_or_help(args, &format!("{USAGE}\n\n{OPTIONS_USAGE}")) else {
+        return Ok(());
+    };
+    let mut args = args.into_iter().peekable();
+    let mut urls = vec![];
+    while let Some(url) = args.next_if(|arg| !arg.starts_with('-')) {
+        urls.push(Url::parse(&url)?);
+    }
+    let url = urls.first().ok_or(USAGE)?;
+    let options = Options {
+        shuffle: false,
+        ..Options::parse(args)?
+    };
+    let config = AgentConfig::load(
+        url,
+        &options.tls,
+        options.web_config.as_deref(),
+        options.netrc.as_deref(),
+    )?;
+    let agent = build_agent(&config, &options.network)?;
+
+    let store = EpisodeStore::load(&options.episodes)?;
+    let mut episodes = vec![];
+    for url in &urls {
+        episodes.extend(get_episodes(&agent, url, &options.network)?);
+    }
+    episodes.retain(|episode| !store.is_played(&episode.id));
+    if episodes.is_empty() {
+        return Err("All the episodes have been played".into());
+    }
+    // The feeds are merged, newest first
+    episodes.sort_by_key(|episode| Reverse(episode.published));
+
+    let tracker = EpisodeTracker::new(store, &episodes);
+    let mut songs = episodes
+        .iter()
+        .map(|episode| Web::new(&episode.url, &agent).with_network(options.network))
+        .collect::<Vec<_>>();
+    Player::new(&mut songs)
+        .with_options(options)
+        .with_plugins(secret_plugins())
+        .with_plugin(tracker)
+        .play()
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{env::temp_dir, fs, process, time::Duration};
+
+    use url::Url;
+
+    use super::{EpisodeState, EpisodeStore, EpisodeTracker};
+    use crate::feed::Episode;
+
+    #[test]
+    fn resume_and_finish() {
+        let path = temp_dir()
+            .join(format!("audio-player-test-episodes-{}", process::id()))
+            .join("episodes.json");
+        let _ = fs::remove_file(&path);
+        let url = Url::parse("https://example.com/1.mp3").unwrap();
+        let second_url = Url::parse("https://example.com/2.mp3").unwrap();
+        let episodes = [
+            Episode {
+                id: "episode-1".to_owned(),
+                title: "Episode 1".to_owned(),
+                url: url.clone(),
+                published: None,
+                duration: Some(Duration::from_mins(10)),
+            },
+            Episode {
+                id: "episode-2".to_owned(),
+                title: "Episode 2".to_owned(),
+                url: second_url.clone(),
+                published: None,
+                duration: None,
+            },
+        ];
+
+        let mut tracker = EpisodeTracker::new(EpisodeStore::load(&path).unwrap(), &episodes);
+        // Unknown songs aren't tracked
+        assert_eq!(tracker.start("https://example.com/other.mp3", None), None);
+        assert!(tracker.current.is_none());
+        // A new episode starts at the beginning
+        assert_eq!(tracker.start(url.as_str(), None), None);
+        tracker.current.as_mut().unwrap().position = 120.5;
+        tracker.finish().unwrap();
+
+        // The position is saved, and only replaced once the player has resumed there
+        let mut tracker = EpisodeTracker::new(EpisodeStore::load(&path).unwrap(), &episodes);
+        assert_eq!(tracker.start(url.as_str(), None), Some(120.5));
+        assert!(!tracker.record_position(Some(url.as_str()), 0.0));
+        assert!(!tracker.record_position(Some(second_url.as_str()), 130.0));
+        tracker.finish().unwrap();
+        let mut tracker = EpisodeTracker::new(EpisodeStore::load(&path).unwrap(), &episodes);
+        assert_eq!(tracker.start(url.as_str(), None), Some(120.5));
+        assert!(tracker.record_position(Some(url.as_str()), 120.0));
+        assert!(tracker.record_position(Some(url.as_str()), 580.0));
+        tracker.finish().unwrap();
+
+        // The episode is played once we stopped near its end
+        let store = EpisodeStore::load(&path).unwrap();
+        assert_eq!(
+            store.get("episode-1"),
+            EpisodeState {
+                played: true,
+                position: 0.0
+            }
+        );
+        assert!(!store.is_played("episode-2"));
+
+        // Without a duration, the episode is played once the track has been played until its end
+        let mut tracker = EpisodeTracker::new(store, &episodes[1..]);
+        assert_eq!(tracker.start(second_url.as_str(), None), None);
+        assert!(tracker.record_position(Some(second_url.as_str()), 5000.0));
+        tracker.finish().unwrap();
+        let mut tracker = EpisodeTracker::new(EpisodeStore::load(&path).unwrap(), &episodes[1..]);
+        assert_eq!(tracker.start(second_url.as_str(), None), Some(5000.0));
+        assert!(tracker.record_position(Some(second_url.as_str()), 6000.0));
+        tracker.current.as_mut().unwrap().finished = true;
+        tracker.finish().unwrap();
+        assert!(EpisodeStore::load(&path).unwrap().is_played("episode-2"));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
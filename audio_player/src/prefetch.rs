@@ -0,0 +1,335 @@
+//! Reading of the live streams and of the remote files ahead of the player.
+//!
+//! A [`Prefetch`] reads its source on a background thread into a bounded buffer,
+//! so the slow requests and the reconnections of the source don't stop the audio thread
+//! while there is buffered data. The thread waits when the buffer is full,
+//! and stops when the [`Prefetch`] is dropped.
+//!
+//! A seekable [`Prefetch`] skips the buffered data when it's seeked forwards in the buffer.
+//! Otherwise, the buffer is emptied and the thread seeks its source before reading it again.
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Seek, SeekFrom},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+/// The size of the reads of the source.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The state shared by a [`Prefetch`] and its thread.
+#[derive(Default)]
+struct State {
+    /// The data that has been read from the source but not by the player.
+    buffer: VecDeque<u8>,
+    /// Has the source ended (or failed)?
+    finished: bool,
+    /// The error of the source, until it's returned to the player.
+    error: Option<io::Error>,
+    /// Has the [`Prefetch`] been dropped?
+    stopped: bool,
+    /// The position where the thread must seek the source before reading it again.
+    seek: Option<u64>,
+    /// The number of seeks, to drop the data read before the last one.
+    generation: u64,
+}
+
+/// The [`State`] and the [`Condvar`] notified when it changes.
+#[derive(Default)]
+struct Shared {
+    /// The state of the buffer.
+    state: Mutex<State>,
+    /// Notified when data is added or removed, and when the source or the reader stops.
+    changed: Condvar,
+}
+
+impl Shared {
+    /// Locks the state.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits until the state changes.
+    fn wait<'state>(&self, state: MutexGuard<'state, State>) -> MutexGuard<'state, State> {
+        self.changed
+            .wait(state)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A live stream or a file read ahead by a background thread.
+///
+/// A live stream can't be seeked, but its position can be read with [`Seek::stream_position`].
+pub struct Prefetch {
+    /// The state shared with the thread.
+    shared: Arc<Shared>,
+    /// The number of bytes read since the start of the stream.
+    position: u64,
+    /// Can the source be seeked?
+    seekable: bool,
+    /// The size of the source, if it's known, for the seeks from the end.
+    size: Option<u64>,
+}
+
+impl Prefetch {
+    /// Starts reading `source` on a background thread, keeping at most `capacity` bytes ahead.
+    #[must_use]
+    pub fn new(source: impl Read + Send + 'static, capacity: usize) -> Self {
+        let shared = Arc::new(Shared::default());
+        let thread_shared = Arc::clone(&shared);
+        // The source is never seeked
+        thread::spawn(move || fill(&thread_shared, source, capacity.max(1), |_, _| Ok(0)));
+        Self {
+            shared,
+            position: 0,
+            seekable: false,
+            size: None,
+        }
+    }
+
+    /// Starts reading the seekable `source` (of the given `size`, if it's known)
+    /// on a background thread, keeping at most `capacity` bytes ahead.
+    #[must_use]
+    pub fn seekable(
+        source: impl Read + Seek + Send + 'static,
+        size: Option<u64>,
+        capacity: usize,
+    ) -> Self {
+        let shared = Arc::new(Shared::default());
+        let thread_shared = Arc::clone(&shared);
+        thread::spawn(move || {
+            fill(
+                &thread_shared,
+                source,
+                capacity.max(1),
+                |source, position| source.seek(SeekFrom::Start(position)),
+            );
+        });
+        Self {
+            shared,
+            position: 0,
+            seekable: true,
+            size,
+        }
+    }
+}
+
+/// Reads `source` into the buffer until the [`Prefetch`] is dropped.
+///
+/// The source is seeked with `seek` when the [`Prefetch`] asks for it.
+fn fill<S: Read>(
+    shared: &Shared,
+    mut source: S,
+    capacity: usize,
+    mut seek: impl FnMut(&mut S, u64) -> io::Result<u64>,
+) {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let (position, generation) = {
+            let mut state = shared.lock();
+            (state.seek.take(), state.generation)
+        };
+        let result = match position {
+            Some(position) => seek(&mut source, position).and_then(|_| source.read(&mut chunk)),
+            None => source.read(&mut chunk),
+        };
+        let mut state = shared.lock();
+        // The data read before a seek is dropped
+        if state.generation != generation {
+            continue;
+        }
+        let mut data = match result {
+            Ok(read) if read > 0 => &chunk[..read],
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Ok(_) | Err(_) => {
+                state.error = result.err();
+                state.finished = true;
+                shared.changed.notify_all();
+                // Wait for a seek
+                while state.generation == generation && !state.stopped {
+                    state = shared.wait(state);
+                }
+                if state.stopped {
+                    return;
+                }
+                continue;
+            }
+        };
+        while !data.is_empty() {
+            while state.buffer.len() >= capacity && state.generation == generation && !state.stopped
+            {
+                state = shared.wait(state);
+            }
+            if state.stopped {
+                return;
+            }
+            if state.generation != generation {
+                break;
+            }
+            let length = data.len().min(capacity - state.buffer.len());
+            state.buffer.extend(&data[..length]);
+            data = &data[length..];
+            shared.changed.notify_all();
+        }
+        if state.stopped {
+            return;
+        }
+    }
+}
+
+impl Read for Prefetch {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.shared.lock();
+        while state.buffer.is_empty() && !state.finished {
+            state = self.shared.wait(state);
+        }
+        if state.buffer.is_empty() {
+            return state.error.take().map_or(Ok(0), Err);
+        }
+        let (front, _) = state.buffer.as_slices();
+        let length = front.len().min(buf.len());
+        buf[..length].copy_from_slice(&front[..length]);
+        state.buffer.drain(..length);
+        self.shared.changed.notify_all();
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl Seek for Prefetch {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Current(0) => return Ok(self.position),
+            _ if !self.seekable => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "A live stream can't be seeked",
+                ))
+            }
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self
+                .size
+                .ok_or_else(|| io::Error::other("Unknown file size"))?
+                .checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek"))?;
+
+        let mut state = self.shared.lock();
+        let skipped = position
+            .checked_sub(self.position)
+            .and_then(|skipped| usize::try_from(skipped).ok())
+            .filter(|&skipped| skipped <= state.buffer.len());
+        if let Some(skipped) = skipped {
+            state.buffer.drain(..skipped);
+        } else {
+            // The thread seeks the source, without stopping the audio thread
+            state.buffer.clear();
+            state.seek = Some(position);
+            state.generation += 1;
+            state.finished = false;
+            state.error = None;
+        }
+        self.shared.changed.notify_all();
+        self.position = position;
+        Ok(position)
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        io::{self, Read, Seek, SeekFrom},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread::sleep,
+        time::Duration,
+    };
+
+    use super::Prefetch;
+
+    /// A source that counts the bytes that have been read from it, and fails at the end.
+    struct Counted {
+        /// The remaining data.
+        data: io::Cursor<Vec<u8>>,
+        /// The number of bytes read.
+        read: Arc<AtomicUsize>,
+    }
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let length = buf.len().min(3);
+            let read = self.data.read(&mut buf[..length])?;
+            if read == 0 {
+                return Err(io::Error::other("Connection lost"));
+            }
+            self.read.fetch_add(read, Ordering::Relaxed);
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn bounded_buffer() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let source = Counted {
+            data: io::Cursor::new(b"abcdefghijklmnop".to_vec()),
+            read: Arc::clone(&read),
+        };
+        let mut prefetch = Prefetch::new(source, 4);
+
+        // The thread stops when the buffer is full
+        sleep(Duration::from_millis(100));
+        assert!(read.load(Ordering::Relaxed) <= 6);
+
+        let mut data = [0; 5];
+        prefetch.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"abcde");
+        let mut data = vec![];
+        let err = prefetch.read_to_end(&mut data).unwrap_err();
+        assert_eq!(err.to_string(), "Connection lost");
+        assert_eq!(data, b"fghijklmnop");
+        assert_eq!(prefetch.read(&mut [0; 4]).unwrap(), 0);
+
+        assert_eq!(prefetch.stream_position().unwrap(), 16);
+        assert!(prefetch.seek(SeekFrom::Start(0)).is_err());
+    }
+
+    #[test]
+    fn seek() {
+        let data = (0..=255).collect::<Vec<u8>>();
+        let mut prefetch = Prefetch::seekable(io::Cursor::new(data.clone()), Some(256), 16);
+        let mut read = [0; 4];
+        prefetch.read_exact(&mut read).unwrap();
+        assert_eq!(read, [0, 1, 2, 3]);
+
+        // In the buffer
+        assert_eq!(prefetch.seek(SeekFrom::Current(2)).unwrap(), 6);
+        prefetch.read_exact(&mut read).unwrap();
+        assert_eq!(read, [6, 7, 8, 9]);
+        // Outside of the buffer
+        assert_eq!(prefetch.seek(SeekFrom::End(-4)).unwrap(), 252);
+        let mut end = vec![];
+        prefetch.read_to_end(&mut end).unwrap();
+        assert_eq!(end, [252, 253, 254, 255]);
+        // After the end of the source
+        assert_eq!(prefetch.seek(SeekFrom::Start(100)).unwrap(), 100);
+        let mut rest = vec![];
+        prefetch.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, data[100..]);
+        assert_eq!(prefetch.stream_position().unwrap(), 256);
+        assert!(prefetch.seek(SeekFrom::Current(-300)).is_err());
+    }
+}
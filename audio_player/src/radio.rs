@@ -0,0 +1,499 @@
+//! Internet radios: endless Icecast and Shoutcast streams.
+//!
+//! The stream is requested with the `Icy-MetaData` header: the server then inserts a metadata block
+//! every `icy-metaint` bytes, whose `StreamTitle` is the title of the song that is playing.
+//! The titles are sent to the player by the [`StreamTitles`] plugin.
+//!
+//! A stream has no duration and can't be seeked. If the connection drops, the stream reconnects.
+//! The stream is read ahead by a [`Prefetch`], so the player keeps playing during the reconnections.
+//!
+//! The `radio` command also plays the HLS playlists (see [`hls`](crate::hls)).
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use ureq::{Agent, Response};
+use url::Url;
+
+use crate::{
+    agent::{build_agent, AgentConfig},
+    download::{fetch, NetworkOptions},
+    hls::{is_playlist, Hls},
+    lyrics::Lyrics,
+    options::{args_or_help, Options, USAGE as OPTIONS_USAGE},
+    player::{
+        plugin::{Plugin, PluginContext},
+        Command, Player,
+    },
+    prefetch::Prefetch,
+    secrets::commands::secret_plugins,
+    song::{EBox, Song},
+    tags::Tags,
+};
+
+/// The usage of the `radio` command.
+pub const USAGE: &str = "Usage: audio-player radio <URL>... [OPTIONS]
+
+Plays the internet radios (Icecast or Shoutcast streams, or HLS playlists) at the given URLs, with the options of the player";
+
+/// The size of the buffer of a stream (about 16 seconds of a 128 kbit/s stream).
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Returns the `StreamTitle` of an ICY metadata block, if it's not empty.
+///
+/// The block looks like `StreamTitle='Artist - Title';StreamUrl='';`, padded with null bytes.
+fn stream_title(metadata: &[u8]) -> Option<String> {
+    let metadata = String::from_utf8_lossy(metadata);
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &metadata[start..];
+    // The title may contain quotes
+    let end = rest.find("';").or_else(|| rest.rfind('\''))?;
+    let title = rest[..end].trim();
+    (!title.is_empty()).then(|| title.to_owned())
+}
+
+/// The data of a stream, without the ICY metadata.
+struct IcyReader {
+    /// The [`Agent`] that fetches the stream.
+    agent: Agent,
+    /// The URL of the stream.
+    url: Url,
+    /// The timeouts and the retries of the connections.
+    network: NetworkOptions,
+    /// Where the titles of the stream are sent.
+    titles: Option<Sender<String>>,
+    /// The body of the current connection.
+    response: Box<dyn Read + Send + Sync>,
+    /// The number of bytes between two metadata blocks (if the server sends metadata).
+    metaint: Option<usize>,
+    /// The number of bytes before the next metadata block.
+    remaining: usize,
+    /// Has the current connection sent some data?
+    received: bool,
+}
+
+impl IcyReader {
+    /// Connects to a stream.
+    ///
+    /// # Errors
+    /// Fails if the stream can't be fetched.
+    fn connect(
+        agent: Agent,
+        url: Url,
+        network: NetworkOptions,
+        titles: Option<Sender<String>>,
+    ) -> Result<Self, EBox> {
+        let response = Self::request(&agent, &url, &network)?;
+        let mut reader = Self {
+            agent,
+            url,
+            network,
+            titles,
+            response: Box::new(io::empty()),
+            metaint: None,
+            remaining: 0,
+            received: false,
+        };
+        reader.start(response);
+        Ok(reader)
+    }
+
+    /// Requests the stream with its metadata.
+    ///
+    /// # Errors
+    /// Fails if the stream can't be fetched.
+    fn request(agent: &Agent, url: &Url, network: &NetworkOptions) -> Result<Response, EBox> {
+        fetch(
+            network,
+            || agent.request_url("GET", url).set("Icy-MetaData", "1"),
+            Ok,
+        )
+    }
+
+    /// Reads the stream from a new connection.
+    fn start(&mut self, response: Response) {
+        self.metaint = response
+            .header("icy-metaint")
+            .and_then(|metaint| metaint.trim().parse().ok())
+            .filter(|metaint| *metaint > 0);
+        self.remaining = self.metaint.unwrap_or(0);
+        self.received = false;
+        self.response = response.into_reader();
+    }
+
+    /// Reads a metadata block and sends its title.
+    ///
+    /// # Errors
+    /// Fails if the block can't be read.
+    fn read_metadata(&mut self, metaint: usize) -> io::Result<()> {
+        let mut length = [0];
+        self.response.read_exact(&mut length)?;
+        let mut metadata = vec![0; usize::from(length[0]) * 16];
+        self.response.read_exact(&mut metadata)?;
+        if let (Some(title), Some(titles)) = (stream_title(&metadata), &self.titles) {
+            // The player may have stopped
+            let _ = titles.send(title);
+        }
+        self.remaining = metaint;
+        Ok(())
+    }
+}
+
+impl Read for IcyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let result = match self.metaint {
+                Some(metaint) if self.remaining == 0 => self.read_metadata(metaint).map(|()| None),
+                Some(_) => {
+                    let length = buf.len().min(self.remaining);
+                    self.response.read(&mut buf[..length]).map(Some)
+                }
+                None => self.response.read(buf).map(Some),
+            };
+            match result {
+                Ok(None) => {}
+                Ok(Some(read)) if read > 0 => {
+                    self.received = true;
+                    self.remaining = self.remaining.saturating_sub(read);
+                    return Ok(read);
+                }
+                // The stream has ended without any data: don't reconnect again
+                Ok(Some(_)) if !self.received => return Ok(0),
+                Err(err) if !self.received => return Err(err),
+                // The connection has dropped
+                Ok(Some(_)) | Err(_) => {
+                    let response = Self::request(&self.agent, &self.url, &self.network)
+                        .map_err(io::Error::other)?;
+                    self.start(response);
+                }
+            }
+        }
+    }
+}
+
+/// An internet radio.
+pub struct Radio<'name, 'agent> {
+    /// The URL of the stream.
+    url: &'name Url,
+    /// The [`Agent`] that will be used to fetch the stream.
+    agent: &'agent Agent,
+    /// The timeouts and the retries of the connections.
+    network: NetworkOptions,
+    /// Where the titles of the stream are sent.
+    titles: Option<Sender<String>>,
+}
+
+impl<'name, 'agent> Radio<'name, 'agent> {
+    /// Creates a new [`Radio`].
+    #[must_use]
+    pub const fn new(url: &'name Url, agent: &'agent Agent) -> Self {
+        Self {
+            url,
+            agent,
+            network: NetworkOptions::DEFAULT,
+            titles: None,
+        }
+    }
+
+    /// Sets the [`NetworkOptions`] of the connections.
+    #[must_use]
+    pub const fn with_network(mut self, network: NetworkOptions) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Sends the titles of the stream to `titles` (see [`StreamTitles`]).
+    #[must_use]
+    pub fn with_titles(mut self, titles: Sender<String>) -> Self {
+        self.titles = Some(titles);
+        self
+    }
+}
+
+impl<'name> Song<'name> for Radio<'name, '_> {
+    fn get_data(&mut self) -> Result<impl Read + Seek + Send + Sync + 'static, EBox> {
+        let reader = IcyReader::connect(
+            self.agent.clone(),
+            self.url.clone(),
+            self.network,
+            self.titles.clone(),
+        )?;
+        Ok(Prefetch::new(reader, BUFFER_SIZE))
+    }
+    fn get_path(&self) -> &'name str {
+        self.url.as_str()
+    }
+    fn is_live(&self) -> bool {
+        true
+    }
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        Ok(None)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        // The tags would be read from the stream: the title comes from the metadata instead
+        Ok(Tags::default())
+    }
+}
+
+/// The data of a [`Station`].
+enum StationData<I, H> {
+    /// The data of an Icecast or Shoutcast stream.
+    Icy(I),
+    /// The data of an HLS playlist.
+    Hls(H),
+}
+
+impl<I: Read, H: Read> Read for StationData<I, H> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Icy(data) => data.read(buf),
+            Self::Hls(data) => data.read(buf),
+        }
+    }
+}
+
+impl<I: Seek, H: Seek> Seek for StationData<I, H> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Icy(data) => data.seek(pos),
+            Self::Hls(data) => data.seek(pos),
+        }
+    }
+}
+
+/// A station of the `radio` command: an Icecast or Shoutcast stream, or an HLS playlist.
+pub enum Station<'name, 'agent> {
+    /// An Icecast or Shoutcast stream.
+    Icy(Radio<'name, 'agent>),
+    /// An HLS playlist.
+    Hls(Hls<'name, 'agent>),
+}
+
+impl<'name, 'agent> Station<'name, 'agent> {
+    /// Creates the station at `url`, depending on its extension.
+    ///
+    /// The titles of the Icecast and Shoutcast streams are sent to `titles`.
+    #[must_use]
+    pub fn new(
+        url: &'name Url,
+        agent: &'agent Agent,
+        network: NetworkOptions,
+        titles: Sender<String>,
+    ) -> Self {
+        if is_playlist(url) {
+            Self::Hls(Hls::new(url, agent).with_network(network))
+        } else {
+            Self::Icy(
+                Radio::new(url, agent)
+                    .with_network(network)
+                    .with_titles(titles),
+            )
+        }
+    }
+}
+
+impl<'name> Song<'name> for Station<'name, '_> {
+    fn get_data(&mut self) -> Result<impl Read + Seek + Send + Sync + 'static, EBox> {
+        Ok(match self {
+            Self::Icy(radio) => StationData::Icy(radio.get_data()?),
+            Self::Hls(hls) => StationData::Hls(hls.get_data()?),
+        })
+    }
+    fn get_path(&self) -> &'name str {
+        match self {
+            Self::Icy(radio) => radio.get_path(),
+            Self::Hls(hls) => hls.get_path(),
+        }
+    }
+    fn preload(&mut self) -> Result<(), EBox> {
+        match self {
+            Self::Icy(radio) => radio.preload(),
+            Self::Hls(hls) => hls.preload(),
+        }
+    }
+    fn is_live(&self) -> bool {
+        match self {
+            Self::Icy(radio) => radio.is_live(),
+            Self::Hls(hls) => hls.is_live(),
+        }
+    }
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        Ok(None)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        Ok(Tags::default())
+    }
+}
+
+/// A [`Plugin`] that shows the titles of the [`Radio`]s in the player.
+pub struct StreamTitles {
+    /// The titles sent by the streams.
+    titles: Receiver<String>,
+}
+
+impl StreamTitles {
+    /// Creates the plugin and the [`Sender`] to give to the [`Radio`]s.
+    #[must_use]
+    pub fn new() -> (Self, Sender<String>) {
+        let (titles_tx, titles) = channel();
+        (Self { titles }, titles_tx)
+    }
+}
+
+impl Plugin for StreamTitles {
+    fn on_tick(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        match self.titles.try_iter().last() {
+            Some(title) => context.send(Command::SetTitle(title)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs the `radio` command with the given arguments.
+///
+/// # Errors
+/// Fails if the arguments are invalid or if the player fails.
+pub fn radio(args: impl IntoIterator<Item = String>) -> Result<(), EBox> {
+    let Some(args) = args_or_help(args, &format!("{USAGE}\n\n{OPTIONS_USAGE}")) else {
+        return Ok(());
+    };
+    let mut args = args.into_iter().peekable();
+    let mut urls = vec![];
+    while let Some(url) = args.next_if(|arg| !arg.starts_with('-')) {
+        urls.push(Url::parse(&url)?);
+    }
+    let url = urls.first().ok_or(USAGE)?;
+    let options = Options::parse(args)?;
+    let config = AgentConfig::load(
+        url,
+        &options.tls,
+        options.web_config.as_deref(),
+        options.netrc.as_deref(),
+    )?;
+    let agent = build_agent(&config, &options.network)?;
+
+    let (plugin, titles) = StreamTitles::new();
+    let mut stations = urls
+        .iter()
+        .map(|url| Station::new(url, &agent, options.network, titles.clone()))
+        .collect::<Vec<_>>();
+    Player::new(&mut stations)
+        .with_options(options)
+        .with_plugins(secret_plugins())
+        .with_plugin(plugin)
+        .play()
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+        net::TcpListener,
+        sync::mpsc::channel,
+        thread::spawn,
+        time::Duration,
+    };
+
+    use url::Url;
+
+    use super::{stream_title, IcyReader};
+    use crate::{download::NetworkOptions, prefetch::Prefetch};
+
+    /// Returns an ICY metadata block with the given content.
+    fn metadata(content: &str) -> Vec<u8> {
+        let length = content.len().div_ceil(16);
+        let mut block = vec![u8::try_from(length).unwrap()];
+        block.extend(content.as_bytes());
+        block.resize(length * 16 + 1, 0);
+        block
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(
+            stream_title(b"StreamTitle='Artist - Title';StreamUrl='';\0\0"),
+            Some("Artist - Title".to_owned())
+        );
+        assert_eq!(
+            stream_title(b"StreamTitle='Don't Stop';"),
+            Some("Don't Stop".to_owned())
+        );
+        assert_eq!(stream_title(b"StreamTitle='';\0"), None);
+        assert_eq!(stream_title(b"\0\0\0"), None);
+    }
+
+    #[test]
+    fn mock_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests_tx, requests_rx) = channel();
+        spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut metadata_requested = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    metadata_requested |= line.eq_ignore_ascii_case("icy-metadata: 1\r\n");
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                requests_tx.send(metadata_requested).unwrap();
+
+                // No Content-Length: the stream is endless
+                stream
+                    .write_all(
+                        b"HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\nicy-metaint: 4\r\n\r\n",
+                    )
+                    .unwrap();
+                match index {
+                    0 => {
+                        stream.write_all(b"abcd").unwrap();
+                        stream.write_all(&metadata("StreamTitle='First';")).unwrap();
+                        stream.write_all(b"efgh").unwrap();
+                        stream.write_all(&[0]).unwrap();
+                        // The connection drops in the middle of the data
+                        stream.write_all(b"ij").unwrap();
+                    }
+                    1 => {
+                        stream.write_all(b"klmn").unwrap();
+                        stream
+                            .write_all(&metadata("StreamTitle='Second';"))
+                            .unwrap();
+                        stream.write_all(b"op").unwrap();
+                    }
+                    // The server stops the stream
+                    _ => {}
+                }
+            }
+        });
+
+        let url = Url::parse(&format!("http://{address}/stream")).unwrap();
+        let network = NetworkOptions {
+            backoff: Duration::from_millis(10),
+            ..NetworkOptions::default()
+        };
+        let (titles_tx, titles_rx) = channel();
+        let reader = IcyReader::connect(ureq::agent(), url, network, Some(titles_tx)).unwrap();
+        let mut reader = Prefetch::new(reader, 4);
+
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "abcdefghijklmnop");
+        assert_eq!(
+            titles_rx.try_iter().collect::<Vec<_>>(),
+            ["First", "Second"]
+        );
+        assert_eq!(requests_rx.try_iter().collect::<Vec<_>>(), [true; 3]);
+
+        assert_eq!(reader.stream_position().unwrap(), 16);
+        assert!(reader.seek(SeekFrom::Start(0)).is_err());
+    }
+}
@@ -1,83 +1,31 @@
 //! The implementation of the secret commands.
-use std::{io::Cursor, string::FromUtf8Error, sync::mpsc::Sender, time::Duration};
+use std::{io::Cursor, string::FromUtf8Error, thread, time::Duration};
 
 use super::obfuscation::deobfuscate;
 use crate::{
-    player::{Command, StatusMessage},
+    player::{
+        plugin::{Plugin, PluginContext},
+        Command, StatusMessage,
+    },
     song::EBox,
 };
 use chrono::{Datelike, Local};
 use rodio::{Decoder, OutputStream, Sink};
 
-/// Checks if one or many [`Secret`]s can be triggered.
-///
-/// # Errors
-/// Depends on the implementation of the [`Secret`]s.
-pub fn check_secrets(tx: &Sender<Command>, stack: &String) -> Result<(), EBox> {
-    Secret1 {}.check(tx, stack)?;
-    Ok(())
-}
-
-/// Checks if one or many [`SecretOnce`]s can be triggered when the program starts.
-///
-/// # Errors
-/// Depends on the implementation of the [`SecretOnce`]s.
-pub fn check_secrets_once(tx: &Sender<Command>) -> Result<(), EBox> {
-    Secret2 {}.check(tx)?;
-    Ok(())
-}
-
-/// A secret feature.
-pub trait Secret {
-    /// Checks if the [`Secret`] can be triggered.
-    ///
-    /// # Errors
-    /// Depends on the implementation.
-    fn check(&mut self, tx: &Sender<Command>, stack: &String) -> Result<(), EBox> {
-        if self.can_be_triggered(tx, stack)? {
-            self.trigger(tx, stack)?;
-        }
-        Ok(())
-    }
-    /// Checks if the [`Secret`] can be triggered.
-    ///
-    /// # Errors
-    /// Depends on the implementation.
-    fn can_be_triggered(&mut self, tx: &Sender<Command>, stack: &String) -> Result<bool, EBox>;
-    /// Trigger the [`Secret`].
-    ///
-    /// # Errors
-    /// Depends on the implementation.
-    fn trigger(&mut self, tx: &Sender<Command>, stack: &String) -> Result<(), EBox>;
+/// Returns the secret features, as [`Plugin`]s.
+#[must_use]
+pub fn secret_plugins() -> Vec<Box<dyn Plugin>> {
+    vec![Box::new(Secret1), Box::new(Secret2)]
 }
 
-/// A secret feature that triggers when the program starts.
-pub trait SecretOnce {
-    /// Checks if the [`Secret`] can be triggered.
-    ///
-    /// # Errors
-    /// Depends on the implementation.
-    fn check(&mut self, tx: &Sender<Command>) -> Result<(), EBox> {
-        if self.can_be_triggered(tx)? {
-            self.trigger(tx)?;
-        }
-        Ok(())
-    }
-    /// Checks if the [`Secret`] can be triggered.
-    ///
-    /// # Errors
-    /// Depends on the implementation.
-    fn can_be_triggered(&mut self, tx: &Sender<Command>) -> Result<bool, EBox>;
-    /// Trigger the [`Secret`].
+/// A secret feature that is triggered by a key sequence.
+struct Secret1;
+impl Secret1 {
+    /// Checks if the [`Secret1`] can be triggered.
     ///
     /// # Errors
-    /// Depends on the implementation.
-    fn trigger(&mut self, tx: &Sender<Command>) -> Result<(), EBox>;
-}
-
-struct Secret1;
-impl Secret for Secret1 {
-    fn can_be_triggered(&mut self, _tx: &Sender<Command>, stack: &String) -> Result<bool, EBox> {
+    /// Fails if the secret data is invalid.
+    fn can_be_triggered(stack: &str) -> Result<bool, EBox> {
         let chars: Vec<u8> = "tblkqmvawbicfizraysbwftntbpyaypnnjhxtflo".into();
         let pwd_chars = deobfuscate(&chars);
         let pwd = String::from_utf8(pwd_chars)?;
@@ -91,20 +39,52 @@ impl Secret for Secret1 {
         Ok(stack.ends_with(&real_pwd))
     }
 
-    fn trigger(&mut self, tx: &Sender<Command>, _stack: &String) -> Result<(), EBox> {
-        tx.send(Command::ForcePause)?;
+    /// Triggers the [`Secret1`].
+    ///
+    /// The sound is played on its own thread, so the other plugins aren't blocked.
+    ///
+    /// # Errors
+    /// Fails if the secret data is invalid or if the player has stopped.
+    fn trigger(context: &PluginContext) -> Result<(), EBox> {
         let secret = include_bytes!("secret1.bin");
         let real_data = deobfuscate(secret);
+        let source = Decoder::new_mp3(Cursor::new(real_data))?;
+        context.send(Command::ForcePause)?;
+        let context = context.clone();
+        thread::spawn(move || {
+            let played = Self::play(source);
+            let _ = context.send(Command::RestorePlayback);
+            let message = played
+                .and_then(|()| {
+                    Ok(StatusMessage::with_duration(
+                        decode_string(2, 31, false)?,
+                        Duration::from_secs(2),
+                    ))
+                })
+                .unwrap_or_else(|err| StatusMessage::error(format!("Plugin failed: {err}")));
+            let _ = context.send(Command::DisplayMessage(message));
+        });
+        Ok(())
+    }
+
+    /// Plays the sound of the [`Secret1`] until its end.
+    ///
+    /// # Errors
+    /// Fails if the sound can't be played.
+    fn play(source: Decoder<Cursor<Vec<u8>>>) -> Result<(), EBox> {
         let (_stream, stream_handle) = OutputStream::try_default()?;
         let sink = Sink::try_new(&stream_handle)?;
-        let source = Decoder::new_mp3(Cursor::new(real_data))?;
         sink.append(source);
         sink.sleep_until_end();
-        tx.send(Command::RestorePlayback)?;
-        tx.send(Command::DisplayMessage(StatusMessage::with_duration(
-            decode_string(2, 31, false)?,
-            Duration::from_secs(2),
-        )))?;
+        Ok(())
+    }
+}
+
+impl Plugin for Secret1 {
+    fn on_keys(&mut self, context: &PluginContext, keys: &str) -> Result<(), EBox> {
+        if Self::can_be_triggered(keys)? {
+            Self::trigger(context)?;
+        }
         Ok(())
     }
 }
@@ -163,19 +143,36 @@ pub fn decode_number(a: usize, l: usize, r: bool) -> u32 {
     n
 }
 
+/// A secret feature that is triggered when the player starts.
 struct Secret2;
-impl SecretOnce for Secret2 {
-    fn can_be_triggered(&mut self, _tx: &Sender<Command>) -> Result<bool, EBox> {
+impl Secret2 {
+    /// Checks if the [`Secret2`] can be triggered.
+    ///
+    /// # Errors
+    /// Fails if the secret data is invalid.
+    fn can_be_triggered() -> Result<bool, EBox> {
         let n = d(decode_number(20, 3, false))?;
         let now = Local::now().date_naive();
         let (a, b) = (now.day(), now.month());
         Ok((a.saturating_sub(b) * (a + b)).saturating_sub(a + b) == n)
     }
 
-    fn trigger(&mut self, tx: &Sender<Command>) -> Result<(), EBox> {
-        tx.send(Command::DisplayMessage(StatusMessage::infinite(
+    /// Triggers the [`Secret2`].
+    ///
+    /// # Errors
+    /// Fails if the secret data is invalid or if the player has stopped.
+    fn trigger(context: &PluginContext) -> Result<(), EBox> {
+        context.send(Command::DisplayMessage(StatusMessage::infinite(
             decode_string(23, 24, false)?,
-        )))?;
+        )))
+    }
+}
+
+impl Plugin for Secret2 {
+    fn on_start(&mut self, context: &PluginContext) -> Result<(), EBox> {
+        if Self::can_be_triggered()? {
+            Self::trigger(context)?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,1088 @@
+//! Playing the songs of a remote machine over SFTP.
+//!
+//! The connection is authenticated with a key (from the SSH agent or a key file), and the key
+//! of the server must be in the `known_hosts` file. The songs are read through the SFTP handle,
+//! so they start playing without being downloaded first. When the session drops
+//! (e.g. after a network change), the files are opened again in a new session.
+use std::{
+    env, fs,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    vec,
+};
+
+use percent_encoding::percent_decode_str;
+use ssh2::{CheckResult, ErrorCode, File, FileStat, KnownHostFileKind, MethodType, Session, Sftp};
+use url::Url;
+
+use crate::{
+    download::NetworkOptions,
+    lyrics::Lyrics,
+    options::{args_or_help, Options, USAGE as OPTIONS_USAGE},
+    player::Player,
+    prefetch::Prefetch,
+    secrets::commands::secret_plugins,
+    song::{EBox, Song, MAX_LYRICS_SIZE},
+    tags::{save_cover_data, Tags, COVER_FILE_NAMES},
+    web_utils::CrawlOptions,
+};
+
+/// The usage of the `sftp` command.
+pub const USAGE: &str = "Usage: audio-player sftp <sftp://[USER@]HOST[:PORT]/PATH> [OPTIONS]
+
+Plays the songs of a folder of a remote machine over SFTP, with the options of the player
+(including the limits and the filters of the web folders).
+A path starting with /~/ is relative to the home folder of the user.
+The user is authenticated with the SSH agent or with a key (--identity, or ~/.ssh/id_ed25519,
+~/.ssh/id_ecdsa and ~/.ssh/id_rsa), whose passphrase is $AUDIO_PLAYER_SSH_PASSPHRASE.
+The key of the server must be in the known hosts (--known-hosts, or ~/.ssh/known_hosts).";
+
+/// The size of the buffer of the songs, large enough to avoid a request for each small read.
+const BUFFER_SIZE: usize = 1 << 16;
+
+/// The size of the data read ahead of the player, so the slow reads don't stop the audio thread.
+const READ_AHEAD_SIZE: usize = 1 << 20;
+
+/// The status code of the SFTP errors for missing files.
+const NO_SUCH_FILE: i32 = 2;
+
+/// The status codes of the SFTP errors for lost connections.
+const NO_CONNECTION: i32 = 6;
+/// See [`NO_CONNECTION`].
+const CONNECTION_LOST: i32 = 7;
+
+/// Returns the home folder of the user.
+fn home_folder() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// Returns the path on the server of an `sftp://` URL.
+///
+/// A path starting with `/~/` is relative to the home folder of the user.
+///
+/// # Errors
+/// Fails if the URL isn't an `sftp://` URL or if its path isn't UTF-8.
+pub fn remote_path(url: &Url) -> Result<String, EBox> {
+    if url.scheme() != "sftp" {
+        return Err(format!("Not an sftp:// URL: {url}").into());
+    }
+    let path = percent_decode_str(url.path())
+        .decode_utf8()
+        .map_err(|err| format!("Invalid path in {url}: {err}"))?;
+    Ok(match path.strip_prefix("/~") {
+        Some("" | "/") => ".".to_owned(),
+        Some(relative) if relative.starts_with('/') => relative[1..].to_owned(),
+        _ => path.into_owned(),
+    })
+}
+
+/// Returns the path of the known hosts: `known_hosts` or `~/.ssh/known_hosts`.
+///
+/// # Errors
+/// Fails if the home folder is unknown.
+fn known_hosts_path(known_hosts: Option<&Path>) -> Result<PathBuf, EBox> {
+    known_hosts
+        .map(Path::to_owned)
+        .or_else(|| home_folder().map(|home| home.join(".ssh/known_hosts")))
+        .ok_or_else(|| "Can't find the known hosts".into())
+}
+
+/// Returns the host key algorithms of the keys of `host` in the known hosts at `path`
+/// (most preferred first), as OpenSSH does.
+///
+/// Otherwise, the server could send a key of another type, that couldn't be checked.
+///
+/// # Errors
+/// Fails if the known hosts can't be read.
+fn known_key_algorithms(
+    session: &Session,
+    host: &str,
+    port: u16,
+    path: &Path,
+) -> Result<Vec<&'static str>, EBox> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Can't read the known hosts {}: {err}", path.display()))?;
+    let mut algorithms = vec![];
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        // The markers (`@revoked` and `@cert-authority`) are skipped
+        let (Some(hosts), Some(key_type)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if hosts.starts_with(['#', '@']) {
+            continue;
+        }
+        let line_algorithms: &[&str] = match key_type {
+            "ssh-ed25519" => &["ssh-ed25519"],
+            "ecdsa-sha2-nistp256" => &["ecdsa-sha2-nistp256"],
+            "ecdsa-sha2-nistp384" => &["ecdsa-sha2-nistp384"],
+            "ecdsa-sha2-nistp521" => &["ecdsa-sha2-nistp521"],
+            "ssh-rsa" => &["rsa-sha2-512", "rsa-sha2-256", "ssh-rsa"],
+            _ => continue,
+        };
+        // Let libssh2 match the (maybe hashed) host names: the key of the line
+        // can't match a dummy key, so a known host is a mismatch
+        let mut line_hosts = session.known_hosts()?;
+        if line_hosts
+            .read_str(line, KnownHostFileKind::OpenSSH)
+            .is_err()
+        {
+            continue;
+        }
+        if matches!(
+            line_hosts.check_port(host, port, &[0]),
+            CheckResult::Mismatch
+        ) {
+            for algorithm in line_algorithms {
+                if !algorithms.contains(algorithm) {
+                    algorithms.push(algorithm);
+                }
+            }
+        }
+    }
+    Ok(algorithms)
+}
+
+/// Checks the key of the server against the known hosts at `path`.
+///
+/// # Errors
+/// Fails if the known hosts can't be read or if the key isn't known.
+fn check_host_key(session: &Session, host: &str, port: u16, path: &Path) -> Result<(), EBox> {
+    let mut hosts = session.known_hosts()?;
+    hosts
+        .read_file(path, KnownHostFileKind::OpenSSH)
+        .map_err(|err| format!("Can't read the known hosts {}: {err}", path.display()))?;
+    let (key, _) = session.host_key().ok_or("The server has no host key")?;
+    match hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => {
+            Err(format!("The host key of {host} isn't in {}", path.display()).into())
+        }
+        CheckResult::Mismatch => Err(format!(
+            "The host key of {host} doesn't match the one in {}",
+            path.display()
+        )
+        .into()),
+        CheckResult::Failure => Err(format!("Can't check the host key of {host}").into()),
+    }
+}
+
+/// Authenticates the user with the given key, or with the SSH agent and the default keys.
+///
+/// # Errors
+/// Fails if no key is accepted by the server.
+fn authenticate(session: &Session, username: &str, identity: Option<&Path>) -> Result<(), EBox> {
+    let passphrase = env::var("AUDIO_PLAYER_SSH_PASSPHRASE").ok();
+    if let Some(identity) = identity {
+        session
+            .userauth_pubkey_file(username, None, identity, passphrase.as_deref())
+            .map_err(|err| format!("Can't authenticate with {}: {err}", identity.display()))?;
+        return Ok(());
+    }
+
+    if session.userauth_agent(username).is_ok() {
+        return Ok(());
+    }
+    let keys = home_folder()
+        .map(|home| {
+            ["id_ed25519", "id_ecdsa", "id_rsa"]
+                .map(|name| home.join(".ssh").join(name))
+                .into_iter()
+                .filter(|path| path.is_file())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    for key in keys {
+        if session
+            .userauth_pubkey_file(username, None, &key, passphrase.as_deref())
+            .is_ok()
+        {
+            return Ok(());
+        }
+    }
+    Err(format!("No key of {username} was accepted by the server").into())
+}
+
+/// Connects to the server of an `sftp://` URL, with key-based authentication.
+///
+/// The user is the one of the URL, or the local one.
+///
+/// # Errors
+/// Fails if the server can't be reached, if its key isn't known
+/// or if the user can't be authenticated.
+pub fn connect(
+    url: &Url,
+    identity: Option<&Path>,
+    known_hosts: Option<&Path>,
+    network: &NetworkOptions,
+) -> Result<Sftp, EBox> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("Missing host in {url}"))?;
+    let port = url.port().unwrap_or(22);
+    let username = match url.username() {
+        "" => env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .map_err(|_| format!("Missing user in {url}"))?,
+        username => percent_decode_str(username).decode_utf8()?.into_owned(),
+    };
+
+    // The IPv6 addresses are in brackets in the URLs, but not in the known hosts
+    let host = host.trim_matches(['[', ']']);
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Can't resolve {host}"))?;
+    let stream = TcpStream::connect_timeout(&address, network.connect_timeout)?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(stream);
+    session.set_timeout(
+        network
+            .read_timeout
+            .as_millis()
+            .try_into()
+            .unwrap_or(u32::MAX),
+    );
+    let known_hosts = known_hosts_path(known_hosts)?;
+    let algorithms = known_key_algorithms(&session, host, port, &known_hosts)?;
+    if !algorithms.is_empty() {
+        session.method_pref(MethodType::HostKey, &algorithms.join(","))?;
+    }
+    session.handshake()?;
+    check_host_key(&session, host, port, &known_hosts)?;
+    authenticate(&session, &username, identity)?;
+    Ok(session.sftp()?)
+}
+
+/// Is the error caused by a dropped session, rather than by the request?
+fn is_disconnection(err: &ssh2::Error) -> bool {
+    matches!(
+        err.code(),
+        ErrorCode::Session(_) | ErrorCode::SFTP(NO_CONNECTION | CONNECTION_LOST)
+    )
+}
+
+/// A connection to an SFTP server, which connects again when the session drops.
+pub struct SftpConnection {
+    /// The `sftp://` URL of the server.
+    url: Url,
+    /// The key of the user, instead of the SSH agent and the default keys.
+    identity: Option<PathBuf>,
+    /// The known hosts, instead of `~/.ssh/known_hosts`.
+    known_hosts: Option<PathBuf>,
+    /// The timeouts of the connections.
+    network: NetworkOptions,
+    /// The SFTP handle of the current session.
+    sftp: Mutex<Arc<Sftp>>,
+}
+impl SftpConnection {
+    /// Connects to the server of an `sftp://` URL, with [`connect`].
+    ///
+    /// # Errors
+    /// Fails if the server can't be reached, if its key isn't known
+    /// or if the user can't be authenticated.
+    pub fn new(
+        url: &Url,
+        identity: Option<&Path>,
+        known_hosts: Option<&Path>,
+        network: &NetworkOptions,
+    ) -> Result<Self, EBox> {
+        let sftp = connect(url, identity, known_hosts, network)?;
+        Ok(Self {
+            url: url.clone(),
+            identity: identity.map(Path::to_owned),
+            known_hosts: known_hosts.map(Path::to_owned),
+            network: *network,
+            sftp: Mutex::new(Arc::new(sftp)),
+        })
+    }
+
+    /// Returns the SFTP handle of the current session.
+    #[must_use]
+    pub fn sftp(&self) -> Arc<Sftp> {
+        Arc::clone(&self.sftp.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Connects again, unless `dropped` has already been replaced by a new session.
+    ///
+    /// # Errors
+    /// Fails if the server can't be reached again.
+    fn reconnect(&self, dropped: &Arc<Sftp>) -> Result<Arc<Sftp>, EBox> {
+        let mut sftp = self.sftp.lock().unwrap_or_else(PoisonError::into_inner);
+        if Arc::ptr_eq(&sftp, dropped) {
+            *sftp = Arc::new(connect(
+                &self.url,
+                self.identity.as_deref(),
+                self.known_hosts.as_deref(),
+                &self.network,
+            )?);
+        }
+        Ok(Arc::clone(&sftp))
+    }
+
+    /// Opens a file, connecting again if the session has dropped.
+    ///
+    /// Returns [`None`] if the file doesn't exist.
+    ///
+    /// # Errors
+    /// Fails if the file can't be opened.
+    fn open(&self, path: &Path) -> Result<Option<File>, EBox> {
+        let sftp = self.sftp();
+        let result = match sftp.open(path) {
+            Err(err) if is_disconnection(&err) => self.reconnect(&sftp)?.open(path),
+            result => result,
+        };
+        match result {
+            Ok(file) => Ok(Some(file)),
+            Err(err) if err.code() == ErrorCode::SFTP(NO_SUCH_FILE) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A file on an SFTP server, opened again at the same position when the session drops.
+struct SftpReader {
+    /// The connection to the server.
+    connection: Arc<SftpConnection>,
+    /// The path of the file on the server.
+    path: PathBuf,
+    /// The file opened in the current session.
+    file: File,
+    /// The size of the file, if known.
+    size: Option<u64>,
+    /// The position in the file.
+    position: u64,
+}
+impl SftpReader {
+    /// Opens the file at `path`.
+    ///
+    /// # Errors
+    /// Fails if the file doesn't exist or can't be opened.
+    fn open(connection: Arc<SftpConnection>, path: &Path) -> Result<Self, EBox> {
+        let mut file = connection
+            .open(path)?
+            .ok_or_else(|| format!("Missing file {}", path.display()))?;
+        let size = file.stat()?.size;
+        Ok(Self {
+            connection,
+            path: path.to_owned(),
+            file,
+            size,
+            position: 0,
+        })
+    }
+
+    /// Opens the file again at the current position, in a new session if needed.
+    ///
+    /// # Errors
+    /// Fails if the file can't be opened again.
+    fn reopen(&mut self) -> Result<(), EBox> {
+        let mut file = self
+            .connection
+            .open(&self.path)?
+            .ok_or_else(|| format!("Missing file {}", self.path.display()))?;
+        file.seek(SeekFrom::Start(self.position))?;
+        self.file = file;
+        Ok(())
+    }
+}
+impl Read for SftpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match self.file.read(buf) {
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(err),
+            // The error doesn't tell whether the session has dropped, so try again once
+            Err(err) => {
+                self.reopen()
+                    .map_err(|reopen_err| io::Error::other(format!("{err} ({reopen_err})")))?;
+                self.file.read(buf)?
+            }
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+impl Seek for SftpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // Seek locally, so that the requests are only made (and retried) by the reads
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                let size = self
+                    .size
+                    .ok_or_else(|| io::Error::other("Unknown file size"))?;
+                size.checked_add_signed(offset)
+            }
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek"))?;
+        self.position = self.file.seek(SeekFrom::Start(position))?;
+        Ok(self.position)
+    }
+}
+
+/// A recursive iterator over the files in a folder of an SFTP server,
+/// like [`RecurseFilesIterator`](files::RecurseFilesIterator).
+///
+/// Only the accepted files are returned, and the skipped folders aren't listed.
+#[must_use]
+pub struct RecurseSftpIterator<'sftp> {
+    /// The SFTP handle.
+    sftp: &'sftp Sftp,
+    /// The crawled folder.
+    folder: PathBuf,
+    /// The limits and the filters of the crawling.
+    options: &'sftp CrawlOptions,
+    /// The entries of the folders to check.
+    stack: Vec<vec::IntoIter<(PathBuf, FileStat)>>,
+}
+impl Iterator for RecurseSftpIterator<'_> {
+    type Item = Result<PathBuf, EBox>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item().transpose()
+    }
+}
+impl<'sftp> RecurseSftpIterator<'sftp> {
+    /// Creates a new [`RecurseSftpIterator`] over the contents of the given `path`.
+    ///
+    /// # Errors
+    /// Fails if the `path` cannot be listed.
+    pub fn new(sftp: &'sftp Sftp, path: &Path, options: &'sftp CrawlOptions) -> Result<Self, EBox> {
+        Ok(Self {
+            sftp,
+            folder: path.to_owned(),
+            options,
+            stack: vec![sftp.readdir(path)?.into_iter()],
+        })
+    }
+    /// Returns the next item as a [`Result`].
+    ///
+    /// # Errors
+    /// Fails if a folder cannot be listed.
+    fn next_item(&mut self) -> Result<Option<PathBuf>, EBox> {
+        loop {
+            let Some(entries) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            let Some((path, stat)) = entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            // Add the accepted folders to the stack, and skip the other file types (e.g. symlinks)
+            if stat.is_dir() {
+                if accepts_folder(&self.folder, &path, self.options) {
+                    self.stack.push(self.sftp.readdir(&path)?.into_iter());
+                }
+            } else if stat.is_file() && accepts_file(&self.folder, &path, self.options) {
+                return Ok(Some(path));
+            }
+        }
+    }
+}
+
+/// Returns the path of `path` relative to the crawled `folder`, with `/` separators.
+fn relative_path(folder: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(folder).ok()?.to_str()?;
+    Some(relative.replace('\\', "/"))
+}
+
+/// Should the subfolder at `path`, in the crawled `folder`, be listed?
+#[must_use]
+pub fn accepts_folder(folder: &Path, path: &Path, options: &CrawlOptions) -> bool {
+    relative_path(folder, path).is_some_and(|relative| {
+        let relative = format!("{relative}/");
+        relative.matches('/').count() <= options.max_depth && options.accepts_folder(&relative)
+    })
+}
+
+/// Should the file at `path`, in the crawled `folder`, be played?
+///
+/// Its folders are checked by [`accepts_folder`] before they are listed.
+#[must_use]
+pub fn accepts_file(folder: &Path, path: &Path, options: &CrawlOptions) -> bool {
+    relative_path(folder, path).is_some_and(|relative| options.accepts_file(&relative))
+}
+
+/// A song on an SFTP server.
+pub struct SftpSong<'name> {
+    /// The path of the song on the server.
+    path: &'name str,
+    /// The connection to the server.
+    connection: Arc<SftpConnection>,
+}
+impl<'name> SftpSong<'name> {
+    /// Creates a new [`SftpSong`].
+    #[must_use]
+    pub const fn new(path: &'name str, connection: Arc<SftpConnection>) -> Self {
+        Self { path, connection }
+    }
+
+    /// Reads at most `max_size` bytes of a file next to the song, if it exists.
+    ///
+    /// # Errors
+    /// Fails if the file exists but can't be read.
+    fn read_sibling(&self, name: &str, max_size: u64) -> Result<Option<Vec<u8>>, EBox> {
+        let path = Path::new(self.path).with_file_name(name);
+        let Some(file) = self.connection.open(&path)? else {
+            return Ok(None);
+        };
+        let mut data = vec![];
+        file.take(max_size).read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+}
+impl<'name> Song<'name> for SftpSong<'name> {
+    fn get_data(&mut self) -> Result<impl io::Read + io::Seek + Send + Sync + 'static, EBox> {
+        let reader = SftpReader::open(Arc::clone(&self.connection), Path::new(self.path))?;
+        let size = reader.size;
+        Ok(Prefetch::seekable(
+            BufReader::with_capacity(BUFFER_SIZE, reader),
+            size,
+            READ_AHEAD_SIZE,
+        ))
+    }
+    fn get_path(&self) -> &'name str {
+        self.path
+    }
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        // Prefer the `.lrc` file next to the song
+        let name = Path::new(self.path).with_extension("lrc");
+        if let Some(name) = name.file_name().and_then(|name| name.to_str()) {
+            if let Some(content) = self.read_sibling(name, MAX_LYRICS_SIZE)? {
+                return Ok(Some(Lyrics::parse(&String::from_utf8_lossy(&content))));
+            }
+        }
+        Lyrics::from_tags(self.get_data()?)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        let mut tags = Tags::from_data(self.get_data()?)?;
+        if tags.cover_url.is_none() {
+            // Look for a cover next to the song
+            for name in COVER_FILE_NAMES {
+                if let Some(data) = self.read_sibling(name, u64::MAX)? {
+                    let extension = name.rsplit('.').next().unwrap_or("jpg");
+                    tags.cover_url = save_cover_data(&data, extension)?;
+                    break;
+                }
+            }
+        }
+        Ok(tags)
+    }
+}
+
+/// Runs the `sftp` command with the given arguments.
+///
+/// # Errors
+/// Fails if the arguments are invalid, if the server can't be reached,
+/// if the folder can't be listed or if the player fails.
+pub fn sftp(args: impl IntoIterator<Item = String>) -> Result<(), EBox> {
+    let Some(args) = args_or_help(args, &format!("{USAGE}\n\n{OPTIONS_USAGE}")) else {
+        return Ok(());
+    };
+    let mut args = args.into_iter().peekable();
+    let url = args.next_if(|arg| !arg.starts_with('-')).ok_or(USAGE)?;
+    let url = Url::parse(&url)?;
+    let options = Options::parse(args)?;
+    let folder = PathBuf::from(remote_path(&url)?);
+    let connection = Arc::new(SftpConnection::new(
+        &url,
+        options.identity.as_deref(),
+        options.known_hosts.as_deref(),
+        &options.network,
+    )?);
+
+    let mut paths = vec![];
+    for path in RecurseSftpIterator::new(&connection.sftp(), &folder, &options.crawl)? {
+        paths.push(
+            path?
+                .into_os_string()
+                .into_string()
+                .map_err(|path| format!("Invalid path {}", path.to_string_lossy()))?,
+        );
+    }
+    if paths.is_empty() {
+        return Err(format!("No song found in {url}").into());
+    }
+    let mut songs = paths
+        .iter()
+        .map(|path| SftpSong::new(path, Arc::clone(&connection)))
+        .collect::<Vec<_>>();
+    Player::new(&mut songs)
+        .with_options(options)
+        .with_plugins(secret_plugins())
+        .play()
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        env::temp_dir,
+        fs,
+        io::{Read, Seek, SeekFrom},
+        net::TcpListener,
+        path::{Path, PathBuf},
+        process,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use russh::{
+        keys::{
+            ssh_key::{private::Ed25519Keypair, LineEnding},
+            PrivateKey, PublicKey,
+        },
+        server::{self, Auth, ChannelOpenHandle, Msg, Session},
+        Channel, ChannelId, Disconnect,
+    };
+    use russh_sftp::{
+        protocol::{
+            Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode,
+        },
+        server as sftp_server,
+    };
+    use tokio::{net, runtime};
+    use url::Url;
+
+    use super::{
+        accepts_file, accepts_folder, remote_path, RecurseSftpIterator, SftpConnection, SftpSong,
+    };
+    use crate::{download::NetworkOptions, lyrics::Lyrics, song::Song, web_utils::CrawlOptions};
+
+    #[test]
+    fn paths() {
+        let path = |url: &str| remote_path(&Url::parse(url).unwrap()).unwrap();
+        assert_eq!(
+            path("sftp://host/srv/Music%20Library"),
+            "/srv/Music Library"
+        );
+        assert_eq!(path("sftp://user@host:2222/~/Music/"), "Music/");
+        assert_eq!(path("sftp://host/~"), ".");
+        assert_eq!(path("sftp://host/~user/Music"), "/~user/Music");
+        assert!(remote_path(&Url::parse("https://host/Music").unwrap()).is_err());
+
+        let options = CrawlOptions {
+            max_depth: 1,
+            exclude: vec!["Live/*".to_owned()],
+            extensions: vec!["mp3".to_owned()],
+            ..CrawlOptions::default()
+        };
+        let folder = Path::new("Music");
+        assert!(accepts_file(folder, Path::new("Music/a.mp3"), &options));
+        assert!(accepts_file(
+            folder,
+            Path::new("Music/Rock/b.mp3"),
+            &options
+        ));
+        assert!(!accepts_file(
+            folder,
+            Path::new("Music/Rock/b.jpg"),
+            &options
+        ));
+        assert!(!accepts_file(folder, Path::new("Other/e.mp3"), &options));
+        assert!(accepts_folder(folder, Path::new("Music/Rock"), &options));
+        assert!(!accepts_folder(folder, Path::new("Music/Live"), &options));
+        assert!(!accepts_folder(
+            folder,
+            Path::new("Music/Rock/90s"),
+            &options
+        ));
+        assert!(!accepts_folder(folder, Path::new("Other"), &options));
+    }
+
+    /// The SFTP requests of a test session, served from the local files.
+    struct Files {
+        /// The paths of the open files and folders, by handle.
+        handles: HashMap<String, PathBuf>,
+        /// The number of handles that have been opened.
+        opened: usize,
+        /// The SSH session, to drop it.
+        session: server::Handle,
+        /// The offset from which the first read drops the session.
+        drop_offset: Arc<AtomicU64>,
+        /// The number of folders that have been listed.
+        listed: Arc<AtomicUsize>,
+    }
+    impl Files {
+        /// Returns a new handle to `path`.
+        fn open_handle(&mut self, path: String) -> String {
+            self.opened += 1;
+            let handle = self.opened.to_string();
+            self.handles.insert(handle.clone(), PathBuf::from(path));
+            handle
+        }
+
+        /// Returns the path of a handle.
+        ///
+        /// # Errors
+        /// Fails if the handle isn't open.
+        fn path(&self, handle: &str) -> Result<&Path, StatusCode> {
+            self.handles
+                .get(handle)
+                .map(PathBuf::as_path)
+                .ok_or(StatusCode::Failure)
+        }
+    }
+    impl sftp_server::Handler for Files {
+        type Error = StatusCode;
+
+        fn unimplemented(&self) -> Self::Error {
+            StatusCode::OpUnsupported
+        }
+
+        async fn open(
+            &mut self,
+            id: u32,
+            filename: String,
+            _: OpenFlags,
+            _: FileAttributes,
+        ) -> Result<Handle, Self::Error> {
+            if !Path::new(&filename).is_file() {
+                return Err(StatusCode::NoSuchFile);
+            }
+            let handle = self.open_handle(filename);
+            Ok(Handle { id, handle })
+        }
+
+        async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+            self.handles.remove(&handle);
+            Ok(Status {
+                id,
+                status_code: StatusCode::Ok,
+                error_message: String::new(),
+                language_tag: String::new(),
+            })
+        }
+
+        async fn read(
+            &mut self,
+            id: u32,
+            handle: String,
+            offset: u64,
+            len: u32,
+        ) -> Result<Data, Self::Error> {
+            if offset >= self.drop_offset.load(Ordering::Relaxed) {
+                self.drop_offset.store(u64::MAX, Ordering::Relaxed);
+                self.session
+                    .disconnect(Disconnect::ByApplication, String::new(), String::new())
+                    .await
+                    .map_err(|_| StatusCode::Failure)?;
+                return Err(StatusCode::ConnectionLost);
+            }
+            let mut file = fs::File::open(self.path(&handle)?).map_err(|_| StatusCode::Failure)?;
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|_| StatusCode::Failure)?;
+            let mut data = vec![];
+            file.take(len.into())
+                .read_to_end(&mut data)
+                .map_err(|_| StatusCode::Failure)?;
+            if data.is_empty() {
+                return Err(StatusCode::Eof);
+            }
+            Ok(Data { id, data })
+        }
+
+        async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+            let metadata = fs::metadata(self.path(&handle)?).map_err(|_| StatusCode::Failure)?;
+            Ok(Attrs {
+                id,
+                attrs: FileAttributes::from(&metadata),
+            })
+        }
+
+        async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+            self.listed.fetch_add(1, Ordering::Relaxed);
+            let handle = self.open_handle(path);
+            Ok(Handle { id, handle })
+        }
+
+        async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+            // List the whole folder at once, and close the handle to end the listing
+            let path = self.handles.remove(&handle).ok_or(StatusCode::Eof)?;
+            let mut files = vec![];
+            for entry in fs::read_dir(path).map_err(|_| StatusCode::NoSuchFile)? {
+                let entry = entry.map_err(|_| StatusCode::Failure)?;
+                let metadata = entry.metadata().map_err(|_| StatusCode::Failure)?;
+                files.push(File::new(
+                    entry.file_name().to_string_lossy(),
+                    FileAttributes::from(&metadata),
+                ));
+            }
+            Ok(Name { id, files })
+        }
+    }
+
+    /// A test SSH session, accepting a single key and serving the SFTP subsystem.
+    struct Ssh {
+        /// The key of the user.
+        user_key: PublicKey,
+        /// The session channels, until they request a subsystem.
+        channels: HashMap<ChannelId, Channel<Msg>>,
+        /// See [`Files::drop_offset`].
+        drop_offset: Arc<AtomicU64>,
+        /// See [`Files::listed`].
+        listed: Arc<AtomicUsize>,
+    }
+    impl server::Handler for Ssh {
+        type Error = russh::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _: &str,
+            public_key: &PublicKey,
+        ) -> Result<Auth, Self::Error> {
+            Ok(if *public_key == self.user_key {
+                Auth::Accept
+            } else {
+                Auth::reject()
+            })
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<Msg>,
+            reply: ChannelOpenHandle,
+            _: &mut Session,
+        ) -> Result<(), Self::Error> {
+            self.channels.insert(channel.id(), channel);
+            reply.accept().await;
+            Ok(())
+        }
+
+        async fn subsystem_request(
+            &mut self,
+            channel: ChannelId,
+            name: &str,
+            session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            match self.channels.remove(&channel) {
+                Some(stream) if name == "sftp" => {
+                    session.channel_success(channel)?;
+                    let files = Files {
+                        handles: HashMap::new(),
+                        opened: 0,
+                        session: session.handle(),
+                        drop_offset: Arc::clone(&self.drop_offset),
+                        listed: Arc::clone(&self.listed),
+                    };
+                    sftp_server::run(stream.into_stream(), files).await;
+                }
+                _ => session.channel_failure(channel)?,
+            }
+            Ok(())
+        }
+    }
+
+    /// A test SFTP server.
+    struct Server {
+        /// The port of the server.
+        port: u16,
+        /// The number of connections to the server.
+        connections: Arc<AtomicUsize>,
+        /// See [`Files::drop_offset`].
+        drop_offset: Arc<AtomicU64>,
+        /// See [`Files::listed`].
+        listed: Arc<AtomicUsize>,
+    }
+
+    /// Starts an SFTP server of the local files on `listener`.
+    fn start_server(
+        listener: TcpListener,
+        host_keys: Vec<PrivateKey>,
+        user_key: PublicKey,
+    ) -> Server {
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let drop_offset = Arc::new(AtomicU64::new(u64::MAX));
+        let listed = Arc::new(AtomicUsize::new(0));
+        let server = Server {
+            port,
+            connections: Arc::clone(&connections),
+            drop_offset: Arc::clone(&drop_offset),
+            listed: Arc::clone(&listed),
+        };
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let listener = net::TcpListener::from_std(listener).unwrap();
+                let config = Arc::new(server::Config {
+                    keys: host_keys,
+                    ..server::Config::default()
+                });
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    let ssh = Ssh {
+                        user_key: user_key.clone(),
+                        channels: HashMap::new(),
+                        drop_offset: Arc::clone(&drop_offset),
+                        listed: Arc::clone(&listed),
+                    };
+                    if let Ok(session) = server::run_stream(Arc::clone(&config), stream, ssh).await
+                    {
+                        tokio::spawn(session);
+                    }
+                }
+            });
+        });
+        server
+    }
+
+    #[test]
+    fn server() {
+        let folder = temp_dir().join(format!("audio-player-test-sftp-{}", process::id()));
+        let music = folder.join("music");
+        fs::create_dir_all(music.join("Rock/90s")).unwrap();
+        fs::create_dir_all(music.join("Live")).unwrap();
+        let data = (0..2_000_000_u32)
+            .map(|index| u8::try_from(index * 7 % 251).unwrap())
+            .collect::<Vec<_>>();
+        fs::write(music.join("a.mp3"), &data).unwrap();
+        fs::write(music.join("a.lrc"), "[00:01.00]Hello").unwrap();
+        fs::write(music.join("Rock/b.mp3"), "b").unwrap();
+        fs::write(music.join("Rock/90s/c.mp3"), "c").unwrap();
+        fs::write(music.join("Live/d.mp3"), "d").unwrap();
+
+        let host_key = PrivateKey::from(Ed25519Keypair::from_seed(&[1; 32]));
+        let user_key = PrivateKey::from(Ed25519Keypair::from_seed(&[2; 32]));
+        let identity = folder.join("id_ed25519");
+        fs::write(&identity, user_key.to_openssh(LineEnding::LF).unwrap()).unwrap();
+        let host_line = host_key.public_key().to_openssh().unwrap();
+
+        let server = start_server(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            vec![host_key.clone()],
+            user_key.public_key().clone(),
+        );
+        let known_hosts = folder.join("known_hosts");
+        fs::write(
+            &known_hosts,
+            format!("[127.0.0.1]:{} {host_line}\n", server.port),
+        )
+        .unwrap();
+        let url = Url::parse(&format!(
+            "sftp://user@127.0.0.1:{}{}",
+            server.port,
+            music.display()
+        ))
+        .unwrap();
+        let connect = |url: &Url, known_hosts: &Path| {
+            SftpConnection::new(
+                url,
+                Some(&identity),
+                Some(known_hosts),
+                &NetworkOptions::DEFAULT,
+            )
+        };
+        let connection = Arc::new(connect(&url, &known_hosts).unwrap());
+
+        // The excluded and too deep folders aren't listed
+        let options = CrawlOptions {
+            max_depth: 1,
+            exclude: vec!["Live/*".to_owned()],
+            ..CrawlOptions::default()
+        };
+        let mut paths = RecurseSftpIterator::new(&connection.sftp(), &music, &options)
+            .unwrap()
+            .map(|path| path.unwrap())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["Rock/b.mp3", "a.lrc", "a.mp3"].map(|name| music.join(name))
+        );
+        assert_eq!(server.listed.load(Ordering::Relaxed), 2);
+
+        // Seeking reads the same data as reading from the start
+        let path = music.join("a.mp3").into_os_string().into_string().unwrap();
+        let mut song = SftpSong::new(&path, Arc::clone(&connection));
+        let mut read = vec![];
+        song.get_data().unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        let mut reader = song.get_data().unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(-1000)).unwrap(), 1_999_000);
+        reader.seek(SeekFrom::Start(1_000_000)).unwrap();
+        let mut end = vec![];
+        reader.read_to_end(&mut end).unwrap();
+        assert_eq!(end, data[1_000_000..]);
+        assert_eq!(
+            song.get_lyrics().unwrap(),
+            Some(Lyrics::parse("[00:01.00]Hello"))
+        );
+        let missing = music.join("c.mp3").into_os_string().into_string().unwrap();
+        assert!(SftpSong::new(&missing, Arc::clone(&connection))
+            .get_data()
+            .is_err());
+
+        // The reads continue in a new session when the session drops
+        server.drop_offset.store(1_000_000, Ordering::Relaxed);
+        let mut read = vec![];
+        song.get_data().unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(server.connections.load(Ordering::Relaxed), 2);
+        let mut read = vec![];
+        song.get_data().unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(server.connections.load(Ordering::Relaxed), 2);
+
+        // The host key must be known
+        let other_key = PrivateKey::from(Ed25519Keypair::from_seed(&[3; 32]));
+        let other_hosts = folder.join("other_known_hosts");
+        fs::write(
+            &other_hosts,
+            format!(
+                "[127.0.0.1]:{} {}\n",
+                server.port,
+                other_key.public_key().to_openssh().unwrap()
+            ),
+        )
+        .unwrap();
+        let err = connect(&url, &other_hosts).err().unwrap();
+        assert!(err.to_string().contains("doesn't match"), "{err}");
+
+        // The server is asked for a key of a known type, even if it prefers another one
+        let ecdsa_key =
+            PrivateKey::from_openssh(include_str!("../fixtures/sftp/ecdsa_host_key")).unwrap();
+        let server = start_server(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            vec![ecdsa_key.clone(), host_key.clone()],
+            user_key.public_key().clone(),
+        );
+        let url = Url::parse(&format!("sftp://user@127.0.0.1:{}/", server.port)).unwrap();
+        for key in [&host_key, &ecdsa_key] {
+            fs::write(
+                &known_hosts,
+                format!(
+                    "# Known hosts\n[127.0.0.1]:{} {}\n",
+                    server.port,
+                    key.public_key().to_openssh().unwrap()
+                ),
+            )
+            .unwrap();
+            connect(&url, &known_hosts).unwrap();
+        }
+
+        // The IPv6 addresses are checked without their brackets
+        if let Ok(listener) = TcpListener::bind("[::1]:0") {
+            let server = start_server(listener, vec![host_key], user_key.public_key().clone());
+            fs::write(&known_hosts, format!("[::1]:{} {host_line}\n", server.port)).unwrap();
+            let url = Url::parse(&format!("sftp://user@[::1]:{}/", server.port)).unwrap();
+            connect(&url, &known_hosts).unwrap();
+        }
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
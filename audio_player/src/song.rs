@@ -1,17 +1,32 @@
 //! Structures representing songs.
+use rodio::Source;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     error::Error,
     io::{BufReader, Cursor, Read, Seek},
     path::Path,
-    sync::Mutex,
+    sync::{Arc, Mutex, PoisonError},
 };
 use ureq::Agent;
 use url::Url;
 
+use crate::{
+    cache::LibraryCache,
+    download::{download, fetch, NetworkOptions},
+    lyrics::Lyrics,
+    manifest,
+    tags::{Tags, COVER_FILE_NAMES},
+};
+
 /// The [`Box`] type that contains [`Error`]s.
 pub type EBox = Box<dyn Error + Send + Sync>;
 
+/// The decoded audio of a song, played by the player.
+pub type AudioSource = Box<dyn Source<Item = i16> + Send>;
+
+/// The maximum size of a `.lrc` file that is read.
+pub const MAX_LYRICS_SIZE: u64 = 1024 * 1024;
+
 /// Returns the "real name" of a song, that is to say
 /// the part after the song number, if there is one.
 ///
@@ -54,6 +69,16 @@ pub trait Song<'name>: Sized + Send + Sync {
     fn get_real_name(&self) -> Option<&'name str> {
         get_real_name(self.get_path())
     }
+    /// Returns the decoded audio of the song, if it isn't decoded from [`Song::get_data`]
+    /// (to seek it without reading the song from its start, for example).
+    ///
+    /// By default, the player decodes the song data.
+    ///
+    /// # Errors
+    /// Fails if the song cannot be fetched or decoded.
+    fn get_source(&mut self) -> Result<Option<AudioSource>, EBox> {
+        Ok(None)
+    }
     /// Downloads the song data so it will be available immediatly later.
     ///
     /// # Errors
@@ -61,6 +86,38 @@ pub trait Song<'name>: Sized + Send + Sync {
     fn preload(&mut self) -> Result<(), EBox> {
         Ok(())
     }
+    /// Can the song be played now? The unavailable songs are skipped.
+    ///
+    /// By default, the songs are always available.
+    #[must_use]
+    fn is_available(&self) -> bool {
+        true
+    }
+    /// Is the song an endless stream (without duration, that can't be seeked)?
+    ///
+    /// By default, the songs are not live.
+    #[must_use]
+    fn is_live(&self) -> bool {
+        false
+    }
+    /// Returns the lyrics of the song, if there are some.
+    ///
+    /// By default, the lyrics are read from the tags embedded in the song data.
+    ///
+    /// # Errors
+    /// Fails if the song or its lyrics cannot be fetched.
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        Lyrics::from_tags(self.get_data()?)
+    }
+    /// Returns the tags of the song (title, artist, album, cover).
+    ///
+    /// By default, the tags are read from the song data.
+    ///
+    /// # Errors
+    /// Fails if the song or its tags cannot be fetched.
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        Tags::from_data(self.get_data()?)
+    }
 }
 
 /// A song whose name is the real name, for testing purposes.
@@ -85,6 +142,12 @@ impl<'name> Song<'name> for TestCase<'name> {
     fn get_real_name(&self) -> Option<&'name str> {
         Some(self.name)
     }
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        Ok(None)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        Ok(Tags::default())
+    }
 }
 impl<'name> TestCase<'name> {
     /// Creates a new [`TestCase`].
@@ -144,16 +207,43 @@ impl<'name> Song<'name> for File<'name> {
     fn get_path(&self) -> &'name str {
         self.path.to_str().unwrap()
     }
+    #[expect(clippy::absolute_paths, reason = "name conflict")]
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        // Prefer the `.lrc` file next to the song
+        let lrc_path = self.path.with_extension("lrc");
+        if lrc_path.is_file() {
+            let mut content = vec![];
+            std::fs::File::open(lrc_path)?
+                .take(MAX_LYRICS_SIZE)
+                .read_to_end(&mut content)?;
+            return Ok(Some(Lyrics::parse(&String::from_utf8_lossy(&content))));
+        }
+        Lyrics::from_tags(self.get_data()?)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        let tags = Tags::from_data(self.get_data()?)?;
+        Ok(match self.path.parent() {
+            Some(folder) => tags.with_folder_cover(folder),
+            None => tags,
+        })
+    }
 }
 
+/// The covers found next to the web songs, by folder URL ([`None`] if the folder has no cover).
+static FOLDER_COVERS: Mutex<BTreeMap<String, Option<String>>> = Mutex::new(BTreeMap::new());
+
 /// A song available on the web.
 pub struct Web<'name, 'agent> {
     /// The URL of the song.
     url: &'name Url,
     /// The [`Agent`] that will be used to fetch the song.
     agent: &'agent Agent,
-    /// The fetched song data.
-    data: Vec<u8>,
+    /// The timeouts and the retries of the download.
+    network: NetworkOptions,
+    /// The cache of the library, if it's enabled.
+    cache: Option<&'agent LibraryCache>,
+    /// The fetched song data (shared with the readers of [`Song::get_data`]).
+    data: Option<Arc<[u8]>>,
     /// A lock that allows launching only one [`Web::preload`] function at a time.
     preloading: Mutex<()>,
 }
@@ -164,33 +254,168 @@ impl<'name, 'agent> Web<'name, 'agent> {
         Self {
             url,
             agent,
-            data: vec![],
+            network: NetworkOptions::DEFAULT,
+            cache: None,
+            data: None,
             preloading: Mutex::new(()),
         }
     }
+
+    /// Sets the [`NetworkOptions`] of the download.
+    #[must_use]
+    pub const fn with_network(mut self, network: NetworkOptions) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Sets the [`LibraryCache`] where the song is kept (to play it offline).
+    #[must_use]
+    pub const fn with_cache(mut self, cache: Option<&'agent LibraryCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Is the library offline?
+    fn is_offline(&self) -> bool {
+        self.cache.is_some_and(LibraryCache::is_offline)
+    }
+
+    /// Returns the URL of the cover next to the song, if there is one.
+    ///
+    /// The folders are only probed once (even if they have no cover), with the connection timeout:
+    /// a `HEAD` request doesn't need to wait for data.
+    fn folder_cover(&self) -> Option<String> {
+        let folder = self.url.join("./").ok()?;
+        let covers = || FOLDER_COVERS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cover) = covers().get(folder.as_str()) {
+            return cover.clone();
+        }
+        let cover = COVER_FILE_NAMES
+            .iter()
+            .filter_map(|name| folder.join(name).ok())
+            .find(|url| {
+                self.agent
+                    .request_url("HEAD", url)
+                    .timeout(self.network.connect_timeout)
+                    .call()
+                    .is_ok()
+            })
+            .map(String::from);
+        covers().insert(folder.into(), cover.clone());
+        cover
+    }
 }
 impl<'name, 'agent> Song<'name> for Web<'name, 'agent> {
     fn get_data(&mut self) -> Result<impl Read + Seek + Send + Sync + 'static, EBox> {
         self.preload()?;
-        Ok(Cursor::new(self.data.clone()))
+        Ok(Cursor::new(self.data.clone().unwrap_or_default()))
     }
     #[expect(clippy::unwrap_in_result, reason = "locks should almost always work")]
     fn preload(&mut self) -> Result<(), EBox> {
         let _lock = self.preloading.lock().expect("error while acquiring lock");
-        if !self.data.is_empty() {
+        if self.data.is_some() {
             return Ok(());
         }
-        let mut resp = self
-            .agent
-            .request_url("GET", self.url)
-            .call()?
-            .into_reader();
-        resp.read_to_end(&mut self.data)?;
+        let entry = manifest::fetched_entry(self.url);
+        if let Some(data) = self.cache.and_then(|cache| cache.load_song(self.url)) {
+            // A song that changed since it was cached is downloaded again
+            if self.is_offline()
+                || entry
+                    .as_ref()
+                    .is_none_or(|entry| entry.check(&data).is_ok())
+            {
+                self.data = Some(data.into());
+                return Ok(());
+            }
+        }
+        if self.is_offline() {
+            return Err("The song isn't available offline".into());
+        }
+        let mut data = vec![];
+        download(self.agent, self.url, &self.network, &mut data)?;
+        if let Some(entry) = entry {
+            entry.check(&data)?;
+        }
+        if let Some(cache) = self.cache {
+            // The song can still be played if it can't be cached
+            let _ = cache.save_song(self.url, &data);
+        }
+        self.data = Some(data.into());
         Ok(())
     }
+    fn is_available(&self) -> bool {
+        !self.is_offline() || self.cache.is_some_and(|cache| cache.contains(self.url))
+    }
     fn get_path(&self) -> &'name str {
         self.url.as_str()
     }
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        if self.is_offline() {
+            return Lyrics::from_tags(self.get_data()?);
+        }
+        // Try the `.lrc` file next to the song
+        let mut lrc_url = self.url.clone();
+        lrc_url.set_path(&lrc_path(self.url.path()));
+        let content = fetch(
+            &self.network,
+            || self.agent.request_url("GET", &lrc_url),
+            |response| {
+                // The servers may send a page (e.g. their error page) instead of the missing file
+                if !is_lyrics_type(response.content_type()) {
+                    return Ok(None);
+                }
+                let mut content = vec![];
+                response
+                    .into_reader()
+                    .take(MAX_LYRICS_SIZE)
+                    .read_to_end(&mut content)?;
+                Ok(Some(content))
+            },
+        );
+        match content {
+            Ok(Some(content)) => {
+                return Ok(Some(Lyrics::parse(&String::from_utf8_lossy(&content))));
+            }
+            // There is no `.lrc` file, try the tags
+            Ok(None) => {}
+            Err(err) if matches!(err.downcast_ref(), Some(ureq::Error::Status(..))) => {}
+            Err(err) => return Err(err),
+        }
+        Lyrics::from_tags(self.get_data()?)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        let mut tags = Tags::from_data(self.get_data()?)?;
+        if let Some(entry) = manifest::fetched_entry(self.url) {
+            tags = entry.fill_tags(tags);
+        }
+        if tags.cover_url.is_none() && !self.is_offline() {
+            // Look for a cover next to the song
+            tags.cover_url = self.folder_cover();
+        }
+        Ok(tags)
+    }
+}
+
+/// Can a response with this content type be a `.lrc` file?
+fn is_lyrics_type(content_type: &str) -> bool {
+    (content_type.starts_with("text/") && content_type != "text/html")
+        || content_type == "application/octet-stream"
+}
+
+/// Returns the path of the `.lrc` file of the song at the URL `path`.
+///
+/// Only the extension of the last segment is replaced (`/v1.2/song` gives `/v1.2/song.lrc`).
+fn lrc_path(path: &str) -> String {
+    let (folder, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    };
+    if path.contains('/') {
+        format!("{folder}/{stem}.lrc")
+    } else {
+        format!("{stem}.lrc")
+    }
 }
 
 /// Searches for double songs.
@@ -283,9 +508,21 @@ pub fn check_double_songs<'name, S: Song<'name>>(files: &mut [S]) {
 #[cfg(test)]
 #[expect(clippy::missing_panics_doc)]
 mod tests {
-    use crate::song::Song;
+    use std::{
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread::spawn,
+    };
+
+    use tiny_http::{Header, Response, Server};
+    use url::Url;
 
-    use super::{check_double_songs, get_real_name, TestCase};
+    use crate::{lyrics::Lyrics, song::Song};
+
+    use super::{check_double_songs, get_real_name, lrc_path, TestCase, Web};
 
     /// Checks if the `a` list, after being passed to [`check_double_songs`],
     /// is equal to the `b` list.
@@ -294,7 +531,7 @@ mod tests {
     /// If `a != b`.
     fn check<const N: usize>(left: &mut [&str; N], right: &[&str; N]) {
         let mut songs = left.map(TestCase::new);
-        check_double_songs(&mut songs[..]);
+        check_double_songs(&mut songs);
         let paths: Vec<_> = songs.iter().map(Song::get_path).collect();
         assert_eq!(&paths, right);
     }
@@ -312,6 +549,86 @@ mod tests {
         assert_eq!(get_real_name("test.mp3"), None);
     }
 
+    #[test]
+    fn lrc_paths() {
+        assert_eq!(lrc_path("/music/song.mp3"), "/music/song.lrc");
+        assert_eq!(lrc_path("/music/a.b.flac"), "/music/a.b.lrc");
+        assert_eq!(lrc_path("/v1.2/song"), "/v1.2/song.lrc");
+        assert_eq!(lrc_path("/music/.hidden"), "/music/.hidden.lrc");
+        assert_eq!(lrc_path("song.mp3"), "song.lrc");
+    }
+
+    #[test]
+    fn web_lyrics() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        spawn(move || {
+            // A silent MP3 frame, without tags
+            let mut frame = vec![0; 384];
+            frame[..4].copy_from_slice(&[0xff, 0xfb, 0x94, 0xc0]);
+            for request in server.incoming_requests() {
+                let response = match request.url() {
+                    "/a.lrc" => Response::from_data("[00:01.00]Hello"),
+                    "/b.lrc" => Response::from_data("<html>Not found</html>"),
+                    _ => Response::from_data(frame.repeat(10)),
+                };
+                let content_type = match request.url() {
+                    "/a.lrc" => "text/plain; charset=utf-8",
+                    "/b.lrc" => "text/html",
+                    _ => "audio/mpeg",
+                };
+                let header = Header::from_bytes("Content-Type", content_type).unwrap();
+                request.respond(response.with_header(header)).unwrap();
+            }
+        });
+
+        let agent = ureq::agent();
+        let lyrics = |path: &str| {
+            let url = Url::parse(&format!("http://{address}{path}")).unwrap();
+            Web::new(&url, &agent).get_lyrics().unwrap()
+        };
+        assert_eq!(lyrics("/a.mp3"), Some(Lyrics::parse("[00:01.00]Hello")));
+        // The page isn't read as lyrics, the tags have none
+        assert_eq!(lyrics("/b.mp3"), None);
+    }
+
+    #[test]
+    fn folder_cover() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        let probes = Arc::new(AtomicUsize::new(0));
+        let server_probes = probes.clone();
+        spawn(move || {
+            for request in server.incoming_requests() {
+                server_probes.fetch_add(1, Ordering::Relaxed);
+                let status = if request.url() == "/b/folder.jpg" {
+                    200
+                } else {
+                    404
+                };
+                request.respond(Response::empty(status)).unwrap();
+            }
+        });
+
+        let agent = ureq::agent();
+        let cover = |path: &str| {
+            let url = Url::parse(&format!("http://{address}{path}")).unwrap();
+            Web::new(&url, &agent).folder_cover()
+        };
+        assert_eq!(cover("/a/1.mp3"), None);
+        assert_eq!(probes.load(Ordering::Relaxed), 4);
+        // The folders without a cover aren't probed again
+        assert_eq!(cover("/a/2.mp3"), None);
+        assert_eq!(probes.load(Ordering::Relaxed), 4);
+        assert_eq!(
+            cover("/b/3.mp3"),
+            Some(format!("http://{address}/b/folder.jpg"))
+        );
+        assert_eq!(cover("/b/4.mp3"), cover("/b/3.mp3"));
+        assert_eq!(probes.load(Ordering::Relaxed), 7);
+    }
+
     #[test]
     fn no_double_songs() {
         let a = &mut ["a", "b", "c", "d", "e"];
@@ -0,0 +1,527 @@
+//! Playing the library of a Subsonic (or `OpenSubsonic`) server, like Navidrome.
+//!
+//! The client logs in with the token scheme of the API (the MD5 hash of the password and of
+//! a random salt), lists the artists, their albums and the songs of the albums,
+//! and streams the songs with the `stream` endpoint.
+//! The tags, the covers and the lyrics come from the API.
+use std::{
+    io::{self, Cursor, Read, Seek},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use md5::{Digest, Md5};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use tinyrand::{Rand, Seeded, StdRand};
+use ureq::{Agent, Response};
+use url::Url;
+
+use crate::{
+    agent::{build_agent, AgentConfig, Auth},
+    download::{download, fetch, NetworkOptions},
+    lyrics::Lyrics,
+    options::{args_or_help, Options, USAGE as OPTIONS_USAGE},
+    player::Player,
+    secrets::commands::secret_plugins,
+    song::{EBox, Song},
+    tags::{save_cover_data, Tags},
+    web_utils::map_with_workers,
+};
+
+/// The usage of the `subsonic` command.
+pub const USAGE: &str = "Usage: audio-player subsonic <URL> [OPTIONS]
+
+Plays the library of the Subsonic server (like Navidrome) at the given URL, with the options of the player.
+The username and the password are the credentials of the server, from the web configuration,
+the netrc file or $AUDIO_PLAYER_WEB_USERNAME and $AUDIO_PLAYER_WEB_PASSWORD.";
+
+/// The version of the API that is used.
+const API_VERSION: &str = "1.16.1";
+
+/// The name of the client, sent to the server.
+const CLIENT_NAME: &str = "audio-player";
+
+/// A song of the library, as listed by the API.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SongInfo {
+    /// The ID of the song.
+    pub id: String,
+    /// The title of the song.
+    pub title: String,
+    /// The artist of the song.
+    pub artist: Option<String>,
+    /// The album of the song.
+    pub album: Option<String>,
+    /// The ID of the cover of the song.
+    pub cover_art: Option<String>,
+    /// The duration of the song, in seconds.
+    pub duration: Option<u64>,
+    /// The path of the song on the server
+    /// (or `artist/album/title` if the server doesn't give it).
+    #[serde(default)]
+    pub path: String,
+}
+
+/// The artists of the library (`getArtists`).
+#[derive(Deserialize)]
+struct Artists {
+    /// The artists, grouped by their first letter.
+    #[serde(default)]
+    index: Vec<ArtistIndex>,
+}
+
+/// The artists whose name starts with a letter.
+#[derive(Deserialize)]
+struct ArtistIndex {
+    /// The artists.
+    #[serde(default)]
+    artist: Vec<Item>,
+}
+
+/// An artist (`getArtist`) or an album (`getAlbum`), with its content.
+#[derive(Deserialize)]
+struct Item {
+    /// The ID of the item.
+    id: String,
+    /// The albums of the artist.
+    #[serde(default)]
+    album: Vec<Self>,
+    /// The songs of the album.
+    #[serde(default)]
+    song: Vec<SongInfo>,
+}
+
+/// The lyrics of a song (`getLyrics`).
+#[derive(Deserialize)]
+struct LyricsValue {
+    /// The text of the lyrics.
+    #[serde(default)]
+    value: String,
+}
+
+/// A client of the API of a Subsonic server.
+pub struct SubsonicClient {
+    /// The [`Agent`] of the requests.
+    agent: Agent,
+    /// The base URL of the server.
+    url: Url,
+    /// The name of the user.
+    username: String,
+    /// The password of the user.
+    password: String,
+    /// The timeouts and the retries of the requests.
+    network: NetworkOptions,
+    /// The seed of the salts of the tokens, incremented at each request.
+    salt_seed: AtomicU64,
+}
+
+impl SubsonicClient {
+    /// Creates a client of the server at `url`.
+    #[must_use]
+    pub fn new(agent: Agent, url: &Url, username: String, password: String) -> Self {
+        let mut url = url.clone();
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() ^ u64::from(time.subsec_nanos()));
+        Self {
+            agent,
+            url,
+            username,
+            password,
+            network: NetworkOptions::DEFAULT,
+            salt_seed: AtomicU64::new(seed),
+        }
+    }
+
+    /// Sets the timeouts and the retries of the requests.
+    #[must_use]
+    pub const fn with_network(mut self, network: NetworkOptions) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Returns the URL of an endpoint of the API, with the authentication parameters.
+    ///
+    /// # Errors
+    /// Fails if the URL of the server is invalid.
+    pub fn endpoint_url(&self, endpoint: &str, parameters: &[(&str, &str)]) -> Result<Url, EBox> {
+        let mut url = self.url.join(&format!("rest/{endpoint}"))?;
+        let salt = format!(
+            "{:016x}",
+            StdRand::seed(self.salt_seed.fetch_add(1, Ordering::Relaxed)).next_u64()
+        );
+        let token = format!("{:x}", Md5::digest(format!("{}{salt}", self.password)));
+        url.query_pairs_mut()
+            .append_pair("u", &self.username)
+            .append_pair("t", &token)
+            .append_pair("s", &salt)
+            .append_pair("v", API_VERSION)
+            .append_pair("c", CLIENT_NAME)
+            .append_pair("f", "json")
+            .extend_pairs(parameters);
+        Ok(url)
+    }
+
+    /// Calls an endpoint of the API and returns the `field` of its response.
+    ///
+    /// # Errors
+    /// Fails if the request fails, if the server returns an error or if the response is invalid.
+    fn get<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        parameters: &[(&str, &str)],
+        field: &str,
+    ) -> Result<T, EBox> {
+        let url = self.endpoint_url(endpoint, parameters)?;
+        let content = fetch(
+            &self.network,
+            || self.agent.request_url("GET", &url),
+            Response::into_string,
+        )?;
+        let mut response: Value = serde_json::from_str(&content)
+            .map_err(|err| format!("Invalid response of {endpoint}: {err}"))?;
+        let response = response
+            .get_mut("subsonic-response")
+            .ok_or_else(|| format!("Invalid response of {endpoint}"))?;
+        if response.get("status").and_then(Value::as_str) != Some("ok") {
+            let message = response
+                .pointer("/error/message")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error");
+            return Err(format!("The server failed to answer {endpoint}: {message}").into());
+        }
+        Ok(
+            serde_json::from_value(response.get_mut(field).map(Value::take).unwrap_or_default())
+                .map_err(|err| format!("Invalid response of {endpoint}: {err}"))?,
+        )
+    }
+
+    /// Checks the connection and the credentials.
+    ///
+    /// # Errors
+    /// Fails if the server can't be reached or if the credentials are wrong.
+    pub fn ping(&self) -> Result<(), EBox> {
+        self.get::<Value>("ping", &[], "status").map(|_| ())
+    }
+
+    /// Returns the songs of the albums of an artist.
+    ///
+    /// # Errors
+    /// Fails if the artist or one of its albums can't be fetched.
+    fn get_artist_songs(&self, id: &str) -> Result<Vec<SongInfo>, EBox> {
+        let artist: Item = self.get("getArtist", &[("id", id)], "artist")?;
+        let mut songs = vec![];
+        for album in artist.album {
+            let album: Item = self.get("getAlbum", &[("id", &album.id)], "album")?;
+            songs.extend(album.song);
+        }
+        Ok(songs)
+    }
+
+    /// Returns all the songs of the library, artist by artist, with at most `workers` threads.
+    ///
+    /// # Errors
+    /// Fails if the library can't be listed.
+    pub fn get_songs(&self, workers: usize) -> Result<Vec<SongInfo>, EBox> {
+        let artists: Artists = self.get("getArtists", &[], "artists")?;
+        let ids: Vec<_> = artists
+            .index
+            .into_iter()
+            .flat_map(|index| index.artist)
+            .map(|artist| artist.id)
+            .collect();
+
+        let results = map_with_workers(&ids, workers, |id| self.get_artist_songs(id));
+        let mut songs = vec![];
+        for result in results {
+            songs.extend(result?);
+        }
+        for song in &mut songs {
+            if song.path.is_empty() {
+                song.path = [song.artist.as_deref(), song.album.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .chain([song.title.as_str()])
+                    .collect::<Vec<_>>()
+                    .join("/");
+            }
+        }
+        Ok(songs)
+    }
+
+    /// Downloads a cover and returns its `file://` URL.
+    ///
+    /// # Errors
+    /// Fails if the cover can't be downloaded or saved.
+    fn get_cover(&self, id: &str) -> Result<Option<String>, EBox> {
+        let url = self.endpoint_url("getCoverArt", &[("id", id)])?;
+        let (extension, data) = fetch(
+            &self.network,
+            || self.agent.request_url("GET", &url),
+            |response| {
+                let extension = match response.content_type() {
+                    "image/png" => "png",
+                    "image/webp" => "webp",
+                    "image/gif" => "gif",
+                    _ => "jpg",
+                };
+                let mut data = vec![];
+                response.into_reader().read_to_end(&mut data)?;
+                Ok::<_, io::Error>((extension, data))
+            },
+        )?;
+        save_cover_data(&data, extension)
+    }
+}
+
+/// A song of a Subsonic library.
+pub struct SubsonicSong<'name, 'client> {
+    /// The song, as listed by the API.
+    info: &'name SongInfo,
+    /// The client of the server.
+    client: &'client SubsonicClient,
+    /// The fetched song data.
+    data: Vec<u8>,
+    /// A lock that allows launching only one [`SubsonicSong::preload`] function at a time.
+    preloading: Mutex<()>,
+}
+
+impl<'name, 'client> SubsonicSong<'name, 'client> {
+    /// Creates a new [`SubsonicSong`].
+    #[must_use]
+    pub const fn new(info: &'name SongInfo, client: &'client SubsonicClient) -> Self {
+        Self {
+            info,
+            client,
+            data: vec![],
+            preloading: Mutex::new(()),
+        }
+    }
+}
+
+impl<'name> Song<'name> for SubsonicSong<'name, '_> {
+    fn get_data(&mut self) -> Result<impl Read + Seek + Send + Sync + 'static, EBox> {
+        self.preload()?;
+        Ok(Cursor::new(self.data.clone()))
+    }
+    #[expect(clippy::unwrap_in_result, reason = "locks should almost always work")]
+    fn preload(&mut self) -> Result<(), EBox> {
+        let _lock = self.preloading.lock().expect("error while acquiring lock");
+        if !self.data.is_empty() {
+            return Ok(());
+        }
+        let url = self
+            .client
+            .endpoint_url("stream", &[("id", &self.info.id)])?;
+        download(
+            &self.client.agent,
+            &url,
+            &self.client.network,
+            &mut self.data,
+        )
+    }
+    fn get_path(&self) -> &'name str {
+        &self.info.path
+    }
+    fn get_real_name(&self) -> Option<&'name str> {
+        Some(&self.info.title)
+    }
+    fn get_lyrics(&mut self) -> Result<Option<Lyrics>, EBox> {
+        if let Some(artist) = &self.info.artist {
+            let lyrics: LyricsValue = self.client.get(
+                "getLyrics",
+                &[("artist", artist), ("title", &self.info.title)],
+                "lyrics",
+            )?;
+            if !lyrics.value.trim().is_empty() {
+                return Ok(Some(Lyrics::plain(&lyrics.value)));
+            }
+        }
+        Lyrics::from_tags(self.get_data()?)
+    }
+    fn get_tags(&mut self) -> Result<Tags, EBox> {
+        Ok(Tags {
+            title: Some(self.info.title.clone()),
+            artist: self.info.artist.clone(),
+            album: self.info.album.clone(),
+            // The song is still played without its cover
+            cover_url: self
+                .info
+                .cover_art
+                .as_deref()
+                .and_then(|id| self.client.get_cover(id).ok().flatten()),
+        })
+    }
+}
+
+/// Runs the `subsonic` command with the given arguments.
+///
+/// # Errors
+/// Fails if the arguments are invalid, if there are no credentials for the server,
+/// if the library can't be listed or if the player fails.
+pub fn subsonic(args: impl IntoIterator<Item = String>) -> Result<(), EBox> {
+    let Some(args) = args_or_help(args, &format!("{USAGE}\n\n{OPTIONS_USAGE}")) else {
+        return Ok(());
+    };
+    let mut args = args.into_iter().peekable();
+    let url = args.next_if(|arg| !arg.starts_with('-')).ok_or(USAGE)?;
+    let url = Url::parse(&url)?;
+    let options = Options::parse(args)?;
+    let config = AgentConfig::load(
+        &url,
+        &options.tls,
+        options.web_config.as_deref(),
+        options.netrc.as_deref(),
+    )?;
+    let Some(Auth::Basic { username, password }) = config.credentials(url.host_str().unwrap_or(""))
+    else {
+        return Err(format!("No username and password for {url}\n\n{USAGE}").into());
+    };
+    let client = SubsonicClient::new(
+        build_agent(&config, &options.network)?,
+        &url,
+        username.clone(),
+        password.clone(),
+    )
+    .with_network(options.network);
+    client.ping()?;
+
+    let infos = client.get_songs(options.crawl.workers)?;
+    if infos.is_empty() {
+        return Err(format!("No song found in {url}").into());
+    }
+    let mut songs = infos
+        .iter()
+        .map(|info| SubsonicSong::new(info, &client))
+        .collect::<Vec<_>>();
+    Player::new(&mut songs)
+        .with_options(options)
+        .with_plugins(secret_plugins())
+        .play()
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{fs, io::Read, net::TcpListener, path::Path, thread::spawn};
+
+    use md5::{Digest, Md5};
+    use tiny_http::{Header, Response, Server};
+    use url::Url;
+
+    use super::{SubsonicClient, SubsonicSong};
+    use crate::{download::NetworkOptions, song::Song};
+
+    /// Starts a mock of the API of a server with the user `alice` and the password `secret`,
+    /// which answers with the fixtures of `fixtures/subsonic`.
+    fn mock_server() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let url = Url::parse(&format!("http://{address}{}", request.url())).unwrap();
+                let parameter = |name: &str| {
+                    url.query_pairs()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.into_owned())
+                        .unwrap_or_default()
+                };
+                let token = format!("{:x}", Md5::digest(format!("secret{}", parameter("s"))));
+                let endpoint = url.path().trim_start_matches("/music/rest/");
+                let response = if parameter("u") != "alice" || parameter("t") != token {
+                    Response::from_data(
+                        r#"{"subsonic-response": {"status": "failed", "version": "1.16.1",
+                            "error": {"code": 40, "message": "Wrong username or password"}}}"#,
+                    )
+                } else if endpoint == "stream" {
+                    Response::from_data(format!("audio of {}", parameter("id")))
+                } else if endpoint == "getCoverArt" {
+                    Response::from_data(format!("cover {}", parameter("id")))
+                        .with_header(Header::from_bytes("Content-Type", "image/png").unwrap())
+                } else if endpoint == "ping" {
+                    Response::from_data(r#"{"subsonic-response": {"status": "ok"}}"#)
+                } else {
+                    let id = parameter("id");
+                    let name = if id.is_empty() {
+                        format!("{endpoint}.json")
+                    } else {
+                        format!("{endpoint}-{id}.json")
+                    };
+                    Response::from_data(
+                        fs::read(
+                            Path::new(env!("CARGO_MANIFEST_DIR"))
+                                .join("fixtures/subsonic")
+                                .join(name),
+                        )
+                        .unwrap(),
+                    )
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        Url::parse(&format!("http://{address}/music")).unwrap()
+    }
+
+    #[test]
+    fn library() {
+        let url = mock_server();
+        let network = NetworkOptions {
+            retries: 0,
+            ..NetworkOptions::default()
+        };
+
+        let client = SubsonicClient::new(ureq::agent(), &url, "alice".into(), "wrong".into())
+            .with_network(network);
+        assert!(client
+            .ping()
+            .unwrap_err()
+            .to_string()
+            .ends_with("Wrong username or password"));
+
+        let client = SubsonicClient::new(ureq::agent(), &url, "alice".into(), "secret".into())
+            .with_network(network);
+        client.ping().unwrap();
+        let infos = client.get_songs(2).unwrap();
+        assert_eq!(
+            infos
+                .iter()
+                .map(|info| info.path.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "The Beatles/Abbey Road/01 - Come Together.mp3",
+                "The Beatles/Abbey Road/Something",
+                "The Beatles/Let It Be/Let It Be"
+            ]
+        );
+
+        let mut song = SubsonicSong::new(&infos[0], &client);
+        let mut audio = String::new();
+        song.get_data().unwrap().read_to_string(&mut audio).unwrap();
+        assert_eq!(audio, "audio of s1");
+        assert_eq!(song.get_real_name(), Some("Come Together"));
+
+        let tags = song.get_tags().unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Come Together"));
+        assert_eq!(tags.artist.as_deref(), Some("The Beatles"));
+        assert_eq!(tags.album.as_deref(), Some("Abbey Road"));
+        let cover_path = Url::parse(&tags.cover_url.unwrap())
+            .unwrap()
+            .to_file_path()
+            .unwrap();
+        assert_eq!(cover_path.extension().unwrap(), "png");
+        assert_eq!(fs::read(cover_path).unwrap(), b"cover al-al1");
+
+        let lyrics = song.get_lyrics().unwrap().unwrap();
+        assert_eq!(lyrics.lines().len(), 2);
+        assert!(!lyrics.is_synchronized());
+    }
+}
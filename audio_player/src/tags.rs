@@ -0,0 +1,112 @@
+//! Tags of the songs (title, artist, album and cover).
+use std::{
+    env::temp_dir,
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{Read, Seek},
+    path::Path,
+};
+
+use lofty::{
+    file::TaggedFileExt,
+    picture::{MimeType, Picture, PictureType},
+    probe::Probe,
+    tag::ItemKey,
+};
+use url::Url;
+
+use crate::song::EBox;
+
+/// The names of the image files that are used as a cover when they are next to a song.
+pub const COVER_FILE_NAMES: [&str; 4] = ["cover.jpg", "cover.png", "folder.jpg", "front.jpg"];
+
+/// The tags of a song.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tags {
+    /// The title of the song.
+    pub title: Option<String>,
+    /// The artist of the song.
+    pub artist: Option<String>,
+    /// The album of the song.
+    pub album: Option<String>,
+    /// The URL of the cover of the song (`file://` URL for local or embedded covers).
+    pub cover_url: Option<String>,
+}
+
+impl Tags {
+    /// Reads the tags embedded in the song data.
+    ///
+    /// The embedded cover is saved in the temporary directory so it can be used with an URL.
+    ///
+    /// # Errors
+    /// Fails if the song data cannot be read, if the tags are malformed
+    /// or if the cover cannot be saved.
+    pub fn from_data(mut data: impl Read + Seek) -> Result<Self, EBox> {
+        let tagged_file = Probe::new(&mut data).guess_file_type()?.read()?;
+        let Some(tag) = tagged_file
+            .primary_tag()
+            .or_else(|| tagged_file.first_tag())
+        else {
+            return Ok(Self::default());
+        };
+
+        let get = |key| tag.get_string(key).map(str::to_owned);
+        Ok(Self {
+            title: get(ItemKey::TrackTitle),
+            artist: get(ItemKey::TrackArtist),
+            album: get(ItemKey::AlbumTitle),
+            cover_url: save_cover(tag.pictures())?,
+        })
+    }
+
+    /// Uses a cover image in the given `folder` if the tags don't contain a cover.
+    #[must_use]
+    pub fn with_folder_cover(mut self, folder: &Path) -> Self {
+        if self.cover_url.is_none() {
+            self.cover_url = COVER_FILE_NAMES
+                .iter()
+                .map(|name| folder.join(name))
+                .find(|path| path.is_file())
+                .and_then(|path| Url::from_file_path(path).ok())
+                .map(String::from);
+        }
+        self
+    }
+}
+
+/// Saves the front cover (or the first picture) in the temporary directory
+/// and returns its `file://` URL.
+///
+/// # Errors
+/// Fails if the cover cannot be written.
+fn save_cover(pictures: &[Picture]) -> Result<Option<String>, EBox> {
+    let Some(picture) = pictures
+        .iter()
+        .find(|picture| picture.pic_type() == PictureType::CoverFront)
+        .or_else(|| pictures.first())
+    else {
+        return Ok(None);
+    };
+
+    let extension = picture.mime_type().and_then(MimeType::ext).unwrap_or("jpg");
+    save_cover_data(picture.data(), extension)
+}
+
+/// Saves a cover image in the temporary directory and returns its `file://` URL.
+///
+/// # Errors
+/// Fails if the cover cannot be written.
+pub fn save_cover_data(data: &[u8], extension: &str) -> Result<Option<String>, EBox> {
+    // Use the hash of the picture as a name to write each cover only once
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    let path = temp_dir().join(format!(
+        "audio-player-cover-{:016x}.{extension}",
+        hasher.finish()
+    ));
+    if !path.exists() {
+        fs::write(&path, data)?;
+    }
+
+    Ok(Url::from_file_path(path).ok().map(String::from))
+}
@@ -1,118 +1,205 @@
 //! Utility functions to work with webpages.
-use std::thread;
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
 
-use ureq::Agent;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use ureq::{Agent, Response};
 use url::Url;
 
-use crate::song::EBox;
+use crate::{
+    download::{fetch, NetworkOptions},
+    html::{Tag, Tags},
+    manifest::{remember_entry, ManifestEntry, MANIFEST_FILE_NAME},
+    song::EBox,
+};
 
-/// An iterator over the links in a webpage.
-struct LinksIterator<'content> {
-    /// The webpage content that hasn't been parsed yet.
-    content: &'content str,
-    /// The previous link (to avoid duplicates).
-    previous_link: &'content str,
+/// The `Accept` header of the directory listing requests (Caddy sends a JSON listing if it's preferred).
+const LISTING_ACCEPT: &str = "application/json, text/html;q=0.9, */*;q=0.8";
+
+/// The limits and the filters of the crawling of a web folder.
+///
+/// The crawler only follows the links to the subfolders of the crawled folder (on the same origin),
+/// and visits each folder once.
+///
+/// The patterns are matched against the decoded path of the files and folders relative
+/// to the crawled folder (like `Rock & Roll/song.mp3`, the folders end with a `/`).
+/// They can contain `*` (any characters, including `/`) and `?` (any character).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrawlOptions {
+    /// The maximum number of folders that are fetched at the same time.
+    pub workers: usize,
+    /// The maximum depth of the crawled subfolders (0 to only list the crawled folder).
+    pub max_depth: usize,
+    /// If it's not empty, only the files matching one of these patterns are kept.
+    pub include: Vec<String>,
+    /// The files and folders matching one of these patterns are skipped.
+    pub exclude: Vec<String>,
+    /// If it's not empty, only the files with one of these extensions (in lowercase, without the `.`) are kept.
+    pub extensions: Vec<String>,
 }
 
-impl<'content> LinksIterator<'content> {
-    /// Creates a new [`LinksIterator`] on the given HTML `content`.
-    #[must_use]
-    fn new(content: &'content str) -> Self {
+impl Default for CrawlOptions {
+    fn default() -> Self {
         Self {
-            content,
-            previous_link: "",
+            workers: 8,
+            max_depth: 16,
+            include: vec![],
+            exclude: vec![],
+            extensions: vec![],
         }
     }
-    /// Remove `bytes` bytes at the start of the content and return them.
-    fn eat(&mut self, bytes: usize) -> &'content str {
-        let (ret, new_content) = self.content.split_at(bytes);
-        self.content = new_content;
-        ret
-    }
-    /// Checks if the asked position is in a comment.
-    /// If this is the case, remove the current comment
-    /// (or the whole document if the comment doesn't end).
-    #[must_use = "the content may be edited and indexes will be invalid; you must run .find() again to get a new index if true is returned"]
-    fn in_comment(&mut self, position: usize) -> bool {
-        // If there is a comment before the current position...
-        if let Some(start) = self.content[..position].find("<!--") {
-            // Remove it (and everything that's before -- we don't need it)
-            self.eat(start + 4); // length of the comment start
-
-            // If it has an end...
-            if let Some(end) = self.content.find("-->") {
-                // Remove it completely
-                self.eat(end + 3); // length of the comment end
-            } else {
-                // Otherwise remove all the other text
-                self.content = "";
+}
+
+impl CrawlOptions {
+    /// Should the file at the (decoded) relative `path` be kept?
+    #[must_use]
+    pub fn accepts_file(&self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        (self.include.is_empty() || self.include.iter().any(|pattern| matches(pattern, path)))
+            && !self.exclude.iter().any(|pattern| matches(pattern, path))
+            && (self.extensions.is_empty()
+                || name.rsplit_once('.').is_some_and(|(_, extension)| {
+                    self.extensions
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+                }))
+    }
+
+    /// Should the folder at the (decoded) relative `path` be crawled?
+    #[must_use]
+    pub fn accepts_folder(&self, path: &str) -> bool {
+        !self.exclude.iter().any(|pattern| matches(pattern, path))
+    }
+}
+
+/// Does `text` match the `pattern`, where `*` matches any characters and `?` matches one character?
+fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut pattern_index, mut text_index) = (0, 0);
+    // The position of the last `*` and the position in the text where it started matching
+    let mut star: Option<(usize, usize)> = None;
+
+    while text_index < text.len() {
+        match pattern.get(pattern_index) {
+            Some('*') => {
+                star = Some((pattern_index, text_index));
+                pattern_index += 1;
+            }
+            Some(&char) if char == '?' || char == text[text_index] => {
+                pattern_index += 1;
+                text_index += 1;
+            }
+            _ => {
+                // Backtrack: the last `*` matches one more character
+                let Some((star_index, start)) = star else {
+                    return false;
+                };
+                star = Some((star_index, start + 1));
+                pattern_index = star_index + 1;
+                text_index = start + 1;
             }
-            // Signal that there is a comment
-            return true;
         }
-        // Signal that there is no comment
-        false
     }
+    pattern[pattern_index..].iter().all(|&char| char == '*')
 }
 
-impl<'content> Iterator for LinksIterator<'content> {
-    type Item = &'content str;
+/// Returns the decoded path of `url` relative to the `folder`, if it's inside it (on the same origin).
+#[must_use]
+pub fn relative_path(folder: &Url, url: &Url) -> Option<String> {
+    if url.origin() != folder.origin() {
+        return None;
+    }
+    let path = url.path().strip_prefix(folder.path())?;
+    Some(percent_decode_str(path).decode_utf8_lossy().into_owned())
+}
 
-    #[expect(clippy::unwrap_used, clippy::unwrap_in_result)]
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            // If we find the pattern...
-            if let Some(index) = self.content.find("<a href=") {
-                // Check if we are in a comment. If it's true, restart
-                if self.in_comment(index) {
-                    continue;
-                }
+/// The files and the folders of a directory listing.
+type Listing = (Vec<Url>, Vec<Url>);
 
-                // Add (don't set) because the index is relative to the new substring
-                self.eat(index + 8); // length of the link start
+/// An entry of a JSON directory listing.
+///
+/// It supports the formats of nginx (`autoindex_format json;`) and Caddy (`browse` with `Accept: application/json`).
+#[derive(Deserialize)]
+struct JsonListingEntry {
+    /// The name of the file or folder (with a trailing `/` for the Caddy folders).
+    name: String,
+    /// The type of the entry for nginx (`file`, `directory` or `other`).
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+    /// Is the entry a folder? (for Caddy)
+    #[serde(default)]
+    is_dir: bool,
+}
 
-                // Find the end character and optionally advance the last index
-                let end_chars = match self.content.get(..1) {
-                    // SAFETY: self.eat(1) will never panic
-                    // because self.content.get(..1) contains exactly one `char`
-                    Some("\"" | "'") => vec![self.eat(1).chars().next().unwrap()],
-                    Some(_) => vec!['>', ' '],
-                    None => break,
-                };
-                // If there is a closing quote (or a space, or a tag end)...
-                if let Some(end_index) = self.content.find(&end_chars[..]) {
-                    let link = self.eat(end_index);
-                    // Don't return duplicate links
-                    if link == self.previous_link {
-                        continue;
-                    }
-                    self.previous_link = link;
-                    // Save the link
-                    return Some(link);
-                }
+/// Returns the URL of a file (or a folder) at the relative `path` (separated by `/`) in the folder of `url`.
+///
+/// # Errors
+/// Fails if the path contains empty, `.` or `..` segments, or if `url` can't contain a path.
+fn child_url(url: &Url, path: &str, folder: bool) -> Result<Url, EBox> {
+    let mut child = url.join("./")?;
+    {
+        let mut segments = child
+            .path_segments_mut()
+            .map_err(|()| format!("Invalid base URL: {url}"))?;
+        segments.pop_if_empty();
+        for segment in path.split('/') {
+            if matches!(segment, "" | "." | "..") {
+                return Err(format!("Invalid path: {path}").into());
             }
-            break;
+            segments.push(segment);
+        }
+        if folder {
+            segments.push("");
         }
+    }
+    Ok(child)
+}
+
+/// Returns the links of a webpage, resolved against its `url` (or its `<base href>`).
+///
+/// The duplicate links and the links to the same page (like the sort links
+/// of the directory listings: `?C=N;O=D`, `#top`...) are skipped.
+fn links(content: &str, url: &Url) -> Vec<Url> {
+    let tags: Vec<Tag> = Tags::new(content).collect();
+    let base = tags
+        .iter()
+        .filter(|tag| tag.name == "base")
+        .find_map(|tag| tag.attribute("href"))
+        .and_then(|href| url.join(href.trim()).ok())
+        .unwrap_or_else(|| url.clone());
 
-        self.content = "";
-        None
+    let mut links: Vec<Url> = vec![];
+    for tag in &tags {
+        let Some(href) = tag.attribute("href").map(str::trim) else {
+            continue;
+        };
+        if tag.name != "a" || href.is_empty() || href.starts_with(['?', '#']) {
+            continue;
+        }
+        let Ok(mut link) = base.join(href) else {
+            continue;
+        };
+        link.set_fragment(None);
+        if !links.contains(&link) {
+            links.push(link);
+        }
     }
+    links
 }
 
-/// Returns the list of the files and folders available at the given `url`.
+/// Splits the links of a directory listing at `url` into files and folders.
 ///
-/// # Errors
-/// Fails if the URL list cannot be fetched properly.
-fn get_files_and_folders(agent: &Agent, url: &Url) -> Result<(Vec<Url>, Vec<Url>), EBox> {
+/// The links to the parent folders, to the folder itself and to other websites are skipped.
+fn files_and_folders(content: &str, url: &Url) -> (Vec<Url>, Vec<Url>) {
     let mut files = vec![];
     let mut folders = vec![];
 
-    // Get the directory listing page
-    let body = agent.request_url("GET", url).call()?.into_string()?;
-
-    for link in LinksIterator::new(&body) {
-        // Get the target URL
-        let target_url = url.join(link)?;
+    for target_url in links(content, url) {
         // Save the URL to the files/folders list
         // Don't add parent/current folders
         if url
@@ -127,45 +214,204 @@ fn get_files_and_folders(agent: &Agent, url: &Url) -> Result<(Vec<Url>, Vec<Url>
         }
     }
 
+    (files, folders)
+}
+
+/// Splits the entries of a JSON directory listing at `url` into files and folders.
+///
+/// # Errors
+/// Fails if the listing is invalid.
+fn json_files_and_folders(content: &str, url: &Url) -> Result<(Vec<Url>, Vec<Url>), EBox> {
+    let mut files = vec![];
+    let mut folders = vec![];
+
+    for entry in serde_json::from_str::<Vec<JsonListingEntry>>(content)? {
+        let name = entry.name.trim_end_matches('/');
+        if matches!(name, "" | "." | "..") {
+            continue;
+        }
+        if entry.is_dir || entry.kind.as_deref() == Some("directory") {
+            folders.push(child_url(url, name, true)?);
+        } else {
+            files.push(child_url(url, name, false)?);
+        }
+    }
+
     Ok((files, folders))
 }
 
-/// Recursively pings the given `url` and its subdirectories and returns the list of the available files.
+/// Returns the list of the files and folders available at the given `url`.
+///
+/// The listing can be an HTML page or a JSON listing.
+///
+/// # Errors
+/// Fails if the URL list cannot be fetched properly.
+fn get_files_and_folders(
+    agent: &Agent,
+    url: &Url,
+    network: &NetworkOptions,
+) -> Result<Listing, EBox> {
+    // Get the directory listing page
+    let (json, body) = fetch(
+        network,
+        || agent.request_url("GET", url).set("Accept", LISTING_ACCEPT),
+        |response| {
+            let json = response.content_type() == "application/json";
+            Ok((json, response.into_string()?))
+        },
+    )?;
+    if json || body.trim_start().starts_with('[') {
+        json_files_and_folders(&body, url)
+    } else {
+        Ok(files_and_folders(&body, url))
+    }
+}
+
+/// Returns the URLs of the songs of a manifest at the root of `url`.
+///
+/// The entries are remembered to check the songs when they are downloaded.
+///
+/// # Errors
+/// Fails if a path of the manifest is invalid.
+fn manifest_files(manifest: &[ManifestEntry], url: &Url) -> Result<Vec<Url>, EBox> {
+    manifest
+        .iter()
+        .map(|entry| {
+            let file = child_url(url, &entry.path, false)?;
+            remember_entry(&file, entry);
+            Ok(file)
+        })
+        .collect()
+}
+
+/// Fetches the manifest at the root of `url`, if there is one.
+///
+/// # Errors
+/// Fails if the manifest cannot be fetched (except if it doesn't exist) or if it's invalid.
+fn get_manifest(
+    agent: &Agent,
+    url: &Url,
+    network: &NetworkOptions,
+) -> Result<Option<Vec<ManifestEntry>>, EBox> {
+    let manifest_url = url.join(MANIFEST_FILE_NAME)?;
+    match fetch(
+        network,
+        || agent.request_url("GET", &manifest_url),
+        Response::into_string,
+    ) {
+        Ok(body) => {
+            let manifest = serde_json::from_str(&body)
+                .map_err(|err| format!("Invalid manifest {manifest_url}: {err}"))?;
+            Ok(Some(manifest))
+        }
+        // No manifest, crawl the folders (an unreadable manifest is an error, like a folder)
+        Err(err) if matches!(err.downcast_ref(), Some(ureq::Error::Status(404 | 410, _))) => {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Calls `function` on each item with at most `workers` threads.
+///
+/// The results are in the same order as the items.
+///
+/// # Panics
+/// Panics if a thread panics.
+pub fn map_with_workers<T: Sync, R: Send>(
+    items: &[T],
+    workers: usize,
+    function: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<_> = thread::scope(|s| {
+        let threads: Vec<_> = (0..workers.clamp(1, items.len().max(1)))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break results;
+                        };
+                        results.push((index, function(item)));
+                    }
+                })
+            })
+            .collect();
+        #[expect(clippy::unwrap_used)]
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Returns the list of the files available at the given `url`.
+///
+/// If there is a [manifest](crate::manifest) at the root of `url`, its songs are returned.
+/// Otherwise, `url` and its subfolders are crawled, within the limits of the [`CrawlOptions`].
+/// In both cases, the files are filtered with the [`CrawlOptions`].
+///
+/// The failed requests are retried according to the [`NetworkOptions`].
 ///
 /// # Errors
 /// Fails:
 /// * if an URL cannot be fetched
 /// * if a response cannot be decoded
+/// * if the manifest is invalid
 /// * if a link cannot be resolved
 ///
 /// # Panics
 /// Panics if a thread that gets the folders on a webpage panics.
-pub fn get_files(agent: &Agent, url: &Url) -> Result<Vec<Url>, EBox> {
+pub fn get_files(
+    agent: &Agent,
+    url: &Url,
+    options: &CrawlOptions,
+    network: &NetworkOptions,
+) -> Result<Vec<Url>, EBox> {
+    let root = url.join("./")?;
+    let accepts_file =
+        |file: &Url| relative_path(&root, file).is_some_and(|path| options.accepts_file(&path));
+
+    if let Some(manifest) = get_manifest(agent, url, network)? {
+        let mut files = manifest_files(&manifest, url)?;
+        files.retain(accepts_file);
+        return Ok(files);
+    }
+
     let mut files: Vec<Url> = vec![];
+    let mut visited = HashSet::from([url.clone()]);
     let mut folders: Vec<Url> = vec![url.clone()];
 
-    while !folders.is_empty() {
-        let mut results = vec![];
-        thread::scope(|s| {
-            let mut threads = vec![];
-            while !folders.is_empty() {
-                let url = folders.remove(0);
-                threads.push(s.spawn(move || get_files_and_folders(agent, &url)));
-            }
-            // for url in &folders {
-            //     threads.push(s.spawn(|| get_files_and_folders(agent, url)));
-            // }
-            for thread in threads {
-                #[expect(clippy::unwrap_used)]
-                results.push(thread.join().unwrap());
-            }
+    for depth in 0..=options.max_depth {
+        if folders.is_empty() {
+            break;
+        }
+        let results = map_with_workers(&folders, options.workers, |folder| {
+            get_files_and_folders(agent, folder, network)
         });
         folders.clear();
 
         for result in results {
-            let (mut new_files, mut new_folders) = result?;
-            files.append(&mut new_files);
-            folders.append(&mut new_folders);
+            let (new_files, new_folders) = result?;
+            for file in new_files {
+                if accepts_file(&file) && visited.insert(file.clone()) {
+                    files.push(file);
+                }
+            }
+            if depth == options.max_depth {
+                continue;
+            }
+            for folder in new_folders {
+                let accepted = relative_path(&root, &folder)
+                    .is_some_and(|path| !path.is_empty() && options.accepts_folder(&path));
+                if accepted && visited.insert(folder.clone()) {
+                    folders.push(folder);
+                }
+            }
         }
     }
 
@@ -175,16 +421,39 @@ pub fn get_files(agent: &Agent, url: &Url) -> Result<Vec<Url>, EBox> {
 #[cfg(test)]
 #[expect(clippy::missing_panics_doc)]
 mod tests {
-    use super::LinksIterator;
+    use url::Url;
+
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread::spawn,
+    };
+
+    use tiny_http::{Header, Response, Server};
+
+    use super::{
+        files_and_folders, get_files, json_files_and_folders, links, matches, CrawlOptions,
+        NetworkOptions,
+    };
+    use crate::song::{Song, Web};
+
+    /// Returns the paths of the links of some HTML content at `https://example.com/music/`.
+    fn link_paths(html: &str) -> Vec<String> {
+        let url = Url::parse("https://example.com/music/").unwrap();
+        links(html, &url)
+            .iter()
+            .map(|link| link[url::Position::BeforePath..].to_owned())
+            .collect()
+    }
 
     #[test]
-    fn links() {
+    fn links_in_html() {
         let html = r#"
         <a href="/a/b/c">...</a>
         <a href="/d/e/f">...</a>
+        <a href="/a/b/c">...</a>
         "#;
-        let links: Vec<_> = LinksIterator::new(html).collect();
-        assert_eq!(links, vec!["/a/b/c", "/d/e/f"]);
+        assert_eq!(link_paths(html), ["/a/b/c", "/d/e/f"]);
     }
 
     #[test]
@@ -194,8 +463,7 @@ mod tests {
         <!-- <a href="/d/e/f">...</a> -->
         <a href="/g/h/i">...</a>
         "#;
-        let links: Vec<_> = LinksIterator::new(html).collect();
-        assert_eq!(links, vec!["/a/b/c", "/g/h/i"]);
+        assert_eq!(link_paths(html), ["/a/b/c", "/g/h/i"]);
     }
 
     #[test]
@@ -205,8 +473,7 @@ mod tests {
         <!-- <a href="/d/e/f">...</a>
         <a href="/g/h/i">...</a>
         "#;
-        let links: Vec<_> = LinksIterator::new(html).collect();
-        assert_eq!(links, vec!["/a/b/c"]);
+        assert_eq!(link_paths(html), ["/a/b/c"]);
     }
 
     #[test]
@@ -215,7 +482,235 @@ mod tests {
         <a href=/a/b/c>...</a>
         <a href=/d/e/f rel=noreferrer>...</a>
         ";
-        let links: Vec<_> = LinksIterator::new(html).collect();
-        assert_eq!(links, vec!["/a/b/c", "/d/e/f"]);
+        assert_eq!(link_paths(html), ["/a/b/c", "/d/e/f"]);
+    }
+
+    #[test]
+    fn attributes_and_entities() {
+        let html = r##"
+        <A CLASS="file" HREF="a.mp3?x=1&amp;y=2">...</A>
+        <a title='b' href = 'b.mp3#start'>...</a>
+        <a href="?C=N;O=D">Name</a>
+        <a href="#top">Top</a>
+        <link href="style.css">
+        "##;
+        assert_eq!(link_paths(html), ["/music/a.mp3?x=1&y=2", "/music/b.mp3"]);
+    }
+
+    #[test]
+    fn base() {
+        let html = r#"
+        <head><base href="/files/"></head>
+        <a href="a.mp3">...</a>
+        "#;
+        assert_eq!(link_paths(html), ["/files/a.mp3"]);
+    }
+
+    #[test]
+    fn autoindex() {
+        let url = Url::parse("https://example.com/music/").unwrap();
+        for (server, html) in [
+            ("apache", include_str!("../fixtures/autoindex/apache.html")),
+            ("nginx", include_str!("../fixtures/autoindex/nginx.html")),
+            (
+                "lighttpd",
+                include_str!("../fixtures/autoindex/lighttpd.html"),
+            ),
+            ("caddy", include_str!("../fixtures/autoindex/caddy.html")),
+            ("python", include_str!("../fixtures/autoindex/python.html")),
+        ] {
+            let (files, folders) = files_and_folders(html, &url);
+            assert_eq!(
+                files.iter().map(Url::as_str).collect::<Vec<_>>(),
+                [
+                    "https://example.com/music/jingle.mp3",
+                    "https://example.com/music/song%20one.mp3"
+                ],
+                "{server}"
+            );
+            assert_eq!(
+                folders.iter().map(Url::as_str).collect::<Vec<_>>(),
+                ["https://example.com/music/Rock%20%26%20Roll/"],
+                "{server}"
+            );
+        }
+    }
+
+    #[test]
+    fn json_autoindex() {
+        let url = Url::parse("https://example.com/music/").unwrap();
+        for (server, json) in [
+            ("nginx", include_str!("../fixtures/autoindex/nginx.json")),
+            ("caddy", include_str!("../fixtures/autoindex/caddy.json")),
+        ] {
+            let (files, folders) = json_files_and_folders(json, &url).unwrap();
+            assert_eq!(
+                files.iter().map(Url::as_str).collect::<Vec<_>>(),
+                [
+                    "https://example.com/music/jingle.mp3",
+                    "https://example.com/music/song%20one%20%231.mp3"
+                ],
+                "{server}"
+            );
+            assert_eq!(
+                folders.iter().map(Url::as_str).collect::<Vec<_>>(),
+                ["https://example.com/music/Rock%20&%20Roll/"],
+                "{server}"
+            );
+        }
+    }
+
+    #[test]
+    fn manifest_and_json_listing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let json = Header::from_bytes("Content-Type", "application/json").unwrap();
+                let response = match request.url() {
+                    "/manifest/manifest.json" => Response::from_string(
+                        r#"[{"path": "a/b #1.mp3", "size": 3, "sha256": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"},
+                            {"path": "c.mp3", "size": 3, "sha256": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"}]"#,
+                    ),
+                    "/manifest/a/b%20%231.mp3" => Response::from_string("abc"),
+                    // A song that changed since the manifest was generated
+                    "/manifest/c.mp3" => Response::from_string("abd"),
+                    "/forbidden/manifest.json" => Response::from_string("").with_status_code(403),
+                    "/listing/" => Response::from_string(
+                        r#"[{"name": "a", "type": "directory"}, {"name": "b.mp3", "type": "file"}]"#,
+                    )
+                    .with_header(json),
+                    "/listing/a/" => Response::from_string("[]").with_header(json),
+                    _ => Response::from_string("").with_status_code(404),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        let agent = ureq::agent();
+        let get = |path: &str| {
+            get_files(
+                &agent,
+                &Url::parse(&format!("http://{address}{path}")).unwrap(),
+                &CrawlOptions::default(),
+                &NetworkOptions::default(),
+            )
+        };
+        let files = |path: &str| {
+            get(path)
+                .unwrap()
+                .iter()
+                .map(|url| url.path().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            files("/manifest/"),
+            ["/manifest/a/b%20%231.mp3", "/manifest/c.mp3"]
+        );
+        assert_eq!(files("/listing/"), ["/listing/b.mp3"]);
+        // Only a missing manifest is skipped
+        assert!(get("/forbidden/").is_err());
+
+        // The songs are checked against the manifest
+        let songs = get("/manifest/").unwrap();
+        Web::new(&songs[0], &agent).preload().unwrap();
+        assert!(Web::new(&songs[1], &agent).preload().is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("*.mp3", "Rock & Roll/song.mp3"));
+        assert!(matches("Rock*/*", "Rock & Roll/"));
+        assert!(matches("*/Live/*", "Artist/Live/song.mp3"));
+        assert!(matches("song?.mp3", "song1.mp3"));
+        assert!(!matches("song?.mp3", "song.mp3"));
+        assert!(!matches("*.mp3", "song.mp3.part"));
+        assert!(matches("**a*b", "aXaXb"));
+
+        let options = CrawlOptions {
+            include: vec!["Rock/*".to_owned()],
+            exclude: vec!["*/Live/*".to_owned()],
+            extensions: vec!["mp3".to_owned()],
+            ..CrawlOptions::default()
+        };
+        assert!(options.accepts_file("Rock/song.MP3"));
+        assert!(!options.accepts_file("Rock/cover.jpg"));
+        assert!(!options.accepts_file("Jazz/song.mp3"));
+        assert!(!options.accepts_file("Rock/Live/song.mp3"));
+        assert!(options.accepts_folder("Jazz/"));
+        assert!(!options.accepts_folder("Rock/Live/"));
+    }
+
+    #[test]
+    fn bounded_crawl() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let server_requests = Arc::clone(&requests);
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let path = request.url().to_owned();
+                let html = if path == "/music/" {
+                    format!(
+                        r#"<a href="../">Parent</a> <a href="/music/">Self</a>
+                        <a href="/other/">Outside</a> <a href="http://example.invalid/music/a/">External</a>
+                        <a href="http://{address}/music/song.mp3">Song</a> <a href="cover.jpg">Cover</a>
+                        <a href="Live/">Live</a> <a href="loop/">Loop</a>"#
+                    )
+                } else if path.ends_with("/loop/") {
+                    // A symbolic link to its own folder
+                    r#"<a href="x.mp3">X</a> <a href="loop/">Loop</a> <a href="/music/">Root</a>"#
+                        .to_owned()
+                } else {
+                    "[]".to_owned()
+                };
+                let status = if path == "/music/manifest.json" {
+                    404
+                } else {
+                    200
+                };
+                server_requests.lock().unwrap().push(path);
+                request
+                    .respond(Response::from_string(html).with_status_code(status))
+                    .unwrap();
+            }
+        });
+
+        let options = CrawlOptions {
+            workers: 2,
+            max_depth: 2,
+            exclude: vec!["Live/*".to_owned()],
+            extensions: vec!["mp3".to_owned()],
+            ..CrawlOptions::default()
+        };
+        let files = get_files(
+            &ureq::agent(),
+            &Url::parse(&format!("http://{address}/music/")).unwrap(),
+            &options,
+            &NetworkOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            files.iter().map(Url::path).collect::<Vec<_>>(),
+            [
+                "/music/song.mp3",
+                "/music/loop/x.mp3",
+                "/music/loop/loop/x.mp3"
+            ]
+        );
+
+        let mut requests = requests.lock().unwrap().clone();
+        requests.sort();
+        assert_eq!(
+            requests,
+            [
+                "/music/",
+                "/music/loop/",
+                "/music/loop/loop/",
+                "/music/manifest.json"
+            ]
+        );
     }
 }
@@ -0,0 +1,453 @@
+//! Playing the songs of a `WebDAV` server (a NAS, Nextcloud, ...).
+//!
+//! The collections are listed with `PROPFIND` requests: with `Depth: infinity` if the server
+//! allows it, or collection by collection with `Depth: 1` otherwise.
+//! The servers usually require Basic or Digest credentials (see [`agent`](crate::agent)).
+use std::{collections::HashSet, mem};
+
+use chrono::{DateTime, FixedOffset};
+use quick_xml::{events::Event, Reader};
+use ureq::{Agent, Response};
+use url::Url;
+
+use crate::{
+    agent::{build_agent, AgentConfig},
+    download::{fetch, NetworkOptions},
+    options::{args_or_help, Options, USAGE as OPTIONS_USAGE},
+    player::Player,
+    secrets::commands::secret_plugins,
+    song::{EBox, Web},
+    web_utils::{map_with_workers, relative_path, CrawlOptions},
+};
+
+/// The usage of the `webdav` command.
+pub const USAGE: &str = "Usage: audio-player webdav <URL> [OPTIONS]
+
+Plays the songs of the WebDAV collection at the given URL and of its subcollections,
+with the options of the player (including the limits and the filters of the web folders).";
+
+/// A resource of a `WebDAV` server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DavEntry {
+    /// The URL of the resource (ending with a `/` for the collections).
+    pub url: Url,
+    /// Is the resource a collection?
+    pub is_collection: bool,
+    /// The size of the resource, in bytes.
+    pub size: Option<u64>,
+    /// The MIME type of the resource.
+    pub content_type: Option<String>,
+    /// The last modification of the resource.
+    pub modified: Option<DateTime<FixedOffset>>,
+}
+
+/// The properties of a resource that is being parsed.
+#[derive(Default)]
+struct Properties {
+    /// Is the resource a collection?
+    is_collection: bool,
+    /// The size of the resource.
+    size: Option<u64>,
+    /// The MIME type of the resource.
+    content_type: Option<String>,
+    /// The last modification of the resource.
+    modified: Option<DateTime<FixedOffset>>,
+}
+
+/// Parses a `207 Multi-Status` response to a `PROPFIND` request of `url`.
+///
+/// Only the properties with a `200` status are read.
+///
+/// # Errors
+/// Fails if the response isn't valid XML, if it's not a multistatus or if an URL is invalid.
+pub fn parse_multistatus(url: &Url, content: &str) -> Result<Vec<DavEntry>, EBox> {
+    let mut reader = Reader::from_str(content);
+    reader.config_mut().trim_text(true);
+
+    let mut is_multistatus = false;
+    let mut entries = vec![];
+    let mut href = None;
+    let mut properties = Properties::default();
+    let mut propstat: Option<Properties> = None;
+    let mut propstat_ok = false;
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(tag) => {
+                text.clear();
+                match tag.local_name().as_ref() {
+                    b"multistatus" => is_multistatus = true,
+                    b"response" => (href, properties) = (None, Properties::default()),
+                    b"propstat" => (propstat, propstat_ok) = (Some(Properties::default()), false),
+                    _ => {}
+                }
+            }
+            Event::Empty(tag) if tag.local_name().as_ref() == b"collection" => {
+                if let Some(propstat) = &mut propstat {
+                    propstat.is_collection = true;
+                }
+            }
+            Event::Text(content) => text.push_str(&content.unescape()?),
+            Event::End(tag) => {
+                let value = text.trim();
+                match (tag.local_name().as_ref(), &mut propstat) {
+                    (b"href", None) => href = Some(value.to_owned()),
+                    (b"status", Some(_)) => propstat_ok = value.split(' ').nth(1) == Some("200"),
+                    (b"getcontentlength", Some(propstat)) => propstat.size = value.parse().ok(),
+                    (b"getcontenttype", Some(propstat)) if !value.is_empty() => {
+                        propstat.content_type = Some(value.to_owned());
+                    }
+                    (b"getlastmodified", Some(propstat)) => {
+                        propstat.modified = DateTime::parse_from_rfc2822(value)
+                            .or_else(|_| DateTime::parse_from_rfc3339(value))
+                            .ok();
+                    }
+                    (b"propstat", Some(_)) => {
+                        if let Some(ok_properties) = propstat.take().filter(|_| propstat_ok) {
+                            properties = ok_properties;
+                        }
+                    }
+                    (b"response", None) => {
+                        let Some(href) = href.take() else {
+                            continue;
+                        };
+                        let properties = mem::take(&mut properties);
+                        let mut url = url.join(&href)?;
+                        if properties.is_collection && !url.path().ends_with('/') {
+                            url.set_path(&format!("{}/", url.path()));
+                        }
+                        entries.push(DavEntry {
+                            url,
+                            is_collection: properties.is_collection,
+                            size: properties.size,
+                            content_type: properties.content_type,
+                            modified: properties.modified,
+                        });
+                    }
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if !is_multistatus {
+        return Err(format!("Not a WebDAV listing: {url}").into());
+    }
+    Ok(entries)
+}
+
+/// Lists the collection at `url` with a `PROPFIND` request of the given `depth`.
+///
+/// # Errors
+/// Fails if the request fails or if the response is invalid.
+fn propfind(
+    agent: &Agent,
+    url: &Url,
+    depth: &str,
+    network: &NetworkOptions,
+) -> Result<Vec<DavEntry>, EBox> {
+    // A request without a body asks for all the properties
+    let content = fetch(
+        network,
+        || agent.request_url("PROPFIND", url).set("Depth", depth),
+        Response::into_string,
+    )?;
+    parse_multistatus(url, &content)
+}
+
+/// Could the resource be a song, according to its MIME type?
+fn is_audio(content_type: &str) -> bool {
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+    mime_type.starts_with("audio/")
+        || matches!(mime_type, "application/ogg" | "application/octet-stream")
+}
+
+/// Returns the songs of the collection at `url` and of its subcollections.
+///
+/// The files are filtered with the [`CrawlOptions`], and the empty files and the files
+/// that aren't audio (according to their MIME type) are skipped.
+///
+/// # Errors
+/// Fails if a collection can't be listed.
+///
+/// # Panics
+/// Panics if a thread that lists a collection panics.
+pub fn get_files(
+    agent: &Agent,
+    url: &Url,
+    options: &CrawlOptions,
+    network: &NetworkOptions,
+) -> Result<Vec<DavEntry>, EBox> {
+    let root = url.join("./")?;
+    let accepts_file = |entry: &DavEntry| {
+        !entry.is_collection
+            && entry.size != Some(0)
+            && entry.content_type.as_deref().is_none_or(is_audio)
+            && relative_path(&root, &entry.url).is_some_and(|path| {
+                // The files of an excluded or too deep collection are skipped
+                path.match_indices('/').count() <= options.max_depth
+                    && path
+                        .match_indices('/')
+                        .all(|(index, _)| options.accepts_folder(&path[..=index]))
+                    && options.accepts_file(&path)
+            })
+    };
+
+    match propfind(agent, &root, "infinity", network) {
+        Ok(mut entries) => {
+            entries.retain(accepts_file);
+            return Ok(entries);
+        }
+        // The server only allows a finite depth
+        Err(err)
+            if matches!(
+                err.downcast_ref(),
+                Some(ureq::Error::Status(400 | 403 | 501, _))
+            ) => {}
+        Err(err) => return Err(err),
+    }
+
+    let mut files = vec![];
+    let mut visited = HashSet::from([root.clone()]);
+    let mut collections = vec![root.clone()];
+    for depth in 0..=options.max_depth {
+        if collections.is_empty() {
+            break;
+        }
+        let results = map_with_workers(&collections, options.workers, |collection| {
+            propfind(agent, collection, "1", network)
+        });
+        collections.clear();
+
+        for result in results {
+            for entry in result? {
+                if !visited.insert(entry.url.clone()) {
+                    continue;
+                }
+                if !entry.is_collection {
+                    if accepts_file(&entry) {
+                        files.push(entry);
+                    }
+                } else if depth < options.max_depth
+                    && relative_path(&root, &entry.url)
+                        .is_some_and(|path| !path.is_empty() && options.accepts_folder(&path))
+                {
+                    collections.push(entry.url);
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Runs the `webdav` command with the given arguments.
+///
+/// # Errors
+/// Fails if the arguments are invalid, if the collection can't be listed
+/// or if the player fails.
+pub fn webdav(args: impl IntoIterator<Item = String>) -> Result<(), EBox> {
+    let Some(args) = args_or_help(args, &format!("{USAGE}\n\n{OPTIONS_USAGE}")) else {
+        return Ok(());
+    };
+    let mut args = args.into_iter().peekable();
+    let url = args.next_if(|arg| !arg.starts_with('-')).ok_or(USAGE)?;
+    let url = Url::parse(&url)?;
+    let options = Options::parse(args)?;
+    let config = AgentConfig::load(
+        &url,
+        &options.tls,
+        options.web_config.as_deref(),
+        options.netrc.as_deref(),
+    )?;
+    let agent = build_agent(&config, &options.network)?;
+
+    let files = get_files(&agent, &url, &options.crawl, &options.network)?;
+    if files.is_empty() {
+        return Err(format!("No song found in {url}").into());
+    }
+    let mut songs = files
+        .iter()
+        .map(|file| Web::new(&file.url, &agent).with_network(options.network))
+        .collect::<Vec<_>>();
+    Player::new(&mut songs)
+        .with_options(options)
+        .with_plugins(secret_plugins())
+        .play()
+}
+
+#[cfg(test)]
+#[expect(clippy::missing_panics_doc)]
+mod tests {
+    use std::{fs, net::TcpListener, path::Path, thread::spawn};
+
+    use chrono::DateTime;
+    use md5::{Digest, Md5};
+    use tiny_http::{Header, Response, Server};
+    use url::Url;
+
+    use super::{get_files, DavEntry};
+    use crate::{
+        agent::{build_agent, AgentConfig, Auth, Credentials},
+        download::NetworkOptions,
+        web_utils::CrawlOptions,
+    };
+
+    /// Returns the responses of a multistatus fixture.
+    fn responses(name: &str) -> String {
+        let content = fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures/webdav")
+                .join(name),
+        )
+        .unwrap();
+        let start = content.find(":response>").unwrap() - 2;
+        let end = content.rfind("</").unwrap();
+        content[start..end].to_owned()
+    }
+
+    /// Returns the value of a parameter of a Digest `Authorization` header.
+    fn parameter<'header>(header: &'header str, name: &str) -> &'header str {
+        let (_, value) = header.split_once(&format!(" {name}=")).unwrap();
+        let value = value.split(',').next().unwrap();
+        value.trim_matches('"')
+    }
+
+    /// Starts a `WebDAV` server that requires the Digest credentials `alice:secret`,
+    /// and that doesn't allow `Depth: infinity` if `finite` is true.
+    fn mock_server(finite: bool) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv(name))
+                        .map(|header| header.value.to_string())
+                        .unwrap_or_default()
+                };
+                let authorization = header("Authorization");
+                let authorized = authorization.starts_with("Digest ") && {
+                    let secret = format!("{:x}", Md5::digest("alice:files:secret"));
+                    let method = format!(
+                        "{:x}",
+                        Md5::digest(format!(
+                            "{}:{}",
+                            request.method(),
+                            parameter(&authorization, "uri")
+                        ))
+                    );
+                    let expected = format!(
+                        "{:x}",
+                        Md5::digest(format!(
+                            "{secret}:nonce:{}:{}:auth:{method}",
+                            parameter(&authorization, "nc"),
+                            parameter(&authorization, "cnonce")
+                        ))
+                    );
+                    parameter(&authorization, "username") == "alice"
+                        && parameter(&authorization, "response") == expected
+                };
+                let response = if !authorized {
+                    Response::from_data("").with_status_code(401).with_header(
+                        Header::from_bytes(
+                            "WWW-Authenticate",
+                            r#"Digest realm="files", qop="auth", nonce="nonce", algorithm=MD5"#,
+                        )
+                        .unwrap(),
+                    )
+                } else if request.method().as_str() != "PROPFIND" {
+                    Response::from_data(format!("song {}", request.url()))
+                } else {
+                    let body = match (header("Depth").as_str(), request.url()) {
+                        ("infinity", _) if finite => None,
+                        ("infinity", "/dav/") => Some(format!(
+                            "{}{}{}",
+                            responses("root.xml"),
+                            responses("music.xml"),
+                            responses("live.xml")
+                        )),
+                        ("1", "/dav/") => Some(responses("root.xml")),
+                        ("1", "/dav/Music/") => Some(responses("music.xml")),
+                        ("1", "/dav/Live/") => Some(responses("live.xml")),
+                        _ => None,
+                    };
+                    match body {
+                        Some(body) => Response::from_data(format!(
+                            r#"<?xml version="1.0" encoding="utf-8"?>
+                            <d:multistatus xmlns:d="DAV:" xmlns:D="DAV:" xmlns:oc="http://owncloud.org/ns">{body}</d:multistatus>"#
+                        ))
+                        .with_status_code(207),
+                        None => Response::from_data("").with_status_code(403),
+                    }
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        Url::parse(&format!("http://{address}/dav/")).unwrap()
+    }
+
+    #[test]
+    fn collections() {
+        let network = NetworkOptions {
+            retries: 0,
+            ..NetworkOptions::default()
+        };
+        let options = CrawlOptions {
+            exclude: vec!["Live/*".to_owned()],
+            ..CrawlOptions::default()
+        };
+        for finite in [false, true] {
+            let url = mock_server(finite);
+            let config = AgentConfig {
+                credentials: vec![Credentials {
+                    host: None,
+                    auth: Auth::Basic {
+                        username: "alice".to_owned(),
+                        password: "secret".to_owned(),
+                    },
+                }],
+                ..AgentConfig::default()
+            };
+            let agent = build_agent(&config, &network).unwrap();
+            let files = get_files(&agent, &url, &options, &network).unwrap();
+            assert_eq!(
+                files,
+                [
+                    DavEntry {
+                        url: url.join("Rock%20%26%20Roll.mp3").unwrap(),
+                        is_collection: false,
+                        size: Some(1234),
+                        content_type: Some("audio/mpeg".to_owned()),
+                        modified: Some(
+                            DateTime::parse_from_rfc3339("2025-01-06T08:00:00Z").unwrap()
+                        ),
+                    },
+                    DavEntry {
+                        url: url.join("Music/Song.flac").unwrap(),
+                        is_collection: false,
+                        size: None,
+                        content_type: Some("audio/flac".to_owned()),
+                        modified: None,
+                    }
+                ]
+            );
+
+            // The songs are fetched with the same credentials
+            assert_eq!(
+                agent
+                    .request_url("GET", &files[1].url)
+                    .call()
+                    .unwrap()
+                    .into_string()
+                    .unwrap(),
+                "song /dav/Music/Song.flac"
+            );
+        }
+    }
+}